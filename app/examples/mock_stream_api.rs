@@ -0,0 +1,152 @@
+//! A local stand-in for the Twitch and YouTube APIs, for trying out stream
+//! shout-outs without real credentials.
+//!
+//! Run it with `cargo run --example mock_stream_api [address]`, then point the bot at it
+//! in the mount/env file:
+//!
+//! ```text
+//! TWITCH_API_URL=http://127.0.0.1:8088/helix
+//! TWITCH_CLIENT_ID=mock
+//! YOUTUBE_API_URL=http://127.0.0.1:8088/youtube/v3
+//! YOUTUBE_API_KEY=mock
+//! ```
+//!
+//! Every channel and video is live, except the Twitch channel `offline` and the video
+//! `offline`. The channel `broken` and the video `broken` get a server error, and `slow`
+//! takes longer to answer than the bot waits.
+//!
+//! The bot's tests start the same mock, with `serve`.
+
+use std::{
+    env,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:8088";
+
+fn main() {
+    let address = env::args().nth(1).unwrap_or_else(|| String::from(DEFAULT_ADDRESS));
+    let listener = TcpListener::bind(&address).expect("Could not bind mock API address");
+    println!("Mock stream API listening on http://{}", address);
+
+    serve(listener);
+}
+
+/// Answer requests on a listener, each on its own thread.
+pub fn serve(listener: TcpListener) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                thread::spawn(move || handle(stream));
+            },
+            Err(why) => eprintln!("Connection failed: {}", why),
+        }
+    }
+}
+
+fn handle(mut stream: TcpStream) {
+    let mut reader = BufReader::new(match stream.try_clone() {
+        Ok(stream) => stream,
+        Err(_) => return,
+    });
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return
+    }
+    // Skip the headers; none of the endpoints care about them
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) if line.trim().is_empty() => break,
+            Ok(_) => (),
+        }
+    }
+
+    let target = request_line.split_whitespace().nth(1).unwrap_or("/");
+    println!("{}", request_line.trim());
+
+    let (status, body) = respond(target);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status, body.len(), body);
+    if let Err(why) = stream.write_all(response.as_bytes()) {
+        eprintln!("Could not send response: {}", why);
+    }
+}
+
+fn respond(target: &str) -> (&'static str, String) {
+    let (path, query) = match target.find('?') {
+        Some(query_start) => (&target[..query_start], &target[query_start + 1..]),
+        None => (target, ""),
+    };
+    let param = |key: &str| query.split('&')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(k), Some(v)) if k == key => Some(String::from(v)),
+                _ => None,
+            }
+        })
+        .next();
+
+    match path {
+        "/helix/streams" => match param("user_login").as_deref() {
+            None => ("400 Bad Request", String::from(r#"{"error":"missing user_login"}"#)),
+            Some(login) => special(login).unwrap_or_else(|| (
+                "200 OK",
+                if login == "offline" {
+                    String::from(r#"{"data":[]}"#)
+                } else {
+                    format!(r#"{{"data":[{{
+                        "user_name":"{0}",
+                        "game_name":"Just Chatting",
+                        "type":"live",
+                        "title":"Mock stream by {0}",
+                        "viewer_count":42,
+                        "started_at":"2019-10-01T18:00:00Z",
+                        "thumbnail_url":"https://static-cdn.jtvnw.net/previews-ttv/live_user_{0}-{{width}}x{{height}}.jpg"
+                    }}]}}"#, login)
+                },
+            )),
+        },
+        "/youtube/v3/videos" => match param("id").as_deref() {
+            None => ("400 Bad Request", String::from(r#"{"error":"missing id"}"#)),
+            Some(video_id) => special(video_id).unwrap_or_else(|| (
+                "200 OK",
+                if video_id == "offline" {
+                    String::from(r#"{"items":[]}"#)
+                } else {
+                    format!(r#"{{"items":[{{
+                        "id":"{0}",
+                        "snippet":{{"thumbnails":{{
+                            "high":{{"url":"https://i.ytimg.com/vi/{0}/hqdefault_live.jpg"}}
+                        }}}},
+                        "liveStreamingDetails":{{
+                            "actualStartTime":"2019-10-01T18:00:00Z",
+                            "concurrentViewers":"1337"
+                        }}
+                    }}]}}"#, video_id)
+                },
+            )),
+        },
+        _ => ("404 Not Found", String::from(r#"{"error":"not found"}"#)),
+    }
+}
+
+/// Responses for IDs that simulate the API misbehaving.
+fn special(id: &str) -> Option<(&'static str, String)> {
+    match id {
+        "broken" => Some(("500 Internal Server Error", String::from(r#"{"error":"broken"}"#))),
+        "slow" => {
+            thread::sleep(Duration::from_secs(10));
+            Some(("200 OK", String::from(r#"{"data":[],"items":[]}"#)))
+        },
+        _ => None,
+    }
+}
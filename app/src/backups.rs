@@ -0,0 +1,130 @@
+//! Scheduled backups of the database, and the checks made before restoring one.
+//!
+//! Backups are copies named `catnip-YYYYMMDD-HHMMSS.db3` in the backup directory,
+//! made with SQLite's online backup API so the bot can keep using the database meanwhile.
+
+use crate::{
+    config::BackupConfig,
+    database::{DatabaseError, Handle, Pool},
+    migrations,
+};
+use rusqlite::{params, Connection, OpenFlags};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+const BACKUP_FILE_PREFIX: &str = "catnip-";
+const BACKUP_FILE_SUFFIX: &str = ".db3";
+
+/// Start a thread that backs up the database every `backup.interval` seconds.
+pub fn spawn_scheduler(pool: Pool, config: BackupConfig) {
+    if config.interval == 0 {
+        info!("Scheduled backups are turned off");
+        return
+    }
+    info!("Backing up the database every {} seconds to {}", config.interval, config.dir);
+
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(config.interval));
+        match pool.get().and_then(|database| backup_now(&database, &config)) {
+            Ok(path) => info!("Backed up the database to {:?}", path),
+            Err(why) => error!("Scheduled backup failed: {}", why),
+        }
+    });
+}
+
+/// Back up the database to a new file in the backup directory,
+/// then delete the oldest backups so that only `backup.keep` are left.
+/// Returns the path of the new backup.
+pub fn backup_now(database: &Handle, config: &BackupConfig) -> Result<PathBuf, DatabaseError> {
+    let dir = Path::new(&config.dir);
+    fs::create_dir_all(dir)?;
+
+    let timestamp = match time::strftime("%Y%m%d-%H%M%S", &time::now_utc()) {
+        Ok(timestamp) => timestamp,
+        Err(why) => return Err(DatabaseError::Backup(format!(
+            "could not format the backup timestamp: {}", why))),
+    };
+    let path = dir.join(format!("{}{}{}", BACKUP_FILE_PREFIX, timestamp, BACKUP_FILE_SUFFIX));
+    if path.exists() {
+        return Err(DatabaseError::Backup(format!(
+            "{:?} already exists, a backup was made less than a second ago", path)))
+    }
+
+    database.backup(&path)?;
+    prune(dir, config.keep)?;
+    Ok(path)
+}
+
+/// The backups in a directory, oldest first.
+pub fn list(dir: &Path) -> Result<Vec<PathBuf>, DatabaseError> {
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_backup = path.file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.starts_with(BACKUP_FILE_PREFIX) && name.ends_with(BACKUP_FILE_SUFFIX))
+            .unwrap_or(false);
+        if is_backup && path.is_file() {
+            backups.push(path);
+        }
+    }
+    // The timestamps in the names sort in the order the backups were made
+    backups.sort();
+    Ok(backups)
+}
+
+/// Delete the oldest backups until only `keep` are left.
+fn prune(dir: &Path, keep: usize) -> Result<(), DatabaseError> {
+    let backups = list(dir)?;
+    if backups.len() <= keep {
+        return Ok(())
+    }
+    for path in &backups[..backups.len() - keep] {
+        info!("Deleting old backup {:?}", path);
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Check that a file can be restored: that it's an intact SQLite database
+/// migrated by a version of catnip with the same migrations as this one.
+/// Returns the file's schema version.
+pub fn check_restorable(path: &Path, migrations_dir: &Path) -> Result<u32, DatabaseError> {
+    if !path.is_file() {
+        return Err(DatabaseError::Backup(format!("{:?} is not a file", path)))
+    }
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let integrity: String = connection.query_row(
+        "PRAGMA integrity_check", params![], |row| row.get(0))?;
+    if integrity != "ok" {
+        return Err(DatabaseError::Backup(format!(
+            "{:?} failed its integrity check: {}", path, integrity)))
+    }
+
+    let version = migrations::user_version(&connection)?;
+    let migrations = migrations::load(migrations_dir)?;
+    if version == 0 {
+        return Err(DatabaseError::Backup(format!("{:?} has no catnip schema", path)))
+    }
+    if version as usize > migrations.len() {
+        return Err(DatabaseError::Backup(format!(
+            "{:?} is at schema version {}, newer than the latest known version {}",
+            path, version, migrations.len())))
+    }
+    for record in migrations::applied(&connection)? {
+        let matches = migrations.get(record.version as usize - 1)
+            .map(|migration| migration.checksum() == record.checksum)
+            .unwrap_or(false);
+        if !matches {
+            return Err(DatabaseError::Backup(format!(
+                "{:?} was migrated with a different {:03}_{}", path, record.version, record.name)))
+        }
+    }
+
+    Ok(version)
+}
@@ -0,0 +1,283 @@
+//! Command-line subcommands for looking after the database without connecting to Discord.
+
+use crate::{
+    backups,
+    commands::{format_timestamp, user::title::validate_title},
+    config::Config,
+    database::{self, DatabaseError},
+    migrations::{self, Direction},
+    title_policy,
+};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+pub const USAGE: &str = "\
+Usage: catnip [COMMAND]
+
+Commands:
+    run                          Start the bot (the default)
+    migrate [--dry-run] [--to N] Migrate the database to the latest or given schema version
+    db status                    Show the schema version and which migrations are applied
+    backup <path>                Copy the database to a new file
+    restore <path>               Replace the database with a backup; stop the bot first
+    user get <id>                Show a user's stored data
+    user set-title <id> [title]  Set a user's title, or clear it if no title is given
+    help                         Show this message";
+
+/// What catnip was asked to do, other than running the bot.
+pub enum Command {
+    Help,
+    Migrate {
+        target: Option<u32>,
+        dry_run: bool,
+    },
+    DbStatus,
+    Backup {
+        path: PathBuf,
+    },
+    Restore {
+        path: PathBuf,
+    },
+    UserGet {
+        user_id: u64,
+    },
+    UserSetTitle {
+        user_id: u64,
+        title: Option<String>,
+    },
+}
+
+/// Why a subcommand failed.
+pub enum CliError {
+    /// The command line couldn't be understood.
+    Usage(String),
+    /// The command was understood but couldn't be carried out.
+    Failed(String),
+    Database(DatabaseError),
+}
+
+impl CliError {
+    /// Exit code for the process: 2 for usage errors, 1 for everything else.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Usage(_) => 2,
+            CliError::Failed(_) | CliError::Database(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Usage(why) => write!(f, "{}\n\n{}", why, USAGE),
+            CliError::Failed(why) => write!(f, "{}", why),
+            CliError::Database(why) => write!(f, "{}", why),
+        }
+    }
+}
+
+impl From<DatabaseError> for CliError {
+    fn from(why: DatabaseError) -> Self {
+        CliError::Database(why)
+    }
+}
+
+/// Parse the command line arguments, not including the program name.
+/// Returns None if the bot should be started.
+pub fn parse(args: &[String]) -> Result<Option<Command>, CliError> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let usage = |why: &str| Err(CliError::Usage(String::from(why)));
+
+    let command = match args.as_slice() {
+        [] | ["run"] => return Ok(None),
+        ["help"] | ["--help"] | ["-h"] => Command::Help,
+        ["migrate", options @ ..] => {
+            let mut target = None;
+            let mut dry_run = false;
+            let mut options = options.iter();
+            while let Some(option) = options.next() {
+                match *option {
+                    "--dry-run" => dry_run = true,
+                    "--to" => match options.next().map(|version| version.parse::<u32>()) {
+                        Some(Ok(version)) => target = Some(version),
+                        _ => return usage("`--to` needs a schema version number"),
+                    },
+                    other => return usage(&format!("unknown migrate option `{}`", other)),
+                }
+            }
+            Command::Migrate { target, dry_run }
+        },
+        ["db", "status"] => Command::DbStatus,
+        ["backup", path] => Command::Backup { path: PathBuf::from(path) },
+        ["backup", ..] => return usage("`backup` needs exactly one path"),
+        ["restore", path] => Command::Restore { path: PathBuf::from(path) },
+        ["restore", ..] => return usage("`restore` needs exactly one path"),
+        ["user", "get", user_id] => Command::UserGet { user_id: parse_user_id(user_id)? },
+        ["user", "set-title", user_id, title @ ..] => Command::UserSetTitle {
+            user_id: parse_user_id(user_id)?,
+            title: if title.is_empty() { None } else { Some(title.join(" ")) },
+        },
+        ["user", ..] => return usage("use `user get <id>` or `user set-title <id> [title]`"),
+        [other, ..] => return usage(&format!("unknown command `{}`", other)),
+    };
+    Ok(Some(command))
+}
+
+fn parse_user_id(user_id: &str) -> Result<u64, CliError> {
+    user_id.parse::<u64>()
+        .map_err(|_| CliError::Usage(format!("`{}` is not a Discord user ID", user_id)))
+}
+
+/// Carry out a command.
+pub fn execute(command: Command, config: &Config) -> Result<(), CliError> {
+    match command {
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+        Command::Migrate { target, dry_run } => migrate(config, target, dry_run),
+        Command::DbStatus => db_status(config),
+        Command::Backup { path } => backup(config, &path),
+        Command::Restore { path } => restore(config, &path),
+        Command::UserGet { user_id } => user_get(config, user_id),
+        Command::UserSetTitle { user_id, title } => user_set_title(config, user_id, title),
+    }
+}
+
+/// Get a handle on the database.
+/// Only migrations may create it; everything else expects an existing database.
+fn open(config: &Config, create: bool) -> Result<database::PooledHandle, CliError> {
+    let path = &config.database.path;
+    if !create && !Path::new(path).exists() {
+        return Err(CliError::Failed(format!(
+            "no database at {}, run `catnip migrate` to create one", path)))
+    }
+    Ok(database::Pool::new(path)?.get()?)
+}
+
+fn migrate(config: &Config, target: Option<u32>, dry_run: bool) -> Result<(), CliError> {
+    let mut database = open(config, true)?;
+    let migrations_dir = Path::new(&config.database.migrations_dir);
+    let steps = database.migrate(migrations_dir, target, dry_run)?;
+    let (version, _) = database.schema_status()?;
+
+    if steps.is_empty() {
+        println!("Nothing to do, the database is at version {}", version);
+        return Ok(())
+    }
+    for step in &steps {
+        println!("{}{}", if dry_run { "would " } else { "" }, step);
+    }
+    match (dry_run, steps.last()) {
+        (true, Some(last)) => println!(
+            "Dry run: the database would be at version {}, it is still at {}",
+            match last.direction {
+                Direction::Up => last.version,
+                Direction::Down => last.version - 1,
+            },
+            version),
+        _ => println!("The database is at version {}", version),
+    }
+    Ok(())
+}
+
+fn db_status(config: &Config) -> Result<(), CliError> {
+    let database = open(config, false)?;
+    let (version, applied) = database.schema_status()?;
+    let migrations = migrations::load(Path::new(&config.database.migrations_dir))?;
+
+    println!("Database: {}", config.database.path);
+    println!("Schema version: {} (latest {})", version, migrations.len());
+    if applied.is_empty() && version > 0 {
+        println!("Applied migrations aren't recorded yet; they will be on the next migration.");
+    }
+
+    let mut problems = 0;
+    for migration in &migrations {
+        let record = applied.iter().find(|record| record.version == migration.version);
+        let state = match record {
+            None if version >= migration.version && applied.is_empty() => String::from("applied"),
+            None => String::from("pending"),
+            Some(record) if record.checksum != migration.checksum() => {
+                problems += 1;
+                String::from("CHANGED since it was applied")
+            },
+            Some(record) => format!("applied {}", format_timestamp(record.applied_at)),
+        };
+        println!("  {:03}_{}: {}", migration.version, migration.name, state);
+    }
+    for record in &applied {
+        if record.version as usize > migrations.len() {
+            problems += 1;
+            println!("  {:03}_{}: applied but NOT FOUND", record.version, record.name);
+        }
+    }
+
+    if problems > 0 {
+        return Err(CliError::Failed(format!(
+            "{} applied migration(s) don't match the migration files", problems)))
+    }
+    Ok(())
+}
+
+fn backup(config: &Config, path: &Path) -> Result<(), CliError> {
+    if path.exists() {
+        return Err(CliError::Failed(format!(
+            "{} already exists, choose a new file for the backup", path.display())))
+    }
+    let database = open(config, false)?;
+    database.backup(path)?;
+    println!("Backed up {} to {}", config.database.path, path.display());
+    Ok(())
+}
+
+fn restore(config: &Config, path: &Path) -> Result<(), CliError> {
+    let migrations_dir = Path::new(&config.database.migrations_dir);
+    let version = backups::check_restorable(path, migrations_dir)?;
+    let mut database = open(config, false)?;
+
+    // Keep what's being replaced, in case the wrong file was restored
+    let safety_copy = backups::backup_now(&database, &config.backup)?;
+    println!("Backed up the current database to {}", safety_copy.display());
+
+    database.restore(path)?;
+    println!("Restored {} (schema version {}) to {}", path.display(), version, config.database.path);
+
+    for step in database.migrate(migrations_dir, None, false)? {
+        println!("{}", step);
+    }
+    let (version, _) = database.schema_status()?;
+    println!("The database is at version {}", version);
+    Ok(())
+}
+
+fn user_get(config: &Config, user_id: u64) -> Result<(), CliError> {
+    let database = open(config, false)?;
+    let user = database.user(user_id)?;
+    match user.title {
+        None => println!("User {} has no title", user_id),
+        Some(title) => println!("User {} has the title {:?}", user_id, title),
+    }
+    Ok(())
+}
+
+fn user_set_title(config: &Config, user_id: u64, title: Option<String>) -> Result<(), CliError> {
+    let title = match title {
+        None => None,
+        Some(title) => match validate_title(&title) {
+            Ok(title) => Some(title),
+            Err(why) => return Err(CliError::Failed(format!("Can't use that title: {}", why))),
+        },
+    };
+
+    let database = open(config, false)?;
+    title_policy::set_title(&database, user_id, None, title.as_deref(), config.title.history_keep)?;
+
+    match title {
+        None => println!("Cleared the title of user {}", user_id),
+        Some(title) => println!("Set the title of user {} to {:?}", user_id, title),
+    }
+    Ok(())
+}
@@ -0,0 +1,433 @@
+use crate::{
+    commands::{
+        change_guild_settings, format_timestamp, message_guild, respond, role_name, update_guild,
+    },
+    config,
+    database,
+    guild_settings::{self, Kind, Setting, SETTINGS},
+};
+use serenity::{
+    prelude::*,
+    model::prelude::*,
+    framework::standard::{
+        Args,
+        CheckResult,
+        CommandError,
+        CommandOptions,
+        CommandResult,
+        macros::{check, command},
+    },
+    utils::MessageBuilder,
+};
+
+/// How many changes `!admin log` shows unless asked for more.
+const DEFAULT_LOG_LENGTH: u32 = 10;
+/// Discord embeds have at most 25 fields, one per change.
+const MAX_LOG_LENGTH: u32 = 25;
+/// Longer text settings are cut short when listed, to keep within Discord's message limits.
+const MAX_LISTED_VALUE_LENGTH: usize = 100;
+const MAX_SHOWN_VALUE_LENGTH: usize = 1000;
+
+/// Passes for members who can manage the guild, and for members with the role
+/// the guild chose for bot admins.
+#[check]
+#[name = "GuildAdmin"]
+fn guild_admin_check(
+    ctx: &mut Context,
+    msg: &Message,
+    _args: &mut Args,
+    _options: &CommandOptions,
+) -> CheckResult {
+    let refusal = "only members who can manage this guild, or who have its bot admin role, \
+        can do that.";
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return CheckResult::new_user(refusal),
+    };
+
+    // The member may not be cached in large guilds, but the message has their roles
+    let message_role_ids = msg.member.as_ref().map(|member| member.roles.clone()).unwrap_or_default();
+    if is_guild_admin(ctx, guild_id, msg.author.id, &message_role_ids) {
+        CheckResult::Success
+    } else {
+        CheckResult::new_user(refusal)
+    }
+}
+
+/// Whether a member can manage the guild, or has the role the guild chose for bot admins.
+/// `role_ids` are used if the member isn't cached.
+pub fn is_guild_admin(ctx: &Context, guild_id: GuildId, user_id: UserId, role_ids: &[RoleId]) -> bool {
+    let guild = match guild_id.to_guild_cached(&ctx.cache) {
+        Some(guild) => guild,
+        None => return false,
+    };
+
+    let (manages_guild, role_ids) = {
+        let guild = guild.read();
+        let role_ids = match guild.members.get(&user_id) {
+            Some(member) => member.roles.clone(),
+            None => role_ids.to_vec(),
+        };
+        let manages_guild = guild.owner_id == user_id
+            || role_ids.iter().chain(Some(&RoleId(guild_id.0)))
+                .filter_map(|role_id| guild.roles.get(role_id))
+                .any(|role| role.permissions.manage_guild() || role.permissions.administrator());
+        (manages_guild, role_ids)
+    };
+    if manages_guild {
+        return true
+    }
+
+    let admin_role_id = match database::handle(ctx).and_then(|database| database.guild(guild_id.0)) {
+        Ok(guild) => guild.admin_role_id,
+        Err(why) => {
+            error!("Could not retrieve guild data from database: {}", why);
+            None
+        },
+    };
+    match admin_role_id {
+        Some(admin_role_id) => role_ids.contains(&RoleId(admin_role_id)),
+        None => false,
+    }
+}
+
+// Command to see and reset every setting of a guild in one place.
+// Only guild admins may use it.
+#[command]
+#[aliases("config")]
+#[description = "Lists the bot's settings for this guild. Members who can manage the guild, \
+and members with its bot admin role, may use it."]
+#[usage = "`!admin`, `!admin show <setting>`, `!admin reset <setting>`, `!admin role @role`, \
+`!admin role none` or `!admin log [count]`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+#[sub_commands(admin_show, admin_reset, admin_role, admin_log)]
+fn admin(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("admin command handler called");
+
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+    let values = setting_values(ctx, guild_id)?;
+
+    let mut response = MessageBuilder::new();
+    response
+        .push_bold_safe(&msg.author)
+        .push(", the bot's settings for this guild:");
+    for (setting, value) in SETTINGS.iter().zip(values) {
+        response
+            .push("\n")
+            .push_mono(setting.name)
+            .push(": ")
+            .push(describe_value(ctx, guild_id, setting, value.as_ref(), MAX_LISTED_VALUE_LENGTH));
+    }
+    response.push("\nUse `!admin show <setting>` for details, \
+        or `!admin reset <setting>` to go back to the default.");
+
+    if let Err(why) = msg.channel_id.say(&ctx.http, response.build()) {
+        error!("Error sending message: {:?}", why);
+    }
+
+    debug!("Listed settings for guild {}", guild_id);
+    Ok(())
+}
+
+#[command("show")]
+#[description = "Shows what a setting does, its value and its default."]
+#[usage = "`!admin show stream.cooldown`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn admin_show(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("admin show command handler called");
+
+    let (guild_id, setting) = match guild_setting(ctx, msg, &args, "show") {
+        Some(found) => found,
+        None => return Ok(()),
+    };
+    let value = setting_value(ctx, guild_id, setting)?;
+
+    let txt = MessageBuilder::new()
+        .push_mono(setting.name)
+        .push(": ")
+        .push_safe(setting.description)
+        .push("\nValue: ")
+        .push(describe_value(ctx, guild_id, setting, value.as_ref(), MAX_SHOWN_VALUE_LENGTH))
+        .push("\nDefault: ")
+        .push(describe_value(ctx, guild_id, setting, None, MAX_SHOWN_VALUE_LENGTH))
+        .build();
+    respond(ctx, msg, &txt);
+
+    Ok(())
+}
+
+#[command("reset")]
+#[description = "Puts a setting back to its default."]
+#[usage = "`!admin reset stream.template`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn admin_reset(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("admin reset command handler called");
+
+    let (guild_id, setting) = match guild_setting(ctx, msg, &args, "reset") {
+        Some(found) => found,
+        None => return Ok(()),
+    };
+    if setting_value(ctx, guild_id, setting)?.is_none() {
+        respond(ctx, msg, &format!("`{}` is already at its default.", setting.name));
+        return Ok(())
+    }
+
+    debug!("Resetting setting {} for guild {}", setting.name, guild_id);
+    change_guild_settings(ctx, msg, guild_id,
+        |database| (setting.reset)(database, guild_id.0))?;
+
+    let txt = MessageBuilder::new()
+        .push_mono(setting.name)
+        .push(" is back to its default: ")
+        .push(describe_value(ctx, guild_id, setting, None, MAX_SHOWN_VALUE_LENGTH))
+        .build();
+    respond(ctx, msg, &txt);
+
+    Ok(())
+}
+
+#[command("role")]
+#[description = "Sets a role whose members may change the bot's settings for this guild, \
+as well as members who can manage the guild."]
+#[usage = "`!admin role @Moderator` or `!admin role none`"]
+#[only_in(guilds)]
+#[required_permissions(MANAGE_GUILD)]
+fn admin_role(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("admin role command handler called");
+
+    let role_id = match args.trimmed().current() {
+        Some("none") => None,
+        _ => match args.single::<RoleId>() {
+            Ok(role_id) => Some(role_id),
+            Err(_) => {
+                // This is a usage error, not a bot failure
+                respond(ctx, msg, "please mention a role; e.g. `!admin role @Moderator`, \
+                    or use `!admin role none`");
+                return Ok(())
+            }
+        },
+    };
+
+    let guild_id = message_guild(ctx, msg)?;
+
+    if let Some(role_id) = role_id {
+        // Only accept roles which belong to this guild
+        let in_guild = match guild_id.to_guild_cached(&ctx.cache) {
+            Some(discord_guild) => discord_guild.read().roles.contains_key(&role_id),
+            None => false,
+        };
+        if !in_guild {
+            respond(ctx, msg, "I can't find that role in this guild.");
+            return Ok(())
+        }
+    }
+
+    update_guild(ctx, msg, guild_id,
+        |guild| guild.admin_role_id = role_id.map(|role_id| role_id.0))?;
+
+    let txt = match role_id {
+        Some(role_id) => MessageBuilder::new()
+            .push("members with the ")
+            .push_bold_safe(role_name(ctx, guild_id, role_id))
+            .push(" role may now change the bot's settings for this guild.")
+            .build(),
+        None => String::from("only members who can manage this guild may change the bot's \
+            settings for it now."),
+    };
+    respond(ctx, msg, &txt);
+
+    Ok(())
+}
+
+#[command("log")]
+#[description = "Shows the most recent changes to the bot's settings for this guild, \
+and who made them."]
+#[usage = "`!admin log` or `!admin log 20`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn admin_log(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("admin log command handler called");
+
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+    let count = if args.rest().trim().is_empty() {
+        DEFAULT_LOG_LENGTH
+    } else {
+        match args.trimmed().single::<u32>() {
+            Ok(count) if (1..=MAX_LOG_LENGTH).contains(&count) => count,
+            _ => {
+                // This is a usage error, not a bot failure
+                respond(ctx, msg, &format!(
+                    "please give a number of changes from 1 to {}; e.g. `!admin log 20`",
+                    MAX_LOG_LENGTH));
+                return Ok(())
+            }
+        }
+    };
+
+    debug!("Settings audit log DB data retrieval...");
+    let database = database::handle(ctx)?;
+    let changes = match database.settings_audit(guild_id.0, count) {
+        Ok(changes) => changes,
+        Err(why) => {
+            let reason = format!("Could not retrieve settings audit log from database: {}", why);
+            error!("{}", reason);
+            return Err(CommandError(reason))
+        }
+    };
+
+    if changes.is_empty() {
+        respond(ctx, msg, "no settings have been changed in this guild yet.");
+        return Ok(())
+    }
+
+    if let Err(why) = msg.channel_id.send_message(&ctx.http, |m| {
+        m.embed(|e| {
+            e.title("Recent changes to settings");
+            for change in &changes {
+                let user_name = match ctx.cache.read().user(change.user_id) {
+                    Some(user) => user.read().tag(),
+                    None => format!("user {}", change.user_id),
+                };
+                // Settings no longer known are shown as they were stored
+                let description = match guild_settings::find(&change.setting) {
+                    Some(setting) => format!("{} → {}",
+                        describe_value(ctx, guild_id, setting, change.old_value.as_ref(),
+                                       MAX_LISTED_VALUE_LENGTH),
+                        describe_value(ctx, guild_id, setting, change.new_value.as_ref(),
+                                       MAX_LISTED_VALUE_LENGTH)),
+                    None => MessageBuilder::new()
+                        .push_mono_safe(change.old_value.as_ref().map_or("default", String::as_str))
+                        .push(" → ")
+                        .push_mono_safe(change.new_value.as_ref().map_or("default", String::as_str))
+                        .build(),
+                };
+                e.field(
+                    format!("{} by {} at {}",
+                            change.setting, user_name, format_timestamp(change.timestamp)),
+                    description,
+                    false);
+            }
+            e
+        })
+    }) {
+        error!("Error sending message: {:?}", why);
+    }
+
+    Ok(())
+}
+
+/// Find the setting named in a command's arguments.
+/// Explain to the user what's wrong otherwise.
+fn guild_setting(
+    ctx: &Context,
+    msg: &Message,
+    args: &Args,
+    subcommand: &str,
+) -> Option<(GuildId, &'static Setting)> {
+    let guild_id = msg.guild_id?;
+    let name = args.rest().trim();
+    if name.is_empty() {
+        // This is a usage error, not a bot failure
+        respond(ctx, msg, &format!(
+            "please name a setting; e.g. `!admin {} stream.cooldown`. \
+             `!admin` lists them all.", subcommand));
+        return None
+    }
+    match guild_settings::find(name) {
+        Some(setting) => Some((guild_id, setting)),
+        None => {
+            let txt = MessageBuilder::new()
+                .push("there's no setting called ")
+                .push_mono_safe(name)
+                .push(". `!admin` lists them all.")
+                .build();
+            respond(ctx, msg, &txt);
+            None
+        },
+    }
+}
+
+fn setting_values(ctx: &Context, guild_id: GuildId) -> Result<Vec<Option<String>>, CommandError> {
+    debug!("Guild settings DB data retrieval...");
+    let database = database::handle(ctx)?;
+    match guild_settings::values(&database, guild_id.0) {
+        Ok(values) => Ok(values),
+        Err(why) => {
+            let reason = format!("Could not retrieve guild settings from database: {}", why);
+            error!("{}", reason);
+            Err(CommandError(reason))
+        }
+    }
+}
+
+fn setting_value(
+    ctx: &Context,
+    guild_id: GuildId,
+    setting: &Setting,
+) -> Result<Option<String>, CommandError> {
+    debug!("Guild setting {} DB data retrieval...", setting.name);
+    let database = database::handle(ctx)?;
+    match guild_settings::value(&database, guild_id.0, setting) {
+        Ok(value) => Ok(value),
+        Err(why) => {
+            let reason = format!("Could not retrieve guild setting from database: {}", why);
+            error!("{}", reason);
+            Err(CommandError(reason))
+        }
+    }
+}
+
+/// Describe the value of a setting for a message, or its default if the value is None.
+/// Roles are named rather than mentioned, so that showing them doesn't notify anyone.
+fn describe_value(
+    ctx: &Context,
+    guild_id: GuildId,
+    setting: &Setting,
+    value: Option<&String>,
+    max_length: usize,
+) -> String {
+    let value = match value {
+        Some(value) => value,
+        None => return MessageBuilder::new()
+            .push_safe(shorten((setting.default)(&config::get(ctx)), max_length))
+            .push(" (default)")
+            .build(),
+    };
+
+    let mut description = MessageBuilder::new();
+    match setting.kind {
+        Kind::Text => description.push_mono_safe(shorten(value.clone(), max_length)),
+        Kind::Number | Kind::Switch => description.push_bold_safe(value),
+        Kind::Seconds => description.push_bold_safe(format!("{} seconds", value)),
+        Kind::Channel => match value.parse::<u64>() {
+            Ok(channel_id) => description.mention(&ChannelId(channel_id)),
+            Err(_) => description.push_safe(value),
+        },
+        Kind::Role | Kind::Roles => description.push_bold_safe(value.split(',')
+            .map(|role_id| match role_id.parse::<u64>() {
+                Ok(role_id) => role_name(ctx, guild_id, RoleId(role_id)),
+                Err(_) => String::from(role_id),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")),
+    };
+    description.build()
+}
+
+/// Cut text down to a number of characters, marking where it was cut.
+fn shorten(mut text: String, max_length: usize) -> String {
+    if let Some((index, _)) = text.char_indices().nth(max_length) {
+        text.truncate(index);
+        text.push('…');
+    }
+    text
+}
@@ -19,13 +19,11 @@ fn cat(context: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
     debug!("Message: {:?}", msg);
     debug!("Args: {:?}", args);
 
-    let mut resp;
-
-    if !args.is_empty() {
-        resp = get_cat(args.single::<String>().unwrap());
+    let resp = if !args.is_empty() {
+        get_cat(args.single::<String>().unwrap())
     } else {
-        resp = get_cat(String::from("search"));
-    }
+        get_cat(String::from("search"))
+    };
 
     let cat = resp.unwrap();
 
@@ -45,8 +43,8 @@ fn cat(context: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
 fn get_cat(id: std::string::String) -> Result<models::Cat, reqwest::Error> {
 
     let baseurl = "https://api.thecatapi.com/v1/images/";
-    let mut callurl;
-    let mut resp;
+    let callurl;
+    let resp;
 
     if !id.eq("search") {
         callurl = format!("{}{}", baseurl, id);
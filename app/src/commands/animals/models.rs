@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod models {
     use serde::Deserialize;
 
@@ -6,6 +7,7 @@ pub mod models {
         pub cat: Cat
     }
 
+    #[allow(dead_code)]
     #[derive(Debug, Deserialize)]
     pub struct Cat {
         #[serde(default)]
@@ -0,0 +1,56 @@
+use crate::{
+    backups,
+    commands::respond,
+    config,
+    database,
+};
+use serenity::{
+    prelude::*,
+    model::prelude::*,
+    framework::standard::{
+        CommandError,
+        CommandResult,
+        macros::command,
+    },
+};
+
+// Command for the bot's owner to back up the database between scheduled backups.
+#[command]
+#[description = "Backs up the database."]
+#[usage = "`!backup now`"]
+#[owners_only]
+#[sub_commands(backup_now)]
+fn backup(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("backup command handler called");
+
+    respond(ctx, msg, "use `!backup now` to back up the database.");
+
+    // This is a usage error, not a bot failure
+    Ok(())
+}
+
+#[command("now")]
+#[description = "Backs up the database now, keeping the newest backups."]
+#[owners_only]
+fn backup_now(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("backup now command handler called");
+
+    let database = database::handle(ctx)?;
+    let path = match backups::backup_now(&database, &config::get(ctx).backup) {
+        Ok(path) => path,
+        Err(why) => {
+            let reason = format!("Could not back up database: {}", why);
+            error!("{}", reason);
+            respond(ctx, msg, "sorry, the backup failed.");
+            return Err(CommandError(reason))
+        },
+    };
+    info!("Backed up the database to {:?}", path);
+
+    let file_name = path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    respond(ctx, msg, &format!("backed up the database to `{}`.", file_name));
+
+    Ok(())
+}
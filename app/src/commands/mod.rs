@@ -1,4 +1,147 @@
+pub mod admin;
 pub mod general;
+pub mod prefix;
 pub mod animals;
 pub mod roll;
-pub mod user;
\ No newline at end of file
+pub mod stream;
+pub mod user;
+pub mod backup;
+
+use crate::{
+    database::{self, DatabaseError},
+    guild_settings,
+};
+use serenity::{
+    prelude::*,
+    model::prelude::*,
+    framework::standard::{Args, CommandError},
+    utils::MessageBuilder,
+};
+
+/// Sends a response to a user's message
+pub fn respond(ctx: &Context, msg: &Message, txt: &str) {
+    let response = MessageBuilder::new()
+        .push_bold_safe(&msg.author)
+        .push(", ")
+        .push(txt)
+        .build();
+
+    if let Err(why) = msg.channel_id.say(&ctx.http, &response) {
+        error!("Error sending message: {:?}", why);
+    }
+}
+
+/// A command's remaining arguments, with the quotes taken off quoted ones,
+/// so that an argument in quotes can contain spaces and delimiters.
+pub fn arguments(args: &mut Args) -> Vec<String> {
+    let mut arguments = Vec::new();
+    while !args.is_empty() {
+        // Serenity can't take the quotes off a lone quote mark
+        if args.current() != Some("\"") {
+            args.quoted();
+        }
+        arguments.push(String::from(args.current().unwrap_or_default()));
+        args.advance();
+    }
+    arguments
+}
+
+/// The name of a role in a guild, for showing without mentioning the role.
+pub fn role_name(ctx: &Context, guild_id: GuildId, role_id: RoleId) -> String {
+    match guild_id.to_guild_cached(&ctx.cache) {
+        Some(guild) => match guild.read().roles.get(&role_id) {
+            Some(role) => role.name.clone(),
+            None => String::from("a deleted role"),
+        },
+        None => role_id.to_string(),
+    }
+}
+
+/// A Unix timestamp as a date and time in UTC, e.g. `2019-10-01 18:00:00 UTC`.
+pub fn format_timestamp(timestamp: i64) -> String {
+    match time::at_utc(time::Timespec::new(timestamp, 0)).strftime("%Y-%m-%d %H:%M:%S UTC") {
+        Ok(formatted) => formatted.to_string(),
+        Err(_) => timestamp.to_string(),
+    }
+}
+
+/// The guild a message was sent in, telling the member if there isn't one.
+pub fn message_guild(ctx: &Context, msg: &Message) -> Result<GuildId, CommandError> {
+    match msg.guild_id {
+        Some(guild_id) => Ok(guild_id),
+        None => {
+            let txt = "we couldn't find which guild this came from. Sorry!";
+            respond(ctx, msg, txt);
+            Err(CommandError(String::from(txt)))
+        }
+    }
+}
+
+/// Loads the settings of the guild a message was sent in.
+pub fn load_guild(
+    ctx: &mut Context,
+    msg: &Message,
+) -> Result<(GuildId, database::Guild), CommandError>
+{
+    let guild_id = message_guild(ctx, msg)?;
+
+    debug!("Guild DB data retrieval...");
+    let database = database::handle(ctx)?;
+    match database.guild(guild_id.0) {
+        Ok(guild) => Ok((guild_id, guild)),
+        Err(why) => {
+            let reason = format!("Could not retrieve guild data from database: {}", why);
+            error!("{}", reason);
+            Err(CommandError(reason))
+        }
+    }
+}
+
+/// Changes the settings of a guild, telling the member if that fails.
+/// The guild is loaded in the same transaction as it's saved in,
+/// so that changes made to other settings at the same time are kept.
+/// Returns the settings as they were saved.
+pub fn update_guild<F>(
+    ctx: &mut Context,
+    msg: &Message,
+    guild_id: GuildId,
+    update: F,
+) -> Result<database::Guild, CommandError>
+where F: FnOnce(&mut database::Guild)
+{
+    debug!("Updating guild DB entry...");
+    let guild = change_guild_settings(ctx, msg, guild_id, |database| {
+        let mut guild = database.guild(guild_id.0)?;
+        update(&mut guild);
+        database.guild_update(guild_id.0, &guild)?;
+        Ok(guild)
+    })?;
+    debug!("Updated guild settings in database to {:?}", guild);
+
+    Ok(guild)
+}
+
+/// Changes the settings of a guild on behalf of the author of a message,
+/// recording what changed in the audit log. Tells the member if that fails.
+pub fn change_guild_settings<T, F>(
+    ctx: &mut Context,
+    msg: &Message,
+    guild_id: GuildId,
+    changes: F,
+) -> Result<T, CommandError>
+where F: FnOnce(&database::Handle) -> Result<T, DatabaseError>
+{
+    let database = database::handle(ctx)?;
+    let changed = guild_settings::change(&database, guild_id.0, msg.author.id.0, changes);
+    // Whatever changed, the prefix may have
+    prefix::forget_prefix(ctx, guild_id);
+    match changed {
+        Ok(result) => Ok(result),
+        Err(why) => {
+            respond(ctx, msg, "something went wrong when saving the settings. Sorry!");
+            let reason = format!("Could not update guild settings in database: {}", why);
+            error!("{}", reason);
+            Err(CommandError(reason))
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use crate::{
+    commands::{admin::GUILDADMIN_CHECK, load_guild, message_guild, respond, update_guild},
+    config,
+    database,
+};
+use serenity::{
+    prelude::*,
+    model::prelude::*,
+    framework::standard::{
+        Args,
+        CommandResult,
+        macros::command,
+    },
+};
+use std::collections::HashMap;
+
+/// Longest prefix a guild may choose.
+const MAX_PREFIX_LENGTH: usize = 8;
+
+/// Key to find the prefix each guild chose in the client's data, or None for guilds
+/// using the default. A guild's entry is forgotten whenever its settings change.
+pub struct PrefixCacheKey;
+
+impl TypeMapKey for PrefixCacheKey {
+    type Value = HashMap<GuildId, Option<String>>;
+}
+
+/// The prefix for commands in the channel a message was sent in:
+/// the guild's own prefix if it has one, or else the bot-wide prefix.
+///
+/// Used as the framework's dynamic prefix hook, so it runs for every message;
+/// guilds' prefixes are kept in the client's data rather than read from the database each time.
+/// Mentioning the bot works as a prefix regardless.
+pub fn dynamic_prefix(ctx: &mut Context, msg: &Message) -> Option<String> {
+    let default_prefix = config::get(ctx).bot.prefix.clone();
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Some(default_prefix),
+    };
+
+    if let Some(prefix) = ctx.data.read().get::<PrefixCacheKey>()
+        .and_then(|cache| cache.get(&guild_id))
+    {
+        return Some(prefix.clone().unwrap_or(default_prefix))
+    }
+
+    let guild_prefix = database::handle(ctx)
+        .and_then(|database| database.guild(guild_id.0));
+    match guild_prefix {
+        Ok(guild) => {
+            if let Some(cache) = ctx.data.write().get_mut::<PrefixCacheKey>() {
+                cache.insert(guild_id, guild.command_prefix.clone());
+            }
+            Some(guild.command_prefix.unwrap_or(default_prefix))
+        },
+        Err(why) => {
+            error!("Could not retrieve guild prefix from database: {}", why);
+            Some(default_prefix)
+        },
+    }
+}
+
+/// Forget the prefix of a guild, so that it's read from the database next time.
+/// Called whenever the guild's settings change.
+pub fn forget_prefix(ctx: &Context, guild_id: GuildId) {
+    if let Some(cache) = ctx.data.write().get_mut::<PrefixCacheKey>() {
+        cache.remove(&guild_id);
+    }
+}
+
+// Command to view and change the command prefix of a guild.
+#[command]
+#[description = "Shows the prefix for commands in this guild. \
+Mentioning the bot instead of the prefix always works too."]
+#[usage = "`!prefix`, `!prefix set ?` or `!prefix reset`"]
+#[only_in(guilds)]
+#[sub_commands(prefix_set, prefix_reset)]
+fn prefix(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("prefix command handler called");
+
+    let (_, guild) = load_guild(ctx, msg)?;
+    let txt = match guild.command_prefix {
+        Some(prefix) => format!("commands in this guild start with `{}`.", prefix),
+        None => format!("commands in this guild start with `{}`, the default.",
+                        config::get(ctx).bot.prefix),
+    };
+    respond(ctx, msg, &txt);
+
+    Ok(())
+}
+
+#[command("set")]
+#[description = "Sets the prefix for commands in this guild."]
+#[usage = "`!prefix set ?`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn prefix_set(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("prefix set command handler called");
+
+    let prefix = args.rest().trim();
+    if prefix.is_empty() {
+        // This is a usage error, not a bot failure
+        respond(ctx, msg, "please give a prefix; e.g. `!prefix set ?`");
+        return Ok(())
+    }
+    if prefix.contains(char::is_whitespace) || prefix.contains('`')
+        || prefix.chars().count() > MAX_PREFIX_LENGTH
+    {
+        respond(ctx, msg, &format!(
+            "a prefix can be up to {} characters, with no spaces or backticks.",
+            MAX_PREFIX_LENGTH));
+        return Ok(())
+    }
+
+    let guild_id = message_guild(ctx, msg)?;
+    update_guild(ctx, msg, guild_id, |guild| guild.command_prefix = Some(String::from(prefix)))?;
+
+    respond(ctx, msg, &format!(
+        "commands in this guild now start with `{}`; e.g. `{}help`. \
+         Mentioning me works too, if you forget it.",
+        prefix, prefix));
+
+    Ok(())
+}
+
+#[command("reset")]
+#[description = "Goes back to the default prefix for commands in this guild."]
+#[usage = "`!prefix reset`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn prefix_reset(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("prefix reset command handler called");
+
+    let guild_id = message_guild(ctx, msg)?;
+    update_guild(ctx, msg, guild_id, |guild| guild.command_prefix = None)?;
+
+    respond(ctx, msg, &format!(
+        "commands in this guild start with the default prefix `{}` again.",
+        config::get(ctx).bot.prefix));
+
+    Ok(())
+}
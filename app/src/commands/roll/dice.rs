@@ -0,0 +1,1128 @@
+//! Parser and evaluator for dice expressions such as `2d6+3`, `4d6kh3` or `1d20+1d4-1`.
+//!
+//! An expression is a sum of terms. Each term is either a constant or a group of
+//! same-sided dice, optionally followed by modifiers:
+//!
+//! - `kh<n>`/`k<n>` and `kl<n>` keep the highest or lowest `n` dice
+//! - `dh<n>` and `dl<n>`/`d<n>` drop the highest or lowest `n` dice
+//! - `!` explodes dice on their highest face, `!<cmp>` on any matching value
+//! - `r<cmp>` rerolls matching dice until they stop matching, `ro<cmp>` rerolls them once
+//! - `<cmp>` on its own counts the dice that match it as successes instead of summing them
+//!
+//! where `<cmp>` is a number, optionally preceded by `<`, `<=`, `>` or `>=`.
+//! `adv` and `dis` are shorthands for `2d20kh1` and `2d20kl1`.
+//!
+//! Any other word is looked up as a macro and replaced by the terms of the
+//! expression it stands for.
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Largest number of dice that may be rolled in one group, e.g. the 100 in `100d6`.
+const MAX_DICE: u32 = 100;
+/// Largest number of sides a die may have.
+const MAX_SIDES: u32 = 1000;
+/// Largest number of terms in one expression.
+const MAX_TERMS: usize = 20;
+/// Largest absolute value of a constant term.
+const MAX_CONSTANT: i64 = 100_000;
+/// How many extra dice a single die may produce by exploding.
+const MAX_EXPLOSIONS: u32 = 100;
+/// How many times a single die may be rerolled.
+const MAX_REROLLS: u32 = 100;
+/// How deeply macros may refer to other macros.
+const MAX_MACRO_DEPTH: usize = 10;
+
+/// Words with a built-in meaning, which can't be used as macro names.
+const SHORTHANDS: &[(&str, Selection)] = &[
+    ("advantage", Selection::KeepHighest(1)),
+    ("disadvantage", Selection::KeepLowest(1)),
+    ("adv", Selection::KeepHighest(1)),
+    ("dis", Selection::KeepLowest(1)),
+];
+
+/// Everything that can be wrong with a dice expression.
+/// The `Display` implementation is meant to be shown to the user.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RollError {
+    Empty,
+    UnexpectedEnd,
+    UnexpectedCharacter(char, usize),
+    NumberTooLarge(usize),
+    TooManyTerms,
+    NoDice,
+    TooManyDice(u32),
+    NoSides,
+    TooManySides(u32),
+    ConstantTooLarge(i64),
+    DuplicateModifier(&'static str),
+    SelectsTooMany(u32, u32),
+    AlwaysExplodes,
+    AlwaysRerolls,
+    ImpossibleComparison(u32),
+    UnknownMacro(String),
+    MacroCycle(Vec<String>),
+    MacroTooDeep,
+    InMacro(String, Box<RollError>),
+}
+
+impl fmt::Display for RollError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RollError::Empty =>
+                write!(f, "there's nothing to roll"),
+            RollError::UnexpectedEnd =>
+                write!(f, "the expression ends too early"),
+            RollError::UnexpectedCharacter(c, position) =>
+                write!(f, "I didn't expect `{}` at position {}", c, position + 1),
+            RollError::NumberTooLarge(position) =>
+                write!(f, "the number at position {} is too large", position + 1),
+            RollError::TooManyTerms =>
+                write!(f, "that's too many terms, the limit is {}", MAX_TERMS),
+            RollError::NoDice =>
+                write!(f, "you need to roll at least one die"),
+            RollError::TooManyDice(count) =>
+                write!(f, "{} dice is too many, the limit is {}", count, MAX_DICE),
+            RollError::NoSides =>
+                write!(f, "dice need at least one side"),
+            RollError::TooManySides(sides) =>
+                write!(f, "a d{} has too many sides, the limit is {}", sides, MAX_SIDES),
+            RollError::ConstantTooLarge(value) =>
+                write!(f, "{} is too large, the limit is {}", value, MAX_CONSTANT),
+            RollError::DuplicateModifier(name) =>
+                write!(f, "a dice group can only have one {} modifier", name),
+            RollError::SelectsTooMany(selected, count) =>
+                write!(f, "can't keep or drop {} of only {} dice", selected, count),
+            RollError::AlwaysExplodes =>
+                write!(f, "those dice would explode on every roll"),
+            RollError::AlwaysRerolls =>
+                write!(f, "those dice would be rerolled on every roll"),
+            RollError::ImpossibleComparison(value) =>
+                write!(f, "no die in that group can roll {}", value),
+            RollError::UnknownMacro(name) =>
+                write!(f, "there's no macro called `{}`", name),
+            RollError::MacroCycle(names) =>
+                write!(f, "macros can't refer back to themselves ({})", names.join(" → ")),
+            RollError::MacroTooDeep =>
+                write!(f, "macros can only refer {} levels deep", MAX_MACRO_DEPTH),
+            RollError::InMacro(name, why) =>
+                write!(f, "in macro `{}`, {}", name, why),
+        }
+    }
+}
+
+/// A comparison against the value of a single die.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Compare {
+    Equal(u32),
+    Less(u32),
+    LessOrEqual(u32),
+    Greater(u32),
+    GreaterOrEqual(u32),
+}
+
+impl Compare {
+    pub fn matches(self, value: u32) -> bool {
+        match self {
+            Compare::Equal(n) => value == n,
+            Compare::Less(n) => value < n,
+            Compare::LessOrEqual(n) => value <= n,
+            Compare::Greater(n) => value > n,
+            Compare::GreaterOrEqual(n) => value >= n,
+        }
+    }
+
+    /// Number of faces of a die with `sides` sides that match this comparison.
+    fn matching_faces(self, sides: u32) -> u32 {
+        (1..=sides).filter(|&face| self.matches(face)).count() as u32
+    }
+}
+
+impl fmt::Display for Compare {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compare::Equal(n) => write!(f, "{}", n),
+            Compare::Less(n) => write!(f, "<{}", n),
+            Compare::LessOrEqual(n) => write!(f, "<={}", n),
+            Compare::Greater(n) => write!(f, ">{}", n),
+            Compare::GreaterOrEqual(n) => write!(f, ">={}", n),
+        }
+    }
+}
+
+/// Which dice of a group count towards its result.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Selection {
+    KeepHighest(u32),
+    KeepLowest(u32),
+    DropHighest(u32),
+    DropLowest(u32),
+}
+
+impl Selection {
+    /// Number of dice this selection keeps or drops.
+    fn count(self) -> u32 {
+        match self {
+            Selection::KeepHighest(n)
+            | Selection::KeepLowest(n)
+            | Selection::DropHighest(n)
+            | Selection::DropLowest(n) => n,
+        }
+    }
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Selection::KeepHighest(n) => write!(f, "kh{}", n),
+            Selection::KeepLowest(n) => write!(f, "kl{}", n),
+            Selection::DropHighest(n) => write!(f, "dh{}", n),
+            Selection::DropLowest(n) => write!(f, "dl{}", n),
+        }
+    }
+}
+
+/// Rerolling of dice that match a comparison.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Reroll {
+    pub compare: Compare,
+    /// Reroll only once, keeping the second result even if it matches again.
+    pub once: bool,
+}
+
+/// A number of same-sided dice together with their modifiers, e.g. `4d6kh3`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DiceGroup {
+    pub count: u32,
+    pub sides: u32,
+    pub selection: Option<Selection>,
+    pub explode: Option<Compare>,
+    pub reroll: Option<Reroll>,
+    /// When set, the group counts successes instead of summing its dice.
+    pub target: Option<Compare>,
+}
+
+impl fmt::Display for DiceGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}d{}", self.count, self.sides)?;
+        if let Some(selection) = self.selection {
+            write!(f, "{}", selection)?;
+        }
+        if let Some(explode) = self.explode {
+            match explode {
+                Compare::Equal(n) if n == self.sides => write!(f, "!")?,
+                _ => write!(f, "!{}", explode)?,
+            }
+        }
+        if let Some(reroll) = self.reroll {
+            write!(f, "{}{}", if reroll.once { "ro" } else { "r" }, reroll.compare)?;
+        }
+        if let Some(target) = self.target {
+            write!(f, "{}", target)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Term {
+    Dice(DiceGroup),
+    Constant(i64),
+}
+
+/// A parsed dice expression: a sum of terms, each of which is added or subtracted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expression {
+    pub terms: Vec<(bool, Term)>,
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (negative, term)) in self.terms.iter().enumerate() {
+            if *negative {
+                write!(f, "-")?;
+            } else if i > 0 {
+                write!(f, "+")?;
+            }
+            match term {
+                Term::Dice(group) => write!(f, "{}", group)?,
+                Term::Constant(value) => write!(f, "{}", value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The outcome of a single die.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Die {
+    pub value: u32,
+    /// Earlier results of this die that were rerolled, oldest first.
+    pub rerolled: Vec<u32>,
+    /// Whether this die was added because the die before it exploded.
+    pub exploded: bool,
+    /// Whether this die counts towards the result of its group.
+    pub kept: bool,
+}
+
+/// The outcome of rolling a dice group.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GroupResult {
+    pub group: DiceGroup,
+    /// Every die rolled, in the order they were rolled.
+    pub dice: Vec<Die>,
+    /// Sum of the kept dice, or the number of successes if the group has a target.
+    pub value: i64,
+}
+
+impl GroupResult {
+    /// Whether this die is a success against the group's target number.
+    pub fn is_success(&self, die: &Die) -> bool {
+        match self.group.target {
+            Some(target) => die.kept && target.matches(die.value),
+            None => false,
+        }
+    }
+
+    /// Whether a kept d20 came up 20.
+    pub fn is_critical(&self) -> bool {
+        self.group.sides == 20 && self.dice.iter().any(|die| die.kept && die.value == 20)
+    }
+
+    /// Whether a kept d20 came up 1.
+    pub fn is_fumble(&self) -> bool {
+        self.group.sides == 20 && self.dice.iter().any(|die| die.kept && die.value == 1)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TermResult {
+    Dice(GroupResult),
+    Constant(i64),
+}
+
+/// The outcome of rolling a whole expression.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RollResult {
+    pub expression: Expression,
+    /// The result of each term, with whether it was subtracted.
+    pub terms: Vec<(bool, TermResult)>,
+    pub total: i64,
+}
+
+impl RollResult {
+    pub fn is_critical(&self) -> bool {
+        self.groups().any(GroupResult::is_critical)
+    }
+
+    pub fn is_fumble(&self) -> bool {
+        self.groups().any(GroupResult::is_fumble)
+    }
+
+    /// The dice groups of the roll, leaving out constants.
+    pub fn groups(&self) -> impl Iterator<Item = &GroupResult> {
+        self.terms.iter().filter_map(|(_, term)| match term {
+            TermResult::Dice(group) => Some(group),
+            TermResult::Constant(_) => None,
+        })
+    }
+
+    /// Sum of the constant terms.
+    pub fn modifier(&self) -> i64 {
+        self.terms.iter()
+            .map(|(negative, term)| match term {
+                TermResult::Constant(value) if *negative => -value,
+                TermResult::Constant(value) => *value,
+                TermResult::Dice(_) => 0,
+            })
+            .sum()
+    }
+}
+
+/// A source of die results.
+///
+/// Every `rand::Rng` is one, so rolls can be made repeatable by using a seeded RNG.
+pub trait DiceRng {
+    /// Roll a single die, giving a value from 1 to `sides`.
+    fn roll_die(&mut self, sides: u32) -> u32;
+}
+
+impl<R: Rng + ?Sized> DiceRng for R {
+    fn roll_die(&mut self, sides: u32) -> u32 {
+        self.gen_range(1, sides + 1)
+    }
+}
+
+impl Expression {
+    /// Roll every dice group in the expression.
+    pub fn roll(&self, rng: &mut dyn DiceRng) -> RollResult {
+        let mut total = 0;
+        let terms = self.terms.iter()
+            .map(|(negative, term)| {
+                let result = match term {
+                    Term::Dice(group) => TermResult::Dice(group.roll(rng)),
+                    Term::Constant(value) => TermResult::Constant(*value),
+                };
+                let value = match &result {
+                    TermResult::Dice(group) => group.value,
+                    TermResult::Constant(value) => *value,
+                };
+                total += if *negative { -value } else { value };
+                (*negative, result)
+            })
+            .collect();
+
+        RollResult {
+            expression: self.clone(),
+            terms,
+            total,
+        }
+    }
+}
+
+impl DiceGroup {
+    fn roll(&self, rng: &mut dyn DiceRng) -> GroupResult {
+        let mut dice: Vec<Die> = Vec::new();
+        for _ in 0..self.count {
+            let mut die = self.roll_die(rng);
+            let mut value = die.value;
+            dice.push(die);
+
+            if let Some(explode) = self.explode {
+                let mut explosions = 0;
+                while explode.matches(value) && explosions < MAX_EXPLOSIONS {
+                    die = self.roll_die(rng);
+                    die.exploded = true;
+                    value = die.value;
+                    dice.push(die);
+                    explosions += 1;
+                }
+            }
+        }
+
+        self.select(&mut dice);
+        let kept_values = dice.iter()
+            .filter(|die| die.kept)
+            .map(|die| die.value);
+
+        let value = match self.target {
+            Some(target) => kept_values.filter(|&value| target.matches(value)).count() as i64,
+            None => kept_values.map(i64::from).sum(),
+        };
+
+        GroupResult {
+            group: self.clone(),
+            dice,
+            value,
+        }
+    }
+
+    /// Roll a single die, applying any reroll modifier.
+    fn roll_die(&self, rng: &mut dyn DiceRng) -> Die {
+        let mut value = rng.roll_die(self.sides);
+        let mut rerolled = Vec::new();
+        if let Some(reroll) = self.reroll {
+            while reroll.compare.matches(value) && rerolled.len() < MAX_REROLLS as usize {
+                rerolled.push(value);
+                value = rng.roll_die(self.sides);
+                if reroll.once {
+                    break
+                }
+            }
+        }
+        Die {
+            value,
+            rerolled,
+            exploded: false,
+            kept: true,
+        }
+    }
+
+    /// Mark which of the rolled dice count towards the result.
+    fn select(&self, dice: &mut [Die]) {
+        let total = dice.len() as u32;
+        let (highest_first, keep) = match self.selection {
+            None => return,
+            Some(Selection::KeepHighest(n)) => (true, n.min(total)),
+            Some(Selection::KeepLowest(n)) => (false, n.min(total)),
+            Some(Selection::DropHighest(n)) => (false, total - n.min(total)),
+            Some(Selection::DropLowest(n)) => (true, total - n.min(total)),
+        };
+
+        let mut order: Vec<usize> = (0..dice.len()).collect();
+        if highest_first {
+            order.sort_by(|&a, &b| dice[b].value.cmp(&dice[a].value));
+        } else {
+            order.sort_by(|&a, &b| dice[a].value.cmp(&dice[b].value));
+        }
+
+        for die in dice.iter_mut() {
+            die.kept = false;
+        }
+        for &index in order.iter().take(keep as usize) {
+            dice[index].kept = true;
+        }
+    }
+}
+
+/// Parse a dice expression.
+///
+/// Whitespace is ignored and letters may be in either case.
+/// For compatibility with the original `!roll` a bare number `N` means `1dN`.
+pub fn parse(input: &str) -> Result<Expression, RollError> {
+    parse_with_macros(input, &|_| None)
+}
+
+/// Parse a dice expression which may refer to macros.
+///
+/// `macros` looks up the expression a macro name stands for.
+pub fn parse_with_macros(
+    input: &str,
+    macros: &dyn Fn(&str) -> Option<String>,
+) -> Result<Expression, RollError> {
+    let input = normalise(input);
+    if input.is_empty() {
+        return Err(RollError::Empty)
+    }
+
+    let mut parser = Parser { input: input.as_bytes(), position: 0, macros, stack: Vec::new() };
+    let mut expression = parser.expression()?;
+
+    if input.bytes().all(|c| c.is_ascii_digit()) {
+        if let [(false, Term::Constant(sides))] = expression.terms.as_slice() {
+            let sides = *sides as u32;
+            let group = DiceGroup {
+                count: 1,
+                sides,
+                selection: None,
+                explode: None,
+                reroll: None,
+                target: None,
+            };
+            validate(&group)?;
+            expression.terms = vec![(false, Term::Dice(group))];
+        }
+    }
+
+    Ok(expression)
+}
+
+/// Check whether `name` can be used as a macro name.
+///
+/// Names are made of letters, digits and underscores, start with a letter and
+/// mustn't be readable as dice by themselves.
+pub fn is_valid_macro_name(name: &str) -> bool {
+    let name = normalise(name);
+    match name.bytes().next() {
+        Some(c) if c.is_ascii_lowercase() => {},
+        _ => return false,
+    }
+    name.bytes().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'_')
+        && SHORTHANDS.iter().all(|(word, _)| *word != name)
+        && parse(&name).is_err()
+}
+
+/// Strip whitespace and lowercase an expression or macro name.
+pub fn normalise(input: &str) -> String {
+    input.chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    position: usize,
+    macros: &'a dyn Fn(&str) -> Option<String>,
+    /// Names of the macros being expanded, outermost first.
+    stack: Vec<String>,
+}
+
+impl<'a> Parser<'a> {
+    fn expression(&mut self) -> Result<Expression, RollError> {
+        let mut terms = Vec::new();
+
+        let mut negative = self.eat("-");
+        if !negative {
+            self.eat("+");
+        }
+        loop {
+            for (term_negative, term) in self.term()? {
+                if terms.len() == MAX_TERMS {
+                    return Err(RollError::TooManyTerms)
+                }
+                // Subtracting a macro subtracts each of its terms
+                terms.push((negative != term_negative, term));
+            }
+
+            if self.eat("+") {
+                negative = false;
+            } else if self.eat("-") {
+                negative = true;
+            } else {
+                break
+            }
+        }
+
+        match self.peek() {
+            None => Ok(Expression { terms }),
+            Some(c) => Err(RollError::UnexpectedCharacter(c, self.position)),
+        }
+    }
+
+    /// Parse a single term, or all the terms of a macro.
+    fn term(&mut self) -> Result<Vec<(bool, Term)>, RollError> {
+        if let Some(word) = self.word() {
+            if let Some(terms) = self.expand_macro(&word)? {
+                return Ok(terms)
+            }
+
+            if let Some((_, selection)) = SHORTHANDS.iter().find(|(name, _)| *name == word) {
+                self.position += word.len();
+                let mut group = DiceGroup {
+                    count: 2,
+                    sides: 20,
+                    selection: Some(*selection),
+                    explode: None,
+                    reroll: None,
+                    target: None,
+                };
+                self.modifiers(&mut group)?;
+                validate(&group)?;
+                return Ok(vec![(false, Term::Dice(group))])
+            }
+
+            // Anything else that isn't dice must have been meant as a macro
+            if !word.starts_with('d') {
+                return Err(RollError::UnknownMacro(word))
+            }
+        }
+
+        Ok(vec![(false, self.dice_or_constant()?)])
+    }
+
+    fn dice_or_constant(&mut self) -> Result<Term, RollError> {
+
+        let start = self.position;
+        let count = self.number()?;
+
+        if !self.eat("d") {
+            return match count {
+                Some(value) if i64::from(value) <= MAX_CONSTANT => Ok(Term::Constant(i64::from(value))),
+                Some(value) => Err(RollError::ConstantTooLarge(i64::from(value))),
+                None => match self.peek() {
+                    Some(c) => Err(RollError::UnexpectedCharacter(c, start)),
+                    None => Err(RollError::UnexpectedEnd),
+                },
+            }
+        }
+
+        let sides = if self.eat("%") {
+            100
+        } else {
+            match self.number()? {
+                Some(sides) => sides,
+                None => return match self.peek() {
+                    Some(c) => Err(RollError::UnexpectedCharacter(c, self.position)),
+                    None => Err(RollError::UnexpectedEnd),
+                },
+            }
+        };
+
+        let mut group = DiceGroup {
+            count: count.unwrap_or(1),
+            sides,
+            selection: None,
+            explode: None,
+            reroll: None,
+            target: None,
+        };
+        self.modifiers(&mut group)?;
+        validate(&group)?;
+
+        Ok(Term::Dice(group))
+    }
+
+    /// Look ahead for a word which could be a macro name or shorthand.
+    fn word(&self) -> Option<String> {
+        let rest = &self.input[self.position..];
+        match rest.first() {
+            Some(c) if c.is_ascii_lowercase() => {},
+            _ => return None,
+        }
+        let length = rest.iter()
+            .take_while(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || **c == b'_')
+            .count();
+        Some(String::from_utf8_lossy(&rest[..length]).into_owned())
+    }
+
+    /// If `name` is a macro, consume it and return the terms it stands for.
+    fn expand_macro(&mut self, name: &str) -> Result<Option<Vec<(bool, Term)>>, RollError> {
+        let definition = match (self.macros)(name) {
+            Some(definition) => definition,
+            None => return Ok(None),
+        };
+
+        if self.stack.iter().any(|expanding| expanding == name) {
+            let mut cycle = self.stack.clone();
+            cycle.push(String::from(name));
+            return Err(RollError::MacroCycle(cycle))
+        }
+        if self.stack.len() == MAX_MACRO_DEPTH {
+            return Err(RollError::MacroTooDeep)
+        }
+
+        let definition = normalise(&definition);
+        let mut stack = self.stack.clone();
+        stack.push(String::from(name));
+        let mut parser = Parser {
+            input: definition.as_bytes(),
+            position: 0,
+            macros: self.macros,
+            stack,
+        };
+        let expression = match parser.expression() {
+            Ok(expression) => expression,
+            // Errors from further down already say where they happened
+            Err(why @ RollError::MacroCycle(_)) | Err(why @ RollError::MacroTooDeep)
+            | Err(why @ RollError::InMacro(..)) => return Err(why),
+            Err(why) => return Err(RollError::InMacro(String::from(name), Box::new(why))),
+        };
+
+        self.position += name.len();
+        Ok(Some(expression.terms))
+    }
+
+    fn modifiers(&mut self, group: &mut DiceGroup) -> Result<(), RollError> {
+        loop {
+            let selection = if self.eat("kl") {
+                Some(Selection::KeepLowest(self.number()?.unwrap_or(1)))
+            } else if self.eat("kh") || self.eat("k") {
+                Some(Selection::KeepHighest(self.number()?.unwrap_or(1)))
+            } else if self.eat("dh") {
+                Some(Selection::DropHighest(self.number()?.unwrap_or(1)))
+            } else if self.eat("dl") || self.eat("d") {
+                Some(Selection::DropLowest(self.number()?.unwrap_or(1)))
+            } else {
+                None
+            };
+            if let Some(selection) = selection {
+                if group.selection.is_some() {
+                    return Err(RollError::DuplicateModifier("keep or drop"))
+                }
+                group.selection = Some(selection);
+                continue
+            }
+
+            if self.eat("!") {
+                if group.explode.is_some() {
+                    return Err(RollError::DuplicateModifier("exploding"))
+                }
+                let explode = self.compare()?.unwrap_or(Compare::Equal(group.sides));
+                group.explode = Some(explode);
+                continue
+            }
+
+            if self.eat("r") {
+                if group.reroll.is_some() {
+                    return Err(RollError::DuplicateModifier("reroll"))
+                }
+                let once = self.eat("o");
+                let compare = match self.compare()? {
+                    Some(compare) => compare,
+                    None => return match self.peek() {
+                        Some(c) => Err(RollError::UnexpectedCharacter(c, self.position)),
+                        None => Err(RollError::UnexpectedEnd),
+                    },
+                };
+                group.reroll = Some(Reroll { compare, once });
+                continue
+            }
+
+            match self.peek() {
+                Some('<') | Some('>') => {
+                    if group.target.is_some() {
+                        return Err(RollError::DuplicateModifier("target number"))
+                    }
+                    group.target = self.compare()?;
+                },
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Parse an optional comparison, e.g. `3`, `<2` or `>=19`.
+    fn compare(&mut self) -> Result<Option<Compare>, RollError> {
+        let kind: fn(u32) -> Compare = if self.eat("<=") {
+            Compare::LessOrEqual
+        } else if self.eat("<") {
+            Compare::Less
+        } else if self.eat(">=") {
+            Compare::GreaterOrEqual
+        } else if self.eat(">") {
+            Compare::Greater
+        } else {
+            return Ok(self.number()?.map(Compare::Equal))
+        };
+
+        match self.number()? {
+            Some(value) => Ok(Some(kind(value))),
+            None => match self.peek() {
+                Some(c) => Err(RollError::UnexpectedCharacter(c, self.position)),
+                None => Err(RollError::UnexpectedEnd),
+            },
+        }
+    }
+
+    /// Parse an optional unsigned number.
+    fn number(&mut self) -> Result<Option<u32>, RollError> {
+        let start = self.position;
+        while let Some(c) = self.peek() {
+            if !c.is_ascii_digit() {
+                break
+            }
+            self.position += 1;
+        }
+        if start == self.position {
+            return Ok(None)
+        }
+
+        // The input is ASCII digits only, so this can only fail on overflow
+        match std::str::from_utf8(&self.input[start..self.position]).unwrap().parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => Err(RollError::NumberTooLarge(start)),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        // Report whole characters rather than bytes in error messages
+        std::str::from_utf8(&self.input[self.position..])
+            .ok()
+            .and_then(|rest| rest.chars().next())
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        if self.input[self.position..].starts_with(token.as_bytes()) {
+            self.position += token.len();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Reject dice groups that are too large or can never finish rolling.
+fn validate(group: &DiceGroup) -> Result<(), RollError> {
+    if group.count == 0 {
+        return Err(RollError::NoDice)
+    }
+    if group.count > MAX_DICE {
+        return Err(RollError::TooManyDice(group.count))
+    }
+    if group.sides == 0 {
+        return Err(RollError::NoSides)
+    }
+    if group.sides > MAX_SIDES {
+        return Err(RollError::TooManySides(group.sides))
+    }
+
+    if let Some(selection) = group.selection {
+        let n = selection.count();
+        if n > group.count {
+            return Err(RollError::SelectsTooMany(n, group.count))
+        }
+    }
+
+    if let Some(explode) = group.explode {
+        if explode.matching_faces(group.sides) == group.sides {
+            return Err(RollError::AlwaysExplodes)
+        }
+    }
+    if let Some(reroll) = group.reroll {
+        if !reroll.once && reroll.compare.matching_faces(group.sides) == group.sides {
+            return Err(RollError::AlwaysRerolls)
+        }
+    }
+    if let Some(Compare::Equal(n)) = group.explode {
+        if n == 0 || n > group.sides {
+            return Err(RollError::ImpossibleComparison(n))
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::collections::HashMap;
+
+    /// Gives the die results it was made with, in order.
+    struct Scripted(Vec<u32>);
+
+    impl DiceRng for Scripted {
+        fn roll_die(&mut self, sides: u32) -> u32 {
+            assert!(!self.0.is_empty(), "rolled more dice than scripted");
+            let value = self.0.remove(0);
+            assert!(value >= 1 && value <= sides, "scripted {} on a d{}", value, sides);
+            value
+        }
+    }
+
+    /// Always gives the same result.
+    struct Always(u32);
+
+    impl DiceRng for Always {
+        fn roll_die(&mut self, _sides: u32) -> u32 {
+            self.0
+        }
+    }
+
+    fn roll(input: &str, script: &[u32]) -> RollResult {
+        let mut rng = Scripted(script.to_vec());
+        let result = parse(input).unwrap().roll(&mut rng);
+        assert!(rng.0.is_empty(), "scripted dice left over: {:?}", rng.0);
+        result
+    }
+
+    fn group(result: &RollResult) -> &GroupResult {
+        result.groups().next().unwrap()
+    }
+
+    fn kept(result: &RollResult) -> Vec<u32> {
+        group(result).dice.iter().filter(|die| die.kept).map(|die| die.value).collect()
+    }
+
+    fn parse_error(input: &str) -> RollError {
+        parse(input).unwrap_err()
+    }
+
+    fn macros(definitions: &[(&str, &str)]) -> HashMap<String, String> {
+        definitions.iter()
+            .map(|(name, definition)| (String::from(*name), String::from(*definition)))
+            .collect()
+    }
+
+    fn parse_macro_error(input: &str, macros: &HashMap<String, String>) -> RollError {
+        parse_with_macros(input, &|name| macros.get(name).cloned()).unwrap_err()
+    }
+
+    #[test]
+    fn sums_dice() {
+        let result = roll("2d6", &[3, 5]);
+        assert_eq!(result.total, 8);
+        assert_eq!(kept(&result), vec![3, 5]);
+    }
+
+    #[test]
+    fn bare_number_is_one_die() {
+        let expression = parse("20").unwrap();
+        assert_eq!(expression.to_string(), "1d20");
+        assert_eq!(roll("20", &[17]).total, 17);
+    }
+
+    #[test]
+    fn keeps_highest() {
+        let result = roll("4d6kh3", &[1, 5, 3, 6]);
+        assert_eq!(kept(&result), vec![5, 3, 6]);
+        assert_eq!(result.total, 14);
+        assert_eq!(roll("4d6k1", &[1, 5, 3, 6]).total, 6);
+    }
+
+    #[test]
+    fn keeps_lowest() {
+        let result = roll("4d6kl1", &[3, 1, 4, 2]);
+        assert_eq!(kept(&result), vec![1]);
+        assert_eq!(result.total, 1);
+    }
+
+    #[test]
+    fn drops_highest() {
+        let result = roll("4d6dh1", &[3, 1, 6, 2]);
+        assert_eq!(kept(&result), vec![3, 1, 2]);
+        assert_eq!(result.total, 6);
+    }
+
+    #[test]
+    fn drops_lowest() {
+        assert_eq!(roll("4d6dl1", &[3, 1, 6, 2]).total, 11);
+        assert_eq!(roll("4d6d2", &[3, 1, 6, 2]).total, 9);
+    }
+
+    #[test]
+    fn advantage_and_disadvantage() {
+        let result = roll("adv", &[20, 3]);
+        assert_eq!(result.total, 20);
+        assert!(result.is_critical());
+        assert!(!result.is_fumble());
+
+        let result = roll("dis+2", &[1, 12]);
+        assert_eq!(result.total, 3);
+        assert!(result.is_fumble());
+        assert!(!result.is_critical());
+    }
+
+    #[test]
+    fn explodes_on_highest_face() {
+        let result = roll("2d6!", &[6, 2, 3]);
+        let dice = &group(&result).dice;
+        assert_eq!(dice.iter().map(|die| die.value).collect::<Vec<_>>(), vec![6, 2, 3]);
+        assert_eq!(dice.iter().map(|die| die.exploded).collect::<Vec<_>>(), vec![false, true, false]);
+        assert_eq!(result.total, 11);
+    }
+
+    #[test]
+    fn explodes_on_comparison() {
+        let result = roll("1d10!>=9", &[9, 10, 4]);
+        assert_eq!(group(&result).dice.len(), 3);
+        assert_eq!(result.total, 23);
+    }
+
+    #[test]
+    fn explosions_are_limited() {
+        let result = parse("1d6!").unwrap().roll(&mut Always(6));
+        assert_eq!(group(&result).dice.len(), 1 + MAX_EXPLOSIONS as usize);
+    }
+
+    #[test]
+    fn rerolls_until_no_match() {
+        let result = roll("1d20r1", &[1, 1, 7]);
+        let die = &group(&result).dice[0];
+        assert_eq!(die.value, 7);
+        assert_eq!(die.rerolled, vec![1, 1]);
+        assert_eq!(result.total, 7);
+
+        assert_eq!(roll("1d20r<3", &[2, 1, 15]).total, 15);
+    }
+
+    #[test]
+    fn rerolls_once() {
+        let result = roll("1d20ro1", &[1, 1]);
+        let die = &group(&result).dice[0];
+        assert_eq!(die.value, 1);
+        assert_eq!(die.rerolled, vec![1]);
+    }
+
+    #[test]
+    fn rerolls_are_limited() {
+        let result = parse("1d6r1").unwrap().roll(&mut Always(1));
+        assert_eq!(group(&result).dice[0].rerolled.len(), MAX_REROLLS as usize);
+    }
+
+    #[test]
+    fn counts_successes() {
+        let result = roll("4d10>=7", &[7, 3, 10, 6]);
+        assert_eq!(result.total, 2);
+        let group = group(&result);
+        let successes: Vec<bool> = group.dice.iter().map(|die| group.is_success(die)).collect();
+        assert_eq!(successes, vec![true, false, true, false]);
+
+        assert_eq!(roll("3d6<3", &[1, 2, 3]).total, 2);
+    }
+
+    #[test]
+    fn only_kept_dice_count_as_successes() {
+        assert_eq!(roll("3d10kh2>5", &[6, 9, 2]).total, 2);
+        assert_eq!(roll("3d10kl2>5", &[6, 9, 2]).total, 1);
+    }
+
+    #[test]
+    fn adds_and_subtracts_modifiers() {
+        let result = roll("1d6+3-1", &[4]);
+        assert_eq!(result.total, 6);
+        assert_eq!(result.modifier(), 2);
+
+        let result = roll("-1d4+1d8", &[3, 5]);
+        assert_eq!(result.total, 2);
+        assert_eq!(result.modifier(), 0);
+    }
+
+    #[test]
+    fn percentile_dice() {
+        assert_eq!(parse("d%").unwrap().to_string(), "1d100");
+    }
+
+    #[test]
+    fn ignores_whitespace_and_case() {
+        assert_eq!(parse(" 4D6 KH3 + 2 ").unwrap(), parse("4d6kh3+2").unwrap());
+    }
+
+    #[test]
+    fn expands_macros() {
+        let macros = macros(&[("sword", "1d8+3"), ("smite", "sword+2d8")]);
+        let expression = parse_with_macros("10-smite", &|name| macros.get(name).cloned()).unwrap();
+        // Subtracting a macro subtracts each of its terms
+        assert_eq!(expression.to_string(), "10-1d8-3-2d8");
+    }
+
+    #[test]
+    fn seeded_rolls_repeat() {
+        let expression = parse("10d20!kh5+4d6r1").unwrap();
+        let first = expression.roll(&mut StdRng::seed_from_u64(42));
+        let second = expression.roll(&mut StdRng::seed_from_u64(42));
+        assert_eq!(first.terms, second.terms);
+        assert_eq!(first.total, second.total);
+        for group in first.groups() {
+            assert!(group.dice.iter().all(|die| die.value >= 1 && die.value <= group.group.sides));
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse_error(""), RollError::Empty);
+        assert_eq!(parse_error("   "), RollError::Empty);
+        assert_eq!(parse_error("2d"), RollError::UnexpectedEnd);
+        assert_eq!(parse_error("1d20+"), RollError::UnexpectedEnd);
+        assert_eq!(parse_error("2d6r"), RollError::UnexpectedEnd);
+        assert_eq!(parse_error("2d6*3"), RollError::UnexpectedCharacter('*', 3));
+        assert_eq!(parse_error("2d6>x"), RollError::UnexpectedCharacter('x', 4));
+        assert_eq!(parse_error("99999999999d6"), RollError::NumberTooLarge(0));
+        assert_eq!(parse_error(&vec!["1"; MAX_TERMS + 1].join("+")), RollError::TooManyTerms);
+        assert_eq!(parse_error("0d6"), RollError::NoDice);
+        assert_eq!(parse_error("101d6"), RollError::TooManyDice(101));
+        assert_eq!(parse_error("1d0"), RollError::NoSides);
+        assert_eq!(parse_error("1d1001"), RollError::TooManySides(1001));
+        assert_eq!(parse_error("1d6+100001"), RollError::ConstantTooLarge(100_001));
+        assert_eq!(parse_error("4d6kh3kl1"), RollError::DuplicateModifier("keep or drop"));
+        assert_eq!(parse_error("2d6!!"), RollError::DuplicateModifier("exploding"));
+        assert_eq!(parse_error("2d6r1r2"), RollError::DuplicateModifier("reroll"));
+        assert_eq!(parse_error("4d6>3<2"), RollError::DuplicateModifier("target number"));
+        assert_eq!(parse_error("2d6kh3"), RollError::SelectsTooMany(3, 2));
+        assert_eq!(parse_error("1d6!>=1"), RollError::AlwaysExplodes);
+        assert_eq!(parse_error("1d6r<7"), RollError::AlwaysRerolls);
+        assert_eq!(parse_error("1d6!7"), RollError::ImpossibleComparison(7));
+        assert_eq!(parse_error("fireball"), RollError::UnknownMacro(String::from("fireball")));
+    }
+
+    #[test]
+    fn macro_errors() {
+        let cycle = macros(&[("a", "b+1"), ("b", "a+1")]);
+        assert_eq!(parse_macro_error("a", &cycle),
+            RollError::MacroCycle(vec![String::from("a"), String::from("b"), String::from("a")]));
+
+        let chain: Vec<(String, String)> = (0..=MAX_MACRO_DEPTH)
+            .map(|i| (format!("m{}", i), format!("m{}", i + 1)))
+            .collect();
+        let mut deep: HashMap<String, String> = chain.into_iter().collect();
+        deep.insert(format!("m{}", MAX_MACRO_DEPTH + 1), String::from("1d6"));
+        assert_eq!(parse_macro_error("m0", &deep), RollError::MacroTooDeep);
+
+        let broken = macros(&[("bad", "2d"), ("outer", "bad+1")]);
+        let in_macro = RollError::InMacro(String::from("bad"), Box::new(RollError::UnexpectedEnd));
+        assert_eq!(parse_macro_error("bad", &broken), in_macro);
+        // The error names the macro that's wrong, not the one that used it
+        assert_eq!(parse_macro_error("outer", &broken), in_macro);
+    }
+
+    #[test]
+    fn macro_names() {
+        assert!(is_valid_macro_name("fireball"));
+        assert!(is_valid_macro_name("Sneak_Attack2"));
+        assert!(!is_valid_macro_name("d20"));
+        assert!(!is_valid_macro_name("adv"));
+        assert!(!is_valid_macro_name("2fast"));
+        assert!(!is_valid_macro_name("fire-ball"));
+    }
+}
@@ -0,0 +1,156 @@
+use super::{breakdown, dice, MAX_BREAKDOWN_LENGTH};
+use crate::{
+    commands::{format_timestamp, respond},
+    database::{self, RollRecord},
+};
+use serenity::{
+    prelude::*,
+    model::prelude::*,
+    framework::standard::{
+        Args,
+        CommandError,
+        CommandResult,
+        macros::command,
+    },
+};
+
+/// How many rolls `!rolls history` shows.
+const HISTORY_LENGTH: u32 = 10;
+/// Discord limits the total length of an embed to 6000 characters, so keep
+/// the description of each roll well below 6000 / HISTORY_LENGTH.
+const MAX_RECORD_LENGTH: usize = 500;
+
+#[command]
+#[description = "Shows past rolls, so that contested results can be checked."]
+#[usage = "`!rolls history` or `!rolls history @user`"]
+#[sub_commands(rolls_history)]
+fn rolls(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("rolls command handler called");
+    // This is a usage error, not a bot failure
+    respond(ctx, msg, "use `!rolls history` or `!rolls history @user`.");
+    Ok(())
+}
+
+#[command("history")]
+#[description = "Shows the most recent rolls made by you or another user in this guild."]
+#[usage = "`!rolls history` or `!rolls history @user`"]
+fn rolls_history(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("rolls history command handler called");
+
+    let user_id = if args.rest().trim().is_empty() {
+        msg.author.id
+    } else {
+        match args.trimmed().single::<UserId>() {
+            Ok(user_id) => user_id,
+            Err(_) => {
+                // This is a usage error, not a bot failure
+                respond(ctx, msg, "please mention a user; e.g. `!rolls history @someone`");
+                return Ok(())
+            }
+        }
+    };
+
+    debug!("Roll history DB data retrieval...");
+    let database = database::handle(ctx)?;
+    let records = match database.roll_history(
+        msg.guild_id.map(|guild_id| guild_id.0), user_id.0, HISTORY_LENGTH)
+    {
+        Ok(records) => records,
+        Err(why) => {
+            let reason = format!("Could not retrieve roll history from database: {}", why);
+            error!("{}", reason);
+            return Err(CommandError(reason))
+        }
+    };
+
+    let user_name = match ctx.cache.read().user(user_id) {
+        Some(user) => user.read().name.clone(),
+        None => String::from("that user"),
+    };
+
+    if records.is_empty() {
+        respond(ctx, msg, &format!("there are no rolls by {} here yet.", user_name));
+        return Ok(())
+    }
+
+    if let Err(why) = msg.channel_id.send_message(&ctx.http, |m| {
+        m.embed(|e| {
+            e.title(format!("Recent rolls by {}", user_name));
+            for (roll_id, record) in &records {
+                e.field(
+                    format!("Roll #{} at {}", roll_id, format_timestamp(record.timestamp)),
+                    describe(record),
+                    false);
+            }
+            e
+        })
+    }) {
+        error!("Error sending message: {:?}", why);
+    }
+
+    Ok(())
+}
+
+/// Record a roll in the roll history.
+/// Return the ID of the history entry, or None if it couldn't be stored.
+pub fn record(
+    ctx: &Context,
+    msg: &Message,
+    input: &str,
+    result: &dice::RollResult,
+) -> Option<i64> {
+    let results = match serde_json::to_string(&result.terms) {
+        Ok(results) => results,
+        Err(why) => {
+            error!("Could not encode roll results: {:?}", why);
+            return None
+        }
+    };
+
+    let data = RollRecord {
+        user_id: msg.author.id.0,
+        guild_id: msg.guild_id.map(|guild_id| guild_id.0),
+        channel_id: msg.channel_id.0,
+        expression: String::from(input.trim()),
+        results,
+        total: result.total,
+        timestamp: time::get_time().sec,
+    };
+
+    debug!("Inserting roll history DB entry...");
+    let database = match database::handle(ctx) {
+        Ok(database) => database,
+        Err(why) => {
+            error!("Could not get a database handle: {}", why);
+            return None
+        }
+    };
+    match database.roll_history_insert(&data) {
+        Ok(roll_id) => Some(roll_id),
+        Err(why) => {
+            error!("Could not record roll in database: {}", why);
+            None
+        }
+    }
+}
+
+/// Describe a recorded roll: what was typed, the total and the dice rolled.
+fn describe(record: &RollRecord) -> String {
+    let mut description = format!("`{}` = **{}**", record.expression, record.total);
+
+    match serde_json::from_str::<Vec<(bool, dice::TermResult)>>(&record.results) {
+        Ok(terms) => for (negative, term) in terms {
+            if let dice::TermResult::Dice(group) = term {
+                description.push_str(&format!("\n{}{}: {}",
+                    if negative { "-" } else { "" }, group.group, breakdown(&group, MAX_BREAKDOWN_LENGTH)));
+            }
+        },
+        Err(why) => error!("Could not decode roll results {:?}: {:?}", record.results, why),
+    }
+
+    if description.chars().count() > MAX_RECORD_LENGTH {
+        description = description.chars().take(MAX_RECORD_LENGTH - 1).collect();
+        description.push('…');
+    }
+    description
+}
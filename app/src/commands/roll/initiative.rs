@@ -0,0 +1,655 @@
+use super::dice::{DiceGroup, DiceRng, Expression, Term};
+use crate::{
+    commands::respond,
+    database::{self, Combatant, Encounter},
+};
+use rand::thread_rng;
+use serenity::{
+    prelude::*,
+    model::prelude::*,
+    framework::standard::{
+        Args,
+        CommandError,
+        CommandResult,
+        macros::command,
+    },
+    utils::MessageBuilder,
+};
+
+/// Largest number of combatants in one encounter.
+const MAX_COMBATANTS: usize = 30;
+const MAX_NAME_LENGTH: usize = 32;
+/// Largest absolute initiative modifier.
+const MAX_MODIFIER: i64 = 100;
+
+// Command group to track initiative order during combat, one encounter per channel.
+// The member who starts an encounter runs it as its GM, as can anyone who may
+// manage messages in the channel.
+#[command("init")]
+#[description = "Tracks initiative order for combat in this channel."]
+#[usage = "`!init`, `!init join [modifier]`, `!init add <name> [modifier]`, `!init roll`, \
+`!init next`, `!init delay [name]`, `!init remove [name]` or `!init end`"]
+#[only_in(guilds)]
+#[sub_commands(initiative_join, initiative_add, initiative_roll, initiative_next,
+               initiative_delay, initiative_remove, initiative_end)]
+fn initiative(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("init command handler called");
+
+    match load_encounter(ctx, msg)? {
+        Some(encounter) => send_order(ctx, msg, &encounter),
+        None => respond(ctx, msg, "there's no combat in this channel. Start one with `!init join`."),
+    }
+
+    Ok(())
+}
+
+#[command("join")]
+#[description = "Joins the combat in this channel, starting one if needed."]
+#[usage = "`!init join` or `!init join +2`"]
+#[only_in(guilds)]
+fn initiative_join(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("init join command handler called");
+
+    let modifier = match parse_modifier(args.rest().trim()) {
+        Some(modifier) => modifier,
+        None => {
+            // This is a usage error, not a bot failure
+            respond(ctx, msg, &format!(
+                "the modifier needs to be a number between -{0} and +{0}; e.g. `!init join +2`",
+                MAX_MODIFIER));
+            return Ok(())
+        }
+    };
+
+    let name = msg.author_nick(&*ctx).unwrap_or_else(|| msg.author.name.clone());
+    let joined = change_encounter(ctx, msg, |encounter| {
+        let mut encounter = encounter.unwrap_or_else(|| new_encounter(msg));
+        if encounter.combatants.iter().any(|c| c.user_id == Some(msg.author.id.0)) {
+            return Err(String::from("you've already joined this combat."))
+        }
+        check_new_combatant(&encounter, &name)?;
+        encounter.combatants.push(Combatant {
+            name: name.clone(),
+            user_id: Some(msg.author.id.0),
+            modifier,
+            initiative: None,
+        });
+        Ok((encounter, ()))
+    })?;
+
+    if joined.is_some() {
+        respond(ctx, msg, &format!("you've joined the combat with a modifier of {:+}.", modifier));
+    }
+
+    Ok(())
+}
+
+#[command("add")]
+#[description = "Adds a non-player character to the combat in this channel."]
+#[usage = "`!init add Goblin Archer +3`"]
+#[only_in(guilds)]
+fn initiative_add(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("init add command handler called");
+
+    // The modifier is optional, so only treat the last word as one if it's a number
+    let input = args.rest().trim();
+    let (name, modifier) = match input.rsplitn(2, char::is_whitespace).collect::<Vec<_>>()[..] {
+        [last, rest] if last.parse::<i64>().is_ok() => (rest.trim(), parse_modifier(last)),
+        _ => (input, Some(0)),
+    };
+    let modifier = match modifier {
+        Some(modifier) => modifier,
+        None => {
+            respond(ctx, msg, &format!(
+                "the modifier needs to be a number between -{0} and +{0}.", MAX_MODIFIER));
+            return Ok(())
+        }
+    };
+    if name.is_empty() {
+        // This is a usage error, not a bot failure
+        respond(ctx, msg, "who should I add? e.g. `!init add Goblin Archer +3`");
+        return Ok(())
+    }
+
+    let added = change_encounter(ctx, msg, |encounter| {
+        let mut encounter = encounter.unwrap_or_else(|| new_encounter(msg));
+        if !is_gm(ctx, msg, &encounter) {
+            return Err(String::from("only the GM of this combat can add characters."))
+        }
+        check_new_combatant(&encounter, name)?;
+        encounter.combatants.push(Combatant {
+            name: String::from(name),
+            user_id: None,
+            modifier,
+            initiative: None,
+        });
+        Ok((encounter, ()))
+    })?;
+
+    if added.is_some() {
+        let txt = MessageBuilder::new()
+            .push("added ")
+            .push_bold_safe(name)
+            .push(format!(" to the combat with a modifier of {:+}.", modifier))
+            .build();
+        respond(ctx, msg, &txt);
+    }
+
+    Ok(())
+}
+
+#[command("roll")]
+#[description = "Rolls initiative for everyone who hasn't rolled yet."]
+#[only_in(guilds)]
+fn initiative_roll(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("init roll command handler called");
+
+    let rolled = change_encounter(ctx, msg, |encounter| {
+        let mut encounter = require_gm(ctx, msg, encounter)?;
+        if encounter.combatants.iter().all(|c| c.initiative.is_some()) {
+            return Err(String::from("everyone has already rolled initiative."))
+        }
+        roll_initiative(&mut encounter, &mut thread_rng());
+        Ok((encounter, ()))
+    })?;
+
+    if let Some((encounter, _)) = rolled {
+        send_order(ctx, msg, &encounter);
+    }
+
+    Ok(())
+}
+
+#[command("next")]
+#[description = "Moves on to the next combatant's turn."]
+#[only_in(guilds)]
+fn initiative_next(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("init next command handler called");
+
+    let moved = change_encounter(ctx, msg, |encounter| {
+        let mut encounter = require_gm(ctx, msg, encounter)?;
+        if encounter.round == 0 {
+            return Err(String::from("roll initiative first with `!init roll`."))
+        }
+        next_turn(&mut encounter);
+        Ok((encounter, ()))
+    })?;
+
+    if let Some((encounter, _)) = moved {
+        send_order(ctx, msg, &encounter);
+    }
+
+    Ok(())
+}
+
+#[command("delay")]
+#[description = "Lets a combatant act after the next one instead. Players can only delay themselves."]
+#[usage = "`!init delay` or `!init delay Goblin Archer`"]
+#[only_in(guilds)]
+fn initiative_delay(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("init delay command handler called");
+
+    let delayed = change_encounter(ctx, msg, |encounter| {
+        let (mut encounter, index) = find_combatant(ctx, msg, encounter, args.rest().trim())?;
+        if encounter.round == 0 || encounter.combatants[index].initiative.is_none() {
+            return Err(String::from("only combatants who have rolled initiative can delay."))
+        }
+        if !delay(&mut encounter, index) {
+            return Err(String::from("they're already last in the initiative order."))
+        }
+        Ok((encounter, ()))
+    })?;
+
+    if let Some((encounter, _)) = delayed {
+        send_order(ctx, msg, &encounter);
+    }
+
+    Ok(())
+}
+
+#[command("remove")]
+#[aliases("leave")]
+#[description = "Removes a combatant. Players can only remove themselves."]
+#[usage = "`!init remove` or `!init remove Goblin Archer`"]
+#[only_in(guilds)]
+fn initiative_remove(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("init remove command handler called");
+
+    let removed = change_encounter(ctx, msg, |encounter| {
+        let (mut encounter, index) = find_combatant(ctx, msg, encounter, args.rest().trim())?;
+        let removed = remove(&mut encounter, index);
+        Ok((encounter, removed))
+    })?;
+
+    if let Some((_, removed)) = removed {
+        let txt = MessageBuilder::new()
+            .push("removed ")
+            .push_bold_safe(&removed.name)
+            .push(" from the combat.")
+            .build();
+        respond(ctx, msg, &txt);
+    }
+
+    Ok(())
+}
+
+#[command("end")]
+#[description = "Ends the combat in this channel."]
+#[only_in(guilds)]
+fn initiative_end(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("init end command handler called");
+
+    let database = database::handle(ctx)?;
+    let ended = database.in_transaction(|database| {
+        let encounter = database.encounter(msg.channel_id.0)?;
+        if let Err(txt) = require_gm(ctx, msg, encounter) {
+            return Ok(Err(txt))
+        }
+        debug!("Deleting encounter DB entry...");
+        database.encounter_delete(msg.channel_id.0)?;
+        Ok(Ok(()))
+    });
+
+    match ended {
+        Ok(Ok(())) => respond(ctx, msg, "the combat is over!"),
+        // This is a usage error, not a bot failure
+        Ok(Err(txt)) => respond(ctx, msg, &txt),
+        Err(why) => {
+            let reason = format!("Could not delete encounter from database: {}", why);
+            error!("{}", reason);
+            return Err(CommandError(reason))
+        },
+    }
+
+    Ok(())
+}
+
+/// Roll initiative for everyone who hasn't rolled yet.
+///
+/// Before the first round everyone is sorted into order. Once combat has started,
+/// latecomers are slotted in without changing the order of everyone else.
+fn roll_initiative(encounter: &mut Encounter, rng: &mut dyn DiceRng) {
+    let d20 = Expression {
+        terms: vec![(false, Term::Dice(DiceGroup {
+            count: 1,
+            sides: 20,
+            selection: None,
+            explode: None,
+            reroll: None,
+            target: None,
+        }))],
+    };
+    let roll = |combatant: &mut Combatant, rng: &mut dyn DiceRng| {
+        combatant.initiative = Some(d20.roll(rng).total + combatant.modifier);
+    };
+
+    if encounter.round == 0 {
+        for combatant in encounter.combatants.iter_mut() {
+            if combatant.initiative.is_none() {
+                roll(combatant, rng);
+            }
+        }
+        // Stable, so ties keep the order people joined in
+        encounter.combatants.sort_by(|a, b| {
+            b.initiative.cmp(&a.initiative).then(b.modifier.cmp(&a.modifier))
+        });
+        encounter.round = 1;
+        encounter.turn = 0;
+        return
+    }
+
+    let (rolled, mut waiting): (Vec<Combatant>, Vec<Combatant>) = encounter.combatants
+        .drain(..)
+        .partition(|c| c.initiative.is_some());
+    encounter.combatants = rolled;
+    for mut combatant in waiting.drain(..) {
+        roll(&mut combatant, rng);
+        let position = encounter.combatants.iter()
+            .position(|c| c.initiative < combatant.initiative)
+            .unwrap_or(encounter.combatants.len());
+        if position <= encounter.turn && !encounter.combatants.is_empty() {
+            // Keep it the same combatant's turn
+            encounter.turn += 1;
+        }
+        encounter.combatants.insert(position, combatant);
+    }
+}
+
+/// Move on to the next combatant who has rolled initiative, starting a new round
+/// after the last one.
+fn next_turn(encounter: &mut Encounter) {
+    let count = encounter.combatants.len();
+    for _ in 0..count {
+        encounter.turn += 1;
+        if encounter.turn >= count {
+            encounter.turn = 0;
+            encounter.round += 1;
+        }
+        if encounter.combatants[encounter.turn].initiative.is_some() {
+            return
+        }
+    }
+}
+
+/// Let the combatant at `index` act after the combatant following them.
+/// Return false if there's nobody to delay for.
+fn delay(encounter: &mut Encounter, index: usize) -> bool {
+    let next = index + 1;
+    let next_initiative = match encounter.combatants.get(next) {
+        Some(combatant) if combatant.initiative.is_some() => combatant.initiative,
+        _ => return false,
+    };
+
+    // Take on the initiative of the combatant now going first, so that the
+    // order still reads from highest to lowest
+    encounter.combatants[index].initiative = next_initiative;
+    encounter.combatants.swap(index, next);
+    if next == encounter.turn {
+        // Keep it the same combatant's turn
+        encounter.turn = index;
+    }
+    true
+}
+
+/// Take the combatant at `index` out of the encounter, keeping track of whose turn it is.
+fn remove(encounter: &mut Encounter, index: usize) -> Combatant {
+    let removed = encounter.combatants.remove(index);
+    if index < encounter.turn {
+        encounter.turn -= 1;
+    } else if encounter.turn >= encounter.combatants.len() && encounter.turn > 0 {
+        // The last combatant of the round left on their turn
+        encounter.turn = 0;
+        encounter.round += 1;
+    }
+    removed
+}
+
+fn parse_modifier(input: &str) -> Option<i64> {
+    if input.is_empty() {
+        return Some(0)
+    }
+    match input.parse::<i64>() {
+        Ok(modifier) if modifier.abs() <= MAX_MODIFIER => Some(modifier),
+        _ => None,
+    }
+}
+
+fn new_encounter(msg: &Message) -> Encounter {
+    Encounter {
+        channel_id: msg.channel_id.0,
+        guild_id: msg.guild_id.map(|guild_id| guild_id.0),
+        gm_user_id: msg.author.id.0,
+        round: 0,
+        turn: 0,
+        combatants: Vec::new(),
+    }
+}
+
+/// Check whether a new combatant called `name` can join.
+/// The error is meant to be shown to the user.
+fn check_new_combatant(encounter: &Encounter, name: &str) -> Result<(), String> {
+    if encounter.combatants.len() >= MAX_COMBATANTS {
+        return Err(format!("a combat can have at most {} combatants.", MAX_COMBATANTS))
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(String::from("please choose a shorter name!"))
+    }
+    if encounter.combatants.iter().any(|c| c.name.to_lowercase() == name.to_lowercase()) {
+        return Err(MessageBuilder::new()
+            .push("there's already someone called ")
+            .push_bold_safe(name)
+            .push(" in this combat.")
+            .build())
+    }
+    Ok(())
+}
+
+/// Whether the author of a message may run an encounter.
+fn is_gm(ctx: &Context, msg: &Message, encounter: &Encounter) -> bool {
+    if encounter.gm_user_id == msg.author.id.0 {
+        return true
+    }
+    match msg.guild(&ctx.cache) {
+        Some(guild) => guild.read()
+            .user_permissions_in(msg.channel_id, msg.author.id)
+            .manage_messages(),
+        None => false,
+    }
+}
+
+/// Check that there's an encounter in this channel and that the author may run it.
+/// The error is meant to be shown to the user.
+fn require_gm(ctx: &Context, msg: &Message, encounter: Option<Encounter>) -> Result<Encounter, String> {
+    let encounter = encounter
+        .ok_or_else(|| String::from("there's no combat in this channel."))?;
+    if !is_gm(ctx, msg, &encounter) {
+        return Err(String::from("only the GM of this combat can do that."))
+    }
+    Ok(encounter)
+}
+
+/// Find a combatant by name, or the author's own combatant if no name is given.
+/// GMs can pick anyone, players only themselves. The error is meant to be shown to the user.
+fn find_combatant(
+    ctx: &Context,
+    msg: &Message,
+    encounter: Option<Encounter>,
+    name: &str,
+) -> Result<(Encounter, usize), String>
+{
+    let encounter = encounter
+        .ok_or_else(|| String::from("there's no combat in this channel."))?;
+
+    let index = if name.is_empty() {
+        encounter.combatants.iter().position(|c| c.user_id == Some(msg.author.id.0))
+    } else {
+        encounter.combatants.iter().position(|c| c.name.to_lowercase() == name.to_lowercase())
+    };
+    let index = index.ok_or_else(|| String::from("I can't find them in this combat."))?;
+
+    if encounter.combatants[index].user_id != Some(msg.author.id.0) && !is_gm(ctx, msg, &encounter) {
+        return Err(String::from("only the GM of this combat can do that to other combatants."))
+    }
+
+    Ok((encounter, index))
+}
+
+fn load_encounter(ctx: &Context, msg: &Message) -> Result<Option<Encounter>, CommandError> {
+    debug!("Encounter DB data retrieval...");
+    let database = database::handle(ctx)?;
+    match database.encounter(msg.channel_id.0) {
+        Ok(encounter) => Ok(encounter),
+        Err(why) => {
+            let reason = format!("Could not retrieve encounter from database: {}", why);
+            error!("{}", reason);
+            Err(CommandError(reason))
+        }
+    }
+}
+
+/// Load the encounter in this channel, change it and save it in one transaction,
+/// so that commands used at the same time in a channel don't undo each other.
+///
+/// `change` is given the encounter, if there is one, and returns the encounter to save
+/// along with anything the command needs afterwards. If it returns an error instead,
+/// nothing is saved and the error is shown to the user.
+fn change_encounter<T, F>(
+    ctx: &Context,
+    msg: &Message,
+    change: F,
+) -> Result<Option<(Encounter, T)>, CommandError>
+where F: FnOnce(Option<Encounter>) -> Result<(Encounter, T), String>
+{
+    let database = database::handle(ctx)?;
+    let changed = database.in_transaction(|database| {
+        debug!("Encounter DB data retrieval...");
+        let encounter = database.encounter(msg.channel_id.0)?;
+        let (encounter, value) = match change(encounter) {
+            Ok(changed) => changed,
+            Err(txt) => return Ok(Err(txt)),
+        };
+        debug!("Updating encounter DB entry...");
+        database.encounter_update(&encounter)?;
+        debug!("Updated encounter to {:?}", encounter);
+        Ok(Ok((encounter, value)))
+    });
+
+    match changed {
+        Ok(Ok(changed)) => Ok(Some(changed)),
+        Ok(Err(txt)) => {
+            // This is a usage error, not a bot failure
+            respond(ctx, msg, &txt);
+            Ok(None)
+        },
+        Err(why) => {
+            let reason = format!("Could not update encounter in database: {}", why);
+            error!("{}", reason);
+            Err(CommandError(reason))
+        },
+    }
+}
+
+/// Send the initiative order as an embed, pointing out whose turn it is.
+fn send_order(ctx: &Context, msg: &Message, encounter: &Encounter) {
+    let title = match encounter.round {
+        0 => String::from("Waiting for initiative"),
+        round => format!("Initiative: round {}", round),
+    };
+
+    let lines: Vec<String> = encounter.combatants.iter()
+        .enumerate()
+        .map(|(i, combatant)| match combatant.initiative {
+            Some(initiative) if encounter.round > 0 && i == encounter.turn =>
+                format!("▶ **{}** ({})", combatant.name, initiative),
+            Some(initiative) => format!("{} ({})", combatant.name, initiative),
+            None => format!("{} (not rolled, {:+})", combatant.name, combatant.modifier),
+        })
+        .collect();
+    let description = if lines.is_empty() {
+        String::from("Nobody has joined yet.")
+    } else {
+        lines.join("\n")
+    };
+
+    if let Err(why) = msg.channel_id.send_message(&ctx.http, |m| {
+        m.embed(|e| e.title(title).description(description))
+    }) {
+        error!("Error sending message: {:?}", why);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An encounter in its first round, in the given initiative order.
+    fn encounter(initiatives: &[(&str, i64)], turn: usize) -> Encounter {
+        Encounter {
+            channel_id: 1,
+            guild_id: Some(2),
+            gm_user_id: 3,
+            round: 1,
+            turn,
+            combatants: initiatives.iter()
+                .map(|(name, initiative)| Combatant {
+                    name: String::from(*name),
+                    user_id: None,
+                    modifier: 0,
+                    initiative: Some(*initiative),
+                })
+                .collect(),
+        }
+    }
+
+    fn order(encounter: &Encounter) -> Vec<&str> {
+        encounter.combatants.iter().map(|c| c.name.as_str()).collect()
+    }
+
+    fn current(encounter: &Encounter) -> &str {
+        &encounter.combatants[encounter.turn].name
+    }
+
+    #[test]
+    fn delay_on_own_turn_passes_it_on() {
+        let mut encounter = encounter(&[("Ann", 18), ("Bob", 12), ("Cat", 5)], 0);
+        assert!(delay(&mut encounter, 0));
+        assert_eq!(order(&encounter), ["Bob", "Ann", "Cat"]);
+        assert_eq!(current(&encounter), "Bob");
+        assert_eq!(encounter.combatants[1].initiative, Some(12));
+    }
+
+    #[test]
+    fn delay_before_current_turn_keeps_it() {
+        let mut encounter = encounter(&[("Ann", 18), ("Bob", 12), ("Cat", 5)], 1);
+        assert!(delay(&mut encounter, 0));
+        assert_eq!(order(&encounter), ["Bob", "Ann", "Cat"]);
+        assert_eq!(current(&encounter), "Bob");
+    }
+
+    #[test]
+    fn delay_after_current_turn_keeps_it() {
+        let mut encounter = encounter(&[("Ann", 18), ("Bob", 12), ("Cat", 5)], 0);
+        assert!(delay(&mut encounter, 1));
+        assert_eq!(order(&encounter), ["Ann", "Cat", "Bob"]);
+        assert_eq!(current(&encounter), "Ann");
+    }
+
+    #[test]
+    fn delay_needs_someone_after() {
+        let mut encounter = encounter(&[("Ann", 18), ("Bob", 12)], 0);
+        encounter.combatants[1].initiative = None;
+        assert!(!delay(&mut encounter, 0));
+        assert!(!delay(&mut encounter, 1));
+        assert_eq!(order(&encounter), ["Ann", "Bob"]);
+    }
+
+    #[test]
+    fn next_turn_starts_a_new_round() {
+        let mut encounter = encounter(&[("Ann", 18), ("Bob", 12)], 1);
+        next_turn(&mut encounter);
+        assert_eq!(current(&encounter), "Ann");
+        assert_eq!(encounter.round, 2);
+    }
+
+    #[test]
+    fn next_turn_skips_combatants_who_havent_rolled() {
+        let mut encounter = encounter(&[("Ann", 18), ("Bob", 12), ("Cat", 5)], 0);
+        encounter.combatants[1].initiative = None;
+        next_turn(&mut encounter);
+        assert_eq!(current(&encounter), "Cat");
+    }
+
+    #[test]
+    fn remove_keeps_the_current_turn() {
+        let mut encounter = encounter(&[("Ann", 18), ("Bob", 12), ("Cat", 5)], 1);
+        assert_eq!(remove(&mut encounter, 0).name, "Ann");
+        assert_eq!(current(&encounter), "Bob");
+
+        // The last combatant leaving on their turn ends the round
+        let mut encounter = self::encounter(&[("Ann", 18), ("Bob", 12)], 1);
+        remove(&mut encounter, 1);
+        assert_eq!(current(&encounter), "Ann");
+        assert_eq!(encounter.round, 2);
+    }
+
+    #[test]
+    fn latecomers_keep_the_current_turn() {
+        struct Always(u32);
+        impl DiceRng for Always {
+            fn roll_die(&mut self, _sides: u32) -> u32 {
+                self.0
+            }
+        }
+
+        let mut encounter = encounter(&[("Ann", 18), ("Bob", 12)], 1);
+        encounter.combatants.push(Combatant {
+            name: String::from("Dan"),
+            user_id: None,
+            modifier: 0,
+            initiative: None,
+        });
+        roll_initiative(&mut encounter, &mut Always(15));
+        assert_eq!(order(&encounter), ["Ann", "Dan", "Bob"]);
+        assert_eq!(current(&encounter), "Bob");
+    }
+}
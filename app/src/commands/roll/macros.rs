@@ -0,0 +1,258 @@
+use super::dice;
+use crate::{
+    commands::{admin::GUILDADMIN_CHECK, respond},
+    database::{self, MacroOwner, RollMacro},
+};
+use serenity::{
+    prelude::*,
+    model::prelude::*,
+    framework::standard::{
+        Args,
+        CommandError,
+        CommandResult,
+        macros::command,
+    },
+};
+use std::collections::HashMap;
+
+const MAX_MACRO_NAME_LENGTH: usize = 32;
+const MAX_MACRO_EXPRESSION_LENGTH: usize = 200;
+/// How many macros a single user or guild may save.
+const MAX_MACROS: usize = 50;
+/// Discord rejects messages longer than 2000 characters.
+const MAX_LIST_LENGTH: usize = 1900;
+
+// Command to manage saved dice expressions, which can then be rolled by name.
+#[command("macro")]
+#[description = "Saves, lists and deletes your roll macros. Roll a macro with `!roll <name>`."]
+#[usage = "`!macro list`, `!macro save <name> <dice>` or `!macro delete <name>`. \
+Guild admins can use `!macro guild save <name> <dice>` and `!macro guild delete <name>`."]
+#[sub_commands(macro_list, macro_save, macro_delete, macro_guild)]
+fn roll_macro(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("macro command handler called");
+    list_macros(ctx, msg)
+}
+
+#[command("list")]
+#[description = "Lists your roll macros and those of this guild."]
+fn macro_list(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("macro list command handler called");
+    list_macros(ctx, msg)
+}
+
+#[command("save")]
+#[description = "Saves a roll macro for yourself."]
+#[usage = "`!macro save attack 1d20+5`"]
+fn macro_save(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("macro save command handler called");
+    save_macro(ctx, msg, &args, MacroOwner::User(msg.author.id.0))
+}
+
+#[command("delete")]
+#[aliases("remove")]
+#[description = "Deletes one of your roll macros."]
+#[usage = "`!macro delete attack`"]
+fn macro_delete(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("macro delete command handler called");
+    delete_macro(ctx, msg, &args, MacroOwner::User(msg.author.id.0))
+}
+
+#[command("guild")]
+#[description = "Manages the roll macros everyone in this guild can use."]
+#[usage = "`!macro guild save <name> <dice>` or `!macro guild delete <name>`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+#[sub_commands(macro_guild_save, macro_guild_delete)]
+fn macro_guild(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("macro guild command handler called");
+    // This is a usage error, not a bot failure
+    respond(ctx, msg, "use `!macro guild save <name> <dice>` or `!macro guild delete <name>`.");
+    Ok(())
+}
+
+#[command("save")]
+#[description = "Saves a roll macro for everyone in this guild."]
+#[usage = "`!macro guild save initiative 1d20`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn macro_guild_save(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("macro guild save command handler called");
+    match msg.guild_id {
+        Some(guild_id) => save_macro(ctx, msg, &args, MacroOwner::Guild(guild_id.0)),
+        None => Ok(()),
+    }
+}
+
+#[command("delete")]
+#[aliases("remove")]
+#[description = "Deletes a roll macro of this guild."]
+#[usage = "`!macro guild delete initiative`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn macro_guild_delete(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("macro guild delete command handler called");
+    match msg.guild_id {
+        Some(guild_id) => delete_macro(ctx, msg, &args, MacroOwner::Guild(guild_id.0)),
+        None => Ok(()),
+    }
+}
+
+/// Get the macros available to the author of a message, by name.
+///
+/// Personal macros take precedence over guild macros of the same name.
+pub fn available_macros(ctx: &Context, msg: &Message) -> Result<HashMap<String, String>, CommandError> {
+    let mut macros = HashMap::new();
+    if let Some(guild_id) = msg.guild_id {
+        macros.extend(load_macros(ctx, MacroOwner::Guild(guild_id.0))?
+            .into_iter()
+            .map(|m| (m.name, m.expression)));
+    }
+    macros.extend(load_macros(ctx, MacroOwner::User(msg.author.id.0))?
+        .into_iter()
+        .map(|m| (m.name, m.expression)));
+    Ok(macros)
+}
+
+fn load_macros(ctx: &Context, owner: MacroOwner) -> Result<Vec<RollMacro>, CommandError> {
+    debug!("Macro DB data retrieval...");
+    let database = database::handle(ctx)?;
+    match database.macros(owner) {
+        Ok(macros) => Ok(macros),
+        Err(why) => {
+            let reason = format!("Could not retrieve macros from database: {}", why);
+            error!("{}", reason);
+            Err(CommandError(reason))
+        }
+    }
+}
+
+fn list_macros(ctx: &mut Context, msg: &Message) -> CommandResult {
+    let user_macros = load_macros(ctx, MacroOwner::User(msg.author.id.0))?;
+    let guild_macros = match msg.guild_id {
+        Some(guild_id) => load_macros(ctx, MacroOwner::Guild(guild_id.0))?,
+        None => Vec::new(),
+    };
+
+    if user_macros.is_empty() && guild_macros.is_empty() {
+        respond(ctx, msg, "there are no macros yet. Save one with `!macro save <name> <dice>`.");
+        return Ok(())
+    }
+
+    let mut lines: Vec<String> = Vec::new();
+    if !user_macros.is_empty() {
+        lines.push(String::from("your macros:"));
+        lines.extend(user_macros.iter().map(|m| format!("`{}`: `{}`", m.name, m.expression)));
+    }
+    if !guild_macros.is_empty() {
+        lines.push(String::from("guild macros:"));
+        lines.extend(guild_macros.iter().map(|m| format!("`{}`: `{}`", m.name, m.expression)));
+    }
+
+    let mut response = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        if response.len() + line.len() > MAX_LIST_LENGTH {
+            response.push_str(&format!("… and {} more", lines.len() - i));
+            break
+        }
+        response.push_str(line);
+        response.push('\n');
+    }
+    respond(ctx, msg, &response);
+
+    Ok(())
+}
+
+fn save_macro(
+    ctx: &mut Context,
+    msg: &Message,
+    args: &Args,
+    owner: MacroOwner,
+) -> CommandResult
+{
+    let mut parts = args.rest().trim().splitn(2, char::is_whitespace);
+    let (name, expression) = match (parts.next(), parts.next()) {
+        (Some(name), Some(expression)) if !name.is_empty() && !expression.trim().is_empty() =>
+            (dice::normalise(name), expression.trim()),
+        _ => {
+            // This is a usage error, not a bot failure
+            respond(ctx, msg, "give the macro a name and some dice; e.g. `!macro save attack 1d20+5`");
+            return Ok(())
+        }
+    };
+
+    if name.len() > MAX_MACRO_NAME_LENGTH || !dice::is_valid_macro_name(&name) {
+        respond(ctx, msg, &format!(
+            "macro names need to start with a letter, can only have letters, numbers and `_`, \
+             can't look like dice and can be at most {} characters long.",
+            MAX_MACRO_NAME_LENGTH));
+        return Ok(())
+    }
+    if expression.len() > MAX_MACRO_EXPRESSION_LENGTH {
+        respond(ctx, msg, "please choose a shorter expression!");
+        return Ok(())
+    }
+
+    let existing = load_macros(ctx, owner)?;
+    if existing.len() >= MAX_MACROS && !existing.iter().any(|m| m.name == name) {
+        respond(ctx, msg, &format!("there's a limit of {} macros, delete one first.", MAX_MACROS));
+        return Ok(())
+    }
+
+    // Check the macro rolls, including any macros it refers to. Guild macros may only
+    // refer to other guild macros, since everyone in the guild needs to be able to use them.
+    let mut macros = match owner {
+        MacroOwner::User(_) => available_macros(ctx, msg)?,
+        MacroOwner::Guild(_) => existing.into_iter().map(|m| (m.name, m.expression)).collect(),
+    };
+    macros.insert(name.clone(), String::from(expression));
+    if let Err(why) = dice::parse_with_macros(&name, &|name| macros.get(name).cloned()) {
+        respond(ctx, msg, &format!("I couldn't save that: {}.", why));
+        return Ok(())
+    }
+
+    debug!("Updating macro DB entry...");
+    let database = database::handle(ctx)?;
+    let data = RollMacro {
+        name: name.clone(),
+        expression: String::from(expression),
+    };
+    if let Err(why) = database.macro_update(owner, &data) {
+        let reason = format!("Could not update macro in database: {}", why);
+        error!("{}", reason);
+        return Err(CommandError(reason))
+    }
+    debug!("Saved macro {:?} for {:?}", data, owner);
+
+    respond(ctx, msg, &format!("saved macro `{}`; roll it with `!roll {}`.", name, name));
+
+    Ok(())
+}
+
+fn delete_macro(
+    ctx: &mut Context,
+    msg: &Message,
+    args: &Args,
+    owner: MacroOwner,
+) -> CommandResult
+{
+    let name = dice::normalise(args.rest());
+    if name.is_empty() {
+        // This is a usage error, not a bot failure
+        respond(ctx, msg, "which macro should I delete? e.g. `!macro delete attack`");
+        return Ok(())
+    }
+
+    debug!("Deleting macro DB entry...");
+    let database = database::handle(ctx)?;
+    match database.macro_delete(owner, &name) {
+        Ok(true) => respond(ctx, msg, &format!("deleted macro `{}`.", name)),
+        Ok(false) => respond(ctx, msg, &format!("there's no macro called `{}`.", name)),
+        Err(why) => {
+            let reason = format!("Could not delete macro from database: {}", why);
+            error!("{}", reason);
+            return Err(CommandError(reason))
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,281 @@
+pub mod dice;
+pub mod history;
+pub mod initiative;
+pub mod macros;
+
+use crate::commands::respond;
+use rand::thread_rng;
+use serenity::{
+    prelude::*,
+    model::prelude::*,
+    framework::standard::{
+        Args,
+        CommandResult,
+        macros::command,
+    },
+    utils::Colour,
+};
+
+/// Discord rejects embed fields longer than 1024 characters; leave some room
+/// for the group total and the note about leftover dice.
+const MAX_BREAKDOWN_LENGTH: usize = 900;
+
+/// Discord rejects embeds with more than 6000 characters of text in total.
+const MAX_EMBED_LENGTH: usize = 6000;
+
+/// Room in a breakdown for the group total and the note about leftover dice.
+const BREAKDOWN_OVERHEAD: usize = 64;
+
+/// Discord rejects embed titles longer than 256 characters.
+const MAX_TITLE_LENGTH: usize = 256;
+
+#[command]
+#[description = "Rolls dice and sends a response with the result."]
+#[usage = "`!roll 2d6+3`, `!roll 1d20+1d4-1`, `!roll 4d6kh3`, `!roll 3d6!`, `!roll 2d20r1`, \
+`!roll adv+5`, `!roll 6d10>=7` or `!roll <macro name>`"]
+fn roll(context: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("roll command handler called");
+
+    if args.rest().trim().is_empty() {
+        // This is a usage error, not a bot failure
+        respond(context, msg, "tell me what to roll; e.g. `!roll 2d6+3`");
+        return Ok(())
+    }
+
+    roll_expression(context, msg, args.rest(), &mut thread_rng())
+}
+
+/// Parse and roll a dice expression with the given RNG, sending a response with the result.
+/// The expression may use any macros available to the author of the message.
+/// Mistakes in the expression are explained to the user.
+pub fn roll_expression(
+    context: &mut Context,
+    msg: &Message,
+    input: &str,
+    rng: &mut dyn dice::DiceRng,
+) -> CommandResult
+{
+    let macros = macros::available_macros(context, msg)?;
+    let expression = match dice::parse_with_macros(input, &|name| macros.get(name).cloned()) {
+        Ok(expression) => expression,
+        Err(why) => {
+            debug!("Couldn't parse {:?} as dice: {:?}", input, why);
+            respond(context, msg, &format!("I couldn't roll that: {}.", why));
+            // This is a usage error, not a bot failure
+            return Ok(())
+        }
+    };
+
+    let result = expression.roll(rng);
+    debug!("Rolled {}: {:?}", expression, result);
+
+    let roll_id = history::record(context, msg, input, &result);
+
+    send_roll(context, msg, &result, roll_id);
+
+    Ok(())
+}
+
+/// Send a roll result as an embed with a breakdown of each dice group.
+/// The ID of the roll in the roll history is shown, so it can be looked up later.
+fn send_roll(context: &Context, msg: &Message, result: &dice::RollResult, roll_id: Option<i64>) {
+    let mut description = format!("Result: **{}**", result.total);
+    let colour = if result.is_critical() && !result.is_fumble() {
+        description.push_str("\n**Critical!**");
+        Colour::DARK_GREEN
+    } else if result.is_fumble() && !result.is_critical() {
+        description.push_str("\n**Fumble!**");
+        Colour::RED
+    } else {
+        Colour::default()
+    };
+
+    let title = title(result);
+    let footer = roll_id.map(|roll_id| format!("Roll #{}", roll_id));
+    let fixed_length = msg.author.name.chars().count()
+        + title.chars().count()
+        + description.chars().count()
+        + footer.as_ref().map_or(0, |footer| footer.chars().count());
+    let fields = fields(result, MAX_EMBED_LENGTH.saturating_sub(fixed_length));
+
+    if let Err(why) = msg.channel_id.send_message(&context.http, |m| {
+        m.embed(|e| {
+            e.author(|a| a.name(&msg.author.name).icon_url(msg.author.face()))
+                .title(title)
+                .description(description)
+                .colour(colour);
+            for (name, value) in fields {
+                e.field(name, value, false);
+            }
+            if let Some(footer) = footer {
+                e.footer(|f| f.text(footer));
+            }
+            e
+        })
+    }) {
+        error!("Error sending message: {:?}", why);
+    }
+}
+
+/// The embed title of a roll, cut short with `…` if the expression is too long.
+fn title(result: &dice::RollResult) -> String {
+    let title = format!("Rolling {}", result.expression);
+    if title.chars().count() <= MAX_TITLE_LENGTH {
+        return title
+    }
+    let mut title: String = title.chars().take(MAX_TITLE_LENGTH - 1).collect();
+    title.push('…');
+    title
+}
+
+/// Build the embed fields of a roll: a breakdown of each dice group, then the modifier.
+///
+/// The fields share `budget` characters between them, so that the embed stays within
+/// Discord's limit however many groups were rolled; once it runs low, groups only
+/// show their total.
+fn fields(result: &dice::RollResult, budget: usize) -> Vec<(String, String)> {
+    let modifier = result.modifier();
+    let modifier = if modifier != 0 {
+        Some((String::from("Modifier"), format!("{:+}", modifier)))
+    } else {
+        None
+    };
+    let mut budget = budget.saturating_sub(modifier.as_ref()
+        .map_or(0, |(name, value)| name.chars().count() + value.chars().count()));
+
+    let groups: Vec<(bool, &dice::GroupResult)> = result.terms.iter()
+        .filter_map(|(negative, term)| match term {
+            dice::TermResult::Dice(group) => Some((*negative, group)),
+            dice::TermResult::Constant(_) => None,
+        })
+        .collect();
+
+    let mut fields = Vec::with_capacity(groups.len() + 1);
+    for (i, (negative, group)) in groups.iter().enumerate() {
+        let name = format!("{}{}", if *negative { "-" } else { "" }, group.group);
+        // Groups that need less than their share leave the rest to the groups after them
+        let share = budget / (groups.len() - i);
+        let limit = share.saturating_sub(name.chars().count() + BREAKDOWN_OVERHEAD)
+            .min(MAX_BREAKDOWN_LENGTH);
+        let value = breakdown(group, limit);
+        budget = budget.saturating_sub(name.chars().count() + value.chars().count());
+        fields.push((name, value));
+    }
+    fields.extend(modifier);
+
+    fields
+}
+
+/// Describe each die of a group, e.g. `[~~2~~, 5, **6**!, 3] = 14`.
+///
+/// Dropped dice are struck through, rerolled results are shown before the
+/// die's final value, and dice that exploded are followed by a `!`.
+/// Groups are cut short once the dice take more than `limit` characters.
+fn breakdown(result: &dice::GroupResult, limit: usize) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    let mut length = 0;
+
+    for (i, die) in result.dice.iter().enumerate() {
+        let mut part = String::new();
+        for value in &die.rerolled {
+            part.push_str(&format!("~~{}~~→", value));
+        }
+
+        let highlight = match result.group.target {
+            Some(_) => result.is_success(die),
+            None => die.value == result.group.sides,
+        };
+        if !die.kept {
+            part.push_str(&format!("~~{}~~", die.value));
+        } else if highlight {
+            part.push_str(&format!("**{}**", die.value));
+        } else {
+            part.push_str(&die.value.to_string());
+        }
+
+        let triggered_explosion = result.dice.get(i + 1)
+            .is_some_and(|next| next.exploded);
+        if triggered_explosion {
+            part.push('!');
+        }
+
+        let part_length = part.chars().count();
+        if length + part_length > limit {
+            parts.push(format!("… and {} more", result.dice.len() - i));
+            break
+        }
+        length += part_length + 2;
+        parts.push(part);
+    }
+
+    match result.group.target {
+        Some(_) => format!("[{}] = {} successes", parts.join(", "), result.value),
+        None => format!("[{}] = {}", parts.join(", "), result.value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn roll(input: &str) -> dice::RollResult {
+        dice::parse(input).unwrap().roll(&mut StdRng::seed_from_u64(42))
+    }
+
+    fn length(fields: &[(String, String)]) -> usize {
+        fields.iter().map(|(name, value)| name.chars().count() + value.chars().count()).sum()
+    }
+
+    #[test]
+    fn fields_show_every_die_of_small_rolls() {
+        let result = roll("2d6+3");
+        let fields = fields(&result, MAX_EMBED_LENGTH);
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0].0, "2d6");
+        assert!(!fields[0].1.contains("more"));
+        assert_eq!(fields[1], (String::from("Modifier"), String::from("+3")));
+    }
+
+    #[test]
+    fn fields_fit_the_embed_budget() {
+        let input = vec!["100d1000!"; 19].join("+") + "+5";
+        let result = roll(&input);
+        let fields = fields(&result, MAX_EMBED_LENGTH - 200);
+        assert_eq!(fields.len(), 20);
+        assert!(length(&fields) <= MAX_EMBED_LENGTH - 200);
+        assert!(fields.iter().all(|(_, value)| value.chars().count() <= 1024));
+        assert_eq!(fields[19].1, "+5");
+    }
+
+    #[test]
+    fn long_titles_are_cut_short() {
+        assert_eq!(title(&roll("2d6+3")), "Rolling 2d6+3");
+
+        let input = vec!["100d1000!r<2"; 20].join("+");
+        let title = title(&roll(&input));
+        assert_eq!(title.chars().count(), MAX_TITLE_LENGTH);
+        assert!(title.starts_with("Rolling 100d1000!r<2+"));
+        assert!(title.ends_with('…'));
+    }
+
+    #[test]
+    fn fields_collapse_to_totals_without_budget() {
+        let result = roll("10d6+10d6");
+        let fields = fields(&result, 0);
+        let total = result.groups().next().unwrap().value;
+        assert_eq!(fields[0].1, format!("[… and 10 more] = {}", total));
+    }
+
+    #[test]
+    fn breakdown_counts_characters() {
+        // The arrow after a rerolled result is 3 bytes but 1 character
+        let result = roll("4d20ro<20");
+        let group = result.groups().next().unwrap();
+        let full = breakdown(group, MAX_BREAKDOWN_LENGTH);
+        assert!(full.contains('→'));
+        let dice = full.split(" = ").next().unwrap();
+        let dice_length = dice.chars().count() - 2;
+        assert_eq!(breakdown(group, dice_length), full);
+    }
+}
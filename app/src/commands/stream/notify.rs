@@ -0,0 +1,99 @@
+use crate::{
+    commands::respond,
+    database,
+};
+use serenity::{
+    prelude::*,
+    model::prelude::*,
+    framework::standard::{
+        CommandError,
+        CommandResult,
+        macros::command,
+    },
+};
+
+// Command for members to choose whether their streams are shouted out in this guild.
+#[command]
+#[description = "Shows or changes whether your streams are shouted out in this guild."]
+#[usage = "`!streamnotify`, `!streamnotify on` or `!streamnotify off`"]
+#[only_in(guilds)]
+#[sub_commands(streamnotify_on, streamnotify_off)]
+fn streamnotify(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("streamnotify command handler called");
+
+    let (_, member) = load_member(ctx, msg)?;
+    let txt = if member.stream_notify_opt_out {
+        "your streams won't be shouted out here. Use `!streamnotify on` to change that."
+    } else {
+        "your streams can be shouted out here. Use `!streamnotify off` to change that."
+    };
+    respond(ctx, msg, txt);
+
+    Ok(())
+}
+
+#[command("on")]
+#[description = "Lets your streams be shouted out in this guild."]
+#[only_in(guilds)]
+fn streamnotify_on(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("streamnotify on command handler called");
+    set_opt_out(ctx, msg, false)
+}
+
+#[command("off")]
+#[description = "Stops your streams being shouted out in this guild."]
+#[only_in(guilds)]
+fn streamnotify_off(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("streamnotify off command handler called");
+    set_opt_out(ctx, msg, true)
+}
+
+fn set_opt_out(
+    ctx: &mut Context,
+    msg: &Message,
+    opt_out: bool,
+) -> CommandResult
+{
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Err(CommandError(String::from("Message has no guild ID"))),
+    };
+
+    debug!("Updating member DB entry...");
+    let database = database::handle(ctx)?;
+    let updated = database.member_stream_notify_opt_out_update(
+        guild_id.0, msg.author.id.0, opt_out);
+    if let Err(why) = updated {
+        let reason = format!("Could not update member data in database: {}", why);
+        error!("{}", reason);
+        return Err(CommandError(reason))
+    }
+    debug!("Updated stream shout-out opt-out of member {} to {}", msg.author.id, opt_out);
+
+    let txt = if opt_out {
+        "your streams won't be shouted out here any more."
+    } else {
+        "your streams can be shouted out here again."
+    };
+    respond(ctx, msg, txt);
+
+    Ok(())
+}
+
+fn load_member(ctx: &Context, msg: &Message) -> Result<(GuildId, database::Member), CommandError> {
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Err(CommandError(String::from("Message has no guild ID"))),
+    };
+
+    debug!("Member DB data retrieval...");
+    let database = database::handle(ctx)?;
+    match database.member(guild_id.0, msg.author.id.0) {
+        Ok(member) => Ok((guild_id, member)),
+        Err(why) => {
+            let reason = format!("Could not retrieve member data from database: {}", why);
+            error!("{}", reason);
+            Err(CommandError(reason))
+        }
+    }
+}
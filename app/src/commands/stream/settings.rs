@@ -0,0 +1,527 @@
+use crate::{
+    commands::{
+        admin::GUILDADMIN_CHECK,
+        change_guild_settings, load_guild, message_guild, respond, role_name, update_guild,
+    },
+    config,
+    database,
+    stream_notify,
+    stream_provider,
+    title_policy,
+};
+use serenity::{
+    prelude::*,
+    model::prelude::*,
+    framework::standard::{
+        Args,
+        CommandError,
+        CommandResult,
+        macros::command,
+    },
+    utils::MessageBuilder,
+};
+
+// Command to view and change the stream shout-out settings of a guild.
+// Only guild admins may use it.
+#[command]
+#[description = "Shows or changes the stream shout-out settings for this guild."]
+#[usage = "`!streamconfig`, `!streamconfig channel #channel`, \
+`!streamconfig cooldown <seconds>`, `!streamconfig enable`, `!streamconfig disable`, \
+`!streamconfig template <text>`, `!streamconfig role @role`, `!streamconfig preview [text]` or \
+`!streamconfig streamers add|remove @role`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+#[sub_commands(streamconfig_channel, streamconfig_cooldown,
+               streamconfig_enable, streamconfig_disable,
+               streamconfig_template, streamconfig_role, streamconfig_preview,
+               streamconfig_streamers)]
+fn streamconfig(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("streamconfig command handler called");
+
+    let (guild_id, guild) = load_guild(ctx, msg)?;
+
+    let channel = match guild.stream_notify_channel_id {
+        Some(channel_id) => ChannelId(channel_id).mention(),
+        None => match stream_notify::default_channel(ctx, guild_id) {
+            Some(channel_id) => format!("{} (default)", channel_id.mention()),
+            None => String::from("not set"),
+        },
+    };
+    let cooldown = match guild.stream_notify_cooldown {
+        Some(cooldown) => format!("{} seconds", cooldown),
+        None => format!("{} seconds (default)",
+                        config::get(ctx).stream_notify.cooldown),
+    };
+    let role = match guild.stream_notify_role_id {
+        Some(role_id) => role_name(ctx, guild_id, RoleId(role_id)),
+        None => String::from("none"),
+    };
+    let streamer_roles = match load_streamer_roles(ctx, guild_id)?.as_slice() {
+        [] => String::from("anyone"),
+        role_ids => role_ids.iter()
+            .map(|role_id| role_name(ctx, guild_id, *role_id))
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+    let template = match &guild.stream_notify_template {
+        Some(template) => template.clone(),
+        None => format!("{} (default)", stream_notify::DEFAULT_STREAM_NOTIFY_TEMPLATE),
+    };
+
+    let response = MessageBuilder::new()
+        .push_bold_safe(&msg.author)
+        .push(", stream shout-out settings for this guild:\n")
+        .push("Shout-outs: ")
+        .push_bold(if guild.stream_notify_enabled { "enabled" } else { "disabled" })
+        .push("\nChannel: ")
+        .push(channel)
+        .push("\nCooldown: ")
+        .push_bold(cooldown)
+        .push("\nShouted out: ")
+        .push_bold_safe(streamer_roles)
+        .push("\nRole mentioned: ")
+        .push_bold_safe(role)
+        .push("\nTemplate: ")
+        .push_mono_safe(template)
+        .build();
+
+    if let Err(why) = msg.channel_id.say(&ctx.http, &response) {
+        error!("Error sending message: {:?}", why);
+    }
+
+    debug!("Showed stream shout-out settings for guild {}", guild_id);
+    Ok(())
+}
+
+#[command("channel")]
+#[description = "Sets the channel stream shout-outs are sent to."]
+#[usage = "`!streamconfig channel #channel`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn streamconfig_channel(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("streamconfig channel command handler called");
+
+    let channel_id = match args.trimmed().single::<ChannelId>() {
+        Ok(channel_id) => channel_id,
+        Err(_) => {
+            // This is a usage error, not a bot failure
+            respond(ctx, msg, "please mention a channel; e.g. `!streamconfig channel #streams`");
+            return Ok(())
+        }
+    };
+
+    let guild_id = message_guild(ctx, msg)?;
+
+    // Only accept channels which belong to this guild
+    let in_guild = match guild_id.to_guild_cached(&ctx.cache) {
+        Some(discord_guild) => discord_guild.read().channels.contains_key(&channel_id),
+        None => false,
+    };
+    if !in_guild {
+        respond(ctx, msg, "I can't find that channel in this guild.");
+        return Ok(())
+    }
+
+    update_guild(ctx, msg, guild_id, |guild| guild.stream_notify_channel_id = Some(channel_id.0))?;
+
+    let response = MessageBuilder::new()
+        .push("stream shout-outs will be sent to ")
+        .mention(&channel_id)
+        .push(".")
+        .build();
+    respond(ctx, msg, &response);
+
+    Ok(())
+}
+
+#[command("cooldown")]
+#[description = "Sets how many seconds must pass before the same member is shouted out again."]
+#[usage = "`!streamconfig cooldown <seconds>` or `!streamconfig cooldown default`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn streamconfig_cooldown(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("streamconfig cooldown command handler called");
+
+    let cooldown: Option<i64> = match args.trimmed().current() {
+        Some("default") => None,
+        _ => match args.parse::<i64>() {
+            Ok(cooldown) if cooldown >= 0 => Some(cooldown),
+            _ => {
+                // This is a usage error, not a bot failure
+                respond(ctx, msg, "please give a number of seconds; e.g. `!streamconfig cooldown 3600`");
+                return Ok(())
+            }
+        },
+    };
+
+    let guild_id = message_guild(ctx, msg)?;
+    update_guild(ctx, msg, guild_id, |guild| guild.stream_notify_cooldown = cooldown)?;
+
+    let txt = match cooldown {
+        Some(cooldown) => format!("the stream shout-out cooldown is now {} seconds.", cooldown),
+        None => format!("the stream shout-out cooldown is back to the default of {} seconds.",
+                        config::get(ctx).stream_notify.cooldown),
+    };
+    respond(ctx, msg, &txt);
+
+    Ok(())
+}
+
+#[command("enable")]
+#[description = "Turns stream shout-outs on for this guild."]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn streamconfig_enable(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("streamconfig enable command handler called");
+    set_enabled(ctx, msg, true)
+}
+
+#[command("disable")]
+#[description = "Turns stream shout-outs off for this guild."]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn streamconfig_disable(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("streamconfig disable command handler called");
+    set_enabled(ctx, msg, false)
+}
+
+#[command("template")]
+#[description = "Sets the wording of stream shout-outs. Placeholders are replaced with \
+details of the stream: `{name}`, `{title}`, `{stream_title}`, `{game}`, `{url}`, `{platform}` \
+and `{role}`."]
+#[usage = "`!streamconfig template {name} is live on {platform}: {url}` or \
+`!streamconfig template default`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn streamconfig_template(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("streamconfig template command handler called");
+
+    let template = match args.rest().trim() {
+        "" => {
+            // This is a usage error, not a bot failure
+            respond(ctx, msg, "please give a template; e.g. \
+                `!streamconfig template {name} is live on {platform}: {url}`");
+            return Ok(())
+        },
+        "default" => None,
+        template => Some(String::from(template)),
+    };
+
+    if let Some(template) = &template {
+        if let Err(why) = stream_notify::render_template(template, &example_shout_out(ctx, msg)) {
+            respond(ctx, msg, &format!("I couldn't use that template: {}.", why));
+            return Ok(())
+        }
+    }
+
+    let guild_id = message_guild(ctx, msg)?;
+    let guild = update_guild(ctx, msg, guild_id, |guild| guild.stream_notify_template = template)?;
+
+    let txt = match guild.stream_notify_template {
+        Some(_) => "saved the stream shout-out template. Try it with `!streamconfig preview`.",
+        None => "stream shout-outs will use the default template again.",
+    };
+    respond(ctx, msg, txt);
+
+    Ok(())
+}
+
+#[command("role")]
+#[description = "Sets a role to mention in stream shout-outs."]
+#[usage = "`!streamconfig role @role` or `!streamconfig role none`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn streamconfig_role(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("streamconfig role command handler called");
+
+    let role_id = match args.trimmed().current() {
+        Some("none") => None,
+        _ => match args.single::<RoleId>() {
+            Ok(role_id) => Some(role_id),
+            Err(_) => {
+                // This is a usage error, not a bot failure
+                respond(ctx, msg, "please mention a role; e.g. `!streamconfig role @streams`, \
+                    or use `!streamconfig role none`");
+                return Ok(())
+            }
+        },
+    };
+
+    let guild_id = message_guild(ctx, msg)?;
+
+    if let Some(role_id) = role_id {
+        // Only accept roles which belong to this guild
+        let in_guild = match guild_id.to_guild_cached(&ctx.cache) {
+            Some(discord_guild) => discord_guild.read().roles.contains_key(&role_id),
+            None => false,
+        };
+        if !in_guild {
+            respond(ctx, msg, "I can't find that role in this guild.");
+            return Ok(())
+        }
+    }
+
+    update_guild(ctx, msg, guild_id,
+        |guild| guild.stream_notify_role_id = role_id.map(|role_id| role_id.0))?;
+
+    let txt = match role_id {
+        Some(role_id) => MessageBuilder::new()
+            .push("stream shout-outs will mention ")
+            .push_bold_safe(role_name(ctx, guild_id, role_id))
+            .push(".")
+            .build(),
+        None => String::from("stream shout-outs won't mention a role."),
+    };
+    respond(ctx, msg, &txt);
+
+    Ok(())
+}
+
+#[command("preview")]
+#[description = "Shows what a stream shout-out of yours would look like, \
+using the saved template or the one given."]
+#[usage = "`!streamconfig preview` or `!streamconfig preview {name} is live: {url}`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn streamconfig_preview(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("streamconfig preview command handler called");
+
+    let (_, guild) = load_guild(ctx, msg)?;
+    let template = match args.rest().trim() {
+        "" => guild.stream_notify_template
+            .unwrap_or_else(|| String::from(stream_notify::DEFAULT_STREAM_NOTIFY_TEMPLATE)),
+        template => String::from(template),
+    };
+
+    let mut shout_out = example_shout_out(ctx, msg);
+    // Name the role rather than mentioning it, so a preview doesn't notify anyone
+    if let (Some(guild_id), Some(role_id)) = (msg.guild_id, guild.stream_notify_role_id) {
+        shout_out.role = format!("@{}", role_name(ctx, guild_id, RoleId(role_id)));
+    }
+
+    let channel_text = match stream_notify::render_template(&template, &shout_out) {
+        Ok(text) => text,
+        Err(why) => {
+            respond(ctx, msg, &format!("I couldn't use that template: {}.", why));
+            return Ok(())
+        }
+    };
+
+    let colour = msg.guild_id
+        .and_then(|guild_id| guild_id.to_guild_cached(&ctx.cache))
+        .and_then(|guild| guild.read().members.get(&msg.author.id)
+            .and_then(|member| member.colour(&ctx.cache)))
+        .unwrap_or_default();
+
+    if let Err(why) = stream_notify::send_shout_out(
+        ctx, msg.channel_id, &channel_text, &shout_out, colour, &msg.author.face())
+    {
+        error!("Error sending message: {:?}", why);
+    }
+
+    Ok(())
+}
+
+#[command("streamers")]
+#[description = "Lists the roles whose members get shouted out. \
+If there are none, everyone who hasn't opted out does."]
+#[usage = "`!streamconfig streamers`, `!streamconfig streamers add @role`, \
+`!streamconfig streamers remove @role` or `!streamconfig streamers clear`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+#[sub_commands(streamers_add, streamers_remove, streamers_clear)]
+fn streamconfig_streamers(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("streamconfig streamers command handler called");
+
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+
+    let txt = match load_streamer_roles(ctx, guild_id)?.as_slice() {
+        [] => String::from("every member who hasn't opted out gets shouted out. \
+            Limit this to some roles with `!streamconfig streamers add @role`."),
+        role_ids => MessageBuilder::new()
+            .push("only members with one of these roles get shouted out: ")
+            .push_bold_safe(role_ids.iter()
+                .map(|role_id| role_name(ctx, guild_id, *role_id))
+                .collect::<Vec<_>>()
+                .join(", "))
+            .build(),
+    };
+    respond(ctx, msg, &txt);
+
+    Ok(())
+}
+
+#[command("add")]
+#[description = "Limits stream shout-outs to members with this role, or one of the other roles added."]
+#[usage = "`!streamconfig streamers add @Streamer`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn streamers_add(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("streamconfig streamers add command handler called");
+
+    let (guild_id, role_id) = match guild_role(ctx, msg, &mut args) {
+        Some(found) => found,
+        None => return Ok(()),
+    };
+
+    debug!("Adding stream shout-out role DB entry...");
+    change_guild_settings(ctx, msg, guild_id,
+        |database| database.stream_notify_role_add(guild_id.0, role_id.0))?;
+
+    let txt = MessageBuilder::new()
+        .push("members with the ")
+        .push_bold_safe(role_name(ctx, guild_id, role_id))
+        .push(" role will be shouted out.")
+        .build();
+    respond(ctx, msg, &txt);
+
+    Ok(())
+}
+
+#[command("remove")]
+#[description = "Stops limiting stream shout-outs to members with this role."]
+#[usage = "`!streamconfig streamers remove @Streamer`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn streamers_remove(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("streamconfig streamers remove command handler called");
+
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+    // The role may have been deleted from the guild since, so don't look it up
+    let role_id = match args.trimmed().single::<RoleId>() {
+        Ok(role_id) => role_id,
+        Err(_) => {
+            // This is a usage error, not a bot failure
+            respond(ctx, msg, "please mention a role; e.g. `!streamconfig streamers remove @Streamer`");
+            return Ok(())
+        }
+    };
+
+    remove_streamer_roles(ctx, msg, guild_id, Some(role_id))
+}
+
+#[command("clear")]
+#[description = "Lets every member who hasn't opted out be shouted out again."]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn streamers_clear(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("streamconfig streamers clear command handler called");
+
+    match msg.guild_id {
+        Some(guild_id) => remove_streamer_roles(ctx, msg, guild_id, None),
+        None => Ok(()),
+    }
+}
+
+fn remove_streamer_roles(
+    ctx: &mut Context,
+    msg: &Message,
+    guild_id: GuildId,
+    role_id: Option<RoleId>,
+) -> CommandResult
+{
+    debug!("Deleting stream shout-out role DB entries...");
+    let removed = change_guild_settings(ctx, msg, guild_id,
+        |database| database.stream_notify_role_remove(guild_id.0, role_id.map(|role_id| role_id.0)))?;
+
+    let remaining = load_streamer_roles(ctx, guild_id)?;
+    let txt = match (removed, remaining.is_empty()) {
+        (false, _) => "that role wasn't limiting shout-outs.",
+        (true, true) => "every member who hasn't opted out will be shouted out again.",
+        (true, false) => "members with that role won't be shouted out unless they have another \
+            of the roles.",
+    };
+    respond(ctx, msg, txt);
+
+    Ok(())
+}
+
+/// Parse a mention of a role in the guild a message was sent in.
+/// Explain to the user what's wrong otherwise.
+fn guild_role(ctx: &Context, msg: &Message, args: &mut Args) -> Option<(GuildId, RoleId)> {
+    let guild_id = msg.guild_id?;
+    let role_id = match args.trimmed().single::<RoleId>() {
+        Ok(role_id) => role_id,
+        Err(_) => {
+            // This is a usage error, not a bot failure
+            respond(ctx, msg, "please mention a role; e.g. `!streamconfig streamers add @Streamer`");
+            return None
+        }
+    };
+
+    // Only accept roles which belong to this guild
+    let in_guild = match guild_id.to_guild_cached(&ctx.cache) {
+        Some(discord_guild) => discord_guild.read().roles.contains_key(&role_id),
+        None => false,
+    };
+    if !in_guild {
+        respond(ctx, msg, "I can't find that role in this guild.");
+        return None
+    }
+
+    Some((guild_id, role_id))
+}
+
+fn load_streamer_roles(ctx: &Context, guild_id: GuildId) -> Result<Vec<RoleId>, CommandError> {
+    debug!("Stream shout-out roles DB data retrieval...");
+    let database = database::handle(ctx)?;
+    match database.stream_notify_roles(guild_id.0) {
+        Ok(role_ids) => Ok(role_ids.into_iter().map(RoleId).collect()),
+        Err(why) => {
+            let reason = format!("Could not retrieve stream shout-out roles from database: {}", why);
+            error!("{}", reason);
+            Err(CommandError(reason))
+        }
+    }
+}
+
+/// A made-up stream by the author of a message, to check templates against.
+fn example_shout_out(ctx: &Context, msg: &Message) -> stream_notify::ShoutOut {
+    let title = database::handle(ctx).and_then(|database| {
+        title_policy::shown_title(&database, msg.author.id.0, msg.guild_id.map(|guild_id| guild_id.0))
+    });
+    let title = match title {
+        Ok(title) => title.unwrap_or_default(),
+        Err(why) => {
+            error!("Could not retrieve user data from database: {}", why);
+            String::new()
+        },
+    };
+    let url = String::from("https://www.twitch.tv/catnip");
+
+    stream_notify::ShoutOut {
+        name: msg.author_nick(ctx).unwrap_or_else(|| msg.author.name.clone()),
+        title,
+        stream_title: String::from("Example stream"),
+        game: String::from("Just Chatting"),
+        platform: stream_provider::platform(&config::get(ctx), &url),
+        url,
+        role: String::new(),
+        info: Default::default(),
+    }
+}
+
+fn set_enabled(
+    ctx: &mut Context,
+    msg: &Message,
+    enabled: bool,
+) -> CommandResult
+{
+    let guild_id = message_guild(ctx, msg)?;
+    update_guild(ctx, msg, guild_id, |guild| guild.stream_notify_enabled = enabled)?;
+
+    let txt = if enabled {
+        "stream shout-outs are now enabled."
+    } else {
+        "stream shout-outs are now disabled."
+    };
+    respond(ctx, msg, txt);
+
+    Ok(())
+}
@@ -1,81 +1,459 @@
+use crate::{
+    commands::{admin::GUILDADMIN_CHECK, arguments, load_guild, respond, update_guild},
+    database,
+};
 use serenity::{
     prelude::*,
     model::prelude::*,
     framework::standard::{
+        Args,
         CommandError,
         CommandResult,
         macros::command,
     },
-    utils::MessageBuilder,
+    utils::{Colour, MessageBuilder},
 };
+use std::collections::HashMap;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Colour roles are named with this prefix, unless a guild chose its own.
+pub const DEFAULT_COLOUR_ROLE_PREFIX: &str = "colour-";
+/// Longest colour role prefix a guild may choose.
+const MAX_PREFIX_LENGTH: usize = 32;
+/// Discord doesn't allow role names longer than this.
+const MAX_ROLE_NAME_LENGTH: usize = 100;
+/// Keep `!colour list` within Discord's limit on the length of an embed description.
+const MAX_LIST_LENGTH: usize = 2000;
+/// Colours are suggested when a name is this many typos away from their name, or closer.
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+const MAX_SUGGESTIONS: usize = 3;
+/// How much of the list of colours to put in a reply to a name that wasn't found.
+const MAX_REPLY_LIST_LENGTH: usize = 1000;
+
+/// How readable custom colours must be against Discord's themes unless a guild chose
+/// its own limits, as contrast ratios. Most members use the dark theme, so it's stricter.
+pub const DEFAULT_MIN_CONTRAST_DARK: f64 = 2.0;
+pub const DEFAULT_MIN_CONTRAST_LIGHT: f64 = 1.5;
+/// Contrast ratios go from 1, for the same colour, to 21, for black on white.
+const MAX_CONTRAST: f64 = 21.0;
+/// Backgrounds of messages in Discord's dark and light themes.
+const DARK_THEME_BACKGROUND: Colour = Colour::new(0x36_39_3F);
+const LIGHT_THEME_BACKGROUND: Colour = Colour::new(0xFF_FF_FF);
+
+/// Square emoji to show roughly what a colour looks like, with the colour each one shows as.
+const SWATCHES: &[(&str, (u8, u8, u8))] = &[
+    ("🟥", (221, 46, 68)),
+    ("🟧", (244, 144, 12)),
+    ("🟨", (253, 203, 88)),
+    ("🟩", (120, 177, 89)),
+    ("🟦", (85, 172, 238)),
+    ("🟪", (170, 142, 214)),
+    ("🟫", (193, 105, 79)),
+    ("⬛", (49, 55, 61)),
+    ("⬜", (230, 231, 232)),
+];
 
 // Command to change a users colour in the guild using currently available
-// roles, prefixed with `colour-`. 
+// roles, prefixed with `colour-` or the guild's own prefix, or any colour
+// if the guild allows custom colours. Arguments are separated by spaces,
+// with quotes around any that contain them.
 #[command]
-#[description = "Sets your colour using designated roles."]
-#[usage = "`!colour <name of colour>`"]
+#[description = "Sets your colour using designated roles, or any colour given as a hex code \
+if this guild allows it."]
+#[usage = "`!colour <name of colour>`, `!colour #ff8800`, `!colour list`, `!colour clear`, or for guild admins \
+`!colour create <name> <hex>`, `!colour delete <name>`, `!colour prefix [prefix]`, \
+`!colour custom on|off` and `!colour contrast [dark|light <ratio>]`"]
 #[only_in(guilds)]
-fn colour(ctx: &mut Context, msg: &Message) -> CommandResult {
+#[delimiters(" ")]
+#[sub_commands(colour_list, colour_clear, colour_create, colour_delete, colour_prefix,
+               colour_custom, colour_contrast)]
+fn colour(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
     debug!("colour command handler called");
-    let args: Vec<&str> = msg.content.split(' ').skip(1).collect();
+    let chosen = arguments(&mut args).join(" ");
 
-    let colour =  match args.get(0) {
-        None => {
+    if chosen.is_empty() {
+        // This is a usage error, not a bot failure
+        respond(ctx, msg, "you forgot to pick a colour; e.g. `!colour blue`. \
+            `!colour list` shows them all.");
+        return Ok(())
+    }
+    let (guild_id, guild) = load_guild(ctx, msg)?;
+    let colours = colour_roles(ctx, msg, guild_id, role_prefix(&guild))?;
+    let mut member = guild_member(ctx, msg, guild_id)?;
+
+    if chosen.starts_with('#') {
+        let role_id = match custom_colour_role(ctx, msg, guild_id, &guild, &chosen)? {
+            Some(role_id) => role_id,
+            None => return Ok(()),
+        };
+        return set_colour_role(ctx, msg, &mut member, &colours, Some(role_id), true)
+    }
+
+    let role_id = match find_colour(&colours, &chosen) {
+        Ok(colour) => colour.role_id,
+        Err(suggestions) => {
             // This is a usage error, not a bot failure
-            respond(ctx, msg, "you forgot to pick a colour; e.g. `!colour blue`");
+            respond(ctx, msg, &not_found(&colours, &chosen, &suggestions, &guild));
             return Ok(())
-        },
-        _ => "colour-".to_owned() + &args.join("").to_lowercase()
+        }
     };
 
-    let p_guild = match msg.guild_id {
-        None => {
-            let txt = "we couldn't find which guild this came from. Sorry!";
-            respond(ctx, msg, txt);
-            return Err(CommandError(String::from(txt)))
-        },
-        Some(guild) => {
-            let p_guild = match guild.to_partial_guild(&ctx.http) {
-                Ok(p_guild) => p_guild,
-                Err(_) => {
-                    let txt = "something went wrong when fetching guild info. Sorry!";
-                    respond(ctx, msg, txt);
-                    return Err(CommandError(String::from(txt)))
-                }
-            };
-            p_guild
+    set_colour_role(ctx, msg, &mut member, &colours, Some(role_id), false)
+}
+
+#[command("clear")]
+#[description = "Takes away your colour."]
+#[usage = "`!colour clear`"]
+#[only_in(guilds)]
+fn colour_clear(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("colour clear command handler called");
+
+    let (guild_id, guild) = load_guild(ctx, msg)?;
+    let colours = colour_roles(ctx, msg, guild_id, role_prefix(&guild))?;
+    let mut member = guild_member(ctx, msg, guild_id)?;
+
+    set_colour_role(ctx, msg, &mut member, &colours, None, false)
+}
+
+#[command("list")]
+#[description = "Shows the colours you can choose from."]
+#[usage = "`!colour list`"]
+#[only_in(guilds)]
+fn colour_list(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("colour list command handler called");
+
+    let (guild_id, guild) = load_guild(ctx, msg)?;
+    let colours = colour_roles(ctx, msg, guild_id, role_prefix(&guild))?;
+
+    if colours.is_empty() {
+        respond(ctx, msg, "there are no colours to choose from yet. \
+            Guild admins can add some with `!colour create <name> <hex>`.");
+        return Ok(())
+    }
+
+    let mut description = String::new();
+    for (index, colour) in colours.iter().enumerate() {
+        let line = MessageBuilder::new()
+            .push(swatch(colour.colour))
+            .push(" ")
+            .push_bold_safe(&colour.name)
+            .push(" ")
+            .push_mono(format!("#{}", colour.colour.hex()))
+            .push("\n")
+            .build();
+        if description.len() + line.len() > MAX_LIST_LENGTH {
+            description.push_str(&format!("…and {} more", colours.len() - index));
+            break
         }
+        description.push_str(&line);
+    }
+
+    if let Err(why) = msg.channel_id.send_message(&ctx.http, |m| {
+        m.embed(|e| {
+            e.title("Colours");
+            e.description(description);
+            e.footer(|f| f.text(if guild.custom_colours_enabled {
+                "Choose one with !colour <name>, or any colour with !colour #<hex>"
+            } else {
+                "Choose one with !colour <name>"
+            }));
+            e
+        })
+    }) {
+        error!("Error sending message: {:?}", why);
+    }
+
+    Ok(())
+}
+
+#[command("create")]
+#[description = "Creates a colour role, placed just below the bot's own role \
+so that it shows over members' other roles."]
+#[usage = "`!colour create <name> <hex>`; e.g. `!colour create sky blue #87ceeb`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+#[delimiters(" ")]
+fn colour_create(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("colour create command handler called");
+
+    let words = arguments(&mut args);
+    let (name, colour) = match words.split_last() {
+        Some((hex, name)) if !name.is_empty() => match parse_hex_colour(hex) {
+            Some(colour) => (name.join(" "), colour),
+            None => {
+                // This is a usage error, not a bot failure
+                respond(ctx, msg, "please give the colour as a hex code; e.g. `#87ceeb`");
+                return Ok(())
+            },
+        },
+        _ => {
+            // This is a usage error, not a bot failure
+            respond(ctx, msg, "please give a name and a hex code; e.g. \
+                `!colour create sky blue #87ceeb`");
+            return Ok(())
+        },
     };
 
-    let mut member = match p_guild.member(&ctx, &msg.author) {
-        Ok(member) => member,
-        Err(_) => {
-            let txt = "I can't find you in the guild. Sorry!";
-            respond(ctx, msg, txt);
-            return Err(CommandError(String::from(txt)))
-        }
+    let (guild_id, guild) = load_guild(ctx, msg)?;
+    let name = role_name(role_prefix(&guild), &name);
+    if name.chars().count() > MAX_ROLE_NAME_LENGTH {
+        respond(ctx, msg, &format!(
+            "role names can be up to {} characters, including the prefix.", MAX_ROLE_NAME_LENGTH));
+        return Ok(())
+    }
+    if find_role(ctx, guild_id, &name).is_some() {
+        let txt = MessageBuilder::new()
+            .push("there's already a role called ")
+            .push_bold_safe(&name)
+            .push(".")
+            .build();
+        respond(ctx, msg, &txt);
+        return Ok(())
+    }
+
+    let (_, positioned) = create_colour_role(ctx, msg, guild_id, &name, colour)?;
+    forget_colour_roles(ctx, guild_id);
+
+    let mut txt = MessageBuilder::new();
+    txt.push("created ")
+        .push_bold_safe(&name)
+        .push("; members can choose it with ")
+        .push_mono_safe(format!("!colour {}", &name[role_prefix(&guild).len()..]))
+        .push(".");
+    if !positioned {
+        txt.push(" I couldn't move it up, so move it above members' other coloured roles \
+            for it to show.");
+    }
+    respond(ctx, msg, &txt.build());
+
+    Ok(())
+}
+
+#[command("delete")]
+#[description = "Deletes a colour role, taking the colour away from everyone who chose it."]
+#[usage = "`!colour delete <name>`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+#[delimiters(" ")]
+fn colour_delete(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("colour delete command handler called");
+
+    let name = &arguments(&mut args).join(" ");
+    if name.is_empty() {
+        // This is a usage error, not a bot failure
+        respond(ctx, msg, "please name a colour; e.g. `!colour delete sky blue`");
+        return Ok(())
+    }
+
+    let (guild_id, guild) = load_guild(ctx, msg)?;
+    let colours = colour_roles(ctx, msg, guild_id, role_prefix(&guild))?;
+    // Only delete exactly the colour named, never a suggestion
+    let colour = match find_colour(&colours, name) {
+        Ok(colour) => colour,
+        Err(suggestions) => {
+            respond(ctx, msg, &not_found(&colours, name, &suggestions, &guild));
+            return Ok(())
+        },
     };
 
-    let role_id = match p_guild.role_by_name(&colour) {
-        Some(role_id) => role_id,
-        None => {
-            let txt = colour + " isn't available. Sorry!";
+    debug!("Deleting colour role {} in guild {}", colour.name, guild_id);
+    if let Err(why) = guild_id.delete_role(&ctx.http, colour.role_id) {
+        respond(ctx, msg, "I couldn't delete the role. Do I have the Manage Roles permission?");
+        let reason = format!("Could not delete colour role: {:?}", why);
+        error!("{}", reason);
+        return Err(CommandError(reason))
+    }
+    forget_colour_roles(ctx, guild_id);
+
+    let txt = MessageBuilder::new()
+        .push("deleted ")
+        .push_bold_safe(&colour.name)
+        .push(".")
+        .build();
+    respond(ctx, msg, &txt);
+
+    Ok(())
+}
+
+#[command("prefix")]
+#[description = "Shows or sets what the names of colour roles start with. \
+Roles named with the old prefix stop being colours."]
+#[usage = "`!colour prefix`, `!colour prefix color-` or `!colour prefix default`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+#[delimiters(" ")]
+fn colour_prefix(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("colour prefix command handler called");
+
+    let (guild_id, guild) = load_guild(ctx, msg)?;
+    let prefix = match arguments(&mut args).join(" ").as_str() {
+        "" => {
+            let txt = MessageBuilder::new()
+                .push("colour roles in this guild are named ")
+                .push_mono_safe(format!("{}<name>", role_prefix(&guild)))
+                .push(".")
+                .build();
             respond(ctx, msg, &txt);
-            return Err(CommandError(String::from(txt)))
-        }
+            return Ok(())
+        },
+        "default" => None,
+        prefix if prefix.contains(char::is_whitespace) || prefix.contains('`')
+            || prefix.graphemes(true).count() > MAX_PREFIX_LENGTH =>
+        {
+            respond(ctx, msg, &format!(
+                "a colour role prefix can be up to {} characters, with no spaces or backticks.",
+                MAX_PREFIX_LENGTH));
+            return Ok(())
+        },
+        prefix => Some(prefix.to_lowercase()),
     };
 
-    let removals: Vec<RoleId> = match member.roles(&ctx.cache) {
-        None => Vec::new(),
-        Some(roles) => {
-            roles.iter()
-                .filter(|role| role.name.starts_with("colour-"))
-                .map(|role| role.id)
-                .collect()
-        }
+    let guild = update_guild(ctx, msg, guild_id, |guild| guild.colour_role_prefix = prefix)?;
+
+    let txt = MessageBuilder::new()
+        .push("colour roles in this guild are now named ")
+        .push_mono_safe(format!("{}<name>", role_prefix(&guild)))
+        .push(". Rename any existing colour roles to match.")
+        .build();
+    respond(ctx, msg, &txt);
+
+    Ok(())
+}
+
+#[command("custom")]
+#[description = "Shows whether members may choose any colour with a hex code, \
+or turns that on or off. Custom colours are given with roles made for them, \
+which are deleted once nobody has their colour."]
+#[usage = "`!colour custom`, `!colour custom on` or `!colour custom off`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+#[delimiters(" ")]
+fn colour_custom(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("colour custom command handler called");
+
+    let (guild_id, guild) = load_guild(ctx, msg)?;
+    let enabled = match arguments(&mut args).join(" ").as_str() {
+        "" => {
+            respond(ctx, msg, if guild.custom_colours_enabled {
+                "members may choose any colour; e.g. `!colour #ff8800`."
+            } else {
+                "members may only choose the colours in `!colour list`. \
+                 Let them choose any colour with `!colour custom on`."
+            });
+            return Ok(())
+        },
+        "on" => true,
+        "off" => false,
+        _ => {
+            // This is a usage error, not a bot failure
+            respond(ctx, msg, "please say `on` or `off`; e.g. `!colour custom on`");
+            return Ok(())
+        },
+    };
+
+    update_guild(ctx, msg, guild_id, |guild| guild.custom_colours_enabled = enabled)?;
+
+    respond(ctx, msg, if enabled {
+        "members may now choose any colour; e.g. `!colour #ff8800`."
+    } else {
+        "members may now only choose the colours in `!colour list`. \
+         Those with a custom colour keep it until they choose another."
+    });
+
+    Ok(())
+}
+
+#[command("contrast")]
+#[description = "Shows or sets how readable custom colours must be on Discord's dark \
+and light themes, as contrast ratios from 1 (anything goes) to 21 (only black or white)."]
+#[usage = "`!colour contrast`, `!colour contrast dark 4.5` or `!colour contrast light default`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+#[delimiters(" ")]
+fn colour_contrast(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("colour contrast command handler called");
+
+    let (guild_id, guild) = load_guild(ctx, msg)?;
+    let arguments = arguments(&mut args);
+    let words: Vec<&str> = arguments.iter().map(String::as_str).collect();
+    let (theme, ratio) = match words.as_slice() {
+        [] => {
+            let (dark, light) = min_contrast(&guild);
+            respond(ctx, msg, &format!(
+                "custom colours need a contrast of at least {} on the dark theme \
+                 and {} on the light theme.", dark, light));
+            return Ok(())
+        },
+        [theme, ratio] if *theme == "dark" || *theme == "light" => match *ratio {
+            "default" => (*theme, None),
+            ratio => match ratio.parse::<f64>() {
+                Ok(ratio) if (1.0..=MAX_CONTRAST).contains(&ratio) => (*theme, Some(ratio)),
+                _ => {
+                    // This is a usage error, not a bot failure
+                    respond(ctx, msg, &format!(
+                        "please give a contrast ratio from 1 to {}, or `default`.", MAX_CONTRAST));
+                    return Ok(())
+                },
+            },
+        },
+        _ => {
+            // This is a usage error, not a bot failure
+            respond(ctx, msg, "please give a theme and a contrast ratio; \
+                e.g. `!colour contrast dark 4.5`");
+            return Ok(())
+        },
     };
 
-    if removals.len() > 0 {
+    let guild = update_guild(ctx, msg, guild_id, |guild| if theme == "dark" {
+        guild.custom_colour_min_contrast_dark = ratio;
+    } else {
+        guild.custom_colour_min_contrast_light = ratio;
+    })?;
+
+    let (dark, light) = min_contrast(&guild);
+    respond(ctx, msg, &format!(
+        "custom colours now need a contrast of at least {} on the dark theme \
+         and {} on the light theme.", dark, light));
+
+    Ok(())
+}
+
+/// Give a member a colour role, or none, taking away any other colour they have.
+/// Custom colour roles that nobody has anymore are deleted.
+fn set_colour_role(
+    ctx: &mut Context,
+    msg: &Message,
+    member: &mut Member,
+    colours: &[ColourRole],
+    role_id: Option<RoleId>,
+    custom: bool,
+) -> CommandResult
+{
+    let guild_id = member.guild_id;
+    debug!("Member DB data retrieval...");
+    let database = database::handle(ctx)?;
+    let data = match database.member(guild_id.0, msg.author.id.0) {
+        Ok(data) => data,
+        Err(why) => {
+            let reason = format!("Could not retrieve member data from database: {}", why);
+            error!("{}", reason);
+            return Err(CommandError(reason))
+        },
+    };
+    let previous_custom = data.custom_colour_role_id.map(RoleId);
+
+    let removals: Vec<RoleId> = member.roles.iter()
+        .filter(|id| Some(**id) != role_id)
+        .filter(|id| Some(**id) == previous_custom
+            || colours.iter().any(|colour| colour.role_id == **id))
+        .cloned()
+        .collect();
+    if role_id.is_none() && removals.is_empty() && previous_custom.is_none() {
+        respond(ctx, msg, "you don't have a colour to clear.");
+        return Ok(())
+    }
+
+    if !removals.is_empty() {
         if let Err(why) = member.remove_roles(&ctx.http, &removals) {
             error!("Error removing roles: {:?}", why);
             let txt = "we couldn't remove your old colours. Sorry!";
@@ -84,30 +462,556 @@ fn colour(ctx: &mut Context, msg: &Message) -> CommandResult {
         };
     }
 
-    match member.add_role(&ctx.http, role_id) {
-        Ok(_) => {
-            let txt = "your colour has been updated!";
-            respond(ctx, msg, txt);
+    if let Some(role_id) = role_id {
+        if !member.roles.contains(&role_id) {
+            if let Err(why) = member.add_role(&ctx.http, role_id) {
+                error!("Error adding role: {:?}", why);
+                let txt = "we couldn't give you this colour. Sorry!";
+                respond(ctx, msg, txt);
+                return Err(CommandError(String::from(txt)))
+            }
+        }
+    }
 
-            Ok(())
+    let custom_colour_role_id = if custom { role_id.map(|role_id| role_id.0) } else { None };
+    debug!("Updating member DB entry...");
+    // Only the colour is written, as the rest may have changed while Discord was asked
+    let updated = database.member_custom_colour_update(
+        guild_id.0, msg.author.id.0, custom_colour_role_id);
+    if let Err(why) = updated {
+        let reason = format!("Could not update member data in database: {}", why);
+        error!("{}", reason);
+        return Err(CommandError(reason))
+    }
+
+    if let Some(previous_custom) = previous_custom {
+        if Some(previous_custom) != role_id {
+            release_custom_colour_role(ctx, guild_id, previous_custom);
+        }
+    }
+
+    respond(ctx, msg, match role_id {
+        Some(_) => "your colour has been updated!",
+        None => "your colour has been cleared.",
+    });
+    Ok(())
+}
+
+/// Find or make the role for a custom colour, if the guild allows the colour.
+/// Explains to the user why a colour can't be used otherwise.
+fn custom_colour_role(
+    ctx: &Context,
+    msg: &Message,
+    guild_id: GuildId,
+    guild: &database::Guild,
+    chosen: &str,
+) -> Result<Option<RoleId>, CommandError>
+{
+    if !guild.custom_colours_enabled {
+        respond(ctx, msg, "you can't choose your own colour in this guild. \
+            `!colour list` shows the colours you can choose from.");
+        return Ok(None)
+    }
+    let colour = match parse_hex_colour(chosen) {
+        Some(colour) => colour,
+        None => {
+            // This is a usage error, not a bot failure
+            respond(ctx, msg, "please give the colour as a hex code; e.g. `!colour #ff8800`");
+            return Ok(None)
         },
+    };
+    if colour.0 == 0 {
+        respond(ctx, msg, "Discord shows `#000000` as no colour at all; try `#010101`.");
+        return Ok(None)
+    }
+    if let Err(why) = check_contrast(colour, guild) {
+        respond(ctx, msg, &why);
+        return Ok(None)
+    }
+
+    // Members who chose the same colour share its role
+    debug!("Custom colour role DB data retrieval...");
+    let database = database::handle(ctx)?;
+    let existing = match database.custom_colour_role(guild_id.0, colour.0) {
+        Ok(existing) => existing.map(RoleId),
+        Err(why) => {
+            let reason = format!("Could not retrieve custom colour role from database: {}", why);
+            error!("{}", reason);
+            return Err(CommandError(reason))
+        },
+    };
+    if let Some(role_id) = existing {
+        // Assume the role is still there if the guild isn't cached
+        let exists = match guild_id.to_guild_cached(&ctx.cache) {
+            Some(guild) => guild.read().roles.contains_key(&role_id),
+            None => true,
+        };
+        if exists {
+            return Ok(Some(role_id))
+        }
+        // Someone deleted the role by hand, so make a new one
+        if let Err(why) = database.custom_colour_role_delete(guild_id.0, role_id.0) {
+            let reason = format!("Could not delete custom colour role from database: {}", why);
+            error!("{}", reason);
+            return Err(CommandError(reason))
+        }
+    }
+
+    let name = format!("#{}", colour.hex().to_lowercase());
+    let (role_id, _) = create_colour_role(ctx, msg, guild_id, &name, colour)?;
+    debug!("Adding custom colour role DB entry...");
+    if let Err(why) = database.custom_colour_role_add(guild_id.0, role_id.0, colour.0) {
+        let reason = format!("Could not add custom colour role to database: {}", why);
+        error!("{}", reason);
+        return Err(CommandError(reason))
+    }
+
+    Ok(Some(role_id))
+}
+
+/// Delete a custom colour role if no member has its colour anymore.
+fn release_custom_colour_role(ctx: &Context, guild_id: GuildId, role_id: RoleId) {
+    let database = match database::handle(ctx) {
+        Ok(database) => database,
+        Err(why) => {
+            error!("Could not get a database handle: {}", why);
+            return
+        },
+    };
+    match database.custom_colour_role_members(guild_id.0, role_id.0) {
+        Ok(0) => (),
+        Ok(_) => return,
+        Err(why) => {
+            error!("Could not count members with custom colour role: {}", why);
+            return
+        },
+    }
+
+    debug!("Deleting unused custom colour role {} in guild {}", role_id, guild_id);
+    if let Err(why) = guild_id.delete_role(&ctx.http, role_id) {
+        // Keep it in the database, so it's used again rather than left behind
+        error!("Could not delete custom colour role: {:?}", why);
+        return
+    }
+    if let Err(why) = database.custom_colour_role_delete(guild_id.0, role_id.0) {
+        error!("Could not delete custom colour role from database: {}", why);
+    }
+}
+
+/// Make a role for a colour, moved up as far as the bot can move it.
+/// Returns the role and whether it could be moved.
+fn create_colour_role(
+    ctx: &Context,
+    msg: &Message,
+    guild_id: GuildId,
+    name: &str,
+    colour: Colour,
+) -> Result<(RoleId, bool), CommandError>
+{
+    debug!("Creating colour role {} in guild {}", name, guild_id);
+    let role = match guild_id.create_role(&ctx.http, |r| r
+        .name(name)
+        .colour(u64::from(colour.0))
+        .permissions(Permissions::empty())
+        .hoist(false)
+        .mentionable(false))
+    {
+        Ok(role) => role,
+        Err(why) => {
+            respond(ctx, msg, "I couldn't create the role. Do I have the Manage Roles permission?");
+            let reason = format!("Could not create colour role: {:?}", why);
+            error!("{}", reason);
+            return Err(CommandError(reason))
+        },
+    };
+
+    // New roles go at the bottom, where any other coloured role a member has would hide them
+    let positioned = match bot_top_position(ctx, guild_id) {
+        Some(position) if position > 1 => match guild_id.edit_role_position(
+            &ctx.http, role.id, (position - 1) as u64)
+        {
+            Ok(_) => true,
+            Err(why) => {
+                error!("Could not move colour role: {:?}", why);
+                false
+            },
+        },
+        _ => false,
+    };
+
+    Ok((role.id, positioned))
+}
+
+/// The contrast custom colours need on Discord's dark and light themes in a guild.
+fn min_contrast(guild: &database::Guild) -> (f64, f64) {
+    (guild.custom_colour_min_contrast_dark.unwrap_or(DEFAULT_MIN_CONTRAST_DARK),
+     guild.custom_colour_min_contrast_light.unwrap_or(DEFAULT_MIN_CONTRAST_LIGHT))
+}
+
+/// Check that a colour is as readable on both of Discord's themes as the guild requires.
+/// Explains what's wrong otherwise.
+fn check_contrast(colour: Colour, guild: &database::Guild) -> Result<(), String> {
+    let (min_dark, min_light) = min_contrast(guild);
+    let dark = contrast_ratio(colour, DARK_THEME_BACKGROUND);
+    if dark < min_dark {
+        return Err(format!(
+            "that colour is too hard to read on Discord's dark theme: its contrast is {:.1}, \
+             and this guild needs at least {}. Try a lighter one.", dark, min_dark))
+    }
+    let light = contrast_ratio(colour, LIGHT_THEME_BACKGROUND);
+    if light < min_light {
+        return Err(format!(
+            "that colour is too hard to read on Discord's light theme: its contrast is {:.1}, \
+             and this guild needs at least {}. Try a darker one.", light, min_light))
+    }
+    Ok(())
+}
+
+/// The contrast ratio between two colours, as defined by the Web Content Accessibility Guidelines.
+fn contrast_ratio(a: Colour, b: Colour) -> f64 {
+    let (a, b) = (relative_luminance(a), relative_luminance(b));
+    let (lighter, darker) = if a > b { (a, b) } else { (b, a) };
+    (lighter + 0.05) / (darker + 0.05)
+}
+
+fn relative_luminance(colour: Colour) -> f64 {
+    let linear = |channel: u8| {
+        let channel = f64::from(channel) / 255.0;
+        if channel <= 0.039_28 {
+            channel / 12.92
+        } else {
+            ((channel + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    0.2126 * linear(colour.r()) + 0.7152 * linear(colour.g()) + 0.0722 * linear(colour.b())
+}
+
+/// What the names of colour roles start with in a guild.
+pub fn role_prefix(guild: &database::Guild) -> &str {
+    guild.colour_role_prefix.as_ref().map_or(DEFAULT_COLOUR_ROLE_PREFIX, String::as_str)
+}
+
+/// The name of the role for a colour, ignoring spaces and case in the name given.
+fn role_name(prefix: &str, colour: &str) -> String {
+    let colour: String = colour.split_whitespace().collect();
+    format!("{}{}", prefix, colour.to_lowercase())
+}
+
+/// Parse a colour written as a hex code, with or without a leading `#`.
+pub fn parse_hex_colour(text: &str) -> Option<Colour> {
+    let hex = text.trim_start_matches('#');
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None
+    }
+    u32::from_str_radix(hex, 16).ok().map(Colour::new)
+}
+
+/// A role members can choose to get its colour.
+#[derive(Clone, Debug)]
+pub struct ColourRole {
+    /// The role's name without the prefix.
+    pub name: String,
+    pub role_id: RoleId,
+    pub colour: Colour,
+}
+
+/// Key to find the colour roles of each guild in the client's data, with the prefix
+/// they were found with. A guild's entry is forgotten whenever its roles change.
+pub struct ColourRoleCacheKey;
+
+impl TypeMapKey for ColourRoleCacheKey {
+    type Value = HashMap<GuildId, (String, Vec<ColourRole>)>;
+}
+
+/// The colour roles of a guild, sorted by name.
+/// They're kept in the client's data, so that Discord needn't be asked for them every time.
+fn colour_roles(
+    ctx: &Context,
+    msg: &Message,
+    guild_id: GuildId,
+    prefix: &str,
+) -> Result<Vec<ColourRole>, CommandError>
+{
+    if let Some((cached_prefix, colours)) = ctx.data.read().get::<ColourRoleCacheKey>()
+        .and_then(|cache| cache.get(&guild_id))
+    {
+        if cached_prefix == prefix {
+            return Ok(colours.clone())
+        }
+    }
+
+    let roles: Vec<Role> = match guild_id.to_guild_cached(&ctx.cache) {
+        Some(guild) => guild.read().roles.values().cloned().collect(),
+        None => match ctx.http.get_guild_roles(guild_id.0) {
+            Ok(roles) => roles,
+            Err(why) => {
+                respond(ctx, msg, "something went wrong when fetching guild info. Sorry!");
+                let reason = format!("Could not fetch guild roles: {:?}", why);
+                error!("{}", reason);
+                return Err(CommandError(reason))
+            },
+        },
+    };
+    let mut colours: Vec<ColourRole> = roles.into_iter()
+        .filter(|role| role.name.starts_with(prefix))
+        .map(|role| ColourRole {
+            name: String::from(&role.name[prefix.len()..]),
+            role_id: role.id,
+            colour: role.colour,
+        })
+        .collect();
+    colours.sort_by(|a, b| a.name.cmp(&b.name));
+
+    debug!("Caching {} colour roles of guild {}", colours.len(), guild_id);
+    if let Some(cache) = ctx.data.write().get_mut::<ColourRoleCacheKey>() {
+        cache.insert(guild_id, (String::from(prefix), colours.clone()));
+    }
+    Ok(colours)
+}
+
+/// Forget the colour roles of a guild, so that they're found again next time.
+/// Called whenever the guild's roles change.
+pub fn forget_colour_roles(ctx: &Context, guild_id: GuildId) {
+    if let Some(cache) = ctx.data.write().get_mut::<ColourRoleCacheKey>() {
+        cache.remove(&guild_id);
+    }
+}
+
+/// Find the colour with a name, ignoring case, spaces, hyphens and underscores.
+/// If there's none, returns the colours with the most similar names instead, most similar first.
+fn find_colour<'a>(colours: &'a [ColourRole], name: &str) -> Result<&'a ColourRole, Vec<&'a ColourRole>> {
+    let wanted = normalise(name);
+    if let Some(colour) = colours.iter().find(|colour| normalise(&colour.name) == wanted) {
+        return Ok(colour)
+    }
+
+    let mut suggestions: Vec<(usize, &ColourRole)> = colours.iter()
+        .filter_map(|colour| {
+            let candidate = normalise(&colour.name);
+            let distance = edit_distance(&wanted, &candidate);
+            let similar = distance <= MAX_SUGGESTION_DISTANCE
+                || (!candidate.is_empty() && wanted.contains(&candidate))
+                || (!wanted.is_empty() && candidate.contains(&wanted));
+            if similar { Some((distance, colour)) } else { None }
+        })
+        .collect();
+    suggestions.sort_by_key(|(distance, _)| *distance);
+    Err(suggestions.into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, colour)| colour)
+        .collect())
+}
+
+/// Explain that there's no colour with a name, suggesting similar ones and listing the rest.
+fn not_found(
+    colours: &[ColourRole],
+    name: &str,
+    suggestions: &[&ColourRole],
+    guild: &database::Guild,
+) -> String
+{
+    let mut txt = MessageBuilder::new();
+    txt.push("there's no colour called ")
+        .push_bold_safe(name)
+        .push(".");
+
+    if let Some((last, others)) = suggestions.split_last() {
+        txt.push(" Did you mean ");
+        for suggestion in others {
+            txt.push_bold_safe(&suggestion.name).push(", ");
+        }
+        if !others.is_empty() {
+            txt.push("or ");
+        }
+        txt.push_bold_safe(&last.name).push("?");
+    }
+
+    if colours.is_empty() {
+        txt.push(" There are no colours to choose from in this guild yet.");
+    } else {
+        let mut list = String::new();
+        for (index, colour) in colours.iter().enumerate() {
+            if list.len() + colour.name.len() > MAX_REPLY_LIST_LENGTH {
+                list.push_str(&format!(" and {} more", colours.len() - index));
+                break
+            }
+            if index > 0 {
+                list.push_str(", ");
+            }
+            list.push_str(&colour.name);
+        }
+        txt.push(" You can choose from: ").push_safe(list).push(".");
+    }
+    if guild.custom_colours_enabled {
+        txt.push(" You can also choose any colour with a hex code; e.g. `!colour #ff8800`.");
+    }
+    txt.build()
+}
+
+/// Colour names are compared ignoring case, spaces, hyphens and underscores.
+fn normalise(name: &str) -> String {
+    name.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-' && *c != '_')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// How many characters need to be added, removed or changed to turn one text into another.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn find_role(ctx: &Context, guild_id: GuildId, name: &str) -> Option<RoleId> {
+    let guild = guild_id.to_guild_cached(&ctx.cache)?;
+    let role_id = guild.read().role_by_name(name).map(|role| role.id);
+    role_id
+}
+
+/// The member who sent a message, from the cache if possible.
+fn guild_member(ctx: &Context, msg: &Message, guild_id: GuildId) -> Result<Member, CommandError> {
+    match guild_id.member(ctx, msg.author.id) {
+        Ok(member) => Ok(member),
         Err(_) => {
-            let txt = "we couldn't give you this colour. Sorry!";
+            let txt = "I can't find you in the guild. Sorry!";
             respond(ctx, msg, txt);
-            return Err(CommandError(String::from(txt)))
+            Err(CommandError(String::from(txt)))
         }
     }
 }
 
-// Sends a response to a user's message
-fn respond(ctx: &mut Context, msg: &Message, txt: &str) {
-    let response = MessageBuilder::new()
-        .push_bold_safe(&msg.author)
-        .push(", ")
-        .push(txt)
-        .build();
+/// The position of the bot's highest role in a guild.
+/// Roles the bot creates can be moved up to just below it.
+fn bot_top_position(ctx: &Context, guild_id: GuildId) -> Option<i64> {
+    let bot_id = ctx.cache.read().user.id;
+    let guild = guild_id.to_guild_cached(&ctx.cache)?;
+    let guild = guild.read();
+    let member = guild.members.get(&bot_id)?;
+    member.roles.iter()
+        .filter_map(|role_id| guild.roles.get(role_id))
+        .map(|role| role.position)
+        .max()
+}
 
-    if let Err(why) = msg.channel_id.say(&ctx.http, &response) {
-        error!("Error sending message: {:?}", why);
+/// The square emoji closest to a colour.
+fn swatch(colour: Colour) -> &'static str {
+    let distance = |(r, g, b): (u8, u8, u8)| {
+        let (dr, dg, db) = (
+            i32::from(colour.r()) - i32::from(r),
+            i32::from(colour.g()) - i32::from(g),
+            i32::from(colour.b()) - i32::from(b),
+        );
+        dr * dr + dg * dg + db * db
+    };
+    SWATCHES.iter()
+        .min_by_key(|(_, rgb)| distance(*rgb))
+        .map_or("⬜", |(emoji, _)| emoji)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn colours(names: &[&str]) -> Vec<ColourRole> {
+        names.iter().enumerate()
+            .map(|(i, name)| ColourRole {
+                name: String::from(*name),
+                role_id: RoleId(i as u64 + 1),
+                colour: Colour::default(),
+            })
+            .collect()
+    }
+
+    fn suggestions(colours: &[ColourRole], name: &str) -> Vec<String> {
+        match find_colour(colours, name) {
+            Ok(colour) => panic!("{} was found as {}", name, colour.name),
+            Err(suggestions) => suggestions.iter().map(|colour| colour.name.clone()).collect(),
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn black_on_white_has_the_highest_contrast() {
+        let ratio = contrast_ratio(Colour::new(0x00_00_00), LIGHT_THEME_BACKGROUND);
+        assert!((ratio - MAX_CONTRAST).abs() < 1e-9);
+        assert!((contrast_ratio(LIGHT_THEME_BACKGROUND, Colour::new(0)) - ratio).abs() < 1e-9);
+        assert!((contrast_ratio(DARK_THEME_BACKGROUND, DARK_THEME_BACKGROUND) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn contrast_is_checked_on_both_themes() {
+        let guild = database::Guild::default();
+        assert!(check_contrast(Colour::new(0xFF_88_00), &guild).is_ok());
+
+        let navy = Colour::new(0x00_00_80);
+        assert!(contrast_ratio(navy, DARK_THEME_BACKGROUND) < DEFAULT_MIN_CONTRAST_DARK);
+        assert!(check_contrast(navy, &guild).unwrap_err().contains("dark theme"));
+        assert!(check_contrast(Colour::new(0xFF_FF_FF), &guild).unwrap_err()
+            .contains("light theme"));
+
+        let lenient = database::Guild {
+            custom_colour_min_contrast_dark: Some(1.0),
+            ..database::Guild::default()
+        };
+        assert!(check_contrast(navy, &lenient).is_ok());
+    }
+
+    #[test]
+    fn hex_colours_need_six_digits() {
+        assert_eq!(parse_hex_colour("#ff8800").map(|colour| colour.0), Some(0xFF_88_00));
+        assert_eq!(parse_hex_colour("ff8800").map(|colour| colour.0), Some(0xFF_88_00));
+        assert_eq!(parse_hex_colour("#FF8800").map(|colour| colour.0), Some(0xFF_88_00));
+        for text in &["#fff", "#gg0000", "#ff88000", "", "#"] {
+            assert!(parse_hex_colour(text).is_none(), "{:?} was accepted", text);
+        }
+    }
+
+    #[test]
+    fn names_ignore_case_spaces_hyphens_and_underscores() {
+        assert_eq!(normalise("Light-Blue_ 2"), "lightblue2");
+
+        let colours = colours(&["Light Blue", "dark-green", "hot_pink"]);
+        for (name, expected) in &[("lightblue", "Light Blue"), ("LIGHT-BLUE", "Light Blue"),
+                                  ("dark green", "dark-green"), ("Dark_Green", "dark-green"),
+                                  ("Hot Pink", "hot_pink"), ("hot-pink", "hot_pink")] {
+            assert_eq!(find_colour(&colours, name).unwrap().name, *expected);
+        }
+    }
+
+    #[test]
+    fn edit_distance_counts_characters() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "red"), 3);
+        assert_eq!(edit_distance("red", "red"), 0);
+        assert_eq!(edit_distance("café", "cafe"), 1);
+    }
+
+    #[test]
+    fn closest_suggestions_come_first() {
+        let colours = colours(&["black", "bluest", "blush", "blues", "blue"]);
+        assert_eq!(suggestions(&colours, "bluee"), vec!["blues", "blue", "bluest"]);
+        assert_eq!(suggestions(&colours, "bluee").len(), MAX_SUGGESTIONS);
+    }
+
+    #[test]
+    fn names_containing_each_other_are_suggested() {
+        let colours = colours(&["Midnight Blue", "Navy", "Red"]);
+        assert_eq!(suggestions(&colours, "blue"), vec!["Midnight Blue"]);
+        assert_eq!(suggestions(&colours, "navy blue"), vec!["Navy"]);
+    }
+
+    #[test]
+    fn distant_names_get_no_suggestions() {
+        let colours = colours(&["red", "blue", "green"]);
+        assert!(suggestions(&colours, "orange").is_empty());
+        assert!(suggestions(&[], "red").is_empty());
+    }
+}
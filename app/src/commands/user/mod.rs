@@ -1,2 +1,3 @@
 pub mod title;
-pub mod colour;
\ No newline at end of file
+pub mod title_moderation;
+pub mod colour;
@@ -1,8 +1,15 @@
-use crate::database;
+use crate::{
+    commands::{arguments, format_timestamp},
+    config,
+    database,
+    title_nickname,
+    title_policy,
+};
 use serenity::{
     prelude::*,
     model::prelude::*,
     framework::standard::{
+        Args,
         CommandError,
         CommandResult,
         macros::command,
@@ -10,116 +17,346 @@ use serenity::{
     utils::MessageBuilder,
     utils::parse_emoji,
 };
+use std::fmt;
+use unicode_segmentation::UnicodeSegmentation;
 
+/// Longest title allowed, in characters as people see them, so that emoji and accented
+/// letters count as one.
 const MAX_TITLE_LENGTH: usize = 128;
+/// Keep `!title history` within Discord's limit on the length of a message.
+const MAX_HISTORY_LENGTH: usize = 1800;
 
+// Titles are free text, so arguments are separated by spaces rather than the bot's
+// delimiters; a title in quotes may contain anything.
 #[command]
-#[description = "Gets, sets or clears your title."]
-#[usage = "`!title`, `!title set ...` or `!title clear`."]
-fn title(ctx: &mut Context, msg: &Message) -> CommandResult {
+#[description = "Gets, sets or clears your title. In a guild this is your title there, \
+which is shown instead of your global title; add `--global` for the title shown everywhere else."]
+#[usage = "`!title`, `!title set ...`, `!title clear`, `!title history` or `!title revert [number]`, \
+each optionally with `--global`."]
+#[delimiters(" ")]
+#[sub_commands(title_set, title_clear, title_history, title_revert)]
+fn title(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
     debug!("title command handler called");
 
-    let args: Vec<&str> = msg.content.split(' ').collect();
+    let mut arguments = arguments(&mut args);
+    let guild_id = title_scope(msg, &mut arguments);
 
-    match args.get(1) {
-        None => handle_get_title(ctx, msg),
-        Some(&"set") => {
-            match args.get(2..args.len()) {
-                None => {
-                    let response = MessageBuilder::new()
-                        .push_bold_safe(&msg.author)
-                        .push(", Usage: `!title set The Fabulous`")
-                        .build();
+    if !arguments.is_empty() {
+        // Unrecognised argument
+        let response = MessageBuilder::new()
+            .push_bold_safe(&msg.author)
+            .push(", unrecognised !title subcommand. ")
+            .push("use `!title`, `!title set ...`, `!title clear`, `!title history` or `!title revert`, ")
+            .push("adding `--global` for your title in every guild.")
+            .build();
 
-                    if let Err(why) = msg.channel_id.say(&ctx.http, &response) {
-                        error!("Error sending message: {:?}", why);
-                    }
+        if let Err(why) = msg.channel_id.say(&ctx.http, &response) {
+            error!("Error sending message: {:?}", why);
+        }
+        // User error
+        return Ok(())
+    }
 
-                    // This is a usage error, not a bot failure
-                    Ok(())
-                },
-                Some(title_args) => {
-                    // Check for custom emojis
-                    if has_custom_emoji(Some(title_args)) {
-                        let response = MessageBuilder::new()
-                            .push_bold_safe(&msg.author)
-                            .push(", custom Emojis are not allowed in titles")
-                            .build();
-
-                        if let Err(why) = msg.channel_id.say(&ctx.http, &response) {
-                            error!("Error sending message: {:?}", why);
-                        }
-
-                        // This is a usage error, not a bot failure
-                        return Ok(())
-                    }
+    handle_get_title(ctx, msg, guild_id)
+}
 
-                    let new_title = title_args.join(" ");
-                    handle_set_title(ctx, msg, Some(new_title))
-                },
-            }
-        },
-        Some(&"clear") => handle_set_title(ctx, msg, None),
-        Some(_) => {
-            // Unrecognised argument
-            let response = MessageBuilder::new()
-                .push_bold_safe(&msg.author)
-                .push(", unrecognised !title subcommand. ")
-                .push("use `!title`, `!title set ...` or `!title clear`.")
-                .build();
+#[command("set")]
+#[description = "Sets your title, in this guild or with `--global` everywhere else. \
+Put the title in quotes to keep the spaces in it as they are."]
+#[usage = "`!title set The Fabulous` or `!title set --global \"The Fabulous\"`"]
+#[delimiters(" ")]
+fn title_set(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("title set command handler called");
 
-            if let Err(why) = msg.channel_id.say(&ctx.http, &response) {
-                error!("Error sending message: {:?}", why);
-            }
-            // User error
-            return Ok(())
+    let mut arguments = arguments(&mut args);
+    let guild_id = title_scope(msg, &mut arguments);
+
+    if arguments.is_empty() {
+        let response = MessageBuilder::new()
+            .push_bold_safe(&msg.author)
+            .push(", Usage: `!title set The Fabulous`")
+            .build();
+
+        if let Err(why) = msg.channel_id.say(&ctx.http, &response) {
+            error!("Error sending message: {:?}", why);
         }
+
+        // This is a usage error, not a bot failure
+        return Ok(())
+    }
+
+    handle_set_title(ctx, msg, guild_id, Some(arguments.join(" ")))
+}
+
+#[command("clear")]
+#[description = "Clears your title in this guild, so your global title shows again, \
+or with `--global` your global title."]
+#[usage = "`!title clear` or `!title clear --global`"]
+#[delimiters(" ")]
+fn title_clear(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("title clear command handler called");
+
+    let guild_id = title_scope(msg, &mut arguments(&mut args));
+    handle_set_title(ctx, msg, guild_id, None)
+}
+
+#[command("history")]
+#[description = "Lists your earlier titles in this guild, or with `--global` your earlier global titles."]
+#[usage = "`!title history` or `!title history --global`"]
+#[delimiters(" ")]
+fn title_history(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("title history command handler called");
+
+    let guild_id = title_scope(msg, &mut arguments(&mut args));
+    handle_title_history(ctx, msg, guild_id)
+}
+
+#[command("revert")]
+#[description = "Goes back to one of your earlier titles, by default the last. \
+Use the numbers from `!title history`."]
+#[usage = "`!title revert`, `!title revert 2` or `!title revert --global`"]
+#[delimiters(" ")]
+fn title_revert(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("title revert command handler called");
+
+    let mut arguments = arguments(&mut args);
+    let guild_id = title_scope(msg, &mut arguments);
+
+    match arguments.as_slice() {
+        [] => handle_revert_title(ctx, msg, guild_id, 1),
+        [number] => match number.parse::<usize>() {
+            Ok(number) if number > 0 => handle_revert_title(ctx, msg, guild_id, number),
+            _ => revert_usage(ctx, msg),
+        },
+        _ => revert_usage(ctx, msg),
+    }
+}
+
+fn revert_usage(ctx: &Context, msg: &Message) -> CommandResult {
+    let response = MessageBuilder::new()
+        .push_bold_safe(&msg.author)
+        .push(", Usage: `!title revert` for your previous title, ")
+        .push("or `!title revert 2` for a number from `!title history`")
+        .build();
+
+    if let Err(why) = msg.channel_id.say(&ctx.http, &response) {
+        error!("Error sending message: {:?}", why);
     }
+
+    // This is a usage error, not a bot failure
+    Ok(())
 }
 
+/// Which title a command is about: the author's title in the guild it was sent in,
+/// or their global title if the arguments have the `--global` flag, which is taken out.
+fn title_scope(msg: &Message, arguments: &mut Vec<String>) -> Option<GuildId> {
+    let count = arguments.len();
+    arguments.retain(|argument| argument != "--global");
+    let global = arguments.len() != count;
+
+    // Titles set in direct messages are global, as they aren't in any guild
+    if global { None } else { msg.guild_id }
+}
+
+/// Show a user's title in a guild, or their global title if `guild_id` is None.
 fn handle_get_title(
     ctx: &mut Context,
     msg: &Message,
+    guild_id: Option<GuildId>,
 ) -> CommandResult
 {
     // User ID of the user executing the command
     let user_id = msg.author.id;
 
     debug!("User DB data retrieval...");
-    let database = database::Handle::new();
+    let database = database::handle(ctx)?;
     let user: database::User = match database.user(user_id.0)
         {
-            Err(_) => {
-                let reason = String::from("Could not retrieve user data from database");
+            Err(why) => {
+                let reason = format!("Could not retrieve user data from database: {}", why);
                 error!("{}", reason);
                 return Err(CommandError(reason))
             },
             Ok(data) => data,
         };
+    let guild_title = match guild_id {
+        None => None,
+        Some(guild_id) => match database.member(guild_id.0, user_id.0) {
+            Err(why) => {
+                let reason = format!("Could not retrieve member data from database: {}", why);
+                error!("{}", reason);
+                return Err(CommandError(reason))
+            },
+            Ok(data) => data.title,
+        },
+    };
 
-    let response = match user.title {
-        None => MessageBuilder::new()
-            .push_bold_safe(&msg.author)
-            .push(", you don't have a title! Use `!title set ...` to set one.")
-            .build(),
-        Some(title) => MessageBuilder::new()
-            .push_bold_safe(&msg.author)
+    let mut response = MessageBuilder::new();
+    response.push_bold_safe(&msg.author);
+    match (guild_title, user.title) {
+        (Some(title), _) => response
+            .push(", your title in this guild is ")
+            .push_bold(title)
+            .push("."),
+        (None, Some(title)) => response
             .push(", your title is ")
             .push_bold(title)
-            .push(".")
-            .build(),
+            .push(if guild_id.is_some() { ", the same as in other guilds." } else { "." }),
+        (None, None) => response
+            .push(", you don't have a title! Use `!title set ...` to set one."),
     };
 
-    if let Err(why) = msg.channel_id.say(&ctx.http, &response) {
+    if let Err(why) = msg.channel_id.say(&ctx.http, response.build()) {
+        error!("Error sending message: {:?}", why);
+    }
+
+    Ok(())
+}
+
+/// Show the titles a user had in a guild, or globally if `guild_id` is None, newest first.
+fn handle_title_history(
+    ctx: &mut Context,
+    msg: &Message,
+    guild_id: Option<GuildId>,
+) -> CommandResult
+{
+    debug!("Title history DB data retrieval...");
+    let history = load_title_history(ctx, msg, guild_id, config::get(ctx).title.history_keep)?;
+
+    let mut response = MessageBuilder::new();
+    response.push_bold_safe(&msg.author);
+    if history.current.is_none() && history.earlier.is_empty() {
+        response.push(", you haven't had a title here yet.");
+        if let Err(why) = msg.channel_id.say(&ctx.http, response.build()) {
+            error!("Error sending message: {:?}", why);
+        }
+        return Ok(())
+    }
+
+    response.push(match guild_id {
+        Some(_) => ", your titles in this guild:\nNow: ",
+        None => ", your global titles:\nNow: ",
+    });
+    response.push(describe_title(history.current.as_deref(), history.since));
+
+    let mut length = 0;
+    for (index, change) in history.earlier.iter().enumerate() {
+        let title = describe_title(change.title.as_deref(), Some(change.timestamp));
+        let line = format!("\n{}. {}", index + 1, title);
+        length += line.len();
+        if length > MAX_HISTORY_LENGTH {
+            response.push(format!("\n…and {} more", history.earlier.len() - index));
+            break
+        }
+        response.push(line);
+    }
+    if history.earlier.is_empty() {
+        response.push("\nYou haven't had any other titles.");
+    } else {
+        response.push("\nUse `!title revert <number>` to go back to one.");
+    }
+
+    if let Err(why) = msg.channel_id.say(&ctx.http, response.build()) {
         error!("Error sending message: {:?}", why);
     }
 
     Ok(())
 }
 
+/// Go back to one of a user's earlier titles, numbered from 1 for the one before the current.
+fn handle_revert_title(
+    ctx: &mut Context,
+    msg: &Message,
+    guild_id: Option<GuildId>,
+    number: usize,
+) -> CommandResult
+{
+    let history = load_title_history(ctx, msg, guild_id, number as u32 + 1)?;
+    match history.earlier.get(number - 1) {
+        Some(change) => {
+            debug!("Reverting title to {:?}", change.title);
+            handle_set_title(ctx, msg, guild_id, change.title.clone())
+        },
+        None => {
+            let response = MessageBuilder::new()
+                .push_bold_safe(&msg.author)
+                .push(format!(", there's no title number {} in your history. ", number))
+                .push("`!title history` lists them.")
+                .build();
+            if let Err(why) = msg.channel_id.say(&ctx.http, &response) {
+                error!("Error sending message: {:?}", why);
+            }
+            // User error
+            Ok(())
+        },
+    }
+}
+
+/// A user's title and the titles they had before it, as `!title history` numbers them.
+struct TitleHistory {
+    current: Option<String>,
+    /// When the current title was set, if the history knows.
+    since: Option<i64>,
+    /// Earlier titles, newest first.
+    earlier: Vec<database::TitleChange>,
+}
+
+/// Load a user's title in a guild, or their global title if `guild_id` is None,
+/// with up to `limit` entries of their title history.
+///
+/// The newest entry is usually the current title, but not for titles set before the
+/// history was kept, so it's only left out of the earlier titles if it matches.
+fn load_title_history(
+    ctx: &Context,
+    msg: &Message,
+    guild_id: Option<GuildId>,
+    limit: u32,
+) -> Result<TitleHistory, CommandError>
+{
+    let database = database::handle(ctx)?;
+    let user_id = msg.author.id.0;
+    let current = match guild_id {
+        Some(guild_id) => database.member(guild_id.0, user_id).map(|member| member.title),
+        None => database.user(user_id).map(|user| user.title),
+    };
+    let history = current.and_then(|current| {
+        let history = database.title_history(user_id, guild_id.map(|guild_id| guild_id.0), limit)?;
+        Ok((current, history))
+    });
+    let (current, mut earlier) = match history {
+        Ok(history) => history,
+        Err(why) => {
+            let reason = format!("Could not retrieve title history from database: {}", why);
+            error!("{}", reason);
+            return Err(CommandError(reason))
+        },
+    };
+
+    let since = match earlier.first() {
+        Some(change) if change.title == current => Some(earlier.remove(0).timestamp),
+        _ => None,
+    };
+    Ok(TitleHistory { current, since, earlier })
+}
+
+/// Describe a title, with when it was set if that's known.
+fn describe_title(title: Option<&str>, timestamp: Option<i64>) -> String {
+    let mut description = MessageBuilder::new();
+    match title {
+        Some(title) => description.push_bold_safe(title),
+        None => description.push("no title"),
+    };
+    if let Some(timestamp) = timestamp {
+        description.push(format!(" ({})", format_timestamp(timestamp)));
+    }
+    description.build()
+}
+
+/// Set or clear a user's title in a guild, or their global title if `guild_id` is None.
 fn handle_set_title(
     ctx: &mut Context,
     msg: &Message,
+    guild_id: Option<GuildId>,
     title: Option<String>
 ) -> CommandResult
 {
@@ -128,101 +365,241 @@ fn handle_set_title(
         None => None,
         Some(title) => {
             debug!("Formatting title {:?}...", title);
-            let title = String::from(title.trim_matches(' '));
-            if title.is_empty() {
-                // Empty, or had emojis or other characters removed by to_snake_case
+            match validate_title(&title) {
+                Ok(title) => {
+                    debug!("Resulting title: {:?}", title);
+                    Some(title)
+                },
+                Err(why) => {
+                    let response = MessageBuilder::new()
+                        .push_bold_safe(&msg.author)
+                        .push(match why {
+                            InvalidTitle::CustomEmoji => ", custom Emojis are not allowed in titles",
+                            // Empty, or had emojis or other characters removed by to_snake_case
+                            InvalidTitle::Empty => ", sorry, that didn't work. Try a different title!",
+                            InvalidTitle::TooLong => ", please choose a shorter title!",
+                        })
+                        .build();
+                    if let Err(why) = msg.channel_id.say(&ctx.http, &response) {
+                        error!("Error sending message: {:?}", why);
+                    }
+                    // User error
+                    return Ok(())
+                },
+            }
+        }
+    };
+
+    // User ID of the user executing the command
+    let user_id = msg.author.id;
+
+    let database = database::handle(ctx)?;
+
+    // Titles set in a guild must pass its filters, even global ones
+    if let (Some(title), Some(current_guild_id)) = (&title, msg.guild_id) {
+        let refused = title_policy::refusing_filter(&database, current_guild_id.0, title)
+            .and_then(|filter| match filter {
+                None => Ok(false),
+                Some(filter) => title_policy::log(&database, current_guild_id.0, None,
+                    Some(user_id.0), "refused", Some(&format!("{} (matched {})", title, filter.pattern)))
+                    .map(|_| true),
+            });
+        match refused {
+            Ok(false) => (),
+            Ok(true) => {
                 let response = MessageBuilder::new()
                     .push_bold_safe(&msg.author)
-                    .push(", sorry, that didn't work. Try a different title!")
+                    .push(", that title isn't allowed in this guild. Try a different title!")
                     .build();
                 if let Err(why) = msg.channel_id.say(&ctx.http, &response) {
                     error!("Error sending message: {:?}", why);
                 }
                 // User error
                 return Ok(())
+            },
+            Err(why) => {
+                let reason = format!("Could not check title against filters: {}", why);
+                error!("{}", reason);
+                return Err(CommandError(reason))
+            },
+        }
+    }
+
+    if let Some(guild_id) = guild_id {
+        debug!("Member DB data retrieval...");
+        let member: database::Member = match database.member(guild_id.0, user_id.0)
+            {
+                Err(why) => {
+                    let reason = format!("Could not retrieve member data from database: {}", why);
+                    error!("{}", reason);
+                    return Err(CommandError(reason))
+                },
+                Ok(data) => data,
+            };
+
+        if member.title_locked {
+            let response = MessageBuilder::new()
+                .push_bold_safe(&msg.author)
+                .push(", a moderator has locked your title in this guild.")
+                .build();
+            if let Err(why) = msg.channel_id.say(&ctx.http, &response) {
+                error!("Error sending message: {:?}", why);
             }
-            if title.len() > MAX_TITLE_LENGTH {
-                // Title is too long
+            // User error
+            return Ok(())
+        }
+
+        let guild: database::Guild = match database.guild(guild_id.0)
+            {
+                Err(why) => {
+                    let reason = format!("Could not retrieve guild data from database: {}", why);
+                    error!("{}", reason);
+                    return Err(CommandError(reason))
+                },
+                Ok(data) => data,
+            };
+        if let (Some(title), true) = (&title, guild.title_approval_enabled) {
+            let channel_id = guild.title_approval_channel_id
+                .map(ChannelId)
+                .unwrap_or(msg.channel_id);
+            if let Err(reason) = title_policy::request_approval(
+                ctx, guild_id, channel_id, &msg.author, title)
+            {
+                error!("{}", reason);
                 let response = MessageBuilder::new()
                     .push_bold_safe(&msg.author)
-                    .push(", please choose a shorter title!")
+                    .push(", I couldn't ask the moderators about your title. Sorry!")
                     .build();
                 if let Err(why) = msg.channel_id.say(&ctx.http, &response) {
                     error!("Error sending message: {:?}", why);
                 }
-                // User error
-                return Ok(())
+                return Err(CommandError(reason))
             }
-            debug!("Resulting title: {:?}", title);
-            Some(title)
-        }
-    };
 
-    // User ID of the user executing the command
-    let user_id = msg.author.id;
+            let response = MessageBuilder::new()
+                .push_bold_safe(&msg.author)
+                .push(", your title ")
+                .push_bold_safe(title)
+                .push(" is waiting for a moderator's approval.")
+                .build();
+            if let Err(why) = msg.channel_id.say(&ctx.http, &response) {
+                error!("Error sending message: {:?}", why);
+            }
+            return Ok(())
+        }
 
-    debug!("User DB data retrieval...");
-    let database = database::Handle::new();
-    let mut user: database::User = match database.user(user_id.0)
-        {
-            Err(_) => {
-                let reason = String::from("Could not retrieve user data from database");
+        // A title set now replaces any still waiting for approval
+        match title_policy::withdraw(&database, guild_id.0, user_id.0) {
+            Ok(Some(withdrawn)) => title_policy::mark_withdrawn(ctx, &withdrawn),
+            Ok(None) => (),
+            Err(why) => {
+                let reason = format!("Could not withdraw pending title in database: {}", why);
                 error!("{}", reason);
                 return Err(CommandError(reason))
             },
-            Ok(data) => data,
-        };
-
-    user.title = title.clone();
+        }
+    }
 
-    debug!("Updating user DB entry...");
-    if let Err(_) = database.user_update(
+    debug!("Updating title in database...");
+    if let Err(why) = title_policy::set_title(
+        &database,
         user_id.0,
-        &user
+        guild_id.map(|guild_id| guild_id.0),
+        title.as_deref(),
+        config::get(ctx).title.history_keep,
     ) {
-        let reason = String::from("Could update user data in database");
+        let reason = format!("Could not update title in database: {}", why);
         error!("{}", reason);
         return Err(CommandError(reason))
     }
-    debug!("Updated user title in database to '{:?}'", user.title);
 
-    let response = match title {
-        Some(title) => MessageBuilder::new()
-            .push_bold_safe(&msg.author)
+    // Guilds may show titles in nicknames; global titles can show in any of them
+    if guild_id.is_none() {
+        if let Err(why) = title_nickname::sync_everywhere(ctx, user_id) {
+            error!("Could not sync nicknames with title: {}", why);
+        }
+    }
+    let nickname = match msg.guild_id {
+        None => title_nickname::Sync::Unchanged,
+        Some(guild_id) => match title_nickname::sync(ctx, guild_id, user_id) {
+            Ok(nickname) => nickname,
+            Err(why) => {
+                error!("Could not sync nickname with title: {}", why);
+                title_nickname::Sync::Unchanged
+            },
+        },
+    };
+
+    let mut response = MessageBuilder::new();
+    response.push_bold_safe(&msg.author);
+    match (title, guild_id) {
+        (Some(title), None) => response
             .push(", set your title to ")
             .push_bold(title)
-            .push("!")
-            .build(),
-        None => MessageBuilder::new()
-            .push_bold_safe(&msg.author)
-            .push(", cleared your title!")
-            .build(),
+            .push(if msg.guild_id.is_some() { " everywhere you haven't chosen another!" } else { "!" }),
+        (Some(title), Some(_)) => response
+            .push(", set your title in this guild to ")
+            .push_bold(title)
+            .push("!"),
+        (None, None) => response
+            .push(", cleared your title!"),
+        (None, Some(_)) => response
+            .push(", cleared your title in this guild! Your global title, if you have one, shows here again."),
     };
+    if let title_nickname::Sync::Failed(reason) = nickname {
+        response.push(" ").push(reason);
+    }
 
-    if let Err(why) = msg.channel_id.say(&ctx.http, &response) {
+    if let Err(why) = msg.channel_id.say(&ctx.http, response.build()) {
         error!("Error sending message: {:?}", why);
     }
 
     Ok(())
 }
 
-fn has_custom_emoji(
-    title: Option<&[&str]>
-) -> bool
-{
+/// Why a title was refused.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvalidTitle {
+    Empty,
+    TooLong,
+    CustomEmoji,
+}
 
-    for (i, item) in title.iter().enumerate() {
-        for(ie, title_part) in item.iter().enumerate() {
-            debug!("The {}th item is {:?}", i+1, title_part);
+impl fmt::Display for InvalidTitle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidTitle::Empty => write!(f, "the title is empty"),
+            InvalidTitle::TooLong => write!(f, "the title is longer than {} characters", MAX_TITLE_LENGTH),
+            InvalidTitle::CustomEmoji => write!(f, "custom emojis are not allowed in titles"),
+        }
+    }
+}
 
-            // if it can be parsed, it is an custom emoji
-            if let Some(emoji) = parse_emoji(title_part) {
-                debug!("Emoji Info, id:{:?}, name:{:?}", emoji.id, emoji.name);
-                return true;
-            }
+/// Check a new title, returning it without surrounding spaces.
+pub fn validate_title(title: &str) -> Result<String, InvalidTitle> {
+    if has_custom_emoji(title) {
+        return Err(InvalidTitle::CustomEmoji)
+    }
+    let title = String::from(title.trim_matches(' '));
+    if title.is_empty() {
+        return Err(InvalidTitle::Empty)
+    }
+    if title.graphemes(true).count() > MAX_TITLE_LENGTH {
+        return Err(InvalidTitle::TooLong)
+    }
+    Ok(title)
+}
+
+fn has_custom_emoji(title: &str) -> bool {
+    for (i, title_part) in title.split(' ').enumerate() {
+        debug!("The {}th item is {:?}", i+1, title_part);
+
+        // if it can be parsed, it is an custom emoji
+        if let Some(emoji) = parse_emoji(title_part) {
+            debug!("Emoji Info, id:{:?}, name:{:?}", emoji.id, emoji.name);
+            return true;
         }
-        debug!("The {}th item is {:?}", i+1, item);
     }
 
-    return false;
+    false
 }
@@ -0,0 +1,687 @@
+use crate::{
+    commands::{
+        admin::GUILDADMIN_CHECK, format_timestamp, load_guild, message_guild, respond, update_guild,
+    },
+    config,
+    database::{self, Handle, TitleFilter},
+    title_nickname,
+    title_policy,
+};
+use serenity::{
+    prelude::*,
+    model::prelude::*,
+    framework::standard::{
+        Args,
+        CommandError,
+        CommandResult,
+        macros::command,
+    },
+    utils::MessageBuilder,
+};
+
+/// How many entries `!titlemod log` shows unless asked for more.
+const DEFAULT_LOG_LENGTH: u32 = 10;
+/// Discord embeds have at most 25 fields, one per entry.
+const MAX_LOG_LENGTH: u32 = 25;
+/// Longest word or regular expression a guild may filter titles with.
+const MAX_PATTERN_LENGTH: usize = 200;
+/// Keep listings within Discord's limit on the length of a message.
+const MAX_LIST_LENGTH: usize = 1800;
+
+// Commands for guild admins to decide which titles are allowed in a guild.
+#[command]
+#[description = "Shows which titles are allowed in this guild: the words and regular expressions \
+titles must not match, and whether titles need a moderator's approval."]
+#[usage = "`!titlemod`, `!titlemod block|unblock <word>`, `!titlemod filter|unfilter <regex>`, \
+`!titlemod approval on|off`, `!titlemod channel #channel|none`, `!titlemod pending`, \
+`!titlemod nicknames on|off`, \
+`!titlemod clear|lock|unlock @member` or `!titlemod log [count]`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+#[sub_commands(titlemod_block, titlemod_unblock, titlemod_filter, titlemod_unfilter,
+               titlemod_approval, titlemod_channel, titlemod_pending, titlemod_nicknames,
+               titlemod_clear, titlemod_lock, titlemod_unlock, titlemod_log)]
+fn titlemod(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("titlemod command handler called");
+
+    let (guild_id, guild) = load_guild(ctx, msg)?;
+    let database = database::handle(ctx)?;
+    let filters = match database.title_filters(guild_id.0) {
+        Ok(filters) => filters,
+        Err(why) => {
+            let reason = format!("Could not retrieve title filters from database: {}", why);
+            error!("{}", reason);
+            return Err(CommandError(reason))
+        }
+    };
+
+    let mut response = MessageBuilder::new();
+    response.push("titles in this guild ");
+    if guild.title_approval_enabled {
+        response.push("need a moderator's approval, asked for in ");
+        match guild.title_approval_channel_id {
+            Some(channel_id) => response.mention(&ChannelId(channel_id)),
+            None => response.push("the channel the title was set in"),
+        };
+        response.push(".");
+    } else {
+        response.push("don't need approval.");
+    }
+    if guild.title_nicknames_enabled {
+        response.push(" Titles are put in front of members' nicknames.");
+    }
+
+    if filters.is_empty() {
+        response.push(" No words or regular expressions are blocked.");
+    } else {
+        response.push(" Titles must not match:");
+        let mut length = 0;
+        for (index, filter) in filters.iter().enumerate() {
+            length += filter.pattern.len();
+            if length > MAX_LIST_LENGTH {
+                response.push(format!("\n…and {} more", filters.len() - index));
+                break
+            }
+            response
+                .push("\n")
+                .push(if filter.is_regex { "regex " } else { "word " })
+                .push_mono_safe(&filter.pattern);
+        }
+    }
+    respond(ctx, msg, &response.build());
+
+    Ok(())
+}
+
+#[command("block")]
+#[description = "Stops titles with a word, or several words in a row, from being used in this guild. \
+Case and punctuation are ignored, and only whole words match."]
+#[usage = "`!titlemod block <word>`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn titlemod_block(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("titlemod block command handler called");
+
+    let word = args.rest().trim();
+    if !title_policy::validate_word(word) || word.chars().count() > MAX_PATTERN_LENGTH {
+        // This is a usage error, not a bot failure
+        respond(ctx, msg, &format!(
+            "please give a word of up to {} characters; e.g. `!titlemod block rude`",
+            MAX_PATTERN_LENGTH));
+        return Ok(())
+    }
+    add_filter(ctx, msg, TitleFilter { is_regex: false, pattern: String::from(word) })
+}
+
+#[command("unblock")]
+#[description = "Allows a word blocked with `!titlemod block` again."]
+#[usage = "`!titlemod unblock <word>`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn titlemod_unblock(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("titlemod unblock command handler called");
+
+    let word = args.rest().trim();
+    remove_filter(ctx, msg, TitleFilter { is_regex: false, pattern: String::from(word) })
+}
+
+#[command("filter")]
+#[description = "Stops titles matching a regular expression from being used in this guild. \
+Case is ignored."]
+#[usage = "`!titlemod filter <regex>`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn titlemod_filter(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("titlemod filter command handler called");
+
+    let pattern = args.rest().trim();
+    if pattern.is_empty() || pattern.chars().count() > MAX_PATTERN_LENGTH {
+        // This is a usage error, not a bot failure
+        respond(ctx, msg, &format!(
+            "please give a regular expression of up to {} characters; \
+             e.g. `!titlemod filter r[u0]de`", MAX_PATTERN_LENGTH));
+        return Ok(())
+    }
+    if let Err(why) = title_policy::validate_regex(pattern) {
+        let txt = MessageBuilder::new()
+            .push("that isn't a regular expression I can use: ")
+            .push_codeblock_safe(why, None)
+            .build();
+        respond(ctx, msg, &txt);
+        return Ok(())
+    }
+    add_filter(ctx, msg, TitleFilter { is_regex: true, pattern: String::from(pattern) })
+}
+
+#[command("unfilter")]
+#[description = "Allows titles matching a regular expression added with `!titlemod filter` again."]
+#[usage = "`!titlemod unfilter <regex>`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn titlemod_unfilter(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("titlemod unfilter command handler called");
+
+    let pattern = args.rest().trim();
+    remove_filter(ctx, msg, TitleFilter { is_regex: true, pattern: String::from(pattern) })
+}
+
+#[command("approval")]
+#[description = "Turns on or off whether titles need a moderator's approval in this guild. \
+Moderators approve or reject titles by reacting to the bot's request."]
+#[usage = "`!titlemod approval on` or `!titlemod approval off`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn titlemod_approval(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("titlemod approval command handler called");
+
+    let enabled = match args.rest().trim() {
+        "on" => true,
+        "off" => false,
+        _ => {
+            // This is a usage error, not a bot failure
+            respond(ctx, msg, "please use `!titlemod approval on` or `!titlemod approval off`");
+            return Ok(())
+        },
+    };
+
+    let guild_id = message_guild(ctx, msg)?;
+    update_guild(ctx, msg, guild_id, |guild| guild.title_approval_enabled = enabled)?;
+
+    respond(ctx, msg, if enabled {
+        "titles in this guild now need a moderator's approval. \
+         Members' global titles aren't shown here anymore."
+    } else {
+        "titles in this guild don't need approval anymore. \
+         Titles already waiting can still be approved or rejected."
+    });
+
+    Ok(())
+}
+
+#[command("channel")]
+#[description = "Sets the channel where moderators are asked to approve titles, \
+or `none` to ask in the channel the title was set in."]
+#[usage = "`!titlemod channel #moderators` or `!titlemod channel none`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn titlemod_channel(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("titlemod channel command handler called");
+
+    let channel_id = match args.trimmed().current() {
+        Some("none") => None,
+        _ => match args.single::<ChannelId>() {
+            Ok(channel_id) => Some(channel_id),
+            Err(_) => {
+                // This is a usage error, not a bot failure
+                respond(ctx, msg, "please mention a channel; e.g. `!titlemod channel #moderators`, \
+                    or use `!titlemod channel none`");
+                return Ok(())
+            }
+        },
+    };
+
+    let guild_id = message_guild(ctx, msg)?;
+
+    if let Some(channel_id) = channel_id {
+        // Only accept channels which belong to this guild
+        let in_guild = match guild_id.to_guild_cached(&ctx.cache) {
+            Some(discord_guild) => discord_guild.read().channels.contains_key(&channel_id),
+            None => false,
+        };
+        if !in_guild {
+            respond(ctx, msg, "I can't find that channel in this guild.");
+            return Ok(())
+        }
+    }
+
+    update_guild(ctx, msg, guild_id,
+        |guild| guild.title_approval_channel_id = channel_id.map(|channel_id| channel_id.0))?;
+
+    let txt = match channel_id {
+        Some(channel_id) => MessageBuilder::new()
+            .push("moderators will be asked to approve titles in ")
+            .mention(&channel_id)
+            .push(".")
+            .build(),
+        None => String::from("moderators will be asked to approve titles in the channel \
+            they were set in."),
+    };
+    respond(ctx, msg, &txt);
+
+    Ok(())
+}
+
+#[command("pending")]
+#[description = "Lists the titles waiting for a moderator's approval."]
+#[usage = "`!titlemod pending`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn titlemod_pending(ctx: &mut Context, msg: &Message) -> CommandResult {
+    debug!("titlemod pending command handler called");
+
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+    let database = database::handle(ctx)?;
+    let pending = match database.pending_titles(guild_id.0) {
+        Ok(pending) => pending,
+        Err(why) => {
+            let reason = format!("Could not retrieve pending titles from database: {}", why);
+            error!("{}", reason);
+            return Err(CommandError(reason))
+        }
+    };
+
+    if pending.is_empty() {
+        respond(ctx, msg, "no titles are waiting for approval.");
+        return Ok(())
+    }
+
+    let mut response = MessageBuilder::new();
+    response.push("titles waiting for approval; react to the requests to approve or reject them:");
+    let mut length = 0;
+    for (index, title) in pending.iter().enumerate() {
+        length += title.title.len();
+        if length > MAX_LIST_LENGTH {
+            response.push(format!("\n…and {} more", pending.len() - index));
+            break
+        }
+        response
+            .push("\n")
+            .push_bold_safe(user_name(ctx, title.user_id))
+            .push(": ")
+            .push_bold_safe(&title.title)
+            .push(format!(" (https://discordapp.com/channels/{}/{}/{})",
+                          guild_id, title.channel_id, title.message_id));
+    }
+    respond(ctx, msg, &response.build());
+
+    Ok(())
+}
+
+#[command("nicknames")]
+#[description = "Turns on or off putting members' titles in front of their nicknames, \
+as \"<title> <name>\". Nicknames change when titles do; turning this off puts the old \
+nicknames back. The bot needs the Manage Nicknames permission and a role above the members'."]
+#[usage = "`!titlemod nicknames on` or `!titlemod nicknames off`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn titlemod_nicknames(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("titlemod nicknames command handler called");
+
+    let enabled = match args.rest().trim() {
+        "on" => true,
+        "off" => false,
+        _ => {
+            // This is a usage error, not a bot failure
+            respond(ctx, msg, "please use `!titlemod nicknames on` or `!titlemod nicknames off`");
+            return Ok(())
+        },
+    };
+
+    let guild_id = message_guild(ctx, msg)?;
+    update_guild(ctx, msg, guild_id, |guild| guild.title_nicknames_enabled = enabled)?;
+
+    if enabled {
+        respond(ctx, msg, "members' titles will be put in front of their nicknames \
+            from the next time they change.");
+        return Ok(())
+    }
+
+    let txt = match title_nickname::restore_all(ctx, guild_id) {
+        Ok(0) => String::from("titles aren't put in nicknames anymore, \
+            and members have their old nicknames back."),
+        Ok(failed) => format!("titles aren't put in nicknames anymore, but I couldn't put back \
+            the old nicknames of {} members. Do I have the Manage Nicknames permission and a role \
+            above theirs?", failed),
+        Err(why) => {
+            respond(ctx, msg, "titles aren't put in nicknames anymore, but something went wrong \
+                when putting back the old nicknames. Sorry!");
+            let reason = format!("Could not restore nicknames: {}", why);
+            error!("{}", reason);
+            return Err(CommandError(reason))
+        },
+    };
+    respond(ctx, msg, &txt);
+
+    Ok(())
+}
+
+#[command("clear")]
+#[description = "Clears a member's title in this guild, and any title they're waiting for. \
+Their global title shows here again unless their title is locked."]
+#[usage = "`!titlemod clear @member`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn titlemod_clear(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("titlemod clear command handler called");
+
+    let (guild_id, user_id) = match member_argument(ctx, msg, args, "clear") {
+        Some(found) => found,
+        None => return Ok(()),
+    };
+    let history_keep = config::get(ctx).title.history_keep;
+    let database = database::handle(ctx)?;
+    let cleared = database.in_transaction(|database| {
+        let previous = database.member(guild_id.0, user_id.0)?.title;
+        title_policy::set_title(database, user_id.0, Some(guild_id.0), None, history_keep)?;
+        let withdrawn = title_policy::withdraw(database, guild_id.0, user_id.0)?;
+        title_policy::log(database, guild_id.0, Some(msg.author.id.0), Some(user_id.0),
+                          "cleared", previous.as_deref())?;
+        Ok((previous, withdrawn))
+    });
+    // Discord is only told once the changes are kept
+    let cleared = cleared.map(|(previous, withdrawn)| {
+        if let Some(withdrawn) = withdrawn {
+            title_policy::mark_withdrawn(ctx, &withdrawn);
+        }
+        previous
+    });
+    if let Ok(Some(_)) = cleared {
+        title_policy::sync_nickname(ctx, guild_id, user_id);
+    }
+
+    let txt = match cleared {
+        Ok(Some(previous)) => MessageBuilder::new()
+            .push("cleared the title ")
+            .push_bold_safe(previous)
+            .push(" of ")
+            .push_bold_safe(user_name(ctx, user_id.0))
+            .push(".")
+            .build(),
+        Ok(None) => MessageBuilder::new()
+            .push_bold_safe(user_name(ctx, user_id.0))
+            .push(" has no title in this guild to clear.")
+            .build(),
+        Err(why) => {
+            respond(ctx, msg, "something went wrong when clearing the title. Sorry!");
+            let reason = format!("Could not clear member title in database: {}", why);
+            error!("{}", reason);
+            return Err(CommandError(reason))
+        },
+    };
+    respond(ctx, msg, &txt);
+
+    Ok(())
+}
+
+#[command("lock")]
+#[description = "Stops a member from changing their title in this guild, withdrawing any title \
+they're waiting for. Their global title isn't shown here while it's locked."]
+#[usage = "`!titlemod lock @member`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn titlemod_lock(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("titlemod lock command handler called");
+    set_locked(ctx, msg, args, true)
+}
+
+#[command("unlock")]
+#[description = "Lets a member change their title in this guild again."]
+#[usage = "`!titlemod unlock @member`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn titlemod_unlock(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    debug!("titlemod unlock command handler called");
+    set_locked(ctx, msg, args, false)
+}
+
+#[command("log")]
+#[description = "Shows the most recent things moderators, and the filters, did to titles \
+in this guild."]
+#[usage = "`!titlemod log` or `!titlemod log 20`"]
+#[only_in(guilds)]
+#[checks(GuildAdmin)]
+fn titlemod_log(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    debug!("titlemod log command handler called");
+
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+    let count = if args.rest().trim().is_empty() {
+        DEFAULT_LOG_LENGTH
+    } else {
+        match args.trimmed().single::<u32>() {
+            Ok(count) if (1..=MAX_LOG_LENGTH).contains(&count) => count,
+            _ => {
+                // This is a usage error, not a bot failure
+                respond(ctx, msg, &format!(
+                    "please give a number of entries from 1 to {}; e.g. `!titlemod log 20`",
+                    MAX_LOG_LENGTH));
+                return Ok(())
+            }
+        }
+    };
+
+    debug!("Title moderation log DB data retrieval...");
+    let database = database::handle(ctx)?;
+    let entries = match database.title_moderation_log(guild_id.0, count) {
+        Ok(entries) => entries,
+        Err(why) => {
+            let reason = format!("Could not retrieve title moderation log from database: {}", why);
+            error!("{}", reason);
+            return Err(CommandError(reason))
+        }
+    };
+
+    if entries.is_empty() {
+        respond(ctx, msg, "nothing has been done to titles in this guild yet.");
+        return Ok(())
+    }
+
+    if let Err(why) = msg.channel_id.send_message(&ctx.http, |m| {
+        m.embed(|e| {
+            e.title("Recent title moderation");
+            for entry in &entries {
+                let moderator = match entry.moderator_id {
+                    Some(moderator_id) => user_name(ctx, moderator_id),
+                    None => String::from("the filters"),
+                };
+                let mut description = MessageBuilder::new();
+                if let Some(user_id) = entry.user_id {
+                    description.push_bold_safe(user_name(ctx, user_id));
+                    if entry.detail.is_some() {
+                        description.push(": ");
+                    }
+                }
+                if let Some(detail) = &entry.detail {
+                    description.push_mono_safe(detail);
+                }
+                e.field(
+                    format!("{} by {} at {}",
+                            entry.action, moderator, format_timestamp(entry.timestamp)),
+                    description.build(),
+                    false);
+            }
+            e
+        })
+    }) {
+        error!("Error sending message: {:?}", why);
+    }
+
+    Ok(())
+}
+
+fn add_filter(ctx: &Context, msg: &Message, filter: TitleFilter) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+    let database = database::handle(ctx)?;
+    let added = database.in_transaction(|database| {
+        let added = database.title_filter_add(guild_id.0, &filter)?;
+        if added {
+            log_filter(database, guild_id, msg, &filter, "blocked")?;
+        }
+        Ok(added)
+    });
+
+    let txt = match added {
+        Ok(true) => MessageBuilder::new()
+            .push("titles matching ")
+            .push_mono_safe(&filter.pattern)
+            .push(" can't be used in this guild anymore. Titles already set that match it \
+                   aren't shown.")
+            .build(),
+        Ok(false) => MessageBuilder::new()
+            .push_mono_safe(&filter.pattern)
+            .push(" is already blocked.")
+            .build(),
+        Err(why) => {
+            respond(ctx, msg, "something went wrong when adding the filter. Sorry!");
+            let reason = format!("Could not add title filter to database: {}", why);
+            error!("{}", reason);
+            return Err(CommandError(reason))
+        },
+    };
+    respond(ctx, msg, &txt);
+
+    Ok(())
+}
+
+fn remove_filter(ctx: &Context, msg: &Message, filter: TitleFilter) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+    let database = database::handle(ctx)?;
+    let removed = database.in_transaction(|database| {
+        let removed = database.title_filter_remove(guild_id.0, &filter)?;
+        if removed {
+            log_filter(database, guild_id, msg, &filter, "unblocked")?;
+        }
+        Ok(removed)
+    });
+
+    let txt = match removed {
+        Ok(true) => MessageBuilder::new()
+            .push("titles matching ")
+            .push_mono_safe(&filter.pattern)
+            .push(" are allowed again.")
+            .build(),
+        Ok(false) => MessageBuilder::new()
+            .push("there's no ")
+            .push(if filter.is_regex { "regular expression " } else { "word " })
+            .push_mono_safe(&filter.pattern)
+            .push(" to allow again. `!titlemod` lists them all.")
+            .build(),
+        Err(why) => {
+            respond(ctx, msg, "something went wrong when removing the filter. Sorry!");
+            let reason = format!("Could not remove title filter from database: {}", why);
+            error!("{}", reason);
+            return Err(CommandError(reason))
+        },
+    };
+    respond(ctx, msg, &txt);
+
+    Ok(())
+}
+
+fn log_filter(
+    database: &Handle,
+    guild_id: GuildId,
+    msg: &Message,
+    filter: &TitleFilter,
+    action: &str,
+) -> Result<(), database::DatabaseError>
+{
+    let action = format!("{} {}", action, if filter.is_regex { "regex" } else { "word" });
+    title_policy::log(database, guild_id.0, Some(msg.author.id.0), None,
+                      &action, Some(&filter.pattern))
+}
+
+fn set_locked(ctx: &Context, msg: &Message, args: Args, locked: bool) -> CommandResult {
+    let subcommand = if locked { "lock" } else { "unlock" };
+    let (guild_id, user_id) = match member_argument(ctx, msg, args, subcommand) {
+        Some(found) => found,
+        None => return Ok(()),
+    };
+    let database = database::handle(ctx)?;
+    let changed = database.in_transaction(|database| {
+        let member = database.member(guild_id.0, user_id.0)?;
+        if member.title_locked == locked {
+            return Ok((false, None))
+        }
+        database.member_title_locked_update(guild_id.0, user_id.0, locked)?;
+        // A locked title can't be changed by approving one either
+        let withdrawn = match locked {
+            true => title_policy::withdraw(database, guild_id.0, user_id.0)?,
+            false => None,
+        };
+        title_policy::log(database, guild_id.0, Some(msg.author.id.0), Some(user_id.0),
+                          if locked { "locked" } else { "unlocked" }, member.title.as_deref())?;
+        Ok((true, withdrawn))
+    });
+    // Discord is only told once the changes are kept
+    let changed = changed.map(|(changed, withdrawn)| {
+        if let Some(withdrawn) = withdrawn {
+            title_policy::mark_withdrawn(ctx, &withdrawn);
+        }
+        changed
+    });
+    if let Ok(true) = changed {
+        // Locking hides the member's global title here
+        title_policy::sync_nickname(ctx, guild_id, user_id);
+    }
+
+    let name = user_name(ctx, user_id.0);
+    let txt = match (changed, locked) {
+        (Ok(true), true) => MessageBuilder::new()
+            .push("locked the title of ")
+            .push_bold_safe(name)
+            .push(" in this guild.")
+            .build(),
+        (Ok(true), false) => MessageBuilder::new()
+            .push_bold_safe(name)
+            .push(" can change their title in this guild again.")
+            .build(),
+        (Ok(false), true) => MessageBuilder::new()
+            .push("the title of ")
+            .push_bold_safe(name)
+            .push(" is already locked.")
+            .build(),
+        (Ok(false), false) => MessageBuilder::new()
+            .push("the title of ")
+            .push_bold_safe(name)
+            .push(" isn't locked.")
+            .build(),
+        (Err(why), _) => {
+            respond(ctx, msg, "something went wrong when changing the lock. Sorry!");
+            let reason = format!("Could not update member title lock in database: {}", why);
+            error!("{}", reason);
+            return Err(CommandError(reason))
+        },
+    };
+    respond(ctx, msg, &txt);
+
+    Ok(())
+}
+
+/// Find the member mentioned in a command's arguments.
+/// Explain to the user what's wrong otherwise.
+fn member_argument(
+    ctx: &Context,
+    msg: &Message,
+    mut args: Args,
+    subcommand: &str,
+) -> Option<(GuildId, UserId)> {
+    let guild_id = msg.guild_id?;
+    match args.trimmed().single::<UserId>() {
+        Ok(user_id) => Some((guild_id, user_id)),
+        Err(_) => {
+            // This is a usage error, not a bot failure
+            respond(ctx, msg, &format!(
+                "please mention a member; e.g. `!titlemod {} @member`", subcommand));
+            None
+        },
+    }
+}
+
+fn user_name(ctx: &Context, user_id: u64) -> String {
+    match ctx.cache.read().user(user_id) {
+        Some(user) => user.read().tag(),
+        None => format!("user {}", user_id),
+    }
+}
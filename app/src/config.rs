@@ -0,0 +1,351 @@
+//! The bot's settings.
+//!
+//! Settings are read from a TOML file, `/catnip/mount/config.toml` unless CONFIG_PATH says
+//! otherwise, and can be overridden by environment variables (including those in `mount/env`).
+//! Anything not set keeps its default. Every value is checked at startup, and catnip refuses
+//! to start with a list of what's wrong rather than guessing.
+
+use serde::Deserialize;
+use serenity::prelude::*;
+use std::{
+    env,
+    error,
+    fmt,
+    fs,
+    io,
+    str::FromStr,
+    sync::Arc,
+};
+
+const DEFAULT_CONFIG_PATH: &str = "/catnip/mount/config.toml";
+/// Discord doesn't show activity text longer than this.
+const MAX_ACTIVITY_LENGTH: usize = 128;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bot: BotConfig,
+    pub database: DatabaseConfig,
+    pub backup: BackupConfig,
+    pub stream_notify: StreamNotifyConfig,
+    pub title: TitleConfig,
+    pub twitch: TwitchConfig,
+    pub youtube: YouTubeConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BotConfig {
+    /// Only needed to connect to Discord, not for the command-line subcommands.
+    pub token: Option<String>,
+    pub prefix: String,
+    pub delimiters: Vec<String>,
+    /// What the bot is shown to be playing.
+    pub activity: String,
+}
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            prefix: String::from("!"),
+            delimiters: vec![String::from(", "), String::from(",")],
+            activity: String::from("with your RNG tables"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub path: String,
+    pub migrations_dir: String,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            path: String::from("/catnip/mount/catnip.db3"),
+            migrations_dir: String::from("/catnip/mount/sql"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    pub dir: String,
+    /// Seconds between scheduled backups; 0 turns them off.
+    pub interval: u64,
+    /// How many backups to keep.
+    pub keep: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            dir: String::from("/catnip/mount/backups"),
+            interval: 24 * 60 * 60,
+            keep: 7,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamNotifyConfig {
+    /// Seconds before the same member is shouted out again, in guilds that haven't set their own.
+    pub cooldown: i64,
+    /// Channel to send shout-outs to in the guild it's in, if that guild hasn't chosen one.
+    /// Kept for setups from before guilds had their own settings.
+    pub channel_id: Option<u64>,
+}
+
+impl Default for StreamNotifyConfig {
+    fn default() -> Self {
+        Self {
+            cooldown: 21600, // 6 hours
+            channel_id: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TitleConfig {
+    /// How many titles to keep in each user's title history, globally and per guild.
+    pub history_keep: u32,
+}
+
+impl Default for TitleConfig {
+    fn default() -> Self {
+        Self {
+            history_keep: 20,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TwitchConfig {
+    pub api_url: String,
+    pub client_id: Option<String>,
+    pub token: Option<String>,
+}
+
+impl Default for TwitchConfig {
+    fn default() -> Self {
+        Self {
+            api_url: String::from("https://api.twitch.tv/helix"),
+            client_id: None,
+            token: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct YouTubeConfig {
+    pub api_url: String,
+    pub api_key: Option<String>,
+}
+
+impl Default for YouTubeConfig {
+    fn default() -> Self {
+        Self {
+            api_url: String::from("https://www.googleapis.com/youtube/v3"),
+            api_key: None,
+        }
+    }
+}
+
+/// Why the settings couldn't be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file couldn't be read.
+    Read(String, io::Error),
+    /// The config file isn't valid TOML, or has settings that don't exist.
+    Parse(String, toml::de::Error),
+    /// Some settings have values that can't be used.
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, why) => write!(f, "could not read config file {}: {}", path, why),
+            ConfigError::Parse(path, why) => write!(f, "could not parse config file {}: {}", path, why),
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ConfigError::Read(_, why) => Some(why),
+            ConfigError::Parse(_, why) => Some(why),
+            ConfigError::Invalid(_) => None,
+        }
+    }
+}
+
+impl Config {
+    /// Load the settings from the config file and environment, and check them.
+    pub fn load() -> Result<Self, ConfigError> {
+        // A missing file is fine unless one was asked for by name
+        let (path, required) = match env::var("CONFIG_PATH") {
+            Ok(path) => (path, true),
+            Err(_) => (String::from(DEFAULT_CONFIG_PATH), false),
+        };
+        let mut config: Config = match fs::read_to_string(&path) {
+            Ok(contents) => match toml::from_str(&contents) {
+                Ok(config) => config,
+                Err(why) => return Err(ConfigError::Parse(path, why)),
+            },
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound && !required => {
+                debug!("No config file at {}, using defaults and environment", path);
+                Config::default()
+            },
+            Err(why) => return Err(ConfigError::Read(path, why)),
+        };
+
+        let mut problems = Vec::new();
+        config.apply_env(&mut problems);
+        config.validate(&mut problems);
+        if !problems.is_empty() {
+            return Err(ConfigError::Invalid(problems))
+        }
+        Ok(config)
+    }
+
+    /// Override settings with any environment variables set for them.
+    fn apply_env(&mut self, problems: &mut Vec<String>) {
+        env_optional("DISCORD_TOKEN", &mut self.bot.token);
+        env_parse("COMMAND_PREFIX", &mut self.bot.prefix, problems);
+        if let Ok(value) = env::var("COMMAND_DELIMITERS") {
+            // A TOML array, like the setting in the config file
+            #[derive(Deserialize)]
+            struct Delimiters { value: Vec<String> }
+            match toml::from_str::<Delimiters>(&format!("value = {}", value)) {
+                Ok(delimiters) => self.bot.delimiters = delimiters.value,
+                Err(_) => problems.push(format!(
+                    "COMMAND_DELIMITERS: {:?} is not a list of strings like [\", \", \",\"]", value)),
+            }
+        }
+        env_parse("BOT_ACTIVITY", &mut self.bot.activity, problems);
+
+        env_parse("DATABASE_PATH", &mut self.database.path, problems);
+        env_parse("MIGRATIONS_DIR", &mut self.database.migrations_dir, problems);
+
+        env_parse("BACKUP_DIR", &mut self.backup.dir, problems);
+        env_parse("BACKUP_INTERVAL", &mut self.backup.interval, problems);
+        env_parse("BACKUP_KEEP", &mut self.backup.keep, problems);
+
+        env_parse("STREAM_NOTIFY_COOLDOWN", &mut self.stream_notify.cooldown, problems);
+        if let Ok(value) = env::var("STREAM_NOTIFY_CHANNEL_ID") {
+            match value.parse() {
+                Ok(channel_id) => self.stream_notify.channel_id = Some(channel_id),
+                Err(_) if value.is_empty() => (),
+                Err(_) => problems.push(format!(
+                    "STREAM_NOTIFY_CHANNEL_ID: {:?} is not a channel ID", value)),
+            }
+        }
+
+        env_parse("TITLE_HISTORY_KEEP", &mut self.title.history_keep, problems);
+
+        env_parse("TWITCH_API_URL", &mut self.twitch.api_url, problems);
+        env_optional("TWITCH_CLIENT_ID", &mut self.twitch.client_id);
+        env_optional("TWITCH_TOKEN", &mut self.twitch.token);
+        env_parse("YOUTUBE_API_URL", &mut self.youtube.api_url, problems);
+        env_optional("YOUTUBE_API_KEY", &mut self.youtube.api_key);
+    }
+
+    fn validate(&self, problems: &mut Vec<String>) {
+        if self.bot.prefix.is_empty() || self.bot.prefix.contains(char::is_whitespace) {
+            problems.push(format!("bot.prefix {:?} must be non-empty with no spaces", self.bot.prefix));
+        }
+        if self.bot.delimiters.is_empty() || self.bot.delimiters.iter().any(String::is_empty) {
+            problems.push(String::from("bot.delimiters must be a list of non-empty strings"));
+        }
+        if self.bot.activity.chars().count() > MAX_ACTIVITY_LENGTH {
+            problems.push(format!("bot.activity must be at most {} characters", MAX_ACTIVITY_LENGTH));
+        }
+        if self.database.path.is_empty() {
+            problems.push(String::from("database.path must not be empty"));
+        }
+        if self.database.migrations_dir.is_empty() {
+            problems.push(String::from("database.migrations_dir must not be empty"));
+        }
+        if self.backup.dir.is_empty() {
+            problems.push(String::from("backup.dir must not be empty"));
+        }
+        if self.backup.keep == 0 {
+            problems.push(String::from("backup.keep must be at least 1"));
+        }
+        if self.stream_notify.cooldown < 0 {
+            problems.push(String::from("stream_notify.cooldown must not be negative"));
+        }
+        if self.title.history_keep == 0 {
+            problems.push(String::from("title.history_keep must be at least 1"));
+        }
+        for (name, url) in &[("twitch.api_url", &self.twitch.api_url),
+                             ("youtube.api_url", &self.youtube.api_url)] {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                problems.push(format!("{} {:?} must be an http:// or https:// URL", name, url));
+            }
+        }
+        if self.twitch.token.is_some() && self.twitch.client_id.is_none() {
+            problems.push(String::from("twitch.token is set, so twitch.client_id must be too"));
+        }
+    }
+
+    /// The Discord bot token, which is required to run the bot.
+    pub fn token(&self) -> Result<&str, ConfigError> {
+        match &self.bot.token {
+            Some(token) if !token.trim().is_empty() => Ok(token),
+            _ => Err(ConfigError::Invalid(vec![String::from(
+                "bot.token must be set, in the config file or as DISCORD_TOKEN")])),
+        }
+    }
+}
+
+/// Replace a setting with an environment variable, if it's set.
+fn env_parse<T: FromStr>(name: &str, setting: &mut T, problems: &mut Vec<String>) {
+    if let Ok(value) = env::var(name) {
+        match value.parse() {
+            Ok(value) => *setting = value,
+            Err(_) => problems.push(format!("{}: {:?} is not a valid value", name, value)),
+        }
+    }
+}
+
+/// Replace an optional setting with an environment variable, if it's set and not empty.
+fn env_optional(name: &str, setting: &mut Option<String>) {
+    if let Ok(value) = env::var(name) {
+        if !value.is_empty() {
+            *setting = Some(value);
+        }
+    }
+}
+
+/// Key to find the settings in the client's data.
+pub struct ConfigKey;
+
+impl TypeMapKey for ConfigKey {
+    type Value = Arc<Config>;
+}
+
+/// Get the settings from the client's data.
+pub fn get(context: &Context) -> Arc<Config> {
+    match context.data.read().get::<ConfigKey>() {
+        Some(config) => Arc::clone(config),
+        None => panic!("settings are put in the client's data before the client starts"),
+    }
+}
@@ -1,128 +1,471 @@
 
-use rusqlite::{params, Connection};
-use std::{env, fs, io::Read};
+use crate::migrations;
+use rusqlite::{backup::Backup, params, Connection, OpenFlags, OptionalExtension};
+use serenity::prelude::*;
+use std::{
+    error,
+    fmt,
+    io,
+    ops::{Deref, DerefMut},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-const DEFAULT_DATABASE_PATH: &'static str = "/catnip/mount/catnip.db3";
+/// How many unused connections the pool keeps open.
+const MAX_IDLE_CONNECTIONS: usize = 4;
+/// Pages copied at a time by backups, pausing in between so that the bot isn't locked out.
+const BACKUP_PAGES_PER_STEP: i32 = 64;
+const BACKUP_STEP_PAUSE: Duration = Duration::from_millis(10);
 
-#[derive(Clone, Debug, Default)]
-pub struct Guild {}
+#[derive(Clone, Debug)]
+pub struct Guild {
+    /// Whether stream shout-outs are sent in this guild at all.
+    pub stream_notify_enabled: bool,
+    /// Channel that stream shout-outs are sent to. No shout-outs are sent while unset.
+    pub stream_notify_channel_id: Option<u64>,
+    /// Minimum number of seconds between shout-outs for the same member.
+    /// Falls back to the bot-wide default when unset.
+    pub stream_notify_cooldown: Option<i64>,
+    /// Wording of stream shout-outs. Falls back to the default template when unset.
+    pub stream_notify_template: Option<String>,
+    /// Role mentioned in stream shout-outs.
+    pub stream_notify_role_id: Option<u64>,
+    /// Prefix for commands in this guild. Falls back to the bot-wide prefix when unset.
+    pub command_prefix: Option<String>,
+    /// Role whose members may change the bot's settings for this guild,
+    /// as well as members who can manage the guild.
+    pub admin_role_id: Option<u64>,
+    /// What the names of colour roles start with. Falls back to the default prefix when unset.
+    pub colour_role_prefix: Option<String>,
+    /// Whether members may choose any colour, rather than only the colour roles.
+    pub custom_colours_enabled: bool,
+    /// How readable custom colours must be against Discord's dark and light themes,
+    /// as contrast ratios. Fall back to the default limits when unset.
+    pub custom_colour_min_contrast_dark: Option<f64>,
+    pub custom_colour_min_contrast_light: Option<f64>,
+    /// Whether members' titles need a moderator's approval.
+    pub title_approval_enabled: bool,
+    /// Channel where moderators are asked to approve titles.
+    /// Falls back to the channel the title was set in when unset.
+    pub title_approval_channel_id: Option<u64>,
+    /// Whether members' titles are put in front of their nicknames.
+    pub title_nicknames_enabled: bool,
+}
+
+impl Default for Guild {
+    fn default() -> Self {
+        Self {
+            stream_notify_enabled: true,
+            stream_notify_channel_id: None,
+            stream_notify_cooldown: None,
+            stream_notify_template: None,
+            stream_notify_role_id: None,
+            command_prefix: None,
+            admin_role_id: None,
+            colour_role_prefix: None,
+            custom_colours_enabled: false,
+            custom_colour_min_contrast_dark: None,
+            custom_colour_min_contrast_light: None,
+            title_approval_enabled: false,
+            title_approval_channel_id: None,
+            title_nicknames_enabled: false,
+        }
+    }
+}
 
 #[derive(Clone, Debug, Default)]
 pub struct User {
+    /// The user's title in every guild where they haven't chosen another.
     pub title: Option<String>,
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct Member {
     pub last_stream_notify_timestamp: i64,
+    /// When the member's current stream started, or None if they aren't streaming.
+    pub stream_start_timestamp: Option<i64>,
+    /// The channel and message of the shout-out for the member's current stream.
+    pub stream_notify_channel_id: Option<u64>,
+    pub stream_notify_message_id: Option<u64>,
+    /// Whether the member asked not to have their streams shouted out.
+    pub stream_notify_opt_out: bool,
+    /// The role giving the member the colour they chose themselves, if any.
+    pub custom_colour_role_id: Option<u64>,
+    /// The member's title in this guild, shown instead of their global title.
+    pub title: Option<String>,
+    /// Whether a moderator locked the member's title in this guild.
+    pub title_locked: bool,
+    /// Whether the bot put the member's title in their nickname.
+    pub title_nickname_synced: bool,
+    /// The nickname the member had before their title was put in it, to put back
+    /// when the title goes. None if they had no nickname.
+    pub nickname_before_title: Option<String>,
 }
 
-pub struct Handle {
-    connection: Connection,
+/// A title a user set, or cleared, kept in their title history.
+#[derive(Clone, Debug)]
+pub struct TitleChange {
+    /// None when the title was cleared.
+    pub title: Option<String>,
+    pub timestamp: i64,
 }
 
-impl Handle {
-    pub fn new() -> Self {
-        // Set DATABASE_PATH in the mount/env file to override the default path.
-        let db_path: String = match env::var("DATABASE_PATH") {
-            Err(_) => String::from(DEFAULT_DATABASE_PATH),
-            Ok(path) => path,
-        };
-        Self {
-            connection: Connection::open(db_path)
-                .expect("rusqlite::Connection::open() failed"),
+/// A word or regular expression that titles in a guild must not match.
+#[derive(Clone, Debug)]
+pub struct TitleFilter {
+    /// Whether the pattern is a regular expression rather than a word.
+    pub is_regex: bool,
+    pub pattern: String,
+}
+
+/// A title waiting for a moderator's approval.
+#[derive(Clone, Debug)]
+pub struct PendingTitle {
+    pub guild_id: u64,
+    pub user_id: u64,
+    pub title: String,
+    /// The message moderators react to, to approve or reject the title.
+    pub channel_id: u64,
+    pub message_id: u64,
+    pub timestamp: i64,
+}
+
+/// Something done to titles in a guild, kept in the moderation log.
+#[derive(Clone, Debug)]
+pub struct TitleModeration {
+    /// None when the filters refused a title by themselves.
+    pub moderator_id: Option<u64>,
+    /// The member whose title it was, or None for changes to the filters.
+    pub user_id: Option<u64>,
+    pub action: String,
+    pub detail: Option<String>,
+    pub timestamp: i64,
+}
+
+/// A change to one of a guild's settings, kept in the audit log.
+#[derive(Clone, Debug)]
+pub struct SettingChange {
+    /// The member who made the change.
+    pub user_id: u64,
+    /// Name of the setting, as listed by `!admin`.
+    pub setting: String,
+    /// None when the setting was or is now at its default.
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    pub timestamp: i64,
+}
+
+/// Who a roll macro belongs to.
+#[derive(Clone, Copy, Debug)]
+pub enum MacroOwner {
+    User(u64),
+    Guild(u64),
+}
+
+impl MacroOwner {
+    /// The table storing this kind of macro, and the column holding the owner ID.
+    fn table(self) -> (&'static str, &'static str, i64) {
+        match self {
+            MacroOwner::User(user_id) => ("UserMacros", "DiscordUserId", user_id as i64),
+            MacroOwner::Guild(guild_id) => ("GuildMacros", "DiscordGuildId", guild_id as i64),
         }
     }
+}
 
-    pub fn update_schema(&self) -> Result<(), ()> {
-        // Read file names into a Vec for sorting
-        let mut sql_files: Vec<_> = match fs::read_dir("/catnip/mount/sql") {
-            Ok(files) => files,
-            Err(_) => {
-                error!("Could not find any sqlfiles");
-                return Err(())
-            }
+#[derive(Clone, Debug)]
+pub struct RollMacro {
+    pub name: String,
+    pub expression: String,
+}
+
+/// A dice roll made by a user, kept so that results can be checked later.
+#[derive(Clone, Debug)]
+pub struct RollRecord {
+    pub user_id: u64,
+    /// None for rolls made in direct messages.
+    pub guild_id: Option<u64>,
+    pub channel_id: u64,
+    pub expression: String,
+    /// JSON encoded results of each term of the expression.
+    pub results: String,
+    pub total: i64,
+    pub timestamp: i64,
+}
+
+/// A combat encounter tracked in a channel.
+#[derive(Clone, Debug)]
+pub struct Encounter {
+    pub channel_id: u64,
+    pub guild_id: Option<u64>,
+    /// The user who started the encounter.
+    pub gm_user_id: u64,
+    /// 0 until initiative has been rolled.
+    pub round: u32,
+    /// Index into `combatants` of whose turn it is.
+    pub turn: usize,
+    /// Everyone taking part, in initiative order.
+    pub combatants: Vec<Combatant>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Combatant {
+    pub name: String,
+    /// None for non-player characters.
+    pub user_id: Option<u64>,
+    pub modifier: i64,
+    /// None until initiative has been rolled.
+    pub initiative: Option<i64>,
+}
+
+/// Everything that can go wrong when using the database.
+#[derive(Debug)]
+pub enum DatabaseError {
+    /// The SQLite library reported an error.
+    Sqlite(rusqlite::Error),
+    /// A migration file couldn't be read.
+    Io(io::Error),
+    /// The migrations couldn't be applied as found.
+    Migration(String),
+    /// A backup couldn't be made or restored.
+    Backup(String),
+    /// The connection pool wasn't set up in the client's data.
+    NoPool,
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DatabaseError::Sqlite(why) => write!(f, "SQLite error: {}", why),
+            DatabaseError::Io(why) => write!(f, "I/O error: {}", why),
+            DatabaseError::Migration(why) => write!(f, "migration error: {}", why),
+            DatabaseError::Backup(why) => write!(f, "backup error: {}", why),
+            DatabaseError::NoPool => write!(f, "no database connection pool in client data"),
+        }
+    }
+}
+
+impl error::Error for DatabaseError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DatabaseError::Sqlite(why) => Some(why),
+            DatabaseError::Io(why) => Some(why),
+            DatabaseError::Migration(_)
+            | DatabaseError::Backup(_)
+            | DatabaseError::NoPool => None,
         }
-        .map(|r| r.unwrap())
-        .collect();
-        sql_files.sort_by_key(|dir| dir.path());
-        debug!("Found sql files {:?}", sql_files);
-
-        let mut sql_file_iter = sql_files.iter();
-        loop {
-            // Read current user_version
-            let user_version: i32 = match self.connection.pragma_query_value(
-                None, "user_version", |row| row.get(0))
-            {
-                Ok(value) => value,
-                Err(_) => {
-                    error!("Could not query user_version of database");
-                    return Err(())
+    }
+}
+
+impl From<rusqlite::Error> for DatabaseError {
+    fn from(why: rusqlite::Error) -> Self {
+        DatabaseError::Sqlite(why)
+    }
+}
+
+impl From<io::Error> for DatabaseError {
+    fn from(why: io::Error) -> Self {
+        DatabaseError::Io(why)
+    }
+}
+
+/// Connections to the database that can be shared between threads.
+///
+/// Opening a connection for every command and presence update is wasteful,
+/// so connections are handed back to the pool when their handle is dropped
+/// and reused by the next caller.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    path: String,
+    idle: Mutex<Vec<Connection>>,
+}
+
+/// Key to find the connection pool in the client's data.
+pub struct PoolKey;
+
+impl TypeMapKey for PoolKey {
+    type Value = Pool;
+}
+
+impl Pool {
+    /// Create a pool for the database at a path,
+    /// checking that it can be opened.
+    pub fn new(path: &str) -> Result<Self, DatabaseError> {
+        let connection = Connection::open(path)?;
+        Ok(Self {
+            inner: Arc::new(PoolInner {
+                path: String::from(path),
+                idle: Mutex::new(vec![connection]),
+            }),
+        })
+    }
+
+    /// Get a handle on the database, reusing an idle connection if there is one.
+    pub fn get(&self) -> Result<PooledHandle, DatabaseError> {
+        let idle = match self.inner.idle.lock() {
+            Ok(mut idle) => idle.pop(),
+            // A thread panicked while returning a connection; open a fresh one instead
+            Err(_) => None,
+        };
+        let connection = match idle {
+            Some(connection) => connection,
+            None => {
+                debug!("Opening new database connection");
+                Connection::open(&self.inner.path)?
+            },
+        };
+        Ok(PooledHandle {
+            handle: Some(Handle { connection }),
+            pool: Arc::clone(&self.inner),
+        })
+    }
+}
+
+/// A handle on the database borrowed from a `Pool`.
+pub struct PooledHandle {
+    handle: Option<Handle>,
+    pool: Arc<PoolInner>,
+}
+
+impl Deref for PooledHandle {
+    type Target = Handle;
+
+    fn deref(&self) -> &Handle {
+        self.handle.as_ref().expect("database handle used after being returned")
+    }
+}
+
+impl DerefMut for PooledHandle {
+    fn deref_mut(&mut self) -> &mut Handle {
+        self.handle.as_mut().expect("database handle used after being returned")
+    }
+}
+
+impl Drop for PooledHandle {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            if let Ok(mut idle) = self.pool.idle.lock() {
+                if idle.len() < MAX_IDLE_CONNECTIONS {
+                    idle.push(handle.connection);
                 }
-            };
-
-            // Try to find the next user_version (N+1) to upgrade to
-            let prefix_to_find = format!("{:03}", user_version+1);
-            debug!("searching for sql file matching prefix {}", prefix_to_find);
-
-            match sql_file_iter.next() {
-                Some(sql_file) => if sql_file.file_name()
-                    .into_string()
-                    .unwrap()
-                    .starts_with(prefix_to_find.as_str())
-                {
-                    let mut file = match fs::File::open(sql_file.path()) {
-                        Ok(file) => file,
-                        Err(_) => return Err(()),
-                    };
-                    debug!("Opened sql file {:?}", sql_file.path());
-
-                    let mut sql_content = String::new();
-                    if let Err(_) = file.read_to_string(&mut sql_content) {
-                        error!("Could not read SQL file {:?}", sql_file.path());
-                        return Err(())
-                    }
-                    debug!("Read sql file content");
-
-                    info!("Applying DB schema migration {:?}", sql_file.path());
-                    match self.connection.execute_batch(sql_content.as_str()) {
-                        Ok(_) => info!("Migrated successfully"),
-                        Err(_) => {
-                            error!("Failed to apply schema migration");
-                            return Err(())
-                        }
-                    };
-                },
-                None => break,
             }
         }
+    }
+}
+
+/// Get a handle on the database from the pool in the client's data.
+pub fn handle(context: &Context) -> Result<PooledHandle, DatabaseError> {
+    match context.data.read().get::<PoolKey>() {
+        Some(pool) => pool.get(),
+        None => Err(DatabaseError::NoPool),
+    }
+}
+
+pub struct Handle {
+    connection: Connection,
+}
+
+impl Handle {
+    /// Apply any migrations the database is missing.
+    pub fn update_schema(&mut self,
+        migrations_dir: &Path,
+    ) -> Result<(), DatabaseError>
+    {
+        let steps = self.migrate(migrations_dir, None, false)?;
+        if steps.is_empty() {
+            debug!("Database schema is up to date");
+        }
+        Ok(())
+    }
+
+    /// Migrate the database to a schema version, or the latest one if there's no target.
+    /// In a dry run, the steps are tried and rolled back.
+    pub fn migrate(&mut self,
+        migrations_dir: &Path,
+        target: Option<u32>,
+        dry_run: bool,
+    ) -> Result<Vec<migrations::Step>, DatabaseError>
+    {
+        let migrations = migrations::load(migrations_dir)?;
+        migrations::migrate(&mut self.connection, &migrations, target, dry_run)
+    }
+
+    /// Get the schema version and the migrations recorded as applied.
+    pub fn schema_status(&self,
+    ) -> Result<(u32, Vec<migrations::AppliedMigration>), DatabaseError>
+    {
+        let version = migrations::user_version(&self.connection)?;
+        let applied = migrations::applied(&self.connection)?;
+        Ok((version, applied))
+    }
+
+    /// Copy the database to a new file with SQLite's online backup API.
+    /// The bot can keep using the database while the copy is made.
+    pub fn backup(&self,
+        path: &Path,
+    ) -> Result<(), DatabaseError>
+    {
+        let mut destination = Connection::open(path)?;
+        let backup = Backup::new(&self.connection, &mut destination)?;
+        backup.run_to_completion(BACKUP_PAGES_PER_STEP, BACKUP_STEP_PAUSE, None)?;
+        Ok(())
+    }
 
+    /// Replace the contents of the database with a copy of another database file.
+    /// Check the file with `backups::check_restorable` first.
+    pub fn restore(&mut self,
+        path: &Path,
+    ) -> Result<(), DatabaseError>
+    {
+        let source = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let backup = Backup::new(&source, &mut self.connection)?;
+        backup.run_to_completion(BACKUP_PAGES_PER_STEP, BACKUP_STEP_PAUSE, None)?;
         Ok(())
     }
 
+    /// Get a guild's settings.
+    /// Return a default Guild instance if no record was found.
     pub fn guild(&self,
         guild_id: u64,
-    ) -> Result<Guild, ()> {
-        let mut stmt = match self.connection.prepare(
-            // Placeholder for querying something of actual use...
-            "SELECT DiscordGuildId FROM Guilds WHERE DiscordGuildId = ?1")
-        {
-            Ok(stmt) => stmt,
-            Err(_) => return Err(()),
-        };
-
-        let mut result_iter = match stmt.query_map(
+    ) -> Result<Guild, DatabaseError> {
+        let guild = self.connection.query_row(
+            "SELECT StreamNotifyEnabled, StreamNotifyChannelId, StreamNotifyCooldown,
+             StreamNotifyTemplate, StreamNotifyRoleId, CommandPrefix, AdminRoleId,
+             ColourRolePrefix, CustomColoursEnabled, CustomColourMinContrastDark,
+             CustomColourMinContrastLight, TitleApprovalEnabled, TitleApprovalChannelId,
+             TitleNicknamesEnabled
+             FROM Guilds WHERE DiscordGuildId = ?1",
             params![guild_id as i64],
-            |_row|
+            |row|
         {
-            Ok(Guild{})
-        }) {
-            Ok(result_iter) => result_iter,
-            Err(_) => return Err(()),
-        };
+            let channel_id: Option<i64> = row.get(1)?;
+            let role_id: Option<i64> = row.get(4)?;
+            let admin_role_id: Option<i64> = row.get(6)?;
+            let title_approval_channel_id: Option<i64> = row.get(12)?;
+            Ok(Guild{
+                stream_notify_enabled: row.get(0)?,
+                stream_notify_channel_id: channel_id.map(|id| id as u64),
+                stream_notify_cooldown: row.get(2)?,
+                stream_notify_template: row.get(3)?,
+                stream_notify_role_id: role_id.map(|id| id as u64),
+                command_prefix: row.get(5)?,
+                admin_role_id: admin_role_id.map(|id| id as u64),
+                colour_role_prefix: row.get(7)?,
+                custom_colours_enabled: row.get(8)?,
+                custom_colour_min_contrast_dark: row.get(9)?,
+                custom_colour_min_contrast_light: row.get(10)?,
+                title_approval_enabled: row.get(11)?,
+                title_approval_channel_id: title_approval_channel_id.map(|id| id as u64),
+                title_nicknames_enabled: row.get(13)?,
+            })
+        }).optional()?;
 
-        match result_iter.next() {
-            Some(guild) => Ok(guild.unwrap()),
+        match guild {
+            Some(guild) => Ok(guild),
             None => {
                 debug!("No db entry found for guild_id {}, returning default Guild instance",
                     guild_id);
@@ -133,46 +476,175 @@ impl Handle {
 
     pub fn guild_update(&self,
         guild_id: u64,
-        _data: &Guild,
-    ) -> Result<(), ()>
+        data: &Guild,
+    ) -> Result<(), DatabaseError>
     {
-        if let Err(_) = self.connection.execute(
-            // Placeholder for storing something of actual use...
-            "INSERT OR REPLACE INTO Guilds(DiscordGuildId) VALUES(?1)",
-            params![guild_id as i64],
-        )
+        self.connection.execute(
+            "INSERT OR REPLACE INTO Guilds(
+             DiscordGuildId, StreamNotifyEnabled, StreamNotifyChannelId, StreamNotifyCooldown,
+             StreamNotifyTemplate, StreamNotifyRoleId, CommandPrefix, AdminRoleId,
+             ColourRolePrefix, CustomColoursEnabled, CustomColourMinContrastDark,
+             CustomColourMinContrastLight, TitleApprovalEnabled, TitleApprovalChannelId,
+             TitleNicknamesEnabled)
+             VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+            params![
+                guild_id as i64,
+                data.stream_notify_enabled,
+                data.stream_notify_channel_id.map(|id| id as i64),
+                data.stream_notify_cooldown,
+                data.stream_notify_template,
+                data.stream_notify_role_id.map(|id| id as i64),
+                data.command_prefix,
+                data.admin_role_id.map(|id| id as i64),
+                data.colour_role_prefix,
+                data.custom_colours_enabled,
+                data.custom_colour_min_contrast_dark,
+                data.custom_colour_min_contrast_light,
+                data.title_approval_enabled,
+                data.title_approval_channel_id.map(|id| id as i64),
+                data.title_nicknames_enabled,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Run several changes as one, so that they are all kept or, if any fails, none are.
+    /// Inside another transaction, the changes simply become part of it.
+    pub fn in_transaction<T, F>(&self,
+        changes: F,
+    ) -> Result<T, DatabaseError>
+    where F: FnOnce(&Handle) -> Result<T, DatabaseError>
+    {
+        if !self.connection.is_autocommit() {
+            return changes(self)
+        }
+        self.connection.execute_batch("BEGIN IMMEDIATE")?;
+        match changes(self) {
+            Ok(result) => {
+                self.connection.execute_batch("COMMIT")?;
+                Ok(result)
+            },
+            Err(why) => {
+                if let Err(rollback_why) = self.connection.execute_batch("ROLLBACK") {
+                    error!("Could not roll back transaction: {}", rollback_why);
+                }
+                Err(why)
+            },
+        }
+    }
+
+    /// Record a change to a guild's settings in the audit log.
+    pub fn settings_audit_insert(&self,
+        guild_id: u64,
+        change: &SettingChange,
+    ) -> Result<(), DatabaseError>
+    {
+        self.connection.execute(
+            "INSERT INTO GuildSettingsAudit(
+             DiscordGuildId, DiscordUserId, Setting, OldValue, NewValue, Timestamp)
+             VALUES(?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                guild_id as i64,
+                change.user_id as i64,
+                change.setting,
+                change.old_value,
+                change.new_value,
+                change.timestamp,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Get the most recent changes to a guild's settings, newest first.
+    pub fn settings_audit(&self,
+        guild_id: u64,
+        limit: u32,
+    ) -> Result<Vec<SettingChange>, DatabaseError>
+    {
+        let mut stmt = self.connection.prepare(
+            "SELECT DiscordUserId, Setting, OldValue, NewValue, Timestamp
+             FROM GuildSettingsAudit WHERE DiscordGuildId = ?1
+             ORDER BY AuditId DESC LIMIT ?2")?;
+
+        let changes = stmt.query_map(
+            params![guild_id as i64, limit],
+            |row|
         {
-            return Err(())
-        };
+            Ok(SettingChange{
+                user_id: row.get::<_, i64>(0)? as u64,
+                setting: row.get(1)?,
+                old_value: row.get(2)?,
+                new_value: row.get(3)?,
+                timestamp: row.get(4)?,
+            })
+        })?
+        .collect::<Result<Vec<SettingChange>, _>>()?;
+        Ok(changes)
+    }
+
+    /// Get the roles a member needs one of to have their streams shouted out.
+    /// An empty list means anyone can be shouted out.
+    pub fn stream_notify_roles(&self,
+        guild_id: u64,
+    ) -> Result<Vec<u64>, DatabaseError> {
+        let mut stmt = self.connection.prepare(
+            "SELECT DiscordRoleId FROM StreamNotifyRoles WHERE DiscordGuildId = ?1")?;
+
+        let role_ids = stmt.query_map(
+            params![guild_id as i64],
+            |row| row.get::<_, i64>(0))?
+            .collect::<Result<Vec<i64>, _>>()?;
+        Ok(role_ids.into_iter().map(|id| id as u64).collect())
+    }
+
+    pub fn stream_notify_role_add(&self,
+        guild_id: u64,
+        role_id: u64,
+    ) -> Result<(), DatabaseError>
+    {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO StreamNotifyRoles(DiscordGuildId, DiscordRoleId)
+             VALUES(?1, ?2)",
+            params![guild_id as i64, role_id as i64],
+        )?;
         Ok(())
     }
 
+    /// Remove a role from the roles required for shout-outs, or all of them if None.
+    /// Return whether any role was removed.
+    pub fn stream_notify_role_remove(&self,
+        guild_id: u64,
+        role_id: Option<u64>,
+    ) -> Result<bool, DatabaseError>
+    {
+        let deleted = match role_id {
+            Some(role_id) => self.connection.execute(
+                "DELETE FROM StreamNotifyRoles WHERE DiscordGuildId = ?1 AND DiscordRoleId = ?2",
+                params![guild_id as i64, role_id as i64])?,
+            None => self.connection.execute(
+                "DELETE FROM StreamNotifyRoles WHERE DiscordGuildId = ?1",
+                params![guild_id as i64])?,
+        };
+        Ok(deleted > 0)
+    }
+
     /// Get a user's data.
     /// Return a default User instance if no record was found.
     pub fn user(&self,
         user_id: u64,
-    ) -> Result<User, ()> {
-        let mut stmt = match self.connection.prepare(
-            "SELECT Title FROM Users WHERE DiscordUserId = ?1")
-        {
-            Ok(stmt) => stmt,
-            Err(_) => return Err(()),
-        };
-
-        let mut result_iter = match stmt.query_map(
+    ) -> Result<User, DatabaseError> {
+        let user = self.connection.query_row(
+            "SELECT Title FROM Users WHERE DiscordUserId = ?1",
             params![user_id as i64],
             |row|
         {
             Ok(User{
-                title: row.get(0).unwrap(),
+                title: row.get(0)?,
             })
-        }) {
-            Ok(result_iter) => result_iter,
-            Err(_) => return Err(()),
-        };
+        }).optional()?;
 
-        match result_iter.next() {
-            Some(user) => Ok(user.unwrap()),
+        match user {
+            Some(user) => Ok(user),
             None => {
                 debug!("No db entry found for user_id {}, returning default User instance",
                     user_id);
@@ -184,84 +656,770 @@ impl Handle {
     pub fn user_update(&self,
         user_id: u64,
         data: &User,
-    ) -> Result<(), ()>
+    ) -> Result<(), DatabaseError>
     {
-        if let Err(_) = self.connection.execute(
+        self.connection.execute(
             "INSERT OR REPLACE INTO Users(DiscordUserId, Title) VALUES(?1, ?2)",
             params![
                 user_id as i64,
                 data.title,
             ],
-        )
-        {
-            return Err(())
-        };
+        )?;
         Ok(())
     }
 
+    /// Get a member's data.
+    /// Return a default Member instance if no record was found.
     pub fn member(&self,
         guild_id: u64,
         user_id: u64,
-    ) -> Result<Member, ()> {
-        let mut stmt = match self.connection.prepare(
-            "SELECT LastStreamNotifyTimestamp FROM Members
-             WHERE DiscordGuildId = ?1 AND DiscordUserId = ?2")
-        {
-            Ok(stmt) => stmt,
-            Err(_) => return Err(()),
-        };
-
-        let mut result_iter = match stmt.query_map(
+    ) -> Result<Member, DatabaseError> {
+        let member = self.connection.query_row(
+            "SELECT LastStreamNotifyTimestamp, StreamStartTimestamp,
+             StreamNotifyChannelId, StreamNotifyMessageId, StreamNotifyOptOut,
+             CustomColourRoleId, Title, TitleLocked, TitleNicknameSynced,
+             NicknameBeforeTitle FROM Members
+             WHERE DiscordGuildId = ?1 AND DiscordUserId = ?2",
             params![
                 guild_id as i64,
                 user_id as i64,
             ],
             |row|
         {
+            let channel_id: Option<i64> = row.get(2)?;
+            let message_id: Option<i64> = row.get(3)?;
+            let custom_colour_role_id: Option<i64> = row.get(5)?;
             Ok(Member {
-                last_stream_notify_timestamp: row.get(0).unwrap(),
+                last_stream_notify_timestamp: row.get(0)?,
+                stream_start_timestamp: row.get(1)?,
+                stream_notify_channel_id: channel_id.map(|id| id as u64),
+                stream_notify_message_id: message_id.map(|id| id as u64),
+                stream_notify_opt_out: row.get(4)?,
+                custom_colour_role_id: custom_colour_role_id.map(|id| id as u64),
+                title: row.get(6)?,
+                title_locked: row.get(7)?,
+                title_nickname_synced: row.get(8)?,
+                nickname_before_title: row.get(9)?,
             })
-        }) {
-            Ok(result_iter) => result_iter,
-            Err(_) => return Err(()),
-        };
+        }).optional()?;
 
-        match result_iter.next() {
-            Some(member) => Ok(member.unwrap()),
+        match member {
+            Some(member) => Ok(member),
             None => {
-                debug!("No db entry found for member_id {}, returning default Member instance",
-                    guild_id);
+                debug!("No db entry found for member {} in guild {}, \
+                        returning default Member instance", user_id, guild_id);
                 Ok(Default::default())
             },
         }
     }
 
-    pub fn member_update(&self,
+    /// Make sure a member has a row, so that single columns of it can be updated.
+    fn member_insert_default(&self,
+        guild_id: u64,
+        user_id: u64,
+    ) -> Result<(), DatabaseError>
+    {
+        self.connection.execute(
+            "INSERT OR IGNORE INTO Members(DiscordGuildId, DiscordUserId, LastStreamNotifyTimestamp)
+             VALUES(?1, ?2, 0)",
+            params![guild_id as i64, user_id as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Get the members who are streaming, as guild and user IDs.
+    pub fn live_members(&self) -> Result<Vec<(u64, u64)>, DatabaseError> {
+        let mut stmt = self.connection.prepare(
+            "SELECT DiscordGuildId, DiscordUserId FROM Members
+             WHERE StreamStartTimestamp IS NOT NULL")?;
+
+        let members = stmt.query_map(
+            params![],
+            |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(members)
+    }
+
+    /// Mark a member as streaming since `timestamp`, forgetting any earlier shout-out.
+    /// Return false, changing nothing, if they were already streaming.
+    pub fn member_stream_start(&self,
         guild_id: u64,
         user_id: u64,
-        data: &Member
-    ) -> Result<(), ()>
+        timestamp: i64,
+    ) -> Result<bool, DatabaseError>
     {
-        let rowschanged = match self.connection.execute("INSERT OR REPLACE INTO Members(
-             DiscordGuildId, DiscordUserId, LastStreamNotifyTimestamp)
+        self.member_insert_default(guild_id, user_id)?;
+        let rowschanged = self.connection.execute(
+            "UPDATE Members SET StreamStartTimestamp = ?3,
+             StreamNotifyChannelId = NULL, StreamNotifyMessageId = NULL
+             WHERE DiscordGuildId = ?1 AND DiscordUserId = ?2 AND StreamStartTimestamp IS NULL",
+            params![guild_id as i64, user_id as i64, timestamp],
+        )?;
+        Ok(rowschanged > 0)
+    }
+
+    /// Mark a member as no longer streaming.
+    /// Return false, changing nothing, if they weren't streaming.
+    pub fn member_stream_end(&self,
+        guild_id: u64,
+        user_id: u64,
+    ) -> Result<bool, DatabaseError>
+    {
+        let rowschanged = self.connection.execute(
+            "UPDATE Members SET StreamStartTimestamp = NULL,
+             StreamNotifyChannelId = NULL, StreamNotifyMessageId = NULL
+             WHERE DiscordGuildId = ?1 AND DiscordUserId = ?2 AND StreamStartTimestamp IS NOT NULL",
+            params![guild_id as i64, user_id as i64],
+        )?;
+        Ok(rowschanged > 0)
+    }
+
+    /// Record the shout-out sent for a member's stream at `timestamp`.
+    pub fn member_stream_shout_out(&self,
+        guild_id: u64,
+        user_id: u64,
+        timestamp: i64,
+        channel_id: u64,
+        message_id: u64,
+    ) -> Result<(), DatabaseError>
+    {
+        self.member_insert_default(guild_id, user_id)?;
+        self.connection.execute(
+            "UPDATE Members SET LastStreamNotifyTimestamp = ?3,
+             StreamNotifyChannelId = ?4, StreamNotifyMessageId = ?5
+             WHERE DiscordGuildId = ?1 AND DiscordUserId = ?2",
+            params![guild_id as i64, user_id as i64, timestamp, channel_id as i64, message_id as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Turn shout-outs of a member's streams off or on, leaving the rest of their data alone.
+    pub fn member_stream_notify_opt_out_update(&self,
+        guild_id: u64,
+        user_id: u64,
+        opt_out: bool,
+    ) -> Result<(), DatabaseError>
+    {
+        self.member_insert_default(guild_id, user_id)?;
+        self.connection.execute(
+            "UPDATE Members SET StreamNotifyOptOut = ?3
+             WHERE DiscordGuildId = ?1 AND DiscordUserId = ?2",
+            params![guild_id as i64, user_id as i64, opt_out],
+        )?;
+        Ok(())
+    }
+
+    /// Set a member's title in a guild, leaving the rest of their data alone.
+    pub fn member_title_update(&self,
+        guild_id: u64,
+        user_id: u64,
+        title: Option<&str>,
+    ) -> Result<(), DatabaseError>
+    {
+        self.member_insert_default(guild_id, user_id)?;
+        self.connection.execute(
+            "UPDATE Members SET Title = ?3 WHERE DiscordGuildId = ?1 AND DiscordUserId = ?2",
+            params![guild_id as i64, user_id as i64, title],
+        )?;
+        Ok(())
+    }
+
+    /// Lock or unlock a member's title in a guild, leaving the rest of their data alone.
+    pub fn member_title_locked_update(&self,
+        guild_id: u64,
+        user_id: u64,
+        locked: bool,
+    ) -> Result<(), DatabaseError>
+    {
+        self.member_insert_default(guild_id, user_id)?;
+        self.connection.execute(
+            "UPDATE Members SET TitleLocked = ?3 WHERE DiscordGuildId = ?1 AND DiscordUserId = ?2",
+            params![guild_id as i64, user_id as i64, locked],
+        )?;
+        Ok(())
+    }
+
+    /// Record whether a member's title is in their nickname, and the nickname they had
+    /// before it was put there, leaving the rest of their data alone.
+    pub fn member_title_nickname_update(&self,
+        guild_id: u64,
+        user_id: u64,
+        synced: bool,
+        nickname_before_title: Option<&str>,
+    ) -> Result<(), DatabaseError>
+    {
+        self.member_insert_default(guild_id, user_id)?;
+        self.connection.execute(
+            "UPDATE Members SET TitleNicknameSynced = ?3, NicknameBeforeTitle = ?4
+             WHERE DiscordGuildId = ?1 AND DiscordUserId = ?2",
+            params![guild_id as i64, user_id as i64, synced, nickname_before_title],
+        )?;
+        Ok(())
+    }
+
+    /// Record the custom colour role a member has, leaving the rest of their data alone.
+    pub fn member_custom_colour_update(&self,
+        guild_id: u64,
+        user_id: u64,
+        role_id: Option<u64>,
+    ) -> Result<(), DatabaseError>
+    {
+        self.member_insert_default(guild_id, user_id)?;
+        self.connection.execute(
+            "UPDATE Members SET CustomColourRoleId = ?3
+             WHERE DiscordGuildId = ?1 AND DiscordUserId = ?2",
+            params![guild_id as i64, user_id as i64, role_id.map(|id| id as i64)],
+        )?;
+        Ok(())
+    }
+
+    /// Get the guilds where members' titles are put in their nicknames.
+    pub fn title_nickname_guilds(&self) -> Result<Vec<u64>, DatabaseError> {
+        let mut stmt = self.connection.prepare(
+            "SELECT DiscordGuildId FROM Guilds WHERE TitleNicknamesEnabled = 1")?;
+
+        let guild_ids = stmt.query_map(
+            params![],
+            |row| row.get::<_, i64>(0))?
+            .collect::<Result<Vec<i64>, _>>()?;
+        Ok(guild_ids.into_iter().map(|id| id as u64).collect())
+    }
+
+    /// Get the members of a guild whose titles the bot put in their nicknames.
+    pub fn title_nickname_members(&self,
+        guild_id: u64,
+    ) -> Result<Vec<u64>, DatabaseError> {
+        let mut stmt = self.connection.prepare(
+            "SELECT DiscordUserId FROM Members
+             WHERE DiscordGuildId = ?1 AND TitleNicknameSynced = 1")?;
+
+        let user_ids = stmt.query_map(
+            params![guild_id as i64],
+            |row| row.get::<_, i64>(0))?
+            .collect::<Result<Vec<i64>, _>>()?;
+        Ok(user_ids.into_iter().map(|id| id as u64).collect())
+    }
+
+    /// Record a change to a user's global title, or their title in a guild, in their history.
+    pub fn title_history_insert(&self,
+        user_id: u64,
+        guild_id: Option<u64>,
+        change: &TitleChange,
+    ) -> Result<(), DatabaseError>
+    {
+        self.connection.execute(
+            "INSERT INTO TitleHistory(DiscordUserId, DiscordGuildId, Title, Timestamp)
+             VALUES(?1, ?2, ?3, ?4)",
+            params![
+                user_id as i64,
+                guild_id.map(|id| id as i64),
+                change.title,
+                change.timestamp,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Get the most recent changes to a user's global title, or their title in a guild,
+    /// newest first.
+    pub fn title_history(&self,
+        user_id: u64,
+        guild_id: Option<u64>,
+        limit: u32,
+    ) -> Result<Vec<TitleChange>, DatabaseError>
+    {
+        let mut stmt = self.connection.prepare(
+            "SELECT Title, Timestamp FROM TitleHistory
+             WHERE DiscordUserId = ?1 AND DiscordGuildId IS ?2
+             ORDER BY HistoryId DESC LIMIT ?3")?;
+
+        let changes = stmt.query_map(
+            params![user_id as i64, guild_id.map(|id| id as i64), limit],
+            |row|
+        {
+            Ok(TitleChange {
+                title: row.get(0)?,
+                timestamp: row.get(1)?,
+            })
+        })?
+        .collect::<Result<Vec<TitleChange>, _>>()?;
+        Ok(changes)
+    }
+
+    /// Forget all but the `keep` most recent changes to a user's global title,
+    /// or their title in a guild.
+    pub fn title_history_prune(&self,
+        user_id: u64,
+        guild_id: Option<u64>,
+        keep: u32,
+    ) -> Result<(), DatabaseError>
+    {
+        self.connection.execute(
+            "DELETE FROM TitleHistory
+             WHERE DiscordUserId = ?1 AND DiscordGuildId IS ?2 AND HistoryId NOT IN (
+                 SELECT HistoryId FROM TitleHistory
+                 WHERE DiscordUserId = ?1 AND DiscordGuildId IS ?2
+                 ORDER BY HistoryId DESC LIMIT ?3)",
+            params![user_id as i64, guild_id.map(|id| id as i64), keep],
+        )?;
+        Ok(())
+    }
+
+    /// Get the words and regular expressions that titles in a guild must not match,
+    /// in the order they were added.
+    pub fn title_filters(&self,
+        guild_id: u64,
+    ) -> Result<Vec<TitleFilter>, DatabaseError> {
+        let mut stmt = self.connection.prepare(
+            "SELECT IsRegex, Pattern FROM TitleFilters WHERE DiscordGuildId = ?1
+             ORDER BY FilterId")?;
+
+        let filters = stmt.query_map(
+            params![guild_id as i64],
+            |row|
+        {
+            Ok(TitleFilter {
+                is_regex: row.get(0)?,
+                pattern: row.get(1)?,
+            })
+        })?
+        .collect::<Result<Vec<TitleFilter>, _>>()?;
+        Ok(filters)
+    }
+
+    /// Add a title filter to a guild. Returns false if it already had it.
+    pub fn title_filter_add(&self,
+        guild_id: u64,
+        filter: &TitleFilter,
+    ) -> Result<bool, DatabaseError>
+    {
+        let added = self.connection.execute(
+            "INSERT OR IGNORE INTO TitleFilters(DiscordGuildId, IsRegex, Pattern)
              VALUES(?1, ?2, ?3)",
-             params![
+            params![guild_id as i64, filter.is_regex, filter.pattern],
+        )?;
+        Ok(added > 0)
+    }
+
+    /// Remove a title filter from a guild. Returns false if it didn't have it.
+    pub fn title_filter_remove(&self,
+        guild_id: u64,
+        filter: &TitleFilter,
+    ) -> Result<bool, DatabaseError>
+    {
+        let removed = self.connection.execute(
+            "DELETE FROM TitleFilters
+             WHERE DiscordGuildId = ?1 AND IsRegex = ?2 AND Pattern = ?3",
+            params![guild_id as i64, filter.is_regex, filter.pattern],
+        )?;
+        Ok(removed > 0)
+    }
+
+    /// Get the titles waiting for approval in a guild, oldest first.
+    pub fn pending_titles(&self,
+        guild_id: u64,
+    ) -> Result<Vec<PendingTitle>, DatabaseError> {
+        let mut stmt = self.connection.prepare(
+            "SELECT DiscordGuildId, DiscordUserId, Title, ChannelId, MessageId, Timestamp
+             FROM PendingTitles WHERE DiscordGuildId = ?1 ORDER BY Timestamp")?;
+
+        let pending = stmt.query_map(params![guild_id as i64], pending_title_from_row)?
+            .collect::<Result<Vec<PendingTitle>, _>>()?;
+        Ok(pending)
+    }
+
+    /// Get the title waiting for approval with the message moderators react to, if any.
+    pub fn pending_title_by_message(&self,
+        message_id: u64,
+    ) -> Result<Option<PendingTitle>, DatabaseError> {
+        let pending = self.connection.query_row(
+            "SELECT DiscordGuildId, DiscordUserId, Title, ChannelId, MessageId, Timestamp
+             FROM PendingTitles WHERE MessageId = ?1",
+            params![message_id as i64],
+            pending_title_from_row,
+        ).optional()?;
+        Ok(pending)
+    }
+
+    /// Store a title waiting for approval, replacing any the member was already waiting for.
+    pub fn pending_title_update(&self,
+        pending: &PendingTitle,
+    ) -> Result<(), DatabaseError>
+    {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO PendingTitles(
+             DiscordGuildId, DiscordUserId, Title, ChannelId, MessageId, Timestamp)
+             VALUES(?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                pending.guild_id as i64,
+                pending.user_id as i64,
+                pending.title,
+                pending.channel_id as i64,
+                pending.message_id as i64,
+                pending.timestamp,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Forget the title a member was waiting for. Returns false if there was none.
+    pub fn pending_title_delete(&self,
+        guild_id: u64,
+        user_id: u64,
+    ) -> Result<bool, DatabaseError>
+    {
+        let deleted = self.connection.execute(
+            "DELETE FROM PendingTitles WHERE DiscordGuildId = ?1 AND DiscordUserId = ?2",
+            params![guild_id as i64, user_id as i64],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    pub fn title_moderation_insert(&self,
+        guild_id: u64,
+        entry: &TitleModeration,
+    ) -> Result<(), DatabaseError>
+    {
+        self.connection.execute(
+            "INSERT INTO TitleModerationLog(
+             DiscordGuildId, ModeratorId, DiscordUserId, Action, Detail, Timestamp)
+             VALUES(?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
                 guild_id as i64,
+                entry.moderator_id.map(|id| id as i64),
+                entry.user_id.map(|id| id as i64),
+                entry.action,
+                entry.detail,
+                entry.timestamp,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Get the most recent entries of a guild's title moderation log, newest first.
+    pub fn title_moderation_log(&self,
+        guild_id: u64,
+        limit: u32,
+    ) -> Result<Vec<TitleModeration>, DatabaseError>
+    {
+        let mut stmt = self.connection.prepare(
+            "SELECT ModeratorId, DiscordUserId, Action, Detail, Timestamp
+             FROM TitleModerationLog WHERE DiscordGuildId = ?1
+             ORDER BY LogId DESC LIMIT ?2")?;
+
+        let entries = stmt.query_map(
+            params![guild_id as i64, limit],
+            |row|
+        {
+            let moderator_id: Option<i64> = row.get(0)?;
+            let user_id: Option<i64> = row.get(1)?;
+            Ok(TitleModeration {
+                moderator_id: moderator_id.map(|id| id as u64),
+                user_id: user_id.map(|id| id as u64),
+                action: row.get(2)?,
+                detail: row.get(3)?,
+                timestamp: row.get(4)?,
+            })
+        })?
+        .collect::<Result<Vec<TitleModeration>, _>>()?;
+        Ok(entries)
+    }
+
+    /// Get the role made for a custom colour in a guild, if there is one.
+    pub fn custom_colour_role(&self,
+        guild_id: u64,
+        colour: u32,
+    ) -> Result<Option<u64>, DatabaseError>
+    {
+        let role_id: Option<i64> = self.connection.query_row(
+            "SELECT DiscordRoleId FROM CustomColourRoles
+             WHERE DiscordGuildId = ?1 AND Colour = ?2",
+            params![guild_id as i64, colour],
+            |row| row.get(0),
+        ).optional()?;
+        Ok(role_id.map(|id| id as u64))
+    }
+
+    pub fn custom_colour_role_add(&self,
+        guild_id: u64,
+        role_id: u64,
+        colour: u32,
+    ) -> Result<(), DatabaseError>
+    {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO CustomColourRoles(DiscordGuildId, DiscordRoleId, Colour)
+             VALUES(?1, ?2, ?3)",
+            params![guild_id as i64, role_id as i64, colour],
+        )?;
+        Ok(())
+    }
+
+    /// Forget a custom colour role, and that any member has it.
+    pub fn custom_colour_role_delete(&self,
+        guild_id: u64,
+        role_id: u64,
+    ) -> Result<(), DatabaseError>
+    {
+        self.connection.execute(
+            "UPDATE Members SET CustomColourRoleId = NULL
+             WHERE DiscordGuildId = ?1 AND CustomColourRoleId = ?2",
+            params![guild_id as i64, role_id as i64],
+        )?;
+        self.connection.execute(
+            "DELETE FROM CustomColourRoles WHERE DiscordGuildId = ?1 AND DiscordRoleId = ?2",
+            params![guild_id as i64, role_id as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Count the members of a guild who chose the colour of a custom colour role.
+    pub fn custom_colour_role_members(&self,
+        guild_id: u64,
+        role_id: u64,
+    ) -> Result<u32, DatabaseError>
+    {
+        let count = self.connection.query_row(
+            "SELECT COUNT(*) FROM Members WHERE DiscordGuildId = ?1 AND CustomColourRoleId = ?2",
+            params![guild_id as i64, role_id as i64],
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
+
+    /// Get all the roll macros of a user or guild, sorted by name.
+    pub fn macros(&self,
+        owner: MacroOwner,
+    ) -> Result<Vec<RollMacro>, DatabaseError> {
+        let (table, owner_column, owner_id) = owner.table();
+        let mut stmt = self.connection.prepare(&format!(
+            "SELECT Name, Expression FROM {} WHERE {} = ?1 ORDER BY Name",
+            table, owner_column))?;
+
+        let macros = stmt.query_map(
+            params![owner_id],
+            |row|
+        {
+            Ok(RollMacro {
+                name: row.get(0)?,
+                expression: row.get(1)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+        Ok(macros)
+    }
+
+    /// Create a roll macro, replacing any existing macro of the same name.
+    pub fn macro_update(&self,
+        owner: MacroOwner,
+        data: &RollMacro,
+    ) -> Result<(), DatabaseError>
+    {
+        let (table, owner_column, owner_id) = owner.table();
+        self.connection.execute(
+            &format!("INSERT OR REPLACE INTO {}({}, Name, Expression) VALUES(?1, ?2, ?3)",
+                     table, owner_column),
+            params![
+                owner_id,
+                data.name,
+                data.expression,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Delete a roll macro.
+    /// Return whether there was a macro of that name.
+    pub fn macro_delete(&self,
+        owner: MacroOwner,
+        name: &str,
+    ) -> Result<bool, DatabaseError>
+    {
+        let (table, owner_column, owner_id) = owner.table();
+        let rows_changed = self.connection.execute(
+            &format!("DELETE FROM {} WHERE {} = ?1 AND Name = ?2", table, owner_column),
+            params![owner_id, name],
+        )?;
+        Ok(rows_changed > 0)
+    }
+
+    /// Record a roll in the roll history.
+    /// Return the ID of the new history entry.
+    pub fn roll_history_insert(&self,
+        data: &RollRecord,
+    ) -> Result<i64, DatabaseError>
+    {
+        self.connection.execute(
+            "INSERT INTO RollHistory(
+             DiscordUserId, DiscordGuildId, DiscordChannelId, Expression, Results, Total, Timestamp)
+             VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                data.user_id as i64,
+                data.guild_id.map(|id| id as i64),
+                data.channel_id as i64,
+                data.expression,
+                data.results,
+                data.total,
+                data.timestamp,
+            ],
+        )?;
+        Ok(self.connection.last_insert_rowid())
+    }
+
+    /// Get the most recent rolls a user made in a guild, or in direct messages
+    /// if `guild_id` is None, newest first.
+    pub fn roll_history(&self,
+        guild_id: Option<u64>,
+        user_id: u64,
+        limit: u32,
+    ) -> Result<Vec<(i64, RollRecord)>, DatabaseError> {
+        let mut stmt = self.connection.prepare(
+            "SELECT RollId, DiscordChannelId, Expression, Results, Total, Timestamp
+             FROM RollHistory
+             WHERE DiscordGuildId IS ?1 AND DiscordUserId = ?2
+             ORDER BY RollId DESC LIMIT ?3")?;
+
+        let records = stmt.query_map(
+            params![
+                guild_id.map(|id| id as i64),
                 user_id as i64,
-                data.last_stream_notify_timestamp,
-            ],) {
-            Ok(updated) => {
-                debug!("Member ID: {:?} updated", user_id);
-            },
-            Err(err) => {
-                debug!("update failed: {}", err);
-                return Err(());
-            },
+                limit,
+            ],
+            |row|
+        {
+            let channel_id: i64 = row.get(1)?;
+            Ok((row.get(0)?, RollRecord {
+                user_id,
+                guild_id,
+                channel_id: channel_id as u64,
+                expression: row.get(2)?,
+                results: row.get(3)?,
+                total: row.get(4)?,
+                timestamp: row.get(5)?,
+            }))
+        })?
+        .collect::<Result<_, _>>()?;
+        Ok(records)
+    }
+
+    /// Get the encounter being tracked in a channel, if there is one.
+    pub fn encounter(&self,
+        channel_id: u64,
+    ) -> Result<Option<Encounter>, DatabaseError> {
+        let encounter = self.connection.query_row(
+            "SELECT DiscordGuildId, GmUserId, Round, Turn FROM Encounters
+             WHERE DiscordChannelId = ?1",
+            params![channel_id as i64],
+            |row|
+        {
+            let guild_id: Option<i64> = row.get(0)?;
+            let gm_user_id: i64 = row.get(1)?;
+            let turn: i64 = row.get(3)?;
+            Ok(Encounter {
+                channel_id,
+                guild_id: guild_id.map(|id| id as u64),
+                gm_user_id: gm_user_id as u64,
+                round: row.get(2)?,
+                turn: turn as usize,
+                combatants: Vec::new(),
+            })
+        }).optional()?;
+
+        let mut encounter = match encounter {
+            Some(encounter) => encounter,
+            None => return Ok(None),
         };
 
-        debug!("Amount of Rows Changed for updating member: {:?}", rowschanged);
+        let mut stmt = self.connection.prepare(
+            "SELECT Name, DiscordUserId, Modifier, Initiative FROM Combatants
+             WHERE DiscordChannelId = ?1 ORDER BY Position")?;
 
-        Ok(())
+        encounter.combatants = stmt.query_map(
+            params![channel_id as i64],
+            |row|
+        {
+            let user_id: Option<i64> = row.get(1)?;
+            Ok(Combatant {
+                name: row.get(0)?,
+                user_id: user_id.map(|id| id as u64),
+                modifier: row.get(2)?,
+                initiative: row.get(3)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+
+        Ok(Some(encounter))
+    }
+
+    /// Store an encounter, replacing its previous state.
+    /// Joins the transaction the caller is in, if any.
+    pub fn encounter_update(&self,
+        data: &Encounter,
+    ) -> Result<(), DatabaseError>
+    {
+        self.in_transaction(|database| {
+            database.connection.execute(
+                "INSERT OR REPLACE INTO Encounters(
+                 DiscordChannelId, DiscordGuildId, GmUserId, Round, Turn)
+                 VALUES(?1, ?2, ?3, ?4, ?5)",
+                params![
+                    data.channel_id as i64,
+                    data.guild_id.map(|id| id as i64),
+                    data.gm_user_id as i64,
+                    data.round,
+                    data.turn as i64,
+                ],
+            )?;
+
+            database.connection.execute(
+                "DELETE FROM Combatants WHERE DiscordChannelId = ?1",
+                params![data.channel_id as i64],
+            )?;
+
+            for (position, combatant) in data.combatants.iter().enumerate() {
+                database.connection.execute(
+                    "INSERT INTO Combatants(
+                     DiscordChannelId, Position, Name, DiscordUserId, Modifier, Initiative)
+                     VALUES(?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        data.channel_id as i64,
+                        position as i64,
+                        combatant.name,
+                        combatant.user_id.map(|id| id as i64),
+                        combatant.modifier,
+                        combatant.initiative,
+                    ],
+                )?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Stop tracking the encounter in a channel.
+    /// Joins the transaction the caller is in, if any.
+    pub fn encounter_delete(&self,
+        channel_id: u64,
+    ) -> Result<(), DatabaseError>
+    {
+        self.in_transaction(|database| {
+            for sql in &[
+                "DELETE FROM Combatants WHERE DiscordChannelId = ?1",
+                "DELETE FROM Encounters WHERE DiscordChannelId = ?1",
+            ] {
+                database.connection.execute(sql, params![channel_id as i64])?;
+            }
+            Ok(())
+        })
     }
 }
 
+fn pending_title_from_row(row: &rusqlite::Row) -> Result<PendingTitle, rusqlite::Error> {
+    Ok(PendingTitle {
+        guild_id: row.get::<_, i64>(0)? as u64,
+        user_id: row.get::<_, i64>(1)? as u64,
+        title: row.get(2)?,
+        channel_id: row.get::<_, i64>(3)? as u64,
+        message_id: row.get::<_, i64>(4)? as u64,
+        timestamp: row.get(5)?,
+    })
+}
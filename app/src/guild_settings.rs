@@ -0,0 +1,278 @@
+//! Every setting a guild can change, in one list, so that they can all be shown,
+//! reset and audited the same way.
+//!
+//! Values are kept as the text stored in the audit log: IDs for channels and roles,
+//! `on`/`off` for switches and a number of seconds for durations.
+
+use crate::{
+    commands::user::colour::{
+        DEFAULT_COLOUR_ROLE_PREFIX, DEFAULT_MIN_CONTRAST_DARK, DEFAULT_MIN_CONTRAST_LIGHT,
+    },
+    config::Config,
+    database::{DatabaseError, Guild, Handle, SettingChange},
+    stream_notify,
+};
+
+/// What a setting's value is, so that it can be shown in a readable way.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Text,
+    Number,
+    Switch,
+    Seconds,
+    Channel,
+    Role,
+    /// Several roles, as a comma separated list of IDs.
+    Roles,
+}
+
+pub struct Setting {
+    /// Name used to pick the setting in commands and in the audit log.
+    pub name: &'static str,
+    pub description: &'static str,
+    pub kind: Kind,
+    /// Get the value a guild chose from its settings row, or None if it uses the default.
+    /// Settings kept outside the row, like lists of roles, are looked up in the database.
+    pub get: fn(&Handle, u64, &Guild) -> Result<Option<String>, DatabaseError>,
+    /// Go back to the default.
+    pub reset: fn(&Handle, u64) -> Result<(), DatabaseError>,
+    /// Describe the default, for guilds that haven't chosen a value.
+    pub default: fn(&Config) -> String,
+}
+
+pub static SETTINGS: &[Setting] = &[
+    Setting {
+        name: "prefix",
+        description: "What commands in this guild start with.",
+        kind: Kind::Text,
+        get: |_, _, guild| Ok(guild.command_prefix.clone()),
+        reset: |database, guild_id| update_guild(database, guild_id,
+            |guild| guild.command_prefix = None),
+        default: |config| config.bot.prefix.clone(),
+    },
+    Setting {
+        name: "admin.role",
+        description: "Members with this role may change these settings, \
+            as well as members who can manage the guild.",
+        kind: Kind::Role,
+        get: |_, _, guild| Ok(guild.admin_role_id
+            .map(|role_id| role_id.to_string())),
+        reset: |database, guild_id| update_guild(database, guild_id,
+            |guild| guild.admin_role_id = None),
+        default: |_| String::from("none"),
+    },
+    Setting {
+        name: "stream.enabled",
+        description: "Whether stream shout-outs are sent.",
+        kind: Kind::Switch,
+        get: |_, _, guild| Ok(match guild.stream_notify_enabled {
+            true => None,
+            false => Some(String::from("off")),
+        }),
+        reset: |database, guild_id| update_guild(database, guild_id,
+            |guild| guild.stream_notify_enabled = true),
+        default: |_| String::from("on"),
+    },
+    Setting {
+        name: "stream.channel",
+        description: "Channel that stream shout-outs are sent to.",
+        kind: Kind::Channel,
+        get: |_, _, guild| Ok(guild.stream_notify_channel_id
+            .map(|channel_id| channel_id.to_string())),
+        reset: |database, guild_id| update_guild(database, guild_id,
+            |guild| guild.stream_notify_channel_id = None),
+        default: |config| match config.stream_notify.channel_id {
+            Some(channel_id) => format!("channel {} from the bot's config if it's in this guild, \
+                otherwise not set, so no shout-outs are sent", channel_id),
+            None => String::from("not set, so no shout-outs are sent"),
+        },
+    },
+    Setting {
+        name: "stream.cooldown",
+        description: "How long before the same member is shouted out again.",
+        kind: Kind::Seconds,
+        get: |_, _, guild| Ok(guild.stream_notify_cooldown
+            .map(|cooldown| cooldown.to_string())),
+        reset: |database, guild_id| update_guild(database, guild_id,
+            |guild| guild.stream_notify_cooldown = None),
+        default: |config| format!("{} seconds", config.stream_notify.cooldown),
+    },
+    Setting {
+        name: "stream.template",
+        description: "Wording of stream shout-outs.",
+        kind: Kind::Text,
+        get: |_, _, guild| Ok(guild.stream_notify_template.clone()),
+        reset: |database, guild_id| update_guild(database, guild_id,
+            |guild| guild.stream_notify_template = None),
+        default: |_| String::from(stream_notify::DEFAULT_STREAM_NOTIFY_TEMPLATE),
+    },
+    Setting {
+        name: "stream.role",
+        description: "Role mentioned in stream shout-outs.",
+        kind: Kind::Role,
+        get: |_, _, guild| Ok(guild.stream_notify_role_id
+            .map(|role_id| role_id.to_string())),
+        reset: |database, guild_id| update_guild(database, guild_id,
+            |guild| guild.stream_notify_role_id = None),
+        default: |_| String::from("none"),
+    },
+    Setting {
+        name: "stream.streamers",
+        description: "Roles a member needs one of to be shouted out.",
+        kind: Kind::Roles,
+        get: |database, guild_id, _| {
+            let mut role_ids = database.stream_notify_roles(guild_id)?;
+            if role_ids.is_empty() {
+                return Ok(None)
+            }
+            // Sorted so that the same roles always read the same in the audit log
+            role_ids.sort();
+            Ok(Some(role_ids.iter()
+                .map(|role_id| role_id.to_string())
+                .collect::<Vec<_>>()
+                .join(",")))
+        },
+        reset: |database, guild_id| database.stream_notify_role_remove(guild_id, None)
+            .map(|_| ()),
+        default: |_| String::from("anyone who hasn't opted out"),
+    },
+    Setting {
+        name: "colour.prefix",
+        description: "What the names of the roles members can choose colours from start with.",
+        kind: Kind::Text,
+        get: |_, _, guild| Ok(guild.colour_role_prefix.clone()),
+        reset: |database, guild_id| update_guild(database, guild_id,
+            |guild| guild.colour_role_prefix = None),
+        default: |_| String::from(DEFAULT_COLOUR_ROLE_PREFIX),
+    },
+    Setting {
+        name: "colour.custom",
+        description: "Whether members may choose any colour with a hex code.",
+        kind: Kind::Switch,
+        get: |_, _, guild| Ok(match guild.custom_colours_enabled {
+            true => Some(String::from("on")),
+            false => None,
+        }),
+        reset: |database, guild_id| update_guild(database, guild_id,
+            |guild| guild.custom_colours_enabled = false),
+        default: |_| String::from("off"),
+    },
+    Setting {
+        name: "colour.contrast.dark",
+        description: "How readable custom colours must be on Discord's dark theme, \
+            as a contrast ratio from 1 to 21.",
+        kind: Kind::Number,
+        get: |_, _, guild| Ok(guild.custom_colour_min_contrast_dark
+            .map(|ratio| ratio.to_string())),
+        reset: |database, guild_id| update_guild(database, guild_id,
+            |guild| guild.custom_colour_min_contrast_dark = None),
+        default: |_| DEFAULT_MIN_CONTRAST_DARK.to_string(),
+    },
+    Setting {
+        name: "colour.contrast.light",
+        description: "How readable custom colours must be on Discord's light theme, \
+            as a contrast ratio from 1 to 21.",
+        kind: Kind::Number,
+        get: |_, _, guild| Ok(guild.custom_colour_min_contrast_light
+            .map(|ratio| ratio.to_string())),
+        reset: |database, guild_id| update_guild(database, guild_id,
+            |guild| guild.custom_colour_min_contrast_light = None),
+        default: |_| DEFAULT_MIN_CONTRAST_LIGHT.to_string(),
+    },
+    Setting {
+        name: "title.approval",
+        description: "Whether titles need a moderator's approval before they're shown in this guild.",
+        kind: Kind::Switch,
+        get: |_, _, guild| Ok(match guild.title_approval_enabled {
+            true => Some(String::from("on")),
+            false => None,
+        }),
+        reset: |database, guild_id| update_guild(database, guild_id,
+            |guild| guild.title_approval_enabled = false),
+        default: |_| String::from("off"),
+    },
+    Setting {
+        name: "title.approval.channel",
+        description: "Channel where moderators are asked to approve titles.",
+        kind: Kind::Channel,
+        get: |_, _, guild| Ok(guild.title_approval_channel_id
+            .map(|channel_id| channel_id.to_string())),
+        reset: |database, guild_id| update_guild(database, guild_id,
+            |guild| guild.title_approval_channel_id = None),
+        default: |_| String::from("the channel the title was set in"),
+    },
+    Setting {
+        name: "title.nicknames",
+        description: "Whether members' titles are put in front of their nicknames.",
+        kind: Kind::Switch,
+        get: |_, _, guild| Ok(match guild.title_nicknames_enabled {
+            true => Some(String::from("on")),
+            false => None,
+        }),
+        reset: |database, guild_id| update_guild(database, guild_id,
+            |guild| guild.title_nicknames_enabled = false),
+        default: |_| String::from("off"),
+    },
+];
+
+/// Find a setting by name, ignoring case.
+pub fn find(name: &str) -> Option<&'static Setting> {
+    SETTINGS.iter().find(|setting| setting.name.eq_ignore_ascii_case(name))
+}
+
+/// Make changes to a guild's settings, recording each setting that changed in the audit log.
+/// If anything fails, none of the changes are kept.
+pub fn change<T, F>(
+    database: &Handle,
+    guild_id: u64,
+    user_id: u64,
+    changes: F,
+) -> Result<T, DatabaseError>
+where F: FnOnce(&Handle) -> Result<T, DatabaseError>
+{
+    database.in_transaction(|database| {
+        let before = values(database, guild_id)?;
+        let result = changes(database)?;
+        let after = values(database, guild_id)?;
+
+        let timestamp = time::get_time().sec;
+        for ((setting, old_value), new_value) in SETTINGS.iter().zip(before).zip(after) {
+            if old_value == new_value {
+                continue
+            }
+            debug!("Guild {} setting {} changed by user {}", guild_id, setting.name, user_id);
+            database.settings_audit_insert(guild_id, &SettingChange {
+                user_id,
+                setting: String::from(setting.name),
+                old_value,
+                new_value,
+                timestamp,
+            })?;
+        }
+        Ok(result)
+    })
+}
+
+/// The value of one setting of a guild.
+pub fn value(
+    database: &Handle,
+    guild_id: u64,
+    setting: &Setting,
+) -> Result<Option<String>, DatabaseError>
+{
+    (setting.get)(database, guild_id, &database.guild(guild_id)?)
+}
+
+/// The value of every setting of a guild, in the same order as `SETTINGS`.
+pub fn values(database: &Handle, guild_id: u64) -> Result<Vec<Option<String>>, DatabaseError> {
+    let guild = database.guild(guild_id)?;
+    SETTINGS.iter().map(|setting| (setting.get)(database, guild_id, &guild)).collect()
+}
+
+fn update_guild<F>(database: &Handle, guild_id: u64, update: F) -> Result<(), DatabaseError>
+where F: FnOnce(&mut Guild)
+{
+    let mut guild = database.guild(guild_id)?;
+    update(&mut guild);
+    database.guild_update(guild_id, &guild)
+}
@@ -1,40 +1,66 @@
 #[macro_use] extern crate log;
 
+mod backups;
+mod cli;
 mod commands;
+mod config;
 mod database;
+mod guild_settings;
+mod migrations;
 mod stream_notify;
+mod stream_provider;
+mod title_nickname;
+mod title_policy;
 
 use commands::{
+    admin::*,
+    backup::*,
     general::*,
+    prefix::*,
     animals::{
         cat::*,
         dog::*
     },
-    roll::*,
+    roll::{
+        *,
+        history::*,
+        initiative::*,
+        macros::*,
+    },
+    stream::{
+        notify::*,
+        settings::*,
+    },
     user::{
         colour::*,
         title::*,
+        title_moderation::*,
     }
 };
+use config::{Config, ConfigKey};
 
-use dotenv;
 use serenity::{
     prelude::*,
     framework::standard::{
-        Args, CommandResult, CommandGroup,
-        HelpOptions, help_commands, StandardFramework,
+        Args, CommandResult, CommandGroup, DispatchError,
+        HelpOptions, help_commands, Reason, StandardFramework,
         macros::{group, help},
     },
     model::{
-        channel::Message,
+        channel::{Message, Reaction},
         event::PresenceUpdateEvent,
         gateway::{Activity, Presence, Ready},
-        id::UserId,
+        guild::Role,
+        id::{GuildId, RoleId, UserId},
     },
 };
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     env,
+    io,
+    path::Path,
+    process,
+    sync::Arc,
 };
 
 struct Handler;
@@ -42,7 +68,7 @@ struct Handler;
 impl EventHandler for Handler {
     fn ready(&self, context: Context, ready: Ready) {
         debug!("Callback ready: {:?}", ready);
-        let activity = Activity::playing("with your RNG tables");
+        let activity = Activity::playing(&config::get(&context).bot.activity);
         context.set_activity(activity);
         info!("{} is connected!", ready.user.name);
     }
@@ -61,6 +87,36 @@ impl EventHandler for Handler {
         debug!("Callback presence_update: {:?}", new.presence);
         stream_notify::handler(context, new);
     }
+
+    fn reaction_add(&self, context: Context, reaction: Reaction) {
+        debug!("Callback reaction_add: {:?}", reaction);
+        title_policy::reaction_handler(context, reaction);
+    }
+
+    fn guild_role_create(&self, context: Context, guild_id: GuildId, new: Role) {
+        debug!("Callback guild_role_create: {:?}", new);
+        forget_colour_roles(&context, guild_id);
+    }
+
+    fn guild_role_delete(&self,
+                         context: Context,
+                         guild_id: GuildId,
+                         role_id: RoleId,
+                         _role: Option<Role>)
+    {
+        debug!("Callback guild_role_delete: {:?}", role_id);
+        forget_colour_roles(&context, guild_id);
+    }
+
+    fn guild_role_update(&self,
+                         context: Context,
+                         guild_id: GuildId,
+                         _old: Option<Role>,
+                         new: Role)
+    {
+        debug!("Callback guild_role_update: {:?}", new);
+        forget_colour_roles(&context, guild_id);
+    }
 }
 
 group!({
@@ -69,6 +125,9 @@ group!({
     commands: [
         roll20,
         roll,
+        roll_macro,
+        rolls,
+        initiative,
     ],
 });
 
@@ -81,15 +140,42 @@ group!({
     ],
 });
 
+group!({
+    name: "stream",
+    options: {},
+    commands: [
+        streamconfig,
+        streamnotify,
+    ],
+});
+
 group!({
     name: "user",
     options: {},
     commands: [
         title,
+        titlemod,
         colour,
     ],
 });
 
+group!({
+    name: "admin",
+    options: {},
+    commands: [
+        admin,
+        prefix,
+    ],
+});
+
+group!({
+    name: "owner",
+    options: {},
+    commands: [
+        backup,
+    ],
+});
+
 #[help]
 #[individual_command_tip =
 "Hello! こんにちは！Hola! Bonjour! 您好!\n\
@@ -112,25 +198,78 @@ fn my_help(
 }
 
 fn main() {
-    dotenv::from_filename("mount/env")
-        .expect("Failed to load mount/env file");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = match cli::parse(&args) {
+        Ok(command) => command,
+        Err(why) => {
+            eprintln!("catnip: {}", why);
+            process::exit(why.exit_code());
+        },
+    };
+
+    // Variables in mount/env override the config file, as if they were set in the environment
+    match dotenv::from_filename("mount/env") {
+        Err(dotenv::Error::Io(ref why)) if why.kind() == io::ErrorKind::NotFound => (),
+        Err(why) => eprintln!("catnip: could not load mount/env: {}", why),
+        Ok(_) => (),
+    }
 
     env_logger::init();
 
-    let token = env::var("DISCORD_TOKEN")
-        .expect("Expected a token in the environment");
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(why) => {
+            eprintln!("catnip: {}", why);
+            process::exit(1);
+        },
+    };
+
+    match command {
+        None => run(Arc::new(config)),
+        Some(command) => if let Err(why) = cli::execute(command, &config) {
+            eprintln!("catnip: {}", why);
+            process::exit(why.exit_code());
+        },
+    }
+}
+
+/// Start the Discord client and run the bot until it stops.
+fn run(config: Arc<Config>) {
+    let token = match config.token() {
+        Ok(token) => token,
+        Err(why) => {
+            eprintln!("catnip: {}", why);
+            process::exit(1);
+        },
+    };
 
     // Do any database schema migration work before starting the Discord client
-    let database = database::Handle::new();
-    database.update_schema()
+    let database_pool = database::Pool::new(&config.database.path)
+        .expect("Couldn't open database, giving up");
+    database_pool.get()
+        .and_then(|mut database| database.update_schema(
+            Path::new(&config.database.migrations_dir)))
         .expect("Couldn't update database schema, giving up");
+    let live_members = database_pool.get()
+        .and_then(|database| stream_notify::live_members(&database))
+        .expect("Couldn't load streaming members, giving up");
+    backups::spawn_scheduler(database_pool.clone(), config.backup.clone());
 
     let mut client = Client::new(
-        &token,
+        token,
         Handler
     )
     .expect("Error creating client");
 
+    {
+        let mut data = client.data.write();
+        data.insert::<database::PoolKey>(database_pool);
+        data.insert::<ConfigKey>(Arc::clone(&config));
+        data.insert::<ColourRoleCacheKey>(HashMap::new());
+        data.insert::<PrefixCacheKey>(HashMap::new());
+        data.insert::<stream_notify::LiveMembersKey>(live_members);
+    }
+
     let (owners, bot_id) = match client.cache_and_http.http.get_current_application_info() {
         Ok(info) => {
             let mut owners = HashSet::new();
@@ -144,10 +283,12 @@ fn main() {
     client.with_framework(
         StandardFramework::new()
         .configure(|c| c
-            .with_whitespace(false)
+            // Allow whitespace between a command and its subcommand
+            .with_whitespace((false, true, true))
+            // Mentioning the bot works as a prefix everywhere, in case the prefix is forgotten
             .on_mention(Some(bot_id))
-            .prefix("!")
-            .delimiters(vec![", ", ","])
+            .dynamic_prefix(commands::prefix::dynamic_prefix)
+            .delimiters(&config.bot.delimiters)
             .owners(owners)
         )
         // Code to execute before a command execution
@@ -170,13 +311,20 @@ fn main() {
             debug!("Could not find command named '{}'", unknown_command_name);
         })
         // Code to execute when commands fail to dispatch
-        .on_dispatch_error(|_context, msg, error| {
+        .on_dispatch_error(|context, msg, error| {
             debug!("Failed to dispatch `{}`: {:?}", msg.content, error);
+            // Tell members why a check turned them away, e.g. that they aren't a guild admin
+            if let DispatchError::CheckFailed(_, Reason::User(reason)) = error {
+                commands::respond(context, msg, &reason);
+            }
         })
         .help(&MY_HELP)
         .group(&GENERAL_GROUP)
         .group(&ANIMALS_GROUP)
+        .group(&STREAM_GROUP)
         .group(&USER_GROUP)
+        .group(&ADMIN_GROUP)
+        .group(&OWNER_GROUP)
     );
 
     if let Err(why) = client.start() {
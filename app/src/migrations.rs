@@ -0,0 +1,565 @@
+//! Schema migrations.
+//!
+//! Migrations are SQL files named `NNN_description.sql` in the migrations directory,
+//! numbered from 001 without gaps. A migration can be undone by an optional
+//! `NNN_description.down.sql` next to it.
+//!
+//! Each migration runs in its own transaction, and the engine sets `user_version`
+//! and records the migration with a checksum of its SQL in the SchemaMigrations table.
+//! The files themselves must not start transactions or set `user_version`.
+
+use crate::database::DatabaseError;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs,
+    path::Path,
+};
+
+/// One version of the schema, as found in the migrations directory.
+pub struct Migration {
+    pub version: u32,
+    pub name: String,
+    pub up: String,
+    pub down: Option<String>,
+}
+
+impl Migration {
+    /// Checksum of the SQL that applies this migration, to notice edits after it's been applied.
+    pub fn checksum(&self) -> String {
+        checksum(&self.up)
+    }
+}
+
+/// A migration as recorded in the database when it was applied.
+pub struct AppliedMigration {
+    pub version: u32,
+    pub name: String,
+    pub checksum: String,
+    /// Unix timestamp of when the migration was applied.
+    pub applied_at: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+/// A migration that was (or in a dry run, would be) applied or reverted.
+#[derive(Clone, Debug)]
+pub struct Step {
+    pub version: u32,
+    pub name: String,
+    pub direction: Direction,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let verb = match self.direction {
+            Direction::Up => "apply",
+            Direction::Down => "revert",
+        };
+        write!(f, "{} {:03}_{}", verb, self.version, self.name)
+    }
+}
+
+/// Load and validate the migrations in a directory, sorted by version.
+pub fn load(dir: &Path) -> Result<Vec<Migration>, DatabaseError> {
+    let mut ups: BTreeMap<u32, (String, String)> = BTreeMap::new();
+    let mut downs: BTreeMap<u32, (String, String)> = BTreeMap::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue
+        }
+        let file_name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(file_name) => file_name.to_owned(),
+            None => return Err(DatabaseError::Migration(format!(
+                "file name {:?} is not valid UTF-8", path))),
+        };
+        if !file_name.ends_with(".sql") {
+            debug!("Ignoring non-SQL file {:?} in migrations directory", path);
+            continue
+        }
+
+        let (version, name, is_down) = parse_file_name(&file_name)?;
+        let sql = fs::read_to_string(&path)?;
+        check_sql(&file_name, &sql)?;
+
+        let files = if is_down { &mut downs } else { &mut ups };
+        if let Some((other_name, _)) = files.insert(version, (name.clone(), sql)) {
+            return Err(DatabaseError::Migration(format!(
+                "duplicate migration number {:03}: {} and {}", version, other_name, name)))
+        }
+    }
+
+    let mut migrations = Vec::with_capacity(ups.len());
+    for (expected, (version, (name, up))) in (1..).zip(ups) {
+        if version != expected {
+            return Err(DatabaseError::Migration(format!(
+                "migration {:03} is missing, found {:03}_{} instead", expected, version, name)))
+        }
+        let down = match downs.remove(&version) {
+            None => None,
+            Some((down_name, down)) => {
+                if down_name != name {
+                    return Err(DatabaseError::Migration(format!(
+                        "down migration {:03}_{} doesn't match {:03}_{}",
+                        version, down_name, version, name)))
+                }
+                Some(down)
+            },
+        };
+        migrations.push(Migration { version, name, up, down });
+    }
+    if let Some((version, (name, _))) = downs.into_iter().next() {
+        return Err(DatabaseError::Migration(format!(
+            "down migration {:03}_{} has no migration to undo", version, name)))
+    }
+
+    Ok(migrations)
+}
+
+/// Split `NNN_name.sql` or `NNN_name.down.sql` into its number, name and direction.
+fn parse_file_name(file_name: &str) -> Result<(u32, String, bool), DatabaseError> {
+    let invalid = || DatabaseError::Migration(format!(
+        "{} is not named like NNN_description.sql", file_name));
+
+    let stem = &file_name[..file_name.len() - ".sql".len()];
+    let is_down = stem.ends_with(".down");
+    let stem = stem.trim_end_matches(".down");
+
+    let mut parts = stem.splitn(2, '_');
+    let number = parts.next().unwrap_or("");
+    let name = parts.next().unwrap_or("");
+    if number.len() != 3 || !number.chars().all(|c| c.is_ascii_digit()) || name.is_empty() {
+        return Err(invalid())
+    }
+    match number.parse::<u32>() {
+        Ok(0) | Err(_) => Err(invalid()),
+        Ok(version) => Ok((version, String::from(name), is_down)),
+    }
+}
+
+/// Refuse SQL that would fight the engine over transactions or the schema version.
+fn check_sql(file_name: &str, sql: &str) -> Result<(), DatabaseError> {
+    for line in sql.lines() {
+        let statement: String = line.split("--").next().unwrap_or("")
+            .to_lowercase()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        let manages_transaction = ["begin", "commit", "rollback"].iter()
+            .any(|keyword| statement == format!("{};", keyword)
+                 || statement.starts_with(&format!("{} transaction", keyword)));
+        if manages_transaction {
+            return Err(DatabaseError::Migration(format!(
+                "{} must not begin or end transactions itself", file_name)))
+        }
+        if statement.starts_with("pragma") && statement.contains("user_version") {
+            return Err(DatabaseError::Migration(format!(
+                "{} must not set user_version itself", file_name)))
+        }
+    }
+    Ok(())
+}
+
+/// 64-bit FNV-1a hash of some SQL, as hex.
+fn checksum(sql: &str) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in sql.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+fn create_table(connection: &Connection) -> Result<(), DatabaseError> {
+    connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS SchemaMigrations (
+             Version INTEGER PRIMARY KEY,
+             Name TEXT NOT NULL,
+             Checksum TEXT NOT NULL,
+             AppliedAt INTEGER NOT NULL
+         );")?;
+    Ok(())
+}
+
+/// The schema version of the database.
+pub fn user_version(connection: &Connection) -> Result<u32, DatabaseError> {
+    let version: i64 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    Ok(version as u32)
+}
+
+/// The migrations recorded as applied, oldest first.
+pub fn applied(connection: &Connection) -> Result<Vec<AppliedMigration>, DatabaseError> {
+    let exists: Option<String> = connection.query_row(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'SchemaMigrations'",
+        params![],
+        |row| row.get(0))
+        .optional()?;
+    if exists.is_none() {
+        return Ok(Vec::new())
+    }
+
+    let mut statement = connection.prepare(
+        "SELECT Version, Name, Checksum, AppliedAt FROM SchemaMigrations ORDER BY Version")?;
+    let rows = statement.query_map(params![], |row| {
+        let version: i64 = row.get(0)?;
+        Ok(AppliedMigration {
+            version: version as u32,
+            name: row.get(1)?,
+            checksum: row.get(2)?,
+            applied_at: row.get(3)?,
+        })
+    })?;
+    Ok(rows.collect::<Result<Vec<_>, _>>()?)
+}
+
+/// Record migrations that were applied before the engine kept track of them.
+///
+/// Older databases only have a `user_version`, set by the migration files themselves,
+/// so the migrations up to that version are trusted as they are now.
+fn adopt(connection: &Connection, migrations: &[Migration]) -> Result<(), DatabaseError> {
+    let version = user_version(connection)?;
+    if version == 0 || !applied(connection)?.is_empty() {
+        return Ok(())
+    }
+    if version as usize > migrations.len() {
+        return Err(DatabaseError::Migration(format!(
+            "database is at version {} but only {} migrations were found",
+            version, migrations.len())))
+    }
+
+    info!("Recording migrations up to version {} applied before checksums were kept", version);
+    for migration in &migrations[..version as usize] {
+        connection.execute(
+            "INSERT INTO SchemaMigrations (Version, Name, Checksum, AppliedAt)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![migration.version as i64, migration.name,
+                    migration.checksum(), time::get_time().sec])?;
+    }
+    Ok(())
+}
+
+/// Check that the applied migrations are the ones found, unchanged, and match `user_version`.
+fn verify(connection: &Connection, migrations: &[Migration]) -> Result<u32, DatabaseError> {
+    let applied = applied(connection)?;
+    for (expected, record) in (1..).zip(&applied) {
+        if record.version != expected {
+            return Err(DatabaseError::Migration(format!(
+                "migration {:03} is not recorded as applied, but {:03}_{} is",
+                expected, record.version, record.name)))
+        }
+        let migration = match migrations.get(record.version as usize - 1) {
+            Some(migration) => migration,
+            None => return Err(DatabaseError::Migration(format!(
+                "applied migration {:03}_{} was not found", record.version, record.name))),
+        };
+        if migration.checksum() != record.checksum {
+            return Err(DatabaseError::Migration(format!(
+                "migration {:03}_{} has changed since it was applied",
+                migration.version, migration.name)))
+        }
+    }
+
+    let version = user_version(connection)?;
+    if version as usize != applied.len() {
+        return Err(DatabaseError::Migration(format!(
+            "database is at version {} but {} migrations are recorded as applied",
+            version, applied.len())))
+    }
+    Ok(version)
+}
+
+/// Work out the steps from the current version to the target version.
+fn plan(
+    migrations: &[Migration],
+    current: u32,
+    target: u32,
+) -> Result<Vec<Step>, DatabaseError> {
+    let step = |migration: &Migration, direction| Step {
+        version: migration.version,
+        name: migration.name.clone(),
+        direction,
+    };
+
+    if target >= current {
+        return Ok(migrations[current as usize..target as usize].iter()
+            .map(|migration| step(migration, Direction::Up))
+            .collect())
+    }
+
+    let to_revert = &migrations[target as usize..current as usize];
+    if let Some(migration) = to_revert.iter().find(|migration| migration.down.is_none()) {
+        return Err(DatabaseError::Migration(format!(
+            "migration {:03}_{} has no down migration", migration.version, migration.name)))
+    }
+    Ok(to_revert.iter().rev()
+        .map(|migration| step(migration, Direction::Down))
+        .collect())
+}
+
+/// Run one step of a plan. The caller owns the transaction.
+fn run_step(
+    connection: &Connection,
+    migration: &Migration,
+    step: &Step,
+) -> Result<(), DatabaseError> {
+    let failed = |why: rusqlite::Error| DatabaseError::Migration(format!(
+        "failed to {}: {}", step, why));
+
+    match step.direction {
+        Direction::Up => {
+            connection.execute_batch(&migration.up).map_err(failed)?;
+            connection.execute(
+                "INSERT INTO SchemaMigrations (Version, Name, Checksum, AppliedAt)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![migration.version as i64, migration.name,
+                        migration.checksum(), time::get_time().sec])?;
+            connection.pragma_update(None, "user_version", &(migration.version as i64))?;
+        },
+        Direction::Down => {
+            let down = match &migration.down {
+                Some(down) => down,
+                None => return Err(DatabaseError::Migration(format!(
+                    "migration {:03}_{} has no down migration", migration.version, migration.name))),
+            };
+            connection.execute_batch(down).map_err(failed)?;
+            connection.execute(
+                "DELETE FROM SchemaMigrations WHERE Version = ?1",
+                params![migration.version as i64])?;
+            connection.pragma_update(None, "user_version", &(migration.version as i64 - 1))?;
+        },
+    }
+    Ok(())
+}
+
+/// Bring the database to the target version, or the latest one if there's no target.
+///
+/// Each migration is applied or reverted in its own transaction, so a failure
+/// leaves the database at the last version that worked. In a dry run, every step is
+/// run in one transaction that is then rolled back.
+/// Returns the steps that were taken.
+pub fn migrate(
+    connection: &mut Connection,
+    migrations: &[Migration],
+    target: Option<u32>,
+    dry_run: bool,
+) -> Result<Vec<Step>, DatabaseError> {
+    let target = target.unwrap_or(migrations.len() as u32);
+    if target as usize > migrations.len() {
+        return Err(DatabaseError::Migration(format!(
+            "can't migrate to version {}, the latest is {}", target, migrations.len())))
+    }
+
+    // Bookkeeping is part of the dry run too, so that nothing is left behind
+    let transaction = connection.transaction()?;
+    create_table(&transaction)?;
+    adopt(&transaction, migrations)?;
+    let current = verify(&transaction, migrations)?;
+    let steps = plan(migrations, current, target)?;
+
+    if dry_run {
+        for step in &steps {
+            info!("Dry run: {}", step);
+            run_step(&transaction, &migrations[step.version as usize - 1], step)?;
+        }
+        // Dropping the transaction rolls it back
+        return Ok(steps)
+    }
+    transaction.commit()?;
+
+    for step in &steps {
+        info!("Migrating: {}", step);
+        let transaction = connection.transaction()?;
+        run_step(&transaction, &migrations[step.version as usize - 1], step)?;
+        transaction.commit()?;
+    }
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, path::PathBuf, process};
+
+    /// A directory of migration files that is removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let path = env::temp_dir()
+                .join(format!("catnip-migrations-{}-{}", process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            for (file_name, sql) in files {
+                fs::write(path.join(file_name), sql).unwrap();
+            }
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn migration(version: u32, name: &str, up: &str, down: Option<&str>) -> Migration {
+        Migration {
+            version,
+            name: String::from(name),
+            up: String::from(up),
+            down: down.map(String::from),
+        }
+    }
+
+    fn migrations() -> Vec<Migration> {
+        vec![
+            migration(1, "cats", "CREATE TABLE Cats (Name TEXT);", Some("DROP TABLE Cats;")),
+            migration(2, "dogs", "CREATE TABLE Dogs (Name TEXT);", Some("DROP TABLE Dogs;")),
+        ]
+    }
+
+    fn migration_error<T>(result: Result<T, DatabaseError>) -> String {
+        match result {
+            Err(DatabaseError::Migration(reason)) => reason,
+            Err(other) => panic!("expected a migration error, got {:?}", other),
+            Ok(_) => panic!("expected a migration error"),
+        }
+    }
+
+    #[test]
+    fn file_names_are_parsed() {
+        assert_eq!(parse_file_name("001_init.sql").unwrap(), (1, String::from("init"), false));
+        assert_eq!(parse_file_name("012_add_titles.down.sql").unwrap(),
+                   (12, String::from("add_titles"), true));
+
+        for file_name in &["000_init.sql", "1_init.sql", "0001_init.sql", "001.sql",
+                           "001_.sql", "abc_init.sql", "init.sql"] {
+            assert!(parse_file_name(file_name).is_err(), "{} was accepted", file_name);
+        }
+    }
+
+    #[test]
+    fn sql_must_not_manage_transactions_or_the_version() {
+        for sql in &["BEGIN;", "BEGIN TRANSACTION;", "  Commit; -- done", "ROLLBACK;",
+                     "PRAGMA user_version = 3;"] {
+            assert!(check_sql("001_init.sql", sql).is_err(), "{:?} was accepted", sql);
+        }
+
+        let trigger = "CREATE TRIGGER Touch AFTER UPDATE ON Cats\n\
+                       BEGIN\n    UPDATE Cats SET Name = Name;\nEND;";
+        assert!(check_sql("001_init.sql", trigger).is_ok());
+        assert!(check_sql("001_init.sql", "PRAGMA foreign_keys = ON;").is_ok());
+    }
+
+    #[test]
+    fn load_sorts_and_pairs_migrations() {
+        let dir = TempDir::new("load", &[
+            ("002_dogs.sql", "CREATE TABLE Dogs (Name TEXT);"),
+            ("001_cats.sql", "CREATE TABLE Cats (Name TEXT);"),
+            ("001_cats.down.sql", "DROP TABLE Cats;"),
+            ("README.md", "Not a migration"),
+        ]);
+        let migrations = load(&dir.0).unwrap();
+        assert_eq!(migrations.len(), 2);
+        assert_eq!((migrations[0].version, migrations[0].name.as_str()), (1, "cats"));
+        assert_eq!(migrations[0].down.as_deref(), Some("DROP TABLE Cats;"));
+        assert_eq!((migrations[1].version, migrations[1].name.as_str()), (2, "dogs"));
+        assert!(migrations[1].down.is_none());
+    }
+
+    #[test]
+    fn load_rejects_gaps() {
+        let dir = TempDir::new("gap", &[
+            ("001_cats.sql", "CREATE TABLE Cats (Name TEXT);"),
+            ("003_dogs.sql", "CREATE TABLE Dogs (Name TEXT);"),
+        ]);
+        assert!(migration_error(load(&dir.0)).contains("migration 002 is missing"));
+    }
+
+    #[test]
+    fn load_rejects_duplicates() {
+        let dir = TempDir::new("duplicate", &[
+            ("001_cats.sql", "CREATE TABLE Cats (Name TEXT);"),
+            ("001_dogs.sql", "CREATE TABLE Dogs (Name TEXT);"),
+        ]);
+        assert!(migration_error(load(&dir.0)).contains("duplicate migration number 001"));
+    }
+
+    #[test]
+    fn load_rejects_orphan_down_migrations() {
+        let dir = TempDir::new("orphan", &[
+            ("001_cats.sql", "CREATE TABLE Cats (Name TEXT);"),
+            ("002_dogs.down.sql", "DROP TABLE Dogs;"),
+        ]);
+        assert!(migration_error(load(&dir.0)).contains("has no migration to undo"));
+    }
+
+    #[test]
+    fn plan_needs_down_migrations_to_revert() {
+        let mut migrations = migrations();
+        let steps = plan(&migrations, 2, 0).unwrap();
+        assert_eq!(steps.iter().map(|step| step.version).collect::<Vec<_>>(), vec![2, 1]);
+        assert!(steps.iter().all(|step| step.direction == Direction::Down));
+
+        migrations[0].down = None;
+        assert!(plan(&migrations, 2, 1).is_ok());
+        assert!(migration_error(plan(&migrations, 2, 0)).contains("001_cats has no down"));
+    }
+
+    #[test]
+    fn migrate_applies_and_reverts() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        let steps = migrate(&mut connection, &migrations(), None, false).unwrap();
+        assert_eq!(steps.len(), 2);
+        assert_eq!(user_version(&connection).unwrap(), 2);
+        assert_eq!(applied(&connection).unwrap().len(), 2);
+
+        let steps = migrate(&mut connection, &migrations(), Some(1), false).unwrap();
+        assert_eq!(steps[0].to_string(), "revert 002_dogs");
+        assert_eq!(user_version(&connection).unwrap(), 1);
+        assert_eq!(applied(&connection).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn dry_runs_change_nothing() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        let steps = migrate(&mut connection, &migrations(), None, true).unwrap();
+        assert_eq!(steps.len(), 2);
+        assert_eq!(user_version(&connection).unwrap(), 0);
+        assert!(applied(&connection).unwrap().is_empty());
+    }
+
+    #[test]
+    fn edited_migrations_fail_verification() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection, &migrations(), None, false).unwrap();
+
+        let mut edited = migrations();
+        edited[0].up = String::from("CREATE TABLE Cats (Name TEXT, Lives INTEGER);");
+        let reason = migration_error(migrate(&mut connection, &edited, None, false));
+        assert!(reason.contains("001_cats has changed since it was applied"));
+    }
+
+    #[test]
+    fn adopt_trusts_the_user_version() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(
+            "CREATE TABLE Cats (Name TEXT);
+             PRAGMA user_version = 1;").unwrap();
+
+        let steps = migrate(&mut connection, &migrations(), None, false).unwrap();
+        assert_eq!(steps.iter().map(|step| step.to_string()).collect::<Vec<_>>(),
+                   vec!["apply 002_dogs"]);
+        let applied = applied(&connection).unwrap();
+        assert_eq!(applied.len(), 2);
+        assert_eq!(applied[0].checksum, migrations()[0].checksum());
+        assert_eq!(user_version(&connection).unwrap(), 2);
+    }
+}
@@ -1,47 +1,113 @@
 
-use crate::database;
+use crate::{
+    config,
+    database::{self, DatabaseError},
+    stream_provider::{self, StreamInfo},
+    title_policy,
+};
 use serenity::{
-    model::{id::{ChannelId, GuildId, UserId},
+    builder::CreateEmbed,
+    model::{channel::Message,
+            id::{ChannelId, GuildId, MessageId, RoleId, UserId},
             event::PresenceUpdateEvent,
             gateway::{ActivityType, Activity},
     },
     prelude::*,
-    utils::{MessageBuilder
-             ,Colour},
+    utils::{Colour, MessageBuilder},
 };
-use std::env;
-use time;
 use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::fmt;
+
+/// The wording of stream shout-outs in guilds that haven't set their own.
+pub const DEFAULT_STREAM_NOTIFY_TEMPLATE: &str =
+    "{title} **{name}** is streaming **{stream_title}**: {url}";
+/// Longest template a guild may save. Discord rejects messages over 2000 characters,
+/// so leave room for the values filled in.
+pub const MAX_TEMPLATE_LENGTH: usize = 1000;
+/// Placeholders that can be used in shout-out templates.
+pub const TEMPLATE_PLACEHOLDERS: &[&str] =
+    &["name", "title", "stream_title", "game", "url", "platform", "role"];
+
+/// Members who are streaming, as guild and user IDs, so that the presence updates
+/// of everyone else can be ignored without going to the database.
+pub struct LiveMembersKey;
+
+impl TypeMapKey for LiveMembersKey {
+    type Value = HashSet<(GuildId, UserId)>;
+}
+
+/// Load the members who were streaming when the bot last stopped.
+pub fn live_members(
+    database: &database::Handle,
+) -> Result<HashSet<(GuildId, UserId)>, DatabaseError> {
+    Ok(database.live_members()?.into_iter()
+        .map(|(guild_id, user_id)| (GuildId(guild_id), UserId(user_id)))
+        .collect())
+}
+
+fn is_live(context: &Context, guild_id: GuildId, user_id: UserId) -> bool {
+    context.data.read().get::<LiveMembersKey>()
+        .is_some_and(|live_members| live_members.contains(&(guild_id, user_id)))
+}
 
-const DEFAULT_STREAM_NOTIFY_COOLDOWN: i64 = 21600; // 6 hours
+fn set_live(context: &Context, guild_id: GuildId, user_id: UserId, live: bool) {
+    if let Some(live_members) = context.data.write().get_mut::<LiveMembersKey>() {
+        if live {
+            live_members.insert((guild_id, user_id));
+        } else {
+            live_members.remove(&(guild_id, user_id));
+        }
+    }
+}
 
 /// Handler that decides whether the updating of the presence of a guild member
 /// should result in the sending of a "shout-out" message in that guild,
 /// and sends that message if required.
+///
+/// Members are tracked as live while they stream, so that the shout-out can be
+/// updated once their stream ends.
 pub fn handler(
     context: Context,
     presence_update_event: PresenceUpdateEvent
 ) {
-    // Stream start detection
     debug!("In stream_notify::handler: presence_update_event.presence.activity = {:?}",
            presence_update_event.presence.activity);
-    let streaming_activity = match presence_update_event.presence.activity {
+    let user_id = presence_update_event.presence.user_id;
+
+    debug!("Guild ID retrieval...");
+    let guild_id = match presence_update_event.guild_id {
         None => {
-            debug!("No activity in presence update, ignoring");
+            debug!("Got presence update with no discord guild ID");
             return
-        }
-        Some(activity) => match activity.kind {
-            ActivityType::Streaming => activity,
-            _ => {
-                debug!("Activity in presence update is not a stream, ignoring");
-                return
-            }
         },
+        Some(guild_id) => guild_id,
     };
 
-    debug!("User Discord data retrieval...");
-    let user_id = presence_update_event.presence.user_id;
+    match presence_update_event.presence.activity {
+        Some(activity) => match activity.kind {
+            ActivityType::Streaming => stream_started(context, guild_id, user_id, activity),
+            _ => stream_ended(context, guild_id, user_id),
+        },
+        None => stream_ended(context, guild_id, user_id),
+    }
+}
+
+/// Stream start detection: mark the member as live and shout out their stream
+/// if the guild wants that and the last shout-out wasn't too recent.
+fn stream_started(
+    context: Context,
+    guild_id: GuildId,
+    user_id: UserId,
+    streaming_activity: Activity,
+) {
+    // Presence updates keep coming in while a member streams
+    if is_live(&context, guild_id, user_id) {
+        debug!("Member {} is already streaming, ignoring", user_id);
+        return
+    }
 
+    debug!("User Discord data retrieval...");
     match context.cache.read().user(user_id) {
         None => {
             error!("Failed to get Discord user object from Serenity cache");
@@ -57,106 +123,274 @@ pub fn handler(
             );
         },
     };
-    let database = database::Handle::new();
+    let database = match database::handle(&context) {
+        Ok(database) => database,
+        Err(why) => {
+            error!("Could not get a database handle: {}", why);
+            return
+        }
+    };
 
-    debug!("Guild ID retrieval...");
-    let guild_id = match presence_update_event.guild_id {
-        None => {
-            debug!("Got presence update with no discord guild ID");
+    match database.member_stream_start(guild_id.0, user_id.0, time::get_time().sec) {
+        Ok(true) => (),
+        Ok(false) => {
+            debug!("Member {} is already streaming, ignoring", user_id);
+            set_live(&context, guild_id, user_id, true);
             return
         },
-        Some(guild_id) => guild_id,
-    };
+        Err(why) => {
+            error!("Could not mark member as streaming in database: {}", why);
+            return
+        },
+    }
+    set_live(&context, guild_id, user_id, true);
 
     debug!("Member DB data retrieval...");
     let member: database::Member = match database.member(
         guild_id.0, user_id.0)
     {
-        Err(_) => {
-            error!("Could not retrieve member data from database");
+        Err(why) => {
+            error!("Could not retrieve member data from database: {}", why);
             return
         },
         Ok(data) => data,
     };
 
-    // Set STREAM_NOTIFY_COOLDOWN in the mount/env file to override the default duration.
-    let stream_notify_cooldown: i64 = match env::var("STREAM_NOTIFY_COOLDOWN") {
-        Ok(val_s) => match val_s.parse() {
-            Ok(val) => val,
-            Err(_) => DEFAULT_STREAM_NOTIFY_COOLDOWN,
+    debug!("Guild DB data retrieval...");
+    let guild: database::Guild = match database.guild(guild_id.0)
+    {
+        Err(why) => {
+            error!("Could not retrieve guild data from database: {}", why);
+            return
         },
-        Err(_) => DEFAULT_STREAM_NOTIFY_COOLDOWN
+        Ok(data) => data,
+    };
+
+    if let Some(discord_channel_id) = shout_out_channel(
+        &context, &database, &guild, guild_id, user_id, &member)
+    {
+        // We will shout out the stream
+        stream_notify(context, &guild, guild_id, user_id, discord_channel_id, streaming_activity);
+    }
+}
+
+/// The channel from the config that shout-outs go to in guilds that haven't chosen one,
+/// if it's in this guild.
+pub fn default_channel(context: &Context, guild_id: GuildId) -> Option<ChannelId> {
+    let channel_id = ChannelId(config::get(context).stream_notify.channel_id?);
+    let in_guild = match guild_id.to_guild_cached(&context.cache) {
+        Some(discord_guild) => discord_guild.read().channels.contains_key(&channel_id),
+        None => false,
+    };
+    if in_guild {
+        Some(channel_id)
+    } else {
+        None
+    }
+}
+
+/// Decide whether a member's stream should be shouted out.
+/// Return the channel to send the shout-out to if so.
+fn shout_out_channel(
+    context: &Context,
+    database: &database::Handle,
+    guild: &database::Guild,
+    guild_id: GuildId,
+    user_id: UserId,
+    member: &database::Member,
+) -> Option<ChannelId> {
+    // By using the ID, the channel can be renamed without breaking the integration.
+    let channel_id = guild.stream_notify_channel_id.map(ChannelId)
+        .or_else(|| default_channel(context, guild_id));
+    let discord_channel_id = match channel_id {
+        Some(channel_id) if guild.stream_notify_enabled => channel_id,
+        _ => {
+            debug!("Stream shout-outs are disabled in guild {}, not shouting out", guild_id);
+            return None
+        }
+    };
+
+    if member.stream_notify_opt_out {
+        debug!("Member {} opted out of stream shout-outs, not shouting out", user_id);
+        return None
+    }
+
+    debug!("Stream shout-out roles DB data retrieval...");
+    let role_ids = match database.stream_notify_roles(guild_id.0) {
+        Ok(role_ids) => role_ids,
+        Err(why) => {
+            error!("Could not retrieve stream shout-out roles from database: {}", why);
+            return None
+        }
     };
+    if !role_ids.is_empty() {
+        let has_role = match context.cache.read().member(guild_id, user_id) {
+            Some(discord_member) => discord_member.roles.iter()
+                .any(|role_id| role_ids.contains(&role_id.0)),
+            None => {
+                error!("Could not retrieve guild member from Serenity cache");
+                false
+            }
+        };
+        if !has_role {
+            debug!("Member {} has none of the stream shout-out roles, not shouting out", user_id);
+            return None
+        }
+    }
+
+    let stream_notify_cooldown: i64 = guild.stream_notify_cooldown
+        .unwrap_or(config::get(context).stream_notify.cooldown);
     debug!("Using stream advertise cooldown = {} seconds",
            stream_notify_cooldown);
 
     debug!("Member data: {:?}", member);
-    if time::get_time().sec - member.last_stream_notify_timestamp > stream_notify_cooldown {
-        // We will shout out the stream
-        stream_notify(context, member, guild_id, user_id, streaming_activity);
-    } else {
+    if time::get_time().sec - member.last_stream_notify_timestamp <= stream_notify_cooldown {
         debug!("Last stream too recent; would not shout out stream");
+        return None
     }
+
+    Some(discord_channel_id)
 }
 
-fn stream_notify(
+/// Stream end detection: if the member was live, mark them as no longer streaming
+/// and edit the shout-out for their stream, if there was one, to say how long it lasted.
+fn stream_ended(
     context: Context,
-    member: database::Member,
     guild_id: GuildId,
     user_id: UserId,
-    streaming_activity: Activity,
 ) {
-    debug!("User DB data retrieval...");
-    let database = database::Handle::new();
-    let user: database::User = match database.user(user_id.0)
-    {
-        Err(_) => {
-            error!("Could not retrieve user data from database");
+    if !is_live(&context, guild_id, user_id) {
+        debug!("Activity in presence update is not a stream, ignoring");
+        return
+    }
+
+    let database = match database::handle(&context) {
+        Ok(database) => database,
+        Err(why) => {
+            error!("Could not get a database handle: {}", why);
+            return
+        }
+    };
+
+    debug!("Member DB data retrieval...");
+    let ended = database.in_transaction(|database| {
+        let member = database.member(guild_id.0, user_id.0)?;
+        let ended = database.member_stream_end(guild_id.0, user_id.0)?;
+        Ok((member, ended))
+    });
+    let member: database::Member = match ended {
+        Ok((member, true)) => member,
+        Ok((_, false)) => {
+            debug!("Member {} already stopped streaming, ignoring", user_id);
+            set_live(&context, guild_id, user_id, false);
             return
         },
-        Ok(data) => data,
+        Err(why) => {
+            error!("Could not mark member as no longer streaming in database: {}", why);
+            return
+        },
+    };
+    set_live(&context, guild_id, user_id, false);
+
+    let stream_start_timestamp = member.stream_start_timestamp.unwrap_or_default();
+    let duration = time::get_time().sec - stream_start_timestamp;
+    debug!("Member {} stopped streaming after {} seconds", user_id, duration);
+
+    let shout_out = match (member.stream_notify_channel_id, member.stream_notify_message_id) {
+        (Some(channel_id), Some(message_id)) => Some((ChannelId(channel_id), MessageId(message_id))),
+        _ => None,
+    };
+
+    let (discord_channel_id, message_id) = match shout_out {
+        Some(shout_out) => shout_out,
+        None => {
+            debug!("No shout-out was sent for this stream, nothing to edit");
+            return
+        }
+    };
+
+    let message = match discord_channel_id.message(&context.http, message_id) {
+        Ok(message) => message,
+        Err(why) => {
+            // The message may well have been deleted by a moderator
+            debug!("Could not retrieve shout-out message: {:?}", why);
+            return
+        }
+    };
+    let embed = match message.embeds.into_iter().next() {
+        Some(embed) => embed,
+        None => {
+            error!("Shout-out message has no embed to edit");
+            return
+        }
     };
 
-    let user_title = match user.title {
+    let member_name = match &embed.author {
+        Some(author) => author.name.trim().to_string(),
         None => String::new(),
-        Some(prefix) => {
-            let mut prefix = prefix.clone();
-            prefix.push(' ');
-            prefix
-        },
     };
+    let stream_title = embed.title.clone().unwrap_or_default();
+    let stream_duration = format_duration(duration);
 
-    // Update the timestamp of the last shout-out in the database
-    let mut member = member.clone();
-    member.last_stream_notify_timestamp = time::get_time().sec;
-    if let Err(_) = database.member_update(
-        guild_id.0,
-        user_id.0,
-        &member
-    ) {
-        error!("Couldn't update member data in database");
-        return
+    let channel_text = MessageBuilder::new()
+        .push_bold_safe(&member_name)
+        .push(" was streaming ")
+        .push_bold_safe(&stream_title)
+        .push(" for ")
+        .push(&stream_duration)
+        .build();
+
+    if let Err(why) = discord_channel_id.edit_message(&context.http, message_id, |m| {
+        m.content(channel_text);
+        m.embed(|e| {
+            *e = CreateEmbed::from(embed);
+            e.field("Streamed for", &stream_duration, true)
+        })
+    }) {
+        error!("Error editing message: {:?}", why);
     }
-    debug!("Updated member timestamp to {}", member.last_stream_notify_timestamp);
+}
 
-    // Set STREAM_NOTIFY_CHANNEL_ID in the mount/env file to the name of a channel in the guild.
-    // By using the ID, the channel can be renamed without breaking the integration.
-    // TODO: Make this a DB setting in the Guilds table instead, since different guilds will
-    // want to use different channel names for this.
-    let discord_channel_id = match env::var("STREAM_NOTIFY_CHANNEL_ID")
-    {
-        Ok(channel_id) => match channel_id.parse::<u64>() {
-            Ok(val) => ChannelId(val),
-            Err(_) => {
-                error!("STREAM_NOTIFY_CHANNEL_ID is invalid, can't send stream notification");
-                return
-            }
-        },
-        Err(_) => {
-            error!("STREAM_NOTIFY_CHANNEL_ID is not set, can't send stream notification");
+/// Describe a stream duration in hours and minutes, e.g. "2h 5m".
+fn format_duration(seconds: i64) -> String {
+    let minutes = seconds.max(0) / 60;
+    match (minutes / 60, minutes % 60) {
+        (0, 0) => String::from("less than a minute"),
+        (0, minutes) => format!("{}m", minutes),
+        (hours, minutes) => format!("{}h {}m", hours, minutes),
+    }
+}
+
+fn stream_notify(
+    context: Context,
+    guild: &database::Guild,
+    guild_id: GuildId,
+    user_id: UserId,
+    discord_channel_id: ChannelId,
+    streaming_activity: Activity,
+) {
+    debug!("User DB data retrieval...");
+    let database = match database::handle(&context) {
+        Ok(database) => database,
+        Err(why) => {
+            error!("Could not get a database handle: {}", why);
             return
         }
     };
+    // The member's title in this guild, or else their global title
+    let title = match title_policy::shown_title(&database, user_id.0, Some(guild_id.0))
+    {
+        Err(why) => {
+            error!("Could not retrieve user data from database: {}", why);
+            return
+        },
+        Ok(title) => title,
+    };
+
+    // Look the stream up before locking the cache, as this can take a while
+    let stream_info = streaming_activity.url.as_ref()
+        .and_then(|url| stream_provider::stream_info(&config::get(&context), url))
+        .unwrap_or_default();
 
     let discord_guild = match context.cache.read().guild(guild_id) {
         Some(guild) => guild,
@@ -179,26 +413,14 @@ fn stream_notify(
         }
     };
 
-    let discord_channel = match discord_guild
-        .channels
-        .get(&discord_channel_id)
-    {
-        Some(channel) => channel.read(),
-        None => {
-            error!("Could not retrieve guild channel from Serenity cache");
-            return
-        }
-    };
-
-    // Get the member display name (there could be a nickname)
-    let member_name = member.display_name();
+    if !discord_guild.channels.contains_key(&discord_channel_id) {
+        error!("Could not retrieve guild channel from Serenity cache");
+        return
+    }
 
-    let member_colour = match member.colour(context.cache.borrow())
-    {
-        Some(member_colour) => member_colour,
-        // If no colour use the default colour (no clue when this would be the case)
-        None => {Colour::default()},
-    };
+    // If no colour use the default colour (no clue when this would be the case)
+    let member_colour = member.colour(context.cache.borrow())
+        .unwrap_or_default();
 
     let stream_url = match streaming_activity.url {
         Some(url) => url,
@@ -222,33 +444,187 @@ fn stream_notify(
         None => {
             /* Can happen it's fine */
             debug!("No state within the activity.");
-            String::new()
+            stream_info.category.clone().unwrap_or_default()
         },
     };
 
-    let channel_text = MessageBuilder::new()
-        .push(&user_title)
-        .push_bold_safe(&member_name)
-        .push(" is streaming ")
-        .push_bold_safe(&stream_title)
-        .push(": ")
-        .push(&stream_url)
-        .build();
+    let shout_out = ShoutOut {
+        // Get the member display name (there could be a nickname)
+        name: member.display_name().into_owned(),
+        title: title.unwrap_or_default(),
+        platform: stream_provider::platform(&config::get(&context), &stream_url),
+        stream_title,
+        game: stream_game,
+        url: stream_url,
+        role: guild.stream_notify_role_id
+            .map(|role_id| RoleId(role_id).mention())
+            .unwrap_or_default(),
+        info: stream_info,
+    };
+
+    let template = guild.stream_notify_template.as_ref()
+        .map_or(DEFAULT_STREAM_NOTIFY_TEMPLATE, String::as_str);
+    let channel_text = match render_template(template, &shout_out) {
+        Ok(text) => text,
+        Err(why) => {
+            // Templates are checked when they're saved, so this shouldn't happen
+            error!("Could not render stream shout-out template {:?}: {}", template, why);
+            return
+        }
+    };
+
+    let message = match send_shout_out(
+        &context, discord_channel_id, &channel_text, &shout_out,
+        member_colour, &member.user.read().face())
+    {
+        Ok(message) => message,
+        Err(why) => {
+            error!("Error sending message: {:?},", why);
+            return
+        }
+    };
+
+    // Start the cooldown only now that the member was shouted out, and remember
+    // the shout-out so it can be edited when the stream ends
+    if let Err(why) = database.member_stream_shout_out(
+        guild_id.0, user_id.0, time::get_time().sec, discord_channel_id.0, message.id.0)
+    {
+        error!("Couldn't record stream shout-out in database: {}", why);
+    }
+}
 
-    //TODO: Look at error handling
-    if let Err(why) = discord_channel.send_message(&context, |m| {
+/// Send a stream shout-out with the given text and an embed describing the stream.
+pub fn send_shout_out(
+    context: &Context,
+    discord_channel_id: ChannelId,
+    channel_text: &str,
+    shout_out: &ShoutOut,
+    colour: Colour,
+    avatar_url: &str,
+) -> serenity::Result<Message> {
+    discord_channel_id.send_message(&context.http, |m| {
         m.content(channel_text);
-        m.embed(|e|
-                    e.title(&stream_title) // Stream Title
-                        .colour(member_colour)
-                        .url(&stream_url) // Stream URL
-                        .author(|a| {
-                            a.name(format!("{} {}", &user_title, &member_name))
-                                // Gets pfp url or just discords default URL for pfp
-                                .icon_url(member.user.read().face())
-                        })
-                        .field("Playing", format!("{}", &stream_game), true) // Game being Played
-                        //.footer(|f| f.text(format!("Stream started at 13:37"))) // Point out stream starting time
-        )
-    }) {error!("Error sending message: {:?},", why)};
+        m.embed(|e| {
+            e.title(&shout_out.stream_title) // Stream Title
+                .colour(colour)
+                .url(&shout_out.url) // Stream URL
+                .author(|a| {
+                    a.name(shout_out.display_name())
+                        // Gets pfp url or just discords default URL for pfp
+                        .icon_url(avatar_url)
+                });
+            // Discord rejects embed fields without a value
+            if !shout_out.game.is_empty() {
+                e.field("Playing", &shout_out.game, true); // Game being Played
+            }
+            e.field("On", &shout_out.platform, true);
+            // Details the stream provider found, if any
+            if let Some(viewer_count) = shout_out.info.viewer_count {
+                e.field("Viewers", viewer_count, true);
+            }
+            if let Some(thumbnail_url) = &shout_out.info.thumbnail_url {
+                e.image(thumbnail_url);
+            }
+            if let Some(started_at) = &shout_out.info.started_at {
+                e.footer(|f| f.text("Stream started")) // Point out stream starting time
+                    .timestamp(started_at.as_str());
+            }
+            e
+        })
+    })
+}
+
+/// Everything a stream shout-out can say about a stream.
+#[derive(Clone, Debug)]
+pub struct ShoutOut {
+    /// The member's display name.
+    pub name: String,
+    /// The member's roleplay title, or empty if they don't have one.
+    pub title: String,
+    pub stream_title: String,
+    pub game: String,
+    pub url: String,
+    /// Where they're streaming, e.g. "Twitch".
+    pub platform: String,
+    /// Mention of the role to notify, or empty if the guild doesn't mention one.
+    pub role: String,
+    /// Details from the stream's platform, which may not be available.
+    pub info: StreamInfo,
+}
+
+impl ShoutOut {
+    /// The member's name preceded by their title, if they have one.
+    pub fn display_name(&self) -> String {
+        if self.title.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} {}", self.title, self.name)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TemplateError {
+    TooLong,
+    Unclosed,
+    UnknownPlaceholder(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::TooLong =>
+                write!(f, "templates can be at most {} characters long", MAX_TEMPLATE_LENGTH),
+            TemplateError::Unclosed =>
+                write!(f, "a `{{` isn't closed with a `}}`"),
+            TemplateError::UnknownPlaceholder(name) =>
+                write!(f, "there's no placeholder called `{{{}}}`; use one of {}", name,
+                       TEMPLATE_PLACEHOLDERS.iter()
+                           .map(|p| format!("`{{{}}}`", p))
+                           .collect::<Vec<_>>()
+                           .join(", ")),
+        }
+    }
+}
+
+/// Fill in the placeholders of a shout-out template, e.g. `{name} is live on {platform}!`.
+///
+/// Values chosen by members are escaped, so they can't mention anyone or break formatting.
+/// If the guild mentions a role but the template doesn't place it, it goes in front.
+pub fn render_template(template: &str, shout_out: &ShoutOut) -> Result<String, TemplateError> {
+    if template.chars().count() > MAX_TEMPLATE_LENGTH {
+        return Err(TemplateError::TooLong)
+    }
+
+    let mut text = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        text.push_str(&rest[..open]);
+        let close = match rest[open..].find('}') {
+            Some(close) => open + close,
+            None => return Err(TemplateError::Unclosed),
+        };
+        let value = match &rest[open + 1..close] {
+            "name" => escape(&shout_out.name),
+            "title" => escape(&shout_out.title),
+            "stream_title" => escape(&shout_out.stream_title),
+            "game" => escape(&shout_out.game),
+            "platform" => escape(&shout_out.platform),
+            "url" => shout_out.url.clone(),
+            "role" => shout_out.role.clone(),
+            name => return Err(TemplateError::UnknownPlaceholder(String::from(name))),
+        };
+        text.push_str(&value);
+        rest = &rest[close + 1..];
+    }
+    text.push_str(rest);
+
+    if !shout_out.role.is_empty() && !template.contains("{role}") {
+        text = format!("{} {}", shout_out.role, text.trim());
+    }
+    Ok(String::from(text.trim()))
+}
+
+fn escape(value: &str) -> String {
+    MessageBuilder::new().push_safe(value).build()
 }
@@ -0,0 +1,527 @@
+extern crate reqwest;
+
+use crate::config::{Config, TwitchConfig, YouTubeConfig};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    time::Duration,
+};
+
+/// Shout-outs wait on the provider, so don't let a slow API hold them up for long.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Size of the stream thumbnail shown in shout-outs.
+const THUMBNAIL_SIZE: (u32, u32) = (640, 360);
+
+/// Extra details about a live stream that Discord doesn't send in presence updates.
+#[derive(Clone, Debug, Default)]
+pub struct StreamInfo {
+    pub thumbnail_url: Option<String>,
+    pub viewer_count: Option<u64>,
+    pub category: Option<String>,
+    /// When the stream started, as an ISO 8601 timestamp.
+    pub started_at: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ProviderError {
+    /// No API credentials were set for this platform.
+    NotConfigured,
+    /// The API doesn't know of a live stream at this URL.
+    NotLive,
+    Http(reqwest::Error),
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProviderError::NotConfigured => write!(f, "no API credentials are configured"),
+            ProviderError::NotLive => write!(f, "the stream isn't live"),
+            ProviderError::Http(why) => write!(f, "request failed: {}", why),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ProviderError {
+    fn from(why: reqwest::Error) -> Self {
+        ProviderError::Http(why)
+    }
+}
+
+/// The parts of a stream URL that providers look at.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamUrl {
+    /// Lowercase host without a leading `www.` or `m.`, e.g. `twitch.tv`.
+    pub host: String,
+    pub path: Vec<String>,
+    pub query: Vec<(String, String)>,
+}
+
+impl StreamUrl {
+    pub fn parse(url: &str) -> Option<StreamUrl> {
+        let rest = match url.find("://") {
+            Some(scheme_end) => &url[scheme_end + 3..],
+            None => url,
+        };
+        let rest = rest.split('#').next().unwrap_or("");
+        let (rest, query) = match rest.find('?') {
+            Some(query_start) => (&rest[..query_start], &rest[query_start + 1..]),
+            None => (rest, ""),
+        };
+
+        let mut path = rest.split('/').filter(|segment| !segment.is_empty());
+        let host = path.next()?
+            .to_lowercase();
+        let host = host
+            .trim_start_matches("www.")
+            .trim_start_matches("m.");
+
+        Some(StreamUrl {
+            host: String::from(host),
+            path: path.map(String::from).collect(),
+            query: query.split('&')
+                .filter_map(|pair| {
+                    let mut parts = pair.splitn(2, '=');
+                    Some((String::from(parts.next()?), String::from(parts.next()?)))
+                })
+                .collect(),
+        })
+    }
+
+    fn query_value(&self, key: &str) -> Option<&str> {
+        self.query.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// A streaming platform that can look up details of streams on it.
+pub trait StreamProvider {
+    /// Name of the platform as shown in shout-outs, e.g. "Twitch".
+    fn platform(&self) -> &'static str;
+
+    /// Hosts that stream URLs of this platform use.
+    fn hosts(&self) -> &'static [&'static str];
+
+    /// Work out which stream a URL of this platform points to.
+    /// Return None if the URL isn't specific enough to look anything up.
+    fn stream_id(&self, url: &StreamUrl) -> Option<String>;
+
+    fn fetch(&self, stream_id: &str) -> Result<StreamInfo, ProviderError>;
+}
+
+/// Stream IDs end up in API requests, so only accept the characters platforms use.
+fn is_valid_stream_id(stream_id: &str) -> bool {
+    !stream_id.is_empty()
+        && stream_id.len() <= 64
+        && stream_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn client() -> Result<reqwest::Client, ProviderError> {
+    Ok(reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?)
+}
+
+/// Twitch, looked up with the Helix API.
+///
+/// Set twitch.client_id and twitch.token in the config to enable lookups.
+/// twitch.api_url overrides where the API is, e.g. to use a mock server.
+pub struct Twitch {
+    base_url: String,
+    client_id: Option<String>,
+    token: Option<String>,
+}
+
+impl Twitch {
+    pub fn new(config: &TwitchConfig) -> Self {
+        Self {
+            base_url: config.api_url.clone(),
+            client_id: config.client_id.clone(),
+            token: config.token.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TwitchStreams {
+    data: Vec<TwitchStream>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TwitchStream {
+    #[serde(default)]
+    game_name: Option<String>,
+    #[serde(default)]
+    viewer_count: Option<u64>,
+    #[serde(default)]
+    started_at: Option<String>,
+    #[serde(default)]
+    thumbnail_url: Option<String>,
+}
+
+impl StreamProvider for Twitch {
+    fn platform(&self) -> &'static str {
+        "Twitch"
+    }
+
+    fn hosts(&self) -> &'static [&'static str] {
+        &["twitch.tv"]
+    }
+
+    fn stream_id(&self, url: &StreamUrl) -> Option<String> {
+        // e.g. https://www.twitch.tv/<login>
+        match url.path.as_slice() {
+            [login] if is_valid_stream_id(login) => Some(login.to_lowercase()),
+            _ => None,
+        }
+    }
+
+    fn fetch(&self, stream_id: &str) -> Result<StreamInfo, ProviderError> {
+        let client_id = match &self.client_id {
+            Some(client_id) => client_id,
+            None => return Err(ProviderError::NotConfigured),
+        };
+
+        let mut request = client()?
+            .get(&format!("{}/streams", self.base_url.trim_end_matches('/')))
+            .query(&[("user_login", stream_id)])
+            .header("Client-ID", client_id.as_str());
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let streams: TwitchStreams = request.send()?
+            .error_for_status()?
+            .json()?;
+        debug!("Twitch API response: {:?}", streams);
+
+        let stream = match streams.data.into_iter().next() {
+            Some(stream) => stream,
+            None => return Err(ProviderError::NotLive),
+        };
+
+        Ok(StreamInfo {
+            thumbnail_url: stream.thumbnail_url.map(|url| url
+                .replace("{width}", &THUMBNAIL_SIZE.0.to_string())
+                .replace("{height}", &THUMBNAIL_SIZE.1.to_string())),
+            viewer_count: stream.viewer_count,
+            category: stream.game_name.filter(|name| !name.is_empty()),
+            started_at: stream.started_at,
+        })
+    }
+}
+
+/// YouTube, looked up with the Data API.
+///
+/// Set youtube.api_key in the config to enable lookups.
+/// youtube.api_url overrides where the API is, e.g. to use a mock server.
+pub struct YouTube {
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl YouTube {
+    pub fn new(config: &YouTubeConfig) -> Self {
+        Self {
+            base_url: config.api_url.clone(),
+            api_key: config.api_key.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct YouTubeVideos {
+    items: Vec<YouTubeVideo>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct YouTubeVideo {
+    #[serde(default)]
+    snippet: Option<YouTubeSnippet>,
+    #[serde(default)]
+    live_streaming_details: Option<YouTubeLiveStreamingDetails>,
+}
+
+#[derive(Debug, Deserialize)]
+struct YouTubeSnippet {
+    #[serde(default)]
+    thumbnails: HashMap<String, YouTubeThumbnail>,
+}
+
+#[derive(Debug, Deserialize)]
+struct YouTubeThumbnail {
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct YouTubeLiveStreamingDetails {
+    /// The API sends this as a string.
+    #[serde(default)]
+    concurrent_viewers: Option<String>,
+    #[serde(default)]
+    actual_start_time: Option<String>,
+}
+
+impl StreamProvider for YouTube {
+    fn platform(&self) -> &'static str {
+        "YouTube"
+    }
+
+    fn hosts(&self) -> &'static [&'static str] {
+        &["youtube.com", "youtu.be"]
+    }
+
+    fn stream_id(&self, url: &StreamUrl) -> Option<String> {
+        // e.g. https://www.youtube.com/watch?v=<id> or https://youtu.be/<id>
+        let video_id = match (url.host.as_str(), url.path.as_slice()) {
+            ("youtu.be", [video_id]) => video_id.as_str(),
+            (_, [watch]) if watch == "watch" => url.query_value("v")?,
+            (_, [live, video_id]) if live == "live" => video_id.as_str(),
+            _ => return None,
+        };
+        if is_valid_stream_id(video_id) {
+            Some(String::from(video_id))
+        } else {
+            None
+        }
+    }
+
+    fn fetch(&self, stream_id: &str) -> Result<StreamInfo, ProviderError> {
+        let api_key = match &self.api_key {
+            Some(api_key) => api_key,
+            None => return Err(ProviderError::NotConfigured),
+        };
+
+        let videos: YouTubeVideos = client()?
+            .get(&format!("{}/videos", self.base_url.trim_end_matches('/')))
+            .query(&[
+                ("part", "snippet,liveStreamingDetails"),
+                ("id", stream_id),
+                ("key", api_key.as_str()),
+            ])
+            .send()?
+            .error_for_status()?
+            .json()?;
+        debug!("YouTube API response: {:?}", videos);
+
+        let video = match videos.items.into_iter().next() {
+            Some(video) => video,
+            None => return Err(ProviderError::NotLive),
+        };
+        let details = match video.live_streaming_details {
+            Some(details) => details,
+            None => return Err(ProviderError::NotLive),
+        };
+
+        // Pick the largest thumbnail on offer
+        let thumbnail_url = video.snippet.and_then(|mut snippet| {
+            ["maxres", "standard", "high", "medium", "default"].iter()
+                .find_map(|size| snippet.thumbnails.remove(*size))
+                .map(|thumbnail| thumbnail.url)
+        });
+
+        Ok(StreamInfo {
+            thumbnail_url,
+            viewer_count: details.concurrent_viewers.and_then(|viewers| viewers.parse().ok()),
+            category: None,
+            started_at: details.actual_start_time,
+        })
+    }
+}
+
+fn providers(config: &Config) -> Vec<Box<dyn StreamProvider>> {
+    vec![
+        Box::new(Twitch::new(&config.twitch)),
+        Box::new(YouTube::new(&config.youtube)),
+    ]
+}
+
+fn provider_for(config: &Config, url: &StreamUrl) -> Option<Box<dyn StreamProvider>> {
+    providers(config).into_iter()
+        .find(|provider| provider.hosts().contains(&url.host.as_str()))
+}
+
+/// Work out which platform a stream is on from its URL.
+/// Unknown platforms are named after the URL's host.
+pub fn platform(config: &Config, url: &str) -> String {
+    let stream_url = match StreamUrl::parse(url) {
+        Some(stream_url) => stream_url,
+        None => return String::from("stream"),
+    };
+    match provider_for(config, &stream_url) {
+        Some(provider) => String::from(provider.platform()),
+        None => stream_url.host,
+    }
+}
+
+/// Look up extra details of the stream at a URL.
+/// Return None if the platform isn't supported or the lookup fails,
+/// in which case shout-outs make do with what Discord sent.
+pub fn stream_info(config: &Config, url: &str) -> Option<StreamInfo> {
+    let stream_url = StreamUrl::parse(url)?;
+    let provider = provider_for(config, &stream_url)?;
+    let stream_id = match provider.stream_id(&stream_url) {
+        Some(stream_id) => stream_id,
+        None => {
+            debug!("Can't tell which {} stream {} is", provider.platform(), url);
+            return None
+        }
+    };
+
+    match provider.fetch(&stream_id) {
+        Ok(info) => {
+            debug!("Fetched {} stream info: {:?}", provider.platform(), info);
+            Some(info)
+        },
+        Err(ProviderError::NotConfigured) => {
+            debug!("{} lookups aren't configured", provider.platform());
+            None
+        },
+        Err(why) => {
+            error!("Could not fetch {} stream info for {}: {}", provider.platform(), stream_id, why);
+            None
+        },
+    }
+}
+
+#[cfg(test)]
+#[path = "../examples/mock_stream_api.rs"]
+#[allow(dead_code)] // Its main is only used when running it as an example
+mod mock_stream_api;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    fn url(url: &str) -> StreamUrl {
+        StreamUrl::parse(url).unwrap()
+    }
+
+    fn twitch_id(input: &str) -> Option<String> {
+        Twitch::new(&TwitchConfig::default()).stream_id(&url(input))
+    }
+
+    fn youtube_id(input: &str) -> Option<String> {
+        YouTube::new(&YouTubeConfig::default()).stream_id(&url(input))
+    }
+
+    /// Start the mock stream API on a free port and return its address.
+    fn mock_api() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || mock_stream_api::serve(listener));
+        format!("http://{}", address)
+    }
+
+    fn twitch(api: &str) -> Twitch {
+        Twitch::new(&TwitchConfig {
+            api_url: format!("{}/helix", api),
+            client_id: Some(String::from("mock")),
+            token: Some(String::from("mock")),
+        })
+    }
+
+    fn youtube(api: &str) -> YouTube {
+        YouTube::new(&YouTubeConfig {
+            api_url: format!("{}/youtube/v3", api),
+            api_key: Some(String::from("mock")),
+        })
+    }
+
+    #[test]
+    fn parse_splits_urls() {
+        assert_eq!(url("https://www.YouTube.com/watch?v=abc&t=10#top"), StreamUrl {
+            host: String::from("youtube.com"),
+            path: vec![String::from("watch")],
+            query: vec![
+                (String::from("v"), String::from("abc")),
+                (String::from("t"), String::from("10")),
+            ],
+        });
+        assert_eq!(url("m.twitch.tv//someone/").path, ["someone"]);
+        assert_eq!(url("https://twitch.tv").path, Vec::<String>::new());
+        assert_eq!(StreamUrl::parse("https://"), None);
+        assert_eq!(StreamUrl::parse(""), None);
+    }
+
+    #[test]
+    fn twitch_stream_ids() {
+        assert_eq!(twitch_id("https://www.twitch.tv/Some_One"), Some(String::from("some_one")));
+        assert_eq!(twitch_id("https://twitch.tv/someone?referrer=raid"), Some(String::from("someone")));
+        assert_eq!(twitch_id("https://www.twitch.tv/"), None);
+        assert_eq!(twitch_id("https://www.twitch.tv/someone/videos"), None);
+        assert_eq!(twitch_id("https://www.twitch.tv/some%20one"), None);
+    }
+
+    #[test]
+    fn youtube_stream_ids() {
+        assert_eq!(youtube_id("https://www.youtube.com/watch?v=dQw4w9WgXcQ"),
+                   Some(String::from("dQw4w9WgXcQ")));
+        assert_eq!(youtube_id("https://youtu.be/dQw4w9WgXcQ"), Some(String::from("dQw4w9WgXcQ")));
+        assert_eq!(youtube_id("https://m.youtube.com/live/dQw4w9WgXcQ?feature=share"),
+                   Some(String::from("dQw4w9WgXcQ")));
+        assert_eq!(youtube_id("https://www.youtube.com/watch"), None);
+        assert_eq!(youtube_id("https://www.youtube.com/channel/someone"), None);
+        assert_eq!(youtube_id("https://www.youtube.com/watch?v=a&b"), Some(String::from("a")));
+        assert_eq!(youtube_id("https://www.youtube.com/watch?v=<script>"), None);
+    }
+
+    #[test]
+    fn platforms() {
+        let config = Config::default();
+        assert_eq!(platform(&config, "https://www.twitch.tv/someone"), "Twitch");
+        assert_eq!(platform(&config, "https://youtu.be/abc"), "YouTube");
+        assert_eq!(platform(&config, "https://www.example.com/stream"), "example.com");
+        assert_eq!(platform(&config, ""), "stream");
+    }
+
+    #[test]
+    fn twitch_fetch() {
+        let api = mock_api();
+        let info = twitch(&api).fetch("someone").unwrap();
+        assert_eq!(info.viewer_count, Some(42));
+        assert_eq!(info.category.as_deref(), Some("Just Chatting"));
+        assert_eq!(info.started_at.as_deref(), Some("2019-10-01T18:00:00Z"));
+        assert_eq!(info.thumbnail_url.as_deref(),
+                   Some("https://static-cdn.jtvnw.net/previews-ttv/live_user_someone-640x360.jpg"));
+
+        match twitch(&api).fetch("offline") {
+            Err(ProviderError::NotLive) => (),
+            other => panic!("expected NotLive, got {:?}", other),
+        }
+        match twitch(&api).fetch("broken") {
+            Err(ProviderError::Http(_)) => (),
+            other => panic!("expected an HTTP error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn youtube_fetch() {
+        let api = mock_api();
+        let info = youtube(&api).fetch("abc").unwrap();
+        assert_eq!(info.viewer_count, Some(1337));
+        assert_eq!(info.category, None);
+        assert_eq!(info.started_at.as_deref(), Some("2019-10-01T18:00:00Z"));
+        assert_eq!(info.thumbnail_url.as_deref(), Some("https://i.ytimg.com/vi/abc/hqdefault_live.jpg"));
+
+        match youtube(&api).fetch("offline") {
+            Err(ProviderError::NotLive) => (),
+            other => panic!("expected NotLive, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn fetch_needs_credentials() {
+        match Twitch::new(&TwitchConfig::default()).fetch("someone") {
+            Err(ProviderError::NotConfigured) => (),
+            other => panic!("expected NotConfigured, got {:?}", other),
+        }
+        match YouTube::new(&YouTubeConfig::default()).fetch("abc") {
+            Err(ProviderError::NotConfigured) => (),
+            other => panic!("expected NotConfigured, got {:?}", other),
+        }
+    }
+}
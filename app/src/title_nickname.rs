@@ -0,0 +1,193 @@
+//! Puts members' titles in front of their nicknames, in guilds that want it,
+//! and puts their old nicknames back when the titles go.
+
+use crate::{
+    database::{self, DatabaseError},
+    title_policy,
+};
+use serenity::{
+    prelude::*,
+    model::prelude::*,
+};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Discord doesn't allow nicknames longer than this.
+const MAX_NICKNAME_LENGTH: usize = 32;
+
+/// What happened to a member's nickname.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sync {
+    /// Nothing needed doing.
+    Unchanged,
+    /// The title was put in the nickname, or the old nickname was put back.
+    Changed,
+    /// The nickname couldn't be changed, for the reason given, as a sentence for the member.
+    Failed(&'static str),
+}
+
+/// Bring a member's nickname in line with the title shown for them in a guild:
+/// "<title> <name>" if the guild puts titles in nicknames, or the nickname they had
+/// before otherwise.
+pub fn sync(context: &Context, guild_id: GuildId, user_id: UserId) -> Result<Sync, DatabaseError> {
+    let database = database::handle(context)?;
+    let guild = database.guild(guild_id.0)?;
+    let data = database.member(guild_id.0, user_id.0)?;
+    if !guild.title_nicknames_enabled && !data.title_nickname_synced {
+        return Ok(Sync::Unchanged)
+    }
+
+    let member = match guild_id.member(context, user_id) {
+        Ok(member) => member,
+        Err(why) => {
+            debug!("Could not find member {} of guild {}: {:?}", user_id, guild_id, why);
+            return Ok(Sync::Unchanged)
+        },
+    };
+    let title = match guild.title_nicknames_enabled {
+        true => title_policy::shown_title(&database, user_id.0, Some(guild_id.0))?,
+        false => None,
+    };
+
+    let nickname = match (&title, data.title_nickname_synced) {
+        (None, false) => return Ok(Sync::Unchanged),
+        (None, true) => data.nickname_before_title.clone(),
+        (Some(title), synced) => {
+            // The member's own nickname, without any title the bot added
+            let own_nickname = if synced { &data.nickname_before_title } else { &member.nick };
+            let name = match own_nickname {
+                Some(nickname) => nickname.clone(),
+                None => member.user.read().name.clone(),
+            };
+            match title_nickname(title, &name) {
+                Some(nickname) => Some(nickname),
+                None => return Ok(Sync::Failed("Your name is too long to fit your title in your nickname.")),
+            }
+        },
+    };
+    let changed = member.nick != nickname;
+    if changed {
+        let is_owner = guild_id.to_guild_cached(&context.cache)
+            .is_some_and(|guild| guild.read().owner_id == user_id);
+        if is_owner {
+            return Ok(Sync::Failed(
+                "Discord doesn't let bots change the nickname of the guild's owner."))
+        }
+
+        debug!("Changing nickname of member {} of guild {} to {:?}", user_id, guild_id, nickname);
+        // An empty nickname removes it
+        let edited = guild_id.edit_member(&context.http, user_id,
+            |m| m.nickname(nickname.clone().unwrap_or_default()));
+        if let Err(why) = edited {
+            info!("Could not change nickname of member {} of guild {}: {:?}", user_id, guild_id, why);
+            return Ok(Sync::Failed("I couldn't change your nickname: I need the Manage Nicknames \
+                permission and a role above all of yours."))
+        }
+    }
+
+    // Only the nickname columns are written, as the rest may have changed while Discord was asked
+    match title {
+        Some(_) if !data.title_nickname_synced => database.member_title_nickname_update(
+            guild_id.0, user_id.0, true, member.nick.as_deref())?,
+        Some(_) => (),
+        None => database.member_title_nickname_update(guild_id.0, user_id.0, false, None)?,
+    }
+    Ok(if changed { Sync::Changed } else { Sync::Unchanged })
+}
+
+/// Bring a user's nicknames in line with their titles in every guild that puts titles in
+/// nicknames, after their global title changed.
+pub fn sync_everywhere(context: &Context, user_id: UserId) -> Result<(), DatabaseError> {
+    let guild_ids = database::handle(context)?.title_nickname_guilds()?;
+    for guild_id in guild_ids.into_iter().map(GuildId) {
+        let is_member = guild_id.to_guild_cached(&context.cache)
+            .is_some_and(|guild| guild.read().members.contains_key(&user_id));
+        if !is_member {
+            continue
+        }
+        if let Sync::Failed(reason) = sync(context, guild_id, user_id)? {
+            debug!("Could not sync nickname of user {} in guild {}: {}", user_id, guild_id, reason);
+        }
+    }
+    Ok(())
+}
+
+/// Put back the nicknames of every member of a guild whose title the bot put in their
+/// nickname, once the guild no longer wants it. Returns how many couldn't be put back.
+pub fn restore_all(context: &Context, guild_id: GuildId) -> Result<usize, DatabaseError> {
+    let user_ids = database::handle(context)?.title_nickname_members(guild_id.0)?;
+    let mut failed = 0;
+    for user_id in user_ids.into_iter().map(UserId) {
+        if let Sync::Failed(reason) = sync(context, guild_id, user_id)? {
+            debug!("Could not restore nickname of user {} in guild {}: {}", user_id, guild_id, reason);
+            failed += 1;
+        }
+    }
+    Ok(failed)
+}
+
+/// A title followed by a name, within Discord's limit on nicknames.
+/// The title is shortened if needed; None if there isn't room for any of it.
+fn title_nickname(title: &str, name: &str) -> Option<String> {
+    // Room for the title, after the name and a space
+    let room = MAX_NICKNAME_LENGTH.checked_sub(name.chars().count() + 1)?;
+    if title.chars().count() <= room {
+        return Some(format!("{} {}", title, name))
+    }
+    // Keep at least a letter and the mark showing it was shortened
+    if room < 2 {
+        return None
+    }
+    // Shortened between characters as people see them, so that emoji aren't split up
+    let mut shortened = String::new();
+    for grapheme in title.graphemes(true) {
+        if shortened.chars().count() + grapheme.chars().count() > room - 1 {
+            break
+        }
+        shortened.push_str(grapheme);
+    }
+    if shortened.trim_end().is_empty() {
+        return None
+    }
+    Some(format!("{}… {}", shortened.trim_end(), name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAMILY: &str = "👨\u{200d}👩\u{200d}👧";
+
+    #[test]
+    fn titles_that_fit_are_kept_whole() {
+        assert_eq!(title_nickname("Cat Whisperer", "Alice").as_deref(),
+                   Some("Cat Whisperer Alice"));
+    }
+
+    #[test]
+    fn long_titles_are_shortened() {
+        let nickname = title_nickname("Supreme Overlord of All the Cats", "Alice").unwrap();
+        assert_eq!(nickname, "Supreme Overlord of All t… Alice");
+        assert!(nickname.chars().count() <= MAX_NICKNAME_LENGTH);
+    }
+
+    #[test]
+    fn emoji_are_not_split() {
+        let title = format!("Queen {} of cats", FAMILY);
+
+        // Not enough room for the whole emoji, so it's left out
+        let nickname = title_nickname(&title, &"a".repeat(20)).unwrap();
+        assert_eq!(nickname, format!("Queen… {}", "a".repeat(20)));
+
+        let nickname = title_nickname(&title, &"a".repeat(15)).unwrap();
+        assert_eq!(nickname, format!("Queen {} of… {}", FAMILY, "a".repeat(15)));
+        assert!(nickname.chars().count() <= MAX_NICKNAME_LENGTH);
+    }
+
+    #[test]
+    fn long_names_leave_no_room() {
+        assert!(title_nickname("Cat", &"a".repeat(31)).is_none());
+        assert!(title_nickname("Cat", &"a".repeat(32)).is_none());
+        assert!(title_nickname("Cat Whisperer", &"a".repeat(30)).is_none());
+        assert_eq!(title_nickname("C", &"a".repeat(30)), Some(format!("C {}", "a".repeat(30))));
+    }
+}
@@ -0,0 +1,458 @@
+//! Which titles are shown in a guild: the words and regular expressions titles there must
+//! not match, the titles waiting for a moderator's approval, and the log of everything
+//! moderators did to titles. Also where titles are changed, so that every change is kept
+//! in the user's title history.
+
+use crate::{
+    commands::admin::is_guild_admin,
+    config,
+    database::{self, DatabaseError, Handle, PendingTitle, TitleChange, TitleFilter, TitleModeration},
+    title_nickname,
+};
+use regex::{Regex, RegexBuilder};
+use serenity::{
+    prelude::*,
+    model::prelude::*,
+    utils::MessageBuilder,
+};
+
+/// Moderators react with these to approve or reject a title.
+pub const APPROVE_REACTION: &str = "✅";
+pub const REJECT_REACTION: &str = "❌";
+/// Regular expressions are limited in size, so that a filter can't make checking titles slow.
+const MAX_REGEX_SIZE: usize = 1 << 16;
+
+/// The title to show for a user: their title in the guild if they chose one there,
+/// or else their global title. Outside of guilds only the global title applies.
+///
+/// Global titles aren't shown in guilds where titles need approval, nor for members whose
+/// title there is locked. No title is shown if it matches one of the guild's filters.
+pub fn shown_title(
+    database: &Handle,
+    user_id: u64,
+    guild_id: Option<u64>,
+) -> Result<Option<String>, DatabaseError>
+{
+    let guild_id = match guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(database.user(user_id)?.title),
+    };
+
+    let member = database.member(guild_id, user_id)?;
+    let title = match member.title {
+        Some(title) => title,
+        None => {
+            if member.title_locked || database.guild(guild_id)?.title_approval_enabled {
+                return Ok(None)
+            }
+            match database.user(user_id)?.title {
+                Some(title) => title,
+                None => return Ok(None),
+            }
+        },
+    };
+
+    match refusing_filter(database, guild_id, &title)? {
+        Some(filter) => {
+            debug!("Not showing title {:?} of user {} in guild {}, it matches {:?}",
+                   title, user_id, guild_id, filter.pattern);
+            Ok(None)
+        },
+        None => Ok(Some(title)),
+    }
+}
+
+/// Change a user's global title, or their title in a guild, or clear it if `title` is None.
+/// The change is kept in their title history, of which only the `history_keep` most recent
+/// entries are kept. Returns false if the title was already that.
+pub fn set_title(
+    database: &Handle,
+    user_id: u64,
+    guild_id: Option<u64>,
+    title: Option<&str>,
+    history_keep: u32,
+) -> Result<bool, DatabaseError>
+{
+    database.in_transaction(|database| {
+        let title = title.map(String::from);
+        match guild_id {
+            None => {
+                let mut user = database.user(user_id)?;
+                if user.title == title {
+                    return Ok(false)
+                }
+                user.title = title.clone();
+                database.user_update(user_id, &user)?;
+            },
+            Some(guild_id) => {
+                if database.member(guild_id, user_id)?.title == title {
+                    return Ok(false)
+                }
+                database.member_title_update(guild_id, user_id, title.as_deref())?;
+            },
+        }
+        debug!("Updated title of user {} in {:?} to {:?}", user_id, guild_id, title);
+
+        database.title_history_insert(user_id, guild_id, &TitleChange {
+            title,
+            timestamp: time::get_time().sec,
+        })?;
+        database.title_history_prune(user_id, guild_id, history_keep)?;
+        Ok(true)
+    })
+}
+
+/// The first of a guild's filters that a title matches, if any.
+pub fn refusing_filter(
+    database: &Handle,
+    guild_id: u64,
+    title: &str,
+) -> Result<Option<TitleFilter>, DatabaseError>
+{
+    let filters = database.title_filters(guild_id)?;
+    Ok(filters.into_iter().find(|filter| matches(filter, title)))
+}
+
+/// Check that a regular expression can be used as a filter, explaining why not otherwise.
+pub fn validate_regex(pattern: &str) -> Result<(), String> {
+    build_regex(pattern).map(|_| ()).map_err(|why| why.to_string())
+}
+
+/// Check that a word, or several, can be used as a filter.
+/// Only letters and numbers are compared, so there must be some.
+pub fn validate_word(word: &str) -> bool {
+    !words(word).is_empty()
+}
+
+fn matches(filter: &TitleFilter, title: &str) -> bool {
+    if filter.is_regex {
+        match build_regex(&filter.pattern) {
+            Ok(regex) => regex.is_match(title),
+            Err(why) => {
+                error!("Could not compile title filter {:?}: {}", filter.pattern, why);
+                false
+            },
+        }
+    } else {
+        // Only whole words match, so that blocking "ass" doesn't block "class"
+        format!(" {} ", words(title)).contains(&format!(" {} ", words(&filter.pattern)))
+    }
+}
+
+fn build_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(MAX_REGEX_SIZE)
+        .build()
+}
+
+/// The words of a text in lower case, separated by single spaces.
+fn words(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Record something done to titles in a guild in its moderation log.
+/// `moderator_id` is None for titles the filters refused by themselves.
+pub fn log(
+    database: &Handle,
+    guild_id: u64,
+    moderator_id: Option<u64>,
+    user_id: Option<u64>,
+    action: &str,
+    detail: Option<&str>,
+) -> Result<(), DatabaseError>
+{
+    info!("Title moderation in guild {}: {} by {:?} for {:?}: {:?}",
+          guild_id, action, moderator_id, user_id, detail);
+    database.title_moderation_insert(guild_id, &TitleModeration {
+        moderator_id,
+        user_id,
+        action: String::from(action),
+        detail: detail.map(String::from),
+        timestamp: time::get_time().sec,
+    })
+}
+
+/// Ask the guild's moderators to approve a member's title, replacing any title the member
+/// was already waiting for. The request is sent to `channel_id`.
+pub fn request_approval(
+    context: &Context,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    user: &User,
+    title: &str,
+) -> Result<(), String>
+{
+    let database = database::handle(context)
+        .map_err(|why| format!("Could not get a database handle: {}", why))?;
+    let previous = database.pending_titles(guild_id.0)
+        .map_err(|why| format!("Could not retrieve pending titles from database: {}", why))?
+        .into_iter()
+        .find(|pending| pending.user_id == user.id.0);
+
+    let txt = MessageBuilder::new()
+        .push_bold_safe(user.tag())
+        .push(" would like the title ")
+        .push_bold_safe(title)
+        .push(" in this guild. Moderators, react with ")
+        .push(APPROVE_REACTION)
+        .push(" to approve it or ")
+        .push(REJECT_REACTION)
+        .push(" to reject it.")
+        .build();
+    let message = channel_id.say(&context.http, &txt)
+        .map_err(|why| format!("Could not send title approval request: {:?}", why))?;
+    for emoji in &[APPROVE_REACTION, REJECT_REACTION] {
+        if let Err(why) = message.react(context, *emoji) {
+            error!("Could not react to title approval request: {:?}", why);
+        }
+    }
+
+    let pending = PendingTitle {
+        guild_id: guild_id.0,
+        user_id: user.id.0,
+        title: String::from(title),
+        channel_id: channel_id.0,
+        message_id: message.id.0,
+        timestamp: time::get_time().sec,
+    };
+    database.pending_title_update(&pending)
+        .map_err(|why| format!("Could not update pending title in database: {}", why))?;
+    debug!("Title {:?} of user {} in guild {} is waiting for approval", title, user.id, guild_id);
+
+    // Reactions to the old request no longer do anything
+    if let Some(previous) = previous {
+        let txt = MessageBuilder::new()
+            .push_bold_safe(user.tag())
+            .push(" asked for another title instead of ")
+            .push_bold_safe(&previous.title)
+            .push(".")
+            .build();
+        edit_request(context, &previous, &txt);
+    }
+    Ok(())
+}
+
+/// Forget the title a member was waiting for, if any, and return it.
+/// Once that's kept, `mark_withdrawn` tells moderators the request no longer does anything.
+pub fn withdraw(database: &Handle, guild_id: u64, user_id: u64)
+    -> Result<Option<PendingTitle>, DatabaseError>
+{
+    let previous = database.pending_titles(guild_id)?
+        .into_iter()
+        .find(|pending| pending.user_id == user_id);
+    if previous.is_some() {
+        database.pending_title_delete(guild_id, user_id)?;
+    }
+    Ok(previous)
+}
+
+/// Mark a withdrawn request for approval as such.
+pub fn mark_withdrawn(context: &Context, pending: &PendingTitle) {
+    let txt = MessageBuilder::new()
+        .push("The request for the title ")
+        .push_bold_safe(&pending.title)
+        .push(" was withdrawn.")
+        .build();
+    edit_request(context, pending, &txt);
+}
+
+/// What became of a title a moderator reacted to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Resolution {
+    Approved,
+    Rejected,
+    /// Approved, but the member's title was locked after they asked for it.
+    Locked,
+}
+
+/// Approve or reject a title when a moderator reacts to the message asking for approval.
+pub fn reaction_handler(context: Context, reaction: Reaction) {
+    let approved = match &reaction.emoji {
+        ReactionType::Unicode(emoji) if emoji == APPROVE_REACTION => true,
+        ReactionType::Unicode(emoji) if emoji == REJECT_REACTION => false,
+        _ => return,
+    };
+    // The bot's own reactions are only there for moderators to click
+    if reaction.user_id == context.cache.read().user.id {
+        return
+    }
+
+    let database = match database::handle(&context) {
+        Ok(database) => database,
+        Err(why) => {
+            error!("Could not get a database handle: {}", why);
+            return
+        }
+    };
+    let pending = match database.pending_title_by_message(reaction.message_id.0) {
+        Ok(Some(pending)) if pending.channel_id == reaction.channel_id.0 => pending,
+        Ok(_) => return,
+        Err(why) => {
+            error!("Could not retrieve pending title from database: {}", why);
+            return
+        },
+    };
+
+    let guild_id = GuildId(pending.guild_id);
+
+    // The member may not be cached in large guilds
+    let role_ids = match guild_id.member(&context, reaction.user_id) {
+        Ok(member) => member.roles,
+        Err(_) => Vec::new(),
+    };
+    if !is_guild_admin(&context, guild_id, reaction.user_id, &role_ids) {
+        debug!("Ignoring reaction to title approval request by non-moderator {}", reaction.user_id);
+        return
+    }
+
+    let resolved = database.in_transaction(|database| {
+        // Another moderator may have got there first
+        if !database.pending_title_delete(guild_id.0, pending.user_id)? {
+            return Ok(None)
+        }
+        let resolution = if !approved {
+            Resolution::Rejected
+        } else if database.member(guild_id.0, pending.user_id)?.title_locked {
+            Resolution::Locked
+        } else {
+            set_title(database, pending.user_id, Some(guild_id.0), Some(&pending.title),
+                      config::get(&context).title.history_keep)?;
+            Resolution::Approved
+        };
+        let (action, detail) = match resolution {
+            Resolution::Approved => ("approved", pending.title.clone()),
+            Resolution::Rejected => ("rejected", pending.title.clone()),
+            Resolution::Locked => ("refused", format!("{} (title locked)", pending.title)),
+        };
+        log(database, guild_id.0, Some(reaction.user_id.0), Some(pending.user_id),
+            action, Some(&detail))?;
+        Ok(Some(resolution))
+    });
+    let resolution = match resolved {
+        Ok(Some(resolution)) => resolution,
+        Ok(None) => return,
+        Err(why) => {
+            error!("Could not resolve pending title in database: {}", why);
+            return
+        },
+    };
+    if resolution == Resolution::Approved {
+        sync_nickname(&context, guild_id, UserId(pending.user_id));
+    }
+
+    let moderator = match reaction.user(&context) {
+        Ok(user) => user.tag(),
+        Err(_) => format!("user {}", reaction.user_id),
+    };
+    let txt = MessageBuilder::new()
+        .push(if resolution == Resolution::Approved { APPROVE_REACTION } else { REJECT_REACTION })
+        .push(" The title ")
+        .push_bold_safe(&pending.title)
+        .push(" for ")
+        .mention(&UserId(pending.user_id))
+        .push(match resolution {
+            Resolution::Approved => " was approved by ",
+            Resolution::Rejected => " was rejected by ",
+            Resolution::Locked => " can't be approved, as their title is locked; reacted to by ",
+        })
+        .push_bold_safe(moderator)
+        .push(".")
+        .build();
+    edit_request(&context, &pending, &txt);
+
+    // Let the member know, if they accept direct messages
+    let guild_name = guild_id.to_guild_cached(&context.cache)
+        .map(|guild| guild.read().name.clone())
+        .unwrap_or_else(|| String::from("a guild"));
+    let txt = MessageBuilder::new()
+        .push("Your title ")
+        .push_bold_safe(&pending.title)
+        .push(match resolution {
+            Resolution::Approved => " was approved in ",
+            Resolution::Rejected => " was rejected in ",
+            Resolution::Locked => " wasn't approved, as a moderator locked your title in ",
+        })
+        .push_bold_safe(guild_name)
+        .push(".")
+        .build();
+    let sent = UserId(pending.user_id).create_dm_channel(&context)
+        .and_then(|channel| channel.say(&context.http, &txt));
+    if let Err(why) = sent {
+        debug!("Could not tell user {} about their title: {:?}", pending.user_id, why);
+    }
+}
+
+/// Bring a member's nickname in line with their title after a moderator changed it.
+/// Failures are only logged, as the member isn't there to be told.
+pub fn sync_nickname(context: &Context, guild_id: GuildId, user_id: UserId) {
+    match title_nickname::sync(context, guild_id, user_id) {
+        Ok(title_nickname::Sync::Failed(reason)) => {
+            debug!("Could not sync nickname of user {} in guild {}: {}", user_id, guild_id, reason);
+        },
+        Ok(_) => (),
+        Err(why) => error!("Could not sync nickname with title: {}", why),
+    }
+}
+
+/// Change the message asking moderators to approve a title, once reacting to it does nothing.
+fn edit_request(context: &Context, pending: &PendingTitle, txt: &str) {
+    let edited = ChannelId(pending.channel_id).edit_message(
+        &context.http, MessageId(pending.message_id), |m| m.content(txt));
+    if let Err(why) = edited {
+        debug!("Could not edit title approval request: {:?}", why);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(pattern: &str) -> TitleFilter {
+        TitleFilter { is_regex: false, pattern: String::from(pattern) }
+    }
+
+    fn regex(pattern: &str) -> TitleFilter {
+        TitleFilter { is_regex: true, pattern: String::from(pattern) }
+    }
+
+    #[test]
+    fn words_ignore_case_and_punctuation() {
+        assert_eq!(words("  Top-Cat, of   the YEAR!! "), "top cat of the year");
+        assert_eq!(words("?!"), "");
+        assert!(validate_word("Top Cat"));
+        assert!(!validate_word("-_-"));
+    }
+
+    #[test]
+    fn word_filters_only_match_whole_words() {
+        assert!(matches(&word("ass"), "Kick-ASS cat"));
+        assert!(matches(&word("ass"), "ass"));
+        assert!(!matches(&word("ass"), "Top of the class"));
+        assert!(!matches(&word("ass"), "Assassin"));
+
+        assert!(matches(&word("bad cat"), "A very BAD, cat!"));
+        assert!(!matches(&word("bad cat"), "Bad catnip"));
+    }
+
+    #[test]
+    fn regex_filters_ignore_case() {
+        assert!(matches(&regex("c[aA]t+"), "Top CATT"));
+        assert!(matches(&regex("^admin"), "Admin of cats"));
+        assert!(!matches(&regex("^admin"), "Not an admin"));
+        // A filter that doesn't compile refuses nothing
+        assert!(!matches(&regex("("), "("));
+    }
+
+    #[test]
+    fn invalid_and_oversized_regexes_are_rejected() {
+        assert!(validate_regex("c[aA]t+").is_ok());
+        assert!(validate_regex("(").is_err());
+        assert!(validate_regex("[z-a]").is_err());
+        assert!(validate_regex(r"\w{1000}").is_err());
+    }
+}
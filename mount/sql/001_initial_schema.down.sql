@@ -0,0 +1,6 @@
+DROP INDEX IF EXISTS IndexDiscordMemberId;
+DROP TABLE IF EXISTS Members;
+DROP INDEX IF EXISTS IndexDiscordUserId;
+DROP TABLE IF EXISTS Users;
+DROP INDEX IF EXISTS IndexDiscordGuildId;
+DROP TABLE IF EXISTS Guilds;
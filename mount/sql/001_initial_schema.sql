@@ -1,11 +1,5 @@
-BEGIN;
-
-PRAGMA encoding = "UTF-8";
-PRAGMA foreign_keys = ON;
-
 -- SQLite commands to create a database.
--- To execute this, create a database using the sqlite3 binary,
--- then type ".read setup.sql".
+-- Migrations are applied by the bot at startup, each in its own transaction.
 
 -- Table storing data about guilds the bot is in
 CREATE TABLE IF NOT EXISTS Guilds (
@@ -31,7 +25,3 @@ CREATE TABLE IF NOT EXISTS Members (
     UNIQUE (DiscordGuildId, DiscordUserId)
 );
 CREATE UNIQUE INDEX IF NOT EXISTS IndexDiscordMemberId ON Members(DiscordGuildId, DiscordUserId);
-
-PRAGMA user_version=1;
-
-COMMIT;
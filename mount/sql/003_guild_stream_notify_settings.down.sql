@@ -0,0 +1,3 @@
+ALTER TABLE Guilds DROP COLUMN StreamNotifyCooldown;
+ALTER TABLE Guilds DROP COLUMN StreamNotifyChannelId;
+ALTER TABLE Guilds DROP COLUMN StreamNotifyEnabled;
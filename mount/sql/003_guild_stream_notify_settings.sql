@@ -0,0 +1,4 @@
+-- Per-guild stream shout-out settings
+ALTER TABLE Guilds ADD COLUMN StreamNotifyEnabled INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE Guilds ADD COLUMN StreamNotifyChannelId INTEGER;
+ALTER TABLE Guilds ADD COLUMN StreamNotifyCooldown INTEGER;
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS GuildMacros;
+DROP TABLE IF EXISTS UserMacros;
@@ -0,0 +1,17 @@
+-- Saved dice expressions belonging to a single user, usable in every guild
+CREATE TABLE IF NOT EXISTS UserMacros (
+    DiscordUserId INTEGER NOT NULL,
+    Name TEXT NOT NULL,
+    Expression TEXT NOT NULL,
+
+    PRIMARY KEY (DiscordUserId, Name)
+);
+
+-- Saved dice expressions shared by everyone in a guild
+CREATE TABLE IF NOT EXISTS GuildMacros (
+    DiscordGuildId INTEGER NOT NULL,
+    Name TEXT NOT NULL,
+    Expression TEXT NOT NULL,
+
+    PRIMARY KEY (DiscordGuildId, Name)
+);
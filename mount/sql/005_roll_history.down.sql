@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS IndexRollHistoryUser;
+DROP TABLE IF EXISTS RollHistory;
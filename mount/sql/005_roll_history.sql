@@ -0,0 +1,14 @@
+-- Every dice roll made, so that contested results can be checked later
+CREATE TABLE IF NOT EXISTS RollHistory (
+    RollId INTEGER PRIMARY KEY AUTOINCREMENT,
+    DiscordUserId INTEGER NOT NULL,
+    -- NULL for rolls made in direct messages
+    DiscordGuildId INTEGER,
+    DiscordChannelId INTEGER NOT NULL,
+    Expression TEXT NOT NULL,
+    -- JSON encoded results of each term, including every die rolled
+    Results TEXT NOT NULL,
+    Total INTEGER NOT NULL,
+    Timestamp INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS IndexRollHistoryUser ON RollHistory(DiscordGuildId, DiscordUserId, Timestamp);
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS IndexCombatantsChannel;
+DROP TABLE IF EXISTS Combatants;
+DROP TABLE IF EXISTS Encounters;
@@ -0,0 +1,28 @@
+-- Combat encounters being tracked, at most one per channel
+CREATE TABLE IF NOT EXISTS Encounters (
+    DiscordChannelId INTEGER PRIMARY KEY,
+    DiscordGuildId INTEGER,
+    -- The user who started the encounter and can run it
+    GmUserId INTEGER NOT NULL,
+    -- 0 until initiative has been rolled
+    Round INTEGER NOT NULL,
+    -- Position of the combatant whose turn it is
+    Turn INTEGER NOT NULL
+);
+
+-- Everyone taking part in an encounter, in initiative order
+CREATE TABLE IF NOT EXISTS Combatants (
+    CombatantId INTEGER PRIMARY KEY AUTOINCREMENT,
+    DiscordChannelId INTEGER NOT NULL,
+    Position INTEGER NOT NULL,
+    Name TEXT NOT NULL,
+    -- NULL for non-player characters
+    DiscordUserId INTEGER,
+    Modifier INTEGER NOT NULL,
+    -- NULL until initiative has been rolled
+    Initiative INTEGER,
+
+    FOREIGN KEY (DiscordChannelId) REFERENCES Encounters(DiscordChannelId),
+    UNIQUE (DiscordChannelId, Position)
+);
+CREATE INDEX IF NOT EXISTS IndexCombatantsChannel ON Combatants(DiscordChannelId, Position);
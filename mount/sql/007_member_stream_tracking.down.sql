@@ -0,0 +1,3 @@
+ALTER TABLE Members DROP COLUMN StreamNotifyMessageId;
+ALTER TABLE Members DROP COLUMN StreamNotifyChannelId;
+ALTER TABLE Members DROP COLUMN StreamStartTimestamp;
@@ -0,0 +1,6 @@
+-- When the member's current stream started, or NULL if they aren't streaming
+ALTER TABLE Members ADD COLUMN StreamStartTimestamp INTEGER;
+-- The shout-out message sent for the member's current stream, so it can be
+-- edited when the stream ends
+ALTER TABLE Members ADD COLUMN StreamNotifyChannelId INTEGER;
+ALTER TABLE Members ADD COLUMN StreamNotifyMessageId INTEGER;
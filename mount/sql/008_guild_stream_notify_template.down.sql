@@ -0,0 +1,2 @@
+ALTER TABLE Guilds DROP COLUMN StreamNotifyRoleId;
+ALTER TABLE Guilds DROP COLUMN StreamNotifyTemplate;
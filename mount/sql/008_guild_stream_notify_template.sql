@@ -0,0 +1,4 @@
+-- Wording of stream shout-outs; NULL means the default template
+ALTER TABLE Guilds ADD COLUMN StreamNotifyTemplate TEXT;
+-- Role mentioned in stream shout-outs, if any
+ALTER TABLE Guilds ADD COLUMN StreamNotifyRoleId INTEGER;
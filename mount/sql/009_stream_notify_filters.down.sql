@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS StreamNotifyRoles;
+ALTER TABLE Members DROP COLUMN StreamNotifyOptOut;
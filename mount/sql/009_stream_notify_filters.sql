@@ -0,0 +1,11 @@
+-- Members who don't want their streams shouted out
+ALTER TABLE Members ADD COLUMN StreamNotifyOptOut INTEGER NOT NULL DEFAULT 0;
+
+-- Roles a member needs one of to be shouted out; no rows means everyone is
+CREATE TABLE IF NOT EXISTS StreamNotifyRoles (
+    DiscordGuildId INTEGER NOT NULL,
+    DiscordRoleId INTEGER NOT NULL,
+
+    FOREIGN KEY (DiscordGuildId) REFERENCES Guilds(DiscordGuildId),
+    PRIMARY KEY (DiscordGuildId, DiscordRoleId)
+);
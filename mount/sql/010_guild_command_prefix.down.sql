@@ -0,0 +1 @@
+ALTER TABLE Guilds DROP COLUMN CommandPrefix;
@@ -0,0 +1,2 @@
+-- Prefix for commands in this guild; NULL means the bot-wide prefix
+ALTER TABLE Guilds ADD COLUMN CommandPrefix TEXT;
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS IndexGuildSettingsAuditGuild;
+DROP TABLE IF EXISTS GuildSettingsAudit;
+ALTER TABLE Guilds DROP COLUMN AdminRoleId;
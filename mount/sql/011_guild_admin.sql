@@ -0,0 +1,16 @@
+-- Role whose members may change the bot's settings for this guild, besides those who can
+-- manage the guild; NULL means there is none
+ALTER TABLE Guilds ADD COLUMN AdminRoleId INTEGER;
+
+-- Every change made to a guild's settings, and who made it
+CREATE TABLE IF NOT EXISTS GuildSettingsAudit (
+    AuditId INTEGER PRIMARY KEY AUTOINCREMENT,
+    DiscordGuildId INTEGER NOT NULL,
+    DiscordUserId INTEGER NOT NULL,
+    Setting TEXT NOT NULL,
+    -- NULL when the setting was at its default
+    OldValue TEXT,
+    NewValue TEXT,
+    Timestamp INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS IndexGuildSettingsAuditGuild ON GuildSettingsAudit(DiscordGuildId, Timestamp);
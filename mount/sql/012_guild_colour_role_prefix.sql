@@ -0,0 +1,2 @@
+-- What the names of colour roles start with in this guild; NULL means `colour-`
+ALTER TABLE Guilds ADD COLUMN ColourRolePrefix TEXT;
@@ -0,0 +1,5 @@
+DROP TABLE IF EXISTS CustomColourRoles;
+ALTER TABLE Members DROP COLUMN CustomColourRoleId;
+ALTER TABLE Guilds DROP COLUMN CustomColourMinContrastLight;
+ALTER TABLE Guilds DROP COLUMN CustomColourMinContrastDark;
+ALTER TABLE Guilds DROP COLUMN CustomColoursEnabled;
@@ -0,0 +1,19 @@
+-- Whether members may choose any colour, and how readable it must be against Discord's
+-- dark and light themes, as a contrast ratio; NULL means the default
+ALTER TABLE Guilds ADD COLUMN CustomColoursEnabled INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE Guilds ADD COLUMN CustomColourMinContrastDark REAL;
+ALTER TABLE Guilds ADD COLUMN CustomColourMinContrastLight REAL;
+
+-- The role giving a member the colour they chose themselves, if any
+ALTER TABLE Members ADD COLUMN CustomColourRoleId INTEGER;
+
+-- Roles made by the bot for custom colours, shared by every member who chose the same colour
+CREATE TABLE IF NOT EXISTS CustomColourRoles (
+    DiscordGuildId INTEGER NOT NULL,
+    DiscordRoleId INTEGER NOT NULL,
+    Colour INTEGER NOT NULL,
+
+    FOREIGN KEY (DiscordGuildId) REFERENCES Guilds(DiscordGuildId),
+    PRIMARY KEY (DiscordGuildId, DiscordRoleId),
+    UNIQUE (DiscordGuildId, Colour)
+);
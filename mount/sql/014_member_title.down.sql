@@ -0,0 +1 @@
+ALTER TABLE Members DROP COLUMN Title;
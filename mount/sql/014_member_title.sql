@@ -0,0 +1,3 @@
+-- The member's title in this guild, shown instead of their global title; NULL means
+-- the global title is used
+ALTER TABLE Members ADD COLUMN Title TEXT;
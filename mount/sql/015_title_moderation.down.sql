@@ -0,0 +1,8 @@
+DROP INDEX IF EXISTS IndexTitleModerationLogGuild;
+DROP TABLE IF EXISTS TitleModerationLog;
+DROP INDEX IF EXISTS IndexPendingTitlesMessage;
+DROP TABLE IF EXISTS PendingTitles;
+DROP TABLE IF EXISTS TitleFilters;
+ALTER TABLE Members DROP COLUMN TitleLocked;
+ALTER TABLE Guilds DROP COLUMN TitleApprovalChannelId;
+ALTER TABLE Guilds DROP COLUMN TitleApprovalEnabled;
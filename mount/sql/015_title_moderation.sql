@@ -0,0 +1,47 @@
+-- Whether titles in this guild need a moderator's approval, and the channel where
+-- moderators are asked; NULL means the channel the title was set in
+ALTER TABLE Guilds ADD COLUMN TitleApprovalEnabled INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE Guilds ADD COLUMN TitleApprovalChannelId INTEGER;
+
+-- Whether a moderator locked the member's title in this guild
+ALTER TABLE Members ADD COLUMN TitleLocked INTEGER NOT NULL DEFAULT 0;
+
+-- Words and regular expressions that titles in a guild must not match
+CREATE TABLE IF NOT EXISTS TitleFilters (
+    FilterId INTEGER PRIMARY KEY AUTOINCREMENT,
+    DiscordGuildId INTEGER NOT NULL,
+    IsRegex INTEGER NOT NULL,
+    Pattern TEXT NOT NULL,
+
+    FOREIGN KEY (DiscordGuildId) REFERENCES Guilds(DiscordGuildId),
+    UNIQUE (DiscordGuildId, IsRegex, Pattern)
+);
+
+-- Titles waiting for a moderator's approval, at most one per member, with the message
+-- moderators react to
+CREATE TABLE IF NOT EXISTS PendingTitles (
+    DiscordGuildId INTEGER NOT NULL,
+    DiscordUserId INTEGER NOT NULL,
+    Title TEXT NOT NULL,
+    ChannelId INTEGER NOT NULL,
+    MessageId INTEGER NOT NULL,
+    Timestamp INTEGER NOT NULL,
+
+    FOREIGN KEY (DiscordGuildId) REFERENCES Guilds(DiscordGuildId),
+    PRIMARY KEY (DiscordGuildId, DiscordUserId)
+);
+CREATE INDEX IF NOT EXISTS IndexPendingTitlesMessage ON PendingTitles(MessageId);
+
+-- Everything moderators, and the title filters, did to titles in a guild
+CREATE TABLE IF NOT EXISTS TitleModerationLog (
+    LogId INTEGER PRIMARY KEY AUTOINCREMENT,
+    DiscordGuildId INTEGER NOT NULL,
+    -- NULL when the filters refused a title by themselves
+    ModeratorId INTEGER,
+    -- The member whose title it was; NULL for changes to the filters
+    DiscordUserId INTEGER,
+    Action TEXT NOT NULL,
+    Detail TEXT,
+    Timestamp INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS IndexTitleModerationLogGuild ON TitleModerationLog(DiscordGuildId, Timestamp);
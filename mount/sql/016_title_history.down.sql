@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS IndexTitleHistoryUser;
+DROP TABLE IF EXISTS TitleHistory;
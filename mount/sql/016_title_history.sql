@@ -0,0 +1,17 @@
+-- Every title users have set, globally or in a guild, so they can go back to one
+CREATE TABLE IF NOT EXISTS TitleHistory (
+    HistoryId INTEGER PRIMARY KEY AUTOINCREMENT,
+    DiscordUserId INTEGER NOT NULL,
+    -- NULL for global titles
+    DiscordGuildId INTEGER,
+    -- NULL when the title was cleared
+    Title TEXT,
+    Timestamp INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS IndexTitleHistoryUser ON TitleHistory(DiscordUserId, DiscordGuildId, HistoryId);
+
+-- Titles set before there was a history are its first entries
+INSERT INTO TitleHistory(DiscordUserId, DiscordGuildId, Title, Timestamp)
+    SELECT DiscordUserId, NULL, Title, strftime('%s', 'now') FROM Users WHERE Title IS NOT NULL;
+INSERT INTO TitleHistory(DiscordUserId, DiscordGuildId, Title, Timestamp)
+    SELECT DiscordUserId, DiscordGuildId, Title, strftime('%s', 'now') FROM Members WHERE Title IS NOT NULL;
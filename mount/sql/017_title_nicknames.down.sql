@@ -0,0 +1,3 @@
+ALTER TABLE Members DROP COLUMN NicknameBeforeTitle;
+ALTER TABLE Members DROP COLUMN TitleNicknameSynced;
+ALTER TABLE Guilds DROP COLUMN TitleNicknamesEnabled;
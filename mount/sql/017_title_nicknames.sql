@@ -0,0 +1,7 @@
+-- Whether members' titles are put in front of their nicknames in this guild
+ALTER TABLE Guilds ADD COLUMN TitleNicknamesEnabled INTEGER NOT NULL DEFAULT 0;
+
+-- Whether the bot put the member's title in their nickname, and the nickname they had
+-- before, to put back when the title goes; NULL means they had none
+ALTER TABLE Members ADD COLUMN TitleNicknameSynced INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE Members ADD COLUMN NicknameBeforeTitle TEXT;